@@ -0,0 +1,162 @@
+//! Async facade over the Cortex runtime
+//!
+//! Engines are synchronous and CPU-bound, so `AsyncCortex` runs them on
+//! tokio's blocking pool and streams tokens back over a channel. Enabled by
+//! the `async` feature; the synchronous API needs no tokio.
+//!
+//! ```rust,ignore
+//! use cortex::{AsyncCortex, Cortex, GenerationConfig, Message};
+//!
+//! let ctx = AsyncCortex::new(Cortex::new());
+//! let mut stream = ctx.chat_stream(vec![Message::user("Hello")], GenerationConfig::default());
+//! while let Some(token) = stream.next().await {
+//!     print!("{}", token);
+//! }
+//! let response = stream.finish().await?;
+//! ```
+
+use crate::config::GenerationConfig;
+use crate::{Cortex, CortexError, Message, Result};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Cloneable handle to a `Cortex` usable from async code
+///
+/// Calls are serialized: a generation holds the runtime until it finishes.
+#[derive(Clone)]
+pub struct AsyncCortex {
+    inner: Arc<Mutex<Cortex>>,
+}
+
+/// Tokens of a generation running in the background
+pub struct TokenStream {
+    tokens: mpsc::UnboundedReceiver<String>,
+    result: JoinHandle<Result<String>>,
+}
+
+impl TokenStream {
+    /// Next generated token, or `None` once generation has ended
+    pub async fn next(&mut self) -> Option<String> {
+        self.tokens.recv().await
+    }
+
+    /// Wait for generation to end and return the full text
+    ///
+    /// Dropping the stream instead stops generation at the next token.
+    pub async fn finish(self) -> Result<String> {
+        join(self.result).await
+    }
+}
+
+impl AsyncCortex {
+    /// Wrap a runtime for async use
+    pub fn new(ctx: Cortex) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ctx)),
+        }
+    }
+
+    /// Lock the runtime for synchronous access (memory, checkpoints, ...)
+    ///
+    /// Blocks while a generation is running; don't hold it across `.await`.
+    pub fn lock(&self) -> MutexGuard<'_, Cortex> {
+        lock(&self.inner)
+    }
+
+    /// Chat with message history on the blocking pool
+    pub async fn chat_async(
+        &self,
+        messages: Vec<Message>,
+        config: GenerationConfig,
+    ) -> Result<String> {
+        let inner = self.inner.clone();
+        join(tokio::task::spawn_blocking(move || {
+            lock(&inner).chat_with_config(&messages, &config)
+        }))
+        .await
+    }
+
+    /// Generate a completion for raw text on the blocking pool
+    pub async fn generate_async(&self, prompt: String, config: GenerationConfig) -> Result<String> {
+        let inner = self.inner.clone();
+        join(tokio::task::spawn_blocking(move || {
+            lock(&inner).generate_with_config(&prompt, &config)
+        }))
+        .await
+    }
+
+    /// Chat with message history, streaming tokens as they are generated
+    pub fn chat_stream(&self, messages: Vec<Message>, config: GenerationConfig) -> TokenStream {
+        let inner = self.inner.clone();
+        spawn_stream(move |callback| lock(&inner).chat_streaming(&messages, &config, callback))
+    }
+
+    /// Generate a completion for raw text, streaming tokens
+    pub fn generate_stream(&self, prompt: String, config: GenerationConfig) -> TokenStream {
+        let inner = self.inner.clone();
+        spawn_stream(move |callback| lock(&inner).generate_streaming(&prompt, &config, callback))
+    }
+}
+
+impl From<Cortex> for AsyncCortex {
+    fn from(ctx: Cortex) -> Self {
+        Self::new(ctx)
+    }
+}
+
+/// Run a streaming generation on the blocking pool, forwarding its tokens
+fn spawn_stream<F>(generate: F) -> TokenStream
+where
+    F: FnOnce(&mut dyn FnMut(&str) -> bool) -> Result<String> + Send + 'static,
+{
+    let (tx, tokens) = mpsc::unbounded_channel();
+    let result = tokio::task::spawn_blocking(move || {
+        // Stop generating once the receiver is gone
+        generate(&mut |token| tx.send(token.to_string()).is_ok())
+    });
+    TokenStream { tokens, result }
+}
+
+/// A panic during generation poisons the lock, but the runtime's state is
+/// still usable, so recover it
+fn lock(inner: &Mutex<Cortex>) -> MutexGuard<'_, Cortex> {
+    inner.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+async fn join(handle: JoinHandle<Result<String>>) -> Result<String> {
+    handle
+        .await
+        .map_err(|e| CortexError::Inference(format!("Generation task failed: {}", e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_streamed_chat_matches_sync() {
+        let messages = vec![Message::user("Tell me a story")];
+        let config = GenerationConfig::default();
+        let expected = Cortex::new().chat_with_config(&messages, &config).unwrap();
+
+        let ctx = AsyncCortex::new(Cortex::new());
+        let mut stream = ctx.chat_stream(messages.clone(), config.clone());
+        let mut streamed = String::new();
+        let mut chunks = 0;
+        while let Some(token) = stream.next().await {
+            streamed.push_str(&token);
+            chunks += 1;
+        }
+        let response = stream.finish().await.unwrap();
+
+        assert!(chunks > 1);
+        assert_eq!(streamed, expected);
+        assert_eq!(response, expected);
+        assert_eq!(ctx.lock().messages().len(), 2);
+
+        let again = ctx.chat_async(messages, config).await.unwrap();
+        assert!(!again.is_empty());
+        assert_eq!(ctx.lock().messages().len(), 4);
+    }
+}
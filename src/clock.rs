@@ -0,0 +1,55 @@
+//! Wall-clock timestamps
+//!
+//! Clocks set before 1970 happen on misconfigured devices and containers;
+//! timestamps clamp to 0 there rather than panicking. Tests move the clock
+//! forward with `advance` instead of sleeping.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+thread_local! {
+    /// Seconds added to the clock on this thread by `advance`
+    static OFFSET: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// Current time in seconds since the unix epoch, 0 if the clock is earlier
+pub(crate) fn now_unix_secs() -> u64 {
+    #[cfg(test)]
+    let offset = OFFSET.with(|offset| offset.get());
+    #[cfg(not(test))]
+    let offset = 0;
+    unix_secs(SystemTime::now()) + offset
+}
+
+/// Move this thread's clock forward by `secs`
+#[cfg(test)]
+pub(crate) fn advance(secs: u64) {
+    OFFSET.with(|offset| offset.set(offset.get() + secs));
+}
+
+/// Seconds from the unix epoch to `time`, 0 for times before it
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pre_epoch_clock_is_zero() {
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(3600);
+        assert_eq!(unix_secs(before_epoch), 0);
+        assert_eq!(unix_secs(UNIX_EPOCH + Duration::from_secs(42)), 42);
+        assert!(now_unix_secs() > 0);
+    }
+
+    #[test]
+    fn test_advance() {
+        let before = now_unix_secs();
+        advance(3600);
+        assert!(now_unix_secs() >= before + 3600);
+    }
+}
@@ -1,6 +1,9 @@
 //! Configuration for Cortex runtime
 
+use crate::inference::{DownloadOptions, Grammar};
+use crate::{CortexError, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 /// Main configuration for the Cortex runtime
@@ -29,6 +32,58 @@ pub struct CortexConfig {
 
     /// Generation defaults
     pub generation: GenerationConfig,
+
+    /// Detect the language of user messages before formatting the prompt
+    pub detect_language: bool,
+
+    /// What to do when the conversation outgrows the context window
+    #[serde(default)]
+    pub context_policy: ContextPolicy,
+
+    /// Prompt asking the model to summarize old messages
+    ///
+    /// `{conversation}` is replaced with the transcript being summarized.
+    #[serde(default = "default_summary_prompt")]
+    pub summary_prompt: String,
+
+    /// Where downloaded tokenizers and embedding models are cached
+    ///
+    /// `None` uses `CORTEX_CACHE_DIR`, or the platform cache directory if
+    /// that is unset.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Never download tokenizers or embedding models
+    ///
+    /// Loading fails at once, naming where to put the files, instead of
+    /// waiting on the network. `CORTEX_OFFLINE` enables this too.
+    #[serde(default)]
+    pub offline: bool,
+
+    /// Fail to load a model whose tokenizer vocabulary doesn't match it
+    ///
+    /// By default a mismatch is only logged as a warning.
+    #[serde(default)]
+    pub strict_tokenizer: bool,
+}
+
+/// How to handle conversations that outgrow the context window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ContextPolicy {
+    /// Always send the full history
+    #[default]
+    Full,
+    /// Fold the oldest `batch` messages into a running summary
+    ///
+    /// Folds until the prompt leaves room for `max_tokens` of reply.
+    /// Leading system messages are kept as-is.
+    Summarize { batch: usize },
+}
+
+fn default_summary_prompt() -> String {
+    "Summarize the following conversation in a few sentences, keeping any facts, \
+     names and decisions needed to continue it.\n\n{conversation}\n\nSummary:"
+        .to_string()
 }
 
 impl Default for CortexConfig {
@@ -42,6 +97,12 @@ impl Default for CortexConfig {
             memory: MemoryConfig::default(),
             state: StateConfig::default(),
             generation: GenerationConfig::default(),
+            detect_language: false,
+            context_policy: ContextPolicy::Full,
+            summary_prompt: default_summary_prompt(),
+            cache_dir: None,
+            offline: false,
+            strict_tokenizer: false,
         }
     }
 }
@@ -73,15 +134,58 @@ impl CortexConfig {
         self
     }
 
+    /// Enable language detection on user messages
+    pub fn with_language_detection(mut self, enabled: bool) -> Self {
+        self.detect_language = enabled;
+        self
+    }
+
+    /// Set how to handle conversations that outgrow the context window
+    pub fn with_context_policy(mut self, policy: ContextPolicy) -> Self {
+        self.context_policy = policy;
+        self
+    }
+
+    /// Set where downloaded tokenizers and embedding models are cached
+    pub fn with_cache_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(path.into());
+        self
+    }
+
+    /// Forbid network access when loading models
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Fail to load models whose tokenizer doesn't match their vocabulary
+    pub fn with_strict_tokenizer(mut self, strict: bool) -> Self {
+        self.strict_tokenizer = strict;
+        self
+    }
+
+    /// How loaders may fetch missing files
+    pub(crate) fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            cache_dir: self.cache_dir.clone(),
+            offline: self.offline,
+        }
+    }
+
     /// Enable memory persistence
+    ///
+    /// Memory is loaded from `path` when the runtime starts, if the file
+    /// exists, and written back after every mutation.
     pub fn with_memory_persistence(mut self, path: impl Into<PathBuf>) -> Self {
         self.memory.persist_path = Some(path.into());
+        self.memory.auto_persist = true;
         self
     }
 }
 
 /// Configuration for the memory subsystem
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct MemoryConfig {
     /// Embedding dimension (must match model)
     pub embedding_dim: usize,
@@ -92,11 +196,58 @@ pub struct MemoryConfig {
     /// Path to persist memory (None = in-memory only)
     pub persist_path: Option<PathBuf>,
 
+    /// Write to `persist_path` after every mutation
+    pub auto_persist: bool,
+
     /// Number of results for similarity search
     pub default_search_k: usize,
 
     /// Similarity threshold (0.0 - 1.0)
     pub similarity_threshold: f32,
+
+    /// Per-namespace similarity thresholds overriding the global one
+    pub namespace_thresholds: HashMap<String, f32>,
+
+    /// Recency decay rate per second for `Memory::search_with_recency`
+    ///
+    /// Scores are scaled by `exp(-recency_lambda * age_seconds)`; 0 ranks
+    /// purely by similarity.
+    pub recency_lambda: f32,
+
+    /// Which entry to drop when `max_entries` is reached
+    pub eviction: EvictionPolicy,
+
+    /// How embeddings are held in RAM
+    pub embedding_storage: EmbeddingStorage,
+
+    /// Number of recall query embeddings to cache (0 disables the cache)
+    pub query_cache_size: usize,
+}
+
+/// How the vector store holds embeddings in RAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EmbeddingStorage {
+    /// Full-precision f32 vectors
+    #[default]
+    F32,
+    /// Int8 scalar quantization with a per-vector scale, about 4x smaller
+    ///
+    /// Similarity scores shift slightly. Entries returned by reference
+    /// (`Memory::read`, `Memory::entries`) have an empty `embedding`;
+    /// search results, `get_state` and exports carry dequantized vectors.
+    Int8,
+}
+
+/// Policy for choosing which memory to evict at capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// Evict the oldest inserted entry
+    #[default]
+    Fifo,
+    /// Evict the entry least recently inserted, read, or returned by search
+    Lru,
+    /// Evict the entry least similar to recent search queries
+    LeastRelevant,
 }
 
 impl Default for MemoryConfig {
@@ -105,8 +256,14 @@ impl Default for MemoryConfig {
             embedding_dim: 4096, // Common for 7B/8B models
             max_entries: 100_000,
             persist_path: None,
+            auto_persist: false,
             default_search_k: 5,
             similarity_threshold: 0.7,
+            namespace_thresholds: HashMap::new(),
+            recency_lambda: 0.0,
+            eviction: EvictionPolicy::Fifo,
+            embedding_storage: EmbeddingStorage::F32,
+            query_cache_size: 256,
         }
     }
 }
@@ -120,8 +277,26 @@ pub struct StateConfig {
     /// Maximum number of checkpoints to keep
     pub max_checkpoints: usize,
 
-    /// Auto-checkpoint interval (in messages, 0 = disabled)
+    /// Auto-checkpoint interval (in messages sent to `chat`, 0 = disabled)
     pub auto_checkpoint_interval: usize,
+
+    /// Gzip checkpoint files written to `directory`
+    #[serde(default)]
+    pub compress: bool,
+
+    /// Encoding for checkpoint files written to `directory`
+    #[serde(default)]
+    pub format: StateFormat,
+}
+
+/// On-disk encoding for checkpoint files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum StateFormat {
+    /// Compact binary encoding, tied to the current struct layout
+    #[default]
+    Bincode,
+    /// Pretty-printed JSON, portable and human-inspectable
+    Json,
 }
 
 impl Default for StateConfig {
@@ -130,12 +305,15 @@ impl Default for StateConfig {
             directory: None,
             max_checkpoints: 100,
             auto_checkpoint_interval: 0,
+            compress: false,
+            format: StateFormat::Bincode,
         }
     }
 }
 
 /// Configuration for text generation
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct GenerationConfig {
     /// Maximum tokens to generate
     pub max_tokens: u32,
@@ -149,11 +327,47 @@ pub struct GenerationConfig {
     /// Top-k sampling (0 = disabled)
     pub top_k: u32,
 
+    /// Min-p sampling: drop tokens below `min_p * max_prob` (0.0 = disabled)
+    pub min_p: f32,
+
     /// Repetition penalty
     pub repeat_penalty: f32,
 
     /// Stop sequences
     pub stop: Vec<String>,
+
+    /// Token IDs that end generation when sampled, checked before decoding
+    ///
+    /// Catches special tokens with no text and stop strings that a tokenizer
+    /// may split across tokens.
+    pub stop_token_ids: Vec<u32>,
+
+    /// Number of top alternative tokens reported alongside logprobs
+    pub n_logprobs: usize,
+
+    /// Added to the logits of the given token IDs before sampling
+    ///
+    /// A bias of -100 or below (including `f32::NEG_INFINITY`) bans the
+    /// token. IDs outside the vocabulary are ignored.
+    pub logit_bias: HashMap<u32, f32>,
+
+    /// Drop the start of a prompt that doesn't fit the context window
+    ///
+    /// When false, a prompt whose length plus `max_tokens` exceeds the
+    /// engine's context size is an error.
+    pub truncate_prompt: bool,
+
+    /// Longest token cycle checked for degenerate loops (0 = disabled)
+    pub loop_max_period: usize,
+
+    /// Consecutive repeats of a cycle that stop generation
+    pub loop_repeats: usize,
+
+    /// Grammar the output must conform to (None = unconstrained)
+    ///
+    /// Generation that ends before the document is complete, other than by
+    /// the callback cancelling it, fails rather than returning a prefix.
+    pub grammar: Option<Grammar>,
 }
 
 impl Default for GenerationConfig {
@@ -163,8 +377,16 @@ impl Default for GenerationConfig {
             temperature: 0.7,
             top_p: 0.9,
             top_k: 40,
+            min_p: 0.0,
             repeat_penalty: 1.1,
             stop: vec![],
+            stop_token_ids: vec![],
+            n_logprobs: 0,
+            logit_bias: HashMap::new(),
+            truncate_prompt: false,
+            loop_max_period: 0,
+            loop_repeats: 0,
+            grammar: None,
         }
     }
 }
@@ -188,6 +410,46 @@ impl GenerationConfig {
         }
     }
 
+    /// Check that sampling parameters are in range
+    pub fn validate(&self) -> Result<()> {
+        if self.temperature.is_nan() || self.temperature < 0.0 {
+            return Err(CortexError::Config(format!(
+                "temperature must be >= 0, got {}",
+                self.temperature
+            )));
+        }
+        if self.top_p.is_nan() || self.top_p <= 0.0 || self.top_p > 1.0 {
+            return Err(CortexError::Config(format!(
+                "top_p must be in (0, 1], got {}",
+                self.top_p
+            )));
+        }
+        if self.repeat_penalty.is_nan() || self.repeat_penalty <= 0.0 {
+            return Err(CortexError::Config(format!(
+                "repeat_penalty must be > 0, got {}",
+                self.repeat_penalty
+            )));
+        }
+        if self.max_tokens == 0 {
+            return Err(CortexError::Config(
+                "max_tokens must be at least 1".to_string(),
+            ));
+        }
+        if self.loop_max_period > 0 && self.loop_repeats < 2 {
+            return Err(CortexError::Config(format!(
+                "loop_repeats must be at least 2 when loop detection is on, got {}",
+                self.loop_repeats
+            )));
+        }
+        if let Some((token, _)) = self.logit_bias.iter().find(|(_, bias)| bias.is_nan()) {
+            return Err(CortexError::Config(format!(
+                "logit_bias for token {} is NaN",
+                token
+            )));
+        }
+        Ok(())
+    }
+
     pub fn with_max_tokens(mut self, n: u32) -> Self {
         self.max_tokens = n;
         self
@@ -198,9 +460,86 @@ impl GenerationConfig {
         self
     }
 
+    pub fn with_min_p(mut self, min_p: f32) -> Self {
+        self.min_p = min_p;
+        self
+    }
+
     pub fn with_stop(mut self, stop: Vec<String>) -> Self {
         self.stop = stop;
         self
     }
+
+    pub fn with_stop_token_ids(mut self, ids: Vec<u32>) -> Self {
+        self.stop_token_ids = ids;
+        self
+    }
+
+    pub fn with_logit_bias(mut self, bias: HashMap<u32, f32>) -> Self {
+        self.logit_bias = bias;
+        self
+    }
+
+    pub fn with_truncate_prompt(mut self, truncate: bool) -> Self {
+        self.truncate_prompt = truncate;
+        self
+    }
+
+    /// Stop once a cycle of up to `max_period` tokens repeats `repeats` times
+    pub fn with_loop_detection(mut self, max_period: usize, repeats: usize) -> Self {
+        self.loop_max_period = max_period;
+        self.loop_repeats = repeats;
+        self
+    }
+
+    pub fn with_grammar(mut self, grammar: Grammar) -> Self {
+        self.grammar = Some(grammar);
+        self
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_generation_config() {
+        assert!(GenerationConfig::default().validate().is_ok());
+        assert!(GenerationConfig::deterministic().validate().is_ok());
+        assert!(GenerationConfig::creative().validate().is_ok());
+
+        let base = GenerationConfig::default;
+        let invalid = [
+            ("temperature", base().with_temperature(-1.0)),
+            (
+                "top_p",
+                GenerationConfig {
+                    top_p: 2.0,
+                    ..base()
+                },
+            ),
+            (
+                "top_p",
+                GenerationConfig {
+                    top_p: 0.0,
+                    ..base()
+                },
+            ),
+            (
+                "repeat_penalty",
+                GenerationConfig {
+                    repeat_penalty: 0.0,
+                    ..base()
+                },
+            ),
+            ("max_tokens", base().with_max_tokens(0)),
+            ("loop_repeats", base().with_loop_detection(4, 1)),
+        ];
+        for (field, config) in invalid {
+            match config.validate() {
+                Err(CortexError::Config(msg)) => assert!(msg.starts_with(field), "{}", msg),
+                other => panic!("expected config error for {}, got {:?}", field, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Sharing one runtime between threads
+//!
+//! Engines are single-threaded, so `CortexHandle` owns a `Cortex` on a
+//! dedicated worker thread and runs requests on it one at a time. Handles
+//! are cheap to clone and can be used from any thread.
+//!
+//! ```rust,ignore
+//! use cortex::{Cortex, CortexHandle, Message};
+//!
+//! let handle = CortexHandle::spawn_with(|| Cortex::load("model.gguf"))?;
+//! let worker = handle.clone();
+//! std::thread::spawn(move || worker.chat(vec![Message::user("Hello")]));
+//! let embedding = handle.embed("The sky is blue")?;
+//! ```
+
+use crate::config::GenerationConfig;
+use crate::{Cortex, CortexError, Message, Result};
+use std::sync::mpsc;
+
+/// Work sent to the worker thread
+type Job = Box<dyn FnOnce(&mut Cortex) + Send>;
+
+/// Cloneable handle to a `Cortex` running on its own thread
+///
+/// Requests from every clone are queued and run in order. The worker
+/// exits once all handles are dropped.
+#[derive(Clone)]
+pub struct CortexHandle {
+    jobs: mpsc::Sender<Job>,
+}
+
+/// Chunks streamed from a generation running on the worker
+///
+/// Iterate to receive chunks as they are generated, then call `finish`
+/// for the result. Dropping it stops generation at the next chunk.
+pub struct TokenReceiver<T> {
+    tokens: mpsc::Receiver<String>,
+    result: mpsc::Receiver<Result<T>>,
+}
+
+impl<T> Iterator for TokenReceiver<T> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.tokens.recv().ok()
+    }
+}
+
+impl<T> TokenReceiver<T> {
+    /// Wait for generation to end and return its result
+    pub fn finish(self) -> Result<T> {
+        self.result.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+impl CortexHandle {
+    /// Move `ctx` onto a new worker thread
+    pub fn spawn(ctx: Cortex) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        std::thread::spawn(move || {
+            let mut ctx = ctx;
+            for job in queue {
+                job(&mut ctx);
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Build the runtime on a new worker thread
+    ///
+    /// The engine never leaves the worker, so this works for engines that
+    /// must stay on the thread that created them. Load errors are returned
+    /// here.
+    pub fn spawn_with<F>(build: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<Cortex> + Send + 'static,
+    {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (loaded, load_result) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut ctx = match build() {
+                Ok(ctx) => {
+                    let _ = loaded.send(Ok(()));
+                    ctx
+                }
+                Err(e) => {
+                    let _ = loaded.send(Err(e));
+                    return;
+                }
+            };
+            for job in queue {
+                job(&mut ctx);
+            }
+        });
+        load_result.recv().unwrap_or_else(|_| Err(stopped()))?;
+        Ok(Self { jobs })
+    }
+
+    /// Run `f` on the worker and wait for its result
+    ///
+    /// Fails with `CortexError::State` if the worker has stopped, e.g.
+    /// after a panic in an earlier request.
+    pub fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Cortex) -> Result<T> + Send + 'static,
+    {
+        let (reply, result) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |ctx| {
+                let _ = reply.send(f(ctx));
+            }))
+            .map_err(|_| stopped())?;
+        result.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+
+    /// Run `f` on the worker, streaming the chunks it passes to its callback
+    ///
+    /// The callback returns false once the receiver is dropped.
+    pub fn stream<T, F>(&self, f: F) -> Result<TokenReceiver<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Cortex, &mut dyn FnMut(&str) -> bool) -> Result<T> + Send + 'static,
+    {
+        let (token_tx, tokens) = mpsc::channel();
+        let (reply, result) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |ctx| {
+                let output = f(ctx, &mut |token| token_tx.send(token.to_string()).is_ok());
+                let _ = reply.send(output);
+            }))
+            .map_err(|_| stopped())?;
+        Ok(TokenReceiver { tokens, result })
+    }
+
+    /// Continue the conversation with `messages`
+    pub fn chat(&self, messages: Vec<Message>) -> Result<String> {
+        self.call(move |ctx| ctx.chat(&messages))
+    }
+
+    /// Continue the conversation, streaming the reply
+    pub fn chat_stream(
+        &self,
+        messages: Vec<Message>,
+        config: GenerationConfig,
+    ) -> Result<TokenReceiver<String>> {
+        self.stream(move |ctx, callback| ctx.chat_streaming(&messages, &config, callback))
+    }
+
+    /// Generate a completion for raw text
+    pub fn generate(&self, prompt: impl Into<String>) -> Result<String> {
+        let prompt = prompt.into();
+        self.call(move |ctx| ctx.generate(&prompt))
+    }
+
+    /// Embed text
+    pub fn embed(&self, text: impl Into<String>) -> Result<Vec<f32>> {
+        let text = text.into();
+        self.call(move |ctx| ctx.embed(&text))
+    }
+}
+
+fn stopped() -> CortexError {
+    CortexError::State("Cortex worker thread has stopped".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_requests() {
+        let handle = CortexHandle::spawn(Cortex::new());
+        let expected = Cortex::new().embed("shared text").unwrap();
+
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    let prompt = format!("prompt {}", i);
+                    let output = handle.generate(prompt.clone()).unwrap();
+                    assert!(output.contains(&prompt), "{}", output);
+                    handle.embed("shared text").unwrap()
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), expected);
+        }
+
+        let mut stream = handle
+            .chat_stream(vec![Message::user("Hello")], GenerationConfig::default())
+            .unwrap();
+        let streamed: String = stream.by_ref().collect();
+        assert_eq!(stream.finish().unwrap(), streamed);
+        assert_eq!(handle.call(|ctx| Ok(ctx.messages().len())).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_spawn_with_reports_errors() {
+        let err = CortexHandle::spawn_with(|| Err(CortexError::ModelLoad("missing".to_string())));
+        assert!(matches!(err, Err(CortexError::ModelLoad(_))));
+
+        let handle = CortexHandle::spawn_with(|| Ok(Cortex::new())).unwrap();
+        let panicked = handle.call(|_| -> Result<()> { panic!("boom") });
+        assert!(matches!(panicked, Err(CortexError::State(_))));
+        assert!(handle.generate("after").is_err());
+    }
+}
@@ -2,45 +2,173 @@
 //!
 //! Supports loading quantized GGUF models (llama.cpp format).
 //! Works with Llama, Mistral, Phi, Qwen, and other architectures.
+//! Unquantized Llama-family safetensors checkpoints load too.
 
 use crate::config::GenerationConfig;
 use crate::{CortexError, Result};
-use candle_core::{quantized::gguf_file, Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::models::quantized_llama::ModelWeights;
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::llama::{LlamaConfig, LlamaEosToks};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
 use tokenizers::Tokenizer;
 
-use super::{EngineState, TextEngine};
+use super::download::{hf_file_url, DownloadOptions, ProgressReporter};
+use super::model_info::ModelInfo;
+use super::grammar::mask_logits;
+use super::sampler::{Sampler, TemperatureSampler};
+use super::sampling::{apply_logit_bias, ends_in_loop, log_softmax, top_logprobs};
+use super::transformer::Transformer;
+use super::{
+    millis, EngineState, FinishReason, GenerationOutput, GenerationStats, RestoreMode,
+    StreamControl, StreamEvent, TextEngine, TokenInfo,
+};
+
+/// Maximum number of KV cache snapshots kept for warm restores
+const MAX_KV_SNAPSHOTS: usize = 4;
+
+/// Maximum number of prompt-prefix snapshots kept by `prefill`
+const MAX_PREFIX_SNAPSHOTS: usize = 4;
+
+/// Number of texts `embed_batch` runs through the model at once
+const EMBED_BATCH_SIZE: usize = 8;
+
+/// A copy of the model's KV cache and the tokens it covers
+struct KvSnapshot {
+    tokens: Vec<u32>,
+    model: Transformer,
+}
+
+/// Largest relative difference between tokenizer and model vocabulary
+/// sizes that is taken for padding rather than a mismatched tokenizer
+const VOCAB_TOLERANCE: f64 = 0.01;
+
+/// What a loader read from a checkpoint besides the weights
+struct ModelParts {
+    info: ModelInfo,
+    eos_token_id: u32,
+    bos_token_id: Option<u32>,
+    context_size: usize,
+    hidden_size: usize,
+    model_id: String,
+}
+
+/// Whether `path` names a safetensors checkpoint rather than a GGUF file
+///
+/// Directories and `.safetensors` files are safetensors; anything else is
+/// read as GGUF.
+fn is_safetensors(path: &Path) -> bool {
+    path.is_dir() || path.extension().is_some_and(|ext| ext == "safetensors")
+}
 
 /// Candle-based LLM engine supporting GGUF quantized models
 pub struct CandleLLM {
-    model: ModelWeights,
+    model: Transformer,
+    /// Freshly loaded weights with an empty KV cache, used by `clear`
+    base_model: Transformer,
     tokenizer: Tokenizer,
     device: Device,
     /// Tokens in current context
     tokens: Vec<u32>,
+    /// Tokens whose keys/values are currently in the model's KV cache
+    kv_tokens: Vec<u32>,
+    /// KV cache snapshots taken by `get_state`, most recent last
+    ///
+    /// Cloning the model only bumps reference counts, so a snapshot
+    /// costs no more than the cache tensors it keeps alive.
+    kv_snapshots: Mutex<Vec<KvSnapshot>>,
+    /// KV cache snapshots at prompt boundaries, most recent last
+    ///
+    /// Taken at the end of each prompt and where a prompt diverged from the
+    /// cached context (typically the end of a shared system prompt), so a
+    /// later prompt sharing that prefix only forwards its suffix.
+    prefix_snapshots: Vec<KvSnapshot>,
+    /// Tokens run through the model since loading
+    tokens_forwarded: usize,
+    /// How the last `set_state` rebuilt the KV cache
+    last_restore: Option<RestoreMode>,
     /// EOS token ID
     eos_token_id: u32,
+    /// BOS token ID, used to seed generation from an empty prompt
+    bos_token_id: Option<u32>,
     /// Context size
     context_size: usize,
     /// Hidden size for embeddings
     hidden_size: usize,
+    /// Model identity (GGUF `general.name` or file stem)
+    model_id: String,
+    /// Architecture and size, for display
+    info: ModelInfo,
+    /// Decoded text of every token, built lazily for grammar masking
+    token_texts: Option<Vec<String>>,
+    /// Custom token selection; `None` samples per the `GenerationConfig`
+    sampler: Option<Box<dyn Sampler>>,
 }
 
 // Safety: CandleLLM is Send when used from single thread context
 unsafe impl Send for CandleLLM {}
 
 impl CandleLLM {
-    /// Load a GGUF model from file
+    /// Load a model from a GGUF file or a safetensors checkpoint
     pub fn load(model_path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with_progress(model_path, &mut |_, _| {})
+    }
+
+    /// Load a model, reporting progress if the tokenizer is downloaded
+    ///
+    /// A directory or `.safetensors` file goes to `load_safetensors`;
+    /// anything else is read as GGUF.
+    pub fn load_with_progress(
+        model_path: impl AsRef<Path>,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
+        Self::load_with_cache_dir(model_path, None, reporter)
+    }
+
+    /// Load a model, caching any downloaded tokenizer under `cache_dir`
+    ///
+    /// `None` uses `CORTEX_CACHE_DIR`, or the platform cache directory if
+    /// that is unset.
+    pub fn load_with_cache_dir(
+        model_path: impl AsRef<Path>,
+        cache_dir: Option<&Path>,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
+        Self::load_with_options(model_path, &DownloadOptions::with_cache_dir(cache_dir), reporter)
+    }
+
+    /// Load a model, fetching any missing tokenizer as `options` allow
+    pub fn load_with_options(
+        model_path: impl AsRef<Path>,
+        options: &DownloadOptions,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
         let model_path = model_path.as_ref();
+        if is_safetensors(model_path) {
+            let dir = if model_path.is_dir() {
+                model_path
+            } else {
+                model_path.parent().unwrap_or(Path::new("."))
+            };
+            return Self::load_safetensors(dir);
+        }
+        Self::load_gguf(model_path, options, reporter)
+    }
 
-        println!("Loading model from {:?}...", model_path);
+    fn load_gguf(
+        model_path: &Path,
+        options: &DownloadOptions,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
+        let _span = tracing::info_span!("load_model", path = %model_path.display(), format = "gguf")
+            .entered();
 
         // Determine device
         let device = Self::get_device()?;
-        println!("Using device: {:?}", device);
+        tracing::info!(device = ?device, "Loading model");
 
         // Load GGUF file
         let mut file = std::fs::File::open(model_path)
@@ -61,27 +189,183 @@ impl CandleLLM {
         // Get EOS token
         let eos_token_id = Self::get_metadata_u32(&gguf, "tokenizer.ggml.eos_token_id")
             .unwrap_or(2);
+        let bos_token_id = Self::get_metadata_u32(&gguf, "tokenizer.ggml.bos_token_id");
+
+        let model_id = Self::get_metadata_string(&gguf, "general.name")
+            .or_else(|| {
+                model_path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .map(|s| s.to_string())
+            })
+            .unwrap_or_else(|| "unknown".to_string());
 
-        println!("Context size: {}, Hidden size: {}", context_size, hidden_size);
+        tracing::debug!(context_size, hidden_size, "Read model dimensions");
+
+        let mut info = ModelInfo::from_gguf_metadata(&gguf.metadata);
+        info.param_count = Some(gguf.tensor_infos.values().map(|t| t.shape.elem_count() as u64).sum());
+        if info.vocab_size.is_none() {
+            info.vocab_size = gguf.tensor_infos.get("token_embd.weight").map(|t| t.shape.dims()[0]);
+        }
+        if info.quantization.is_none() {
+            // Name the dtype holding most of the weights
+            let mut elements: HashMap<String, usize> = HashMap::new();
+            for tensor in gguf.tensor_infos.values() {
+                *elements.entry(format!("{:?}", tensor.ggml_dtype)).or_default() += tensor.shape.elem_count();
+            }
+            info.quantization = elements.into_iter().max_by_key(|(_, n)| *n).map(|(dtype, _)| dtype);
+        }
 
         // Load model weights
-        let model = ModelWeights::from_gguf(gguf, &mut file, &device)
+        let model = Transformer::from_gguf(&gguf, &mut file, &device)
             .map_err(|e| CortexError::ModelLoad(format!("Failed to load weights: {}", e)))?;
 
         // Try to load tokenizer from same directory or HF cache
-        let tokenizer = Self::load_tokenizer(model_path)?;
+        let tokenizer = Self::load_tokenizer(model_path, options, reporter)?;
 
-        println!("Model loaded successfully!");
+        tracing::info!(model_id = %model_id, "Model loaded");
 
-        Ok(Self {
+        let parts = ModelParts {
+            info,
+            eos_token_id,
+            bos_token_id,
+            context_size,
+            hidden_size,
+            model_id,
+        };
+        Ok(Self::from_parts(model, tokenizer, device, parts))
+    }
+
+    /// Load an unquantized Llama-family checkpoint from a directory
+    ///
+    /// The directory holds `config.json`, `tokenizer.json` and one or more
+    /// `*.safetensors` weight files, as in a Hugging Face model snapshot.
+    pub fn load_safetensors(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let _span =
+            tracing::info_span!("load_model", path = %dir.display(), format = "safetensors")
+                .entered();
+
+        let device = Self::get_device()?;
+        tracing::info!(device = ?device, "Loading model");
+
+        let config_str = std::fs::read_to_string(dir.join("config.json"))
+            .map_err(|e| CortexError::ModelLoad(format!("Failed to read config: {}", e)))?;
+        let config: LlamaConfig = serde_json::from_str(&config_str)
+            .map_err(|e| CortexError::ModelLoad(format!("Failed to parse config: {}", e)))?;
+        let config = config.into_config(false);
+
+        let mut weight_files: Vec<_> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "safetensors"))
+            .collect();
+        weight_files.sort();
+        if weight_files.is_empty() {
+            return Err(CortexError::ModelLoad(format!(
+                "No .safetensors files in {}",
+                dir.display()
+            )));
+        }
+
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&weight_files, DType::F32, &device)
+                .map_err(|e| CortexError::ModelLoad(format!("Failed to load weights: {}", e)))?
+        };
+        let err = |e: candle_core::Error| CortexError::ModelLoad(format!("Failed to build model: {}", e));
+        let model = Transformer::from_safetensors(vb, &config).map_err(err)?;
+        let param_count = weight_files
+            .iter()
+            .map(|path| {
+                let tensors = unsafe { candle_core::safetensors::MmapedSafetensors::new(path) }.map_err(err)?;
+                Ok(tensors.tensors().iter().map(|(_, view)| view.shape().iter().product::<usize>() as u64).sum::<u64>())
+            })
+            .sum::<Result<u64>>()?;
+
+        let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))
+            .map_err(|e| CortexError::ModelLoad(format!("Failed to load tokenizer: {}", e)))?;
+
+        let eos_token_id = match &config.eos_token_id {
+            Some(LlamaEosToks::Single(id)) => *id,
+            Some(LlamaEosToks::Multiple(ids)) => ids.first().copied().unwrap_or(2),
+            None => 2,
+        };
+        let model_id = dir
+            .file_name()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        tracing::debug!(
+            context_size = config.max_position_embeddings,
+            hidden_size = config.hidden_size,
+            "Read model dimensions"
+        );
+        tracing::info!(model_id = %model_id, "Model loaded");
+
+        let parts = ModelParts {
+            info: ModelInfo {
+                architecture: Some("llama".to_string()),
+                quantization: Some("F32".to_string()),
+                n_layers: Some(config.num_hidden_layers),
+                n_heads: Some(config.num_attention_heads),
+                vocab_size: Some(config.vocab_size),
+                param_count: Some(param_count),
+            },
+            eos_token_id,
+            bos_token_id: config.bos_token_id,
+            context_size: config.max_position_embeddings,
+            hidden_size: config.hidden_size,
+            model_id,
+        };
+        Ok(Self::from_parts(model, tokenizer, device, parts))
+    }
+
+    fn from_parts(model: Transformer, tokenizer: Tokenizer, device: Device, parts: ModelParts) -> Self {
+        let llm = Self {
+            base_model: model.clone(),
             model,
             tokenizer,
             device,
             tokens: Vec::new(),
-            eos_token_id,
-            context_size,
-            hidden_size,
-        })
+            kv_tokens: Vec::new(),
+            kv_snapshots: Mutex::new(Vec::new()),
+            prefix_snapshots: Vec::new(),
+            tokens_forwarded: 0,
+            last_restore: None,
+            eos_token_id: parts.eos_token_id,
+            bos_token_id: parts.bos_token_id,
+            context_size: parts.context_size,
+            hidden_size: parts.hidden_size,
+            model_id: parts.model_id,
+            info: parts.info,
+            token_texts: None,
+            sampler: None,
+        };
+        if let Err(e) = llm.check_tokenizer() {
+            tracing::warn!("{}", e);
+        }
+        llm
+    }
+
+    /// Check that the tokenizer's vocabulary fits the model's
+    ///
+    /// Models often pad their vocabulary, so sizes within 1% pass. A
+    /// larger difference usually means the wrong tokenizer was picked, and
+    /// fails with `CortexError::ModelLoad`. Loading only warns about it.
+    pub fn check_tokenizer(&self) -> Result<()> {
+        let Some(model_vocab) = self.info.vocab_size else {
+            return Ok(());
+        };
+        let tokenizer_vocab = self.tokenizer.get_vocab_size(true);
+        let difference = tokenizer_vocab.abs_diff(model_vocab);
+        if difference as f64 <= model_vocab.max(tokenizer_vocab) as f64 * VOCAB_TOLERANCE {
+            return Ok(());
+        }
+        Err(CortexError::ModelLoad(format!(
+            "Tokenizer has {} tokens but {} expects {}; put the model's own \
+             tokenizer.json next to it",
+            tokenizer_vocab, self.model_id, model_vocab
+        )))
     }
 
     fn get_device() -> Result<Device> {
@@ -90,7 +374,7 @@ impl CandleLLM {
         {
             match Device::new_metal(0) {
                 Ok(device) => return Ok(device),
-                Err(e) => eprintln!("Metal unavailable: {}, falling back to CPU", e),
+                Err(e) => tracing::warn!("Metal unavailable: {}, falling back to CPU", e),
             }
         }
 
@@ -99,7 +383,7 @@ impl CandleLLM {
         {
             match Device::new_cuda(0) {
                 Ok(device) => return Ok(device),
-                Err(e) => eprintln!("CUDA unavailable: {}, falling back to CPU", e),
+                Err(e) => tracing::warn!("CUDA unavailable: {}, falling back to CPU", e),
             }
         }
 
@@ -118,7 +402,30 @@ impl CandleLLM {
         })
     }
 
-    fn load_tokenizer(model_path: &Path) -> Result<Tokenizer> {
+    fn get_metadata_string(gguf: &gguf_file::Content, key: &str) -> Option<String> {
+        match gguf.metadata.get(key) {
+            Some(gguf_file::Value::String(s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    fn load_tokenizer(
+        model_path: &Path,
+        options: &DownloadOptions,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Tokenizer> {
+        let cache_dir = options.cache_root().join("tokenizers");
+        Self::find_tokenizer(model_path, &cache_dir, |url, dest| options.download(url, dest, reporter))
+    }
+
+    /// Load the tokenizer next to the model, from the cache, or download it
+    ///
+    /// `fetch` downloads a URL to a path in `cache_dir`.
+    fn find_tokenizer(
+        model_path: &Path,
+        cache_dir: &Path,
+        fetch: impl FnOnce(&str, &Path) -> Result<()>,
+    ) -> Result<Tokenizer> {
         // Try to find tokenizer in same directory
         let dir = model_path.parent().unwrap_or(Path::new("."));
         let tokenizer_path = dir.join("tokenizer.json");
@@ -153,63 +460,205 @@ impl CandleLLM {
             "NousResearch/Llama-2-7b-hf"
         };
 
-        println!("Downloading tokenizer from {}...", model_id);
-        Self::download_tokenizer(model_id)
-    }
-
-    fn download_tokenizer(model_id: &str) -> Result<Tokenizer> {
-        // Try direct HTTP download
-        let url = format!(
-            "https://huggingface.co/{}/resolve/main/tokenizer.json",
-            model_id
-        );
-
-        println!("Fetching tokenizer from {}", url);
-
-        let response = ureq::get(&url)
-            .call()
-            .map_err(|e| CortexError::ModelLoad(format!("Failed to download tokenizer: {}", e)))?;
-
-        let json: serde_json::Value = response.into_json()
-            .map_err(|e| CortexError::ModelLoad(format!("Failed to parse tokenizer JSON: {}", e)))?;
-
-        // Save to cache
-        let cache_dir = dirs::cache_dir()
-            .unwrap_or_else(|| std::path::PathBuf::from("."))
-            .join("cortex")
-            .join("tokenizers");
-        std::fs::create_dir_all(&cache_dir).ok();
-
+        // Reuse a tokenizer downloaded on an earlier run, so loading works offline
         let cache_path = cache_dir.join(format!("{}.json", model_id.replace('/', "_")));
-        std::fs::write(&cache_path, serde_json::to_string(&json).unwrap_or_default()).ok();
+        if !cache_path.exists() {
+            let url = hf_file_url(model_id, "tokenizer.json");
+            tracing::info!(url = %url, "Downloading tokenizer");
+            fetch(&url, &cache_path).map_err(|e| {
+                CortexError::ModelLoad(format!(
+                    "No tokenizer.json next to {} and downloading {}'s tokenizer failed ({}). \
+                     Place a tokenizer.json next to the model or at {}",
+                    model_path.display(),
+                    model_id,
+                    e,
+                    cache_path.display()
+                ))
+            })?;
+        }
 
-        Tokenizer::from_bytes(serde_json::to_vec(&json).unwrap_or_default())
-            .map_err(|e| CortexError::ModelLoad(format!("Failed to load tokenizer: {}", e)))
+        Tokenizer::from_file(&cache_path)
+            .map_err(|e| CortexError::ModelLoad(format!("Failed to load tokenizer {}: {}", cache_path.display(), e)))
     }
 
-    fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
+    /// Convert text to token IDs
+    pub fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
         let encoding = self.tokenizer.encode(text, true)
             .map_err(|e| CortexError::Inference(format!("Tokenization failed: {}", e)))?;
         Ok(encoding.get_ids().to_vec())
     }
 
-    fn decode(&self, tokens: &[u32]) -> Result<String> {
+    /// Convert token IDs back to text
+    pub fn decode(&self, tokens: &[u32]) -> Result<String> {
         self.tokenizer.decode(tokens, true)
             .map_err(|e| CortexError::Inference(format!("Decoding failed: {}", e)))
     }
 
+    /// Forward tokens at `pos`, extending the KV cache
+    ///
+    /// The model appends to its cache for any `pos > 0` and resets it at
+    /// `pos == 0`, so callers must pass either 0 or the current cache length.
     fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+        debug_assert!(pos == 0 || pos == self.kv_tokens.len());
+        self.kv_tokens.truncate(pos);
+        self.kv_tokens.extend_from_slice(tokens);
+        self.tokens_forwarded += tokens.len();
+
         // Create 1D tensor and add batch dimension for [batch, seq_len]
         let input = Tensor::new(tokens, &self.device)
             .map_err(|e| CortexError::Inference(e.to_string()))?
             .unsqueeze(0)  // Add batch dimension: [seq_len] -> [1, seq_len]
             .map_err(|e| CortexError::Inference(e.to_string()))?;
 
-        self.model.forward(&input, pos)
+        self.model.forward(&input, &[0], pos)
             .map_err(|e| CortexError::Inference(e.to_string()))
     }
 
-    fn sample(&self, logits: &Tensor, config: &GenerationConfig) -> Result<u32> {
+    /// Make `tokens` the current context and return the next-token logits
+    ///
+    /// Resumes from the longest cached prefix of `tokens` (the live KV cache
+    /// or a prefix snapshot) and only runs the remaining suffix through the
+    /// model. Without one, the whole prompt is re-encoded.
+    fn prefill(&mut self, tokens: &[u32]) -> Result<Vec<f32>> {
+        if tokens.is_empty() {
+            return Err(CortexError::Inference("Cannot prefill an empty context".to_string()));
+        }
+
+        // At least one token has to be forwarded to produce logits
+        let shared = common_prefix_len(&self.kv_tokens, tokens);
+        let live = if shared == self.kv_tokens.len() && shared < tokens.len() {
+            shared
+        } else {
+            0
+        };
+        let snapshot = self
+            .prefix_snapshots
+            .iter()
+            .filter(|s| s.tokens.len() > live && s.tokens.len() < tokens.len())
+            .filter(|s| tokens.starts_with(&s.tokens))
+            .max_by_key(|s| s.tokens.len());
+        let reuse = match snapshot {
+            Some(snapshot) => {
+                self.model = snapshot.model.clone();
+                self.kv_tokens = snapshot.tokens.clone();
+                self.kv_tokens.len()
+            }
+            None => live,
+        };
+
+        // Where the prompt diverged from the old cache is worth remembering
+        let branch_point = (shared > reuse && shared < tokens.len()).then_some(shared);
+
+        let mut logits = None;
+        for (i, &token) in tokens.iter().enumerate().skip(reuse) {
+            logits = Some(self.forward(&[token], i)?);
+            if branch_point == Some(i + 1) {
+                self.store_prefix_snapshot();
+            }
+        }
+        self.store_prefix_snapshot();
+        self.tokens = tokens.to_vec();
+
+        match logits {
+            Some(logits) => Self::last_logits(&logits),
+            None => Err(CortexError::Inference("Prefill produced no logits".to_string())),
+        }
+    }
+
+    /// Remember the current KV cache as a reusable prompt prefix
+    fn store_prefix_snapshot(&mut self) {
+        self.prefix_snapshots.retain(|s| s.tokens != self.kv_tokens);
+        self.prefix_snapshots.push(KvSnapshot {
+            tokens: self.kv_tokens.clone(),
+            model: self.model.clone(),
+        });
+        if self.prefix_snapshots.len() > MAX_PREFIX_SNAPSHOTS {
+            self.prefix_snapshots.remove(0);
+        }
+    }
+
+    /// Total tokens run through the model since loading
+    ///
+    /// Useful for checking how much work prompt caching saves.
+    pub fn tokens_forwarded(&self) -> usize {
+        self.tokens_forwarded
+    }
+
+    /// How the last `set_state` rebuilt the KV cache, if it was called
+    pub fn last_restore(&self) -> Option<RestoreMode> {
+        self.last_restore
+    }
+
+    /// Decode every vocabulary entry on its own (used for grammar masking)
+    fn build_token_texts(&self) -> Result<Vec<String>> {
+        (0..self.tokenizer.get_vocab_size(true) as u32)
+            .map(|id| self.decode(&[id]))
+            .collect()
+    }
+
+    /// End-of-sequence token ID
+    pub fn eos_token_id(&self) -> u32 {
+        self.eos_token_id
+    }
+
+    /// Pick tokens with `sampler` instead of the config's temperature/top-p
+    ///
+    /// `temperature`, `top_p` and `min_p` in the `GenerationConfig` are then
+    /// ignored; grammar masking and stop conditions still apply.
+    pub fn with_sampler(mut self, sampler: impl Sampler + 'static) -> Self {
+        self.set_sampler(sampler);
+        self
+    }
+
+    /// Replace the sampler used by subsequent generations
+    pub fn set_sampler(&mut self, sampler: impl Sampler + 'static) {
+        self.sampler = Some(Box::new(sampler));
+    }
+
+    /// Go back to sampling per the `GenerationConfig`
+    pub fn clear_sampler(&mut self) {
+        self.sampler = None;
+    }
+
+    /// Run tokens through the model starting at `pos` and return raw logits
+    ///
+    /// This is the low-level primitive for custom decoding loops. Tokens
+    /// already in context after `pos` are discarded, the new tokens are
+    /// appended, and the logits for the last position are returned.
+    ///
+    /// ```rust,ignore
+    /// let prompt = llm.tokenize("Hello")?;
+    /// let mut logits = llm.step(&prompt, 0)?;
+    /// for _ in 0..16 {
+    ///     let next = my_decoding_rule(&logits);
+    ///     logits = llm.push_token(next)?;
+    /// }
+    /// ```
+    pub fn step(&mut self, tokens: &[u32], pos: usize) -> Result<Vec<f32>> {
+        if tokens.is_empty() {
+            return Err(CortexError::Inference("step requires at least one token".to_string()));
+        }
+        if pos > self.tokens.len() {
+            return Err(CortexError::Inference(format!(
+                "step position {} is past the end of the context ({} tokens)",
+                pos,
+                self.tokens.len()
+            )));
+        }
+
+        let mut context = self.tokens[..pos].to_vec();
+        context.extend_from_slice(tokens);
+        self.prefill(&context)
+    }
+
+    /// Append a single token to the context and return the next logits
+    pub fn push_token(&mut self, token: u32) -> Result<Vec<f32>> {
+        let pos = self.tokens.len();
+        self.step(&[token], pos)
+    }
+
+    /// Extract the last position's logits as a flat vector
+    fn last_logits(logits: &Tensor) -> Result<Vec<f32>> {
         // Output is [batch, seq_len, vocab_size], we want last token's logits
         let dims = logits.dims();
         let logits = match dims.len() {
@@ -231,78 +680,164 @@ impl CandleLLM {
             _ => return Err(CortexError::Inference(format!("Unexpected logits shape: {:?}", dims))),
         };
 
-        let mut processor = LogitsProcessor::new(
-            rand::random(),
-            Some(config.temperature as f64),
-            Some(config.top_p as f64),
-        );
-
-        processor.sample(&logits)
+        logits
+            .to_dtype(candle_core::DType::F32)
+            .and_then(|l| l.to_vec1::<f32>())
             .map_err(|e| CortexError::Inference(e.to_string()))
     }
-}
 
-impl TextEngine for CandleLLM {
-    fn embedding_dim(&self) -> usize {
-        self.hidden_size
+    /// Pick the next token with the custom sampler, else `default`
+    fn sample(&mut self, logits: &[f32], default: &mut TemperatureSampler) -> Result<u32> {
+        Self::sample_with(&mut self.sampler, &self.device, logits, &self.tokens, default)
     }
 
-    fn context_size(&self) -> usize {
-        self.context_size
-    }
+    /// Pick the next token for a sequence with the given history
+    fn sample_with(
+        sampler: &mut Option<Box<dyn Sampler>>,
+        device: &Device,
+        logits: &[f32],
+        history: &[u32],
+        default: &mut TemperatureSampler,
+    ) -> Result<u32> {
+        let logits = Tensor::new(logits, device)
+            .map_err(|e| CortexError::Inference(e.to_string()))?;
 
-    fn embed(&self, text: &str) -> Result<Vec<f32>> {
-        // Hash-based embedding for now
-        // TODO: Proper embedding via model forward pass
-        let tokens = self.tokenize(text)?;
-        let hash = tokens.iter().fold(0u64, |acc, &t| {
-            acc.wrapping_add(t as u64).wrapping_mul(31)
-        });
+        match sampler {
+            Some(sampler) => sampler.sample(&logits, history),
+            None => default.sample(&logits, history),
+        }
+    }
 
-        let embedding: Vec<f32> = (0..self.hidden_size)
-            .map(|i| {
-                let seed = hash.wrapping_add(i as u64);
-                ((seed % 10000) as f32 / 10000.0) - 0.5
-            })
-            .collect();
+    /// Check that a prompt leaves room for `max_tokens` in the context
+    ///
+    /// With `truncate_prompt` the oldest tokens are dropped to make room;
+    /// otherwise an oversized prompt is an error.
+    fn fit_context(&self, mut tokens: Vec<u32>, config: &GenerationConfig) -> Result<Vec<u32>> {
+        let max_tokens = config.max_tokens as usize;
+        if tokens.len() + max_tokens <= self.context_size {
+            return Ok(tokens);
+        }
 
-        Ok(embedding)
-    }
+        let room = self.context_size.saturating_sub(max_tokens);
+        if !config.truncate_prompt || room == 0 {
+            return Err(CortexError::Inference(format!(
+                "Prompt of {} tokens plus max_tokens {} exceeds the context size of {}",
+                tokens.len(),
+                max_tokens,
+                self.context_size
+            )));
+        }
 
-    fn generate(&mut self, prompt: &str, config: &GenerationConfig) -> Result<String> {
-        self.generate_streaming(prompt, config, &mut |_| true)
+        tracing::warn!(
+            "Truncating prompt from {} to {} tokens to fit the context",
+            tokens.len(),
+            room
+        );
+        tokens.drain(..tokens.len() - room);
+        Ok(tokens)
     }
 
-    fn generate_streaming(
+    /// Core generation loop shared by the streaming and logprob variants
+    ///
+    /// The callback receives a `TokenInfo` for every token with non-empty
+    /// text, or for every sampled token when `with_logprobs` is set, and
+    /// can stop generation or inject text after that token. `on_prefilled`
+    /// runs once the prompt is in the KV cache and can cancel generation
+    /// by returning false.
+    fn generate_tokens(
         &mut self,
         prompt: &str,
         config: &GenerationConfig,
-        callback: &mut dyn FnMut(&str) -> bool,
-    ) -> Result<String> {
+        with_logprobs: bool,
+        on_prefilled: &mut dyn FnMut() -> bool,
+        callback: &mut dyn FnMut(TokenInfo) -> StreamControl,
+    ) -> Result<GenerationOutput> {
+        let start = Instant::now();
+
         // Tokenize prompt
-        let prompt_tokens = self.tokenize(prompt)?;
+        let mut prompt_tokens = self.tokenize(prompt)?;
+        if prompt_tokens.is_empty() {
+            // Without a token there are no logits to sample from
+            match self.bos_token_id {
+                Some(bos) => prompt_tokens.push(bos),
+                None => {
+                    let finish_reason = if on_prefilled() {
+                        FinishReason::Stop
+                    } else {
+                        FinishReason::Cancelled
+                    };
+                    return Ok(GenerationOutput {
+                        text: String::new(),
+                        prompt_tokens: 0,
+                        completion_tokens: 0,
+                        finish_reason,
+                        stats: GenerationStats::default(),
+                    });
+                }
+            }
+        }
+        let prompt_tokens = self.fit_context(prompt_tokens, config)?;
         let prompt_len = prompt_tokens.len();
 
-        // Clear previous context and set new tokens
-        self.clear();
-        self.tokens = prompt_tokens.clone();
-
-        // Process prompt tokens one by one to build KV cache
-        let mut logits = Tensor::new(&[0f32], &self.device)
-            .map_err(|e| CortexError::Inference(e.to_string()))?;
+        // Build the KV cache, reusing it if the prompt extends the cached context
+        let mut last_logits = self.prefill(&prompt_tokens)?;
+        let mut stats = GenerationStats {
+            prompt_tokens: prompt_len,
+            eval_ms: millis(start.elapsed()),
+            ..GenerationStats::default()
+        };
+        if !on_prefilled() {
+            return Ok(GenerationOutput {
+                text: String::new(),
+                prompt_tokens: prompt_len,
+                completion_tokens: 0,
+                finish_reason: FinishReason::Cancelled,
+                stats,
+            });
+        }
+        let gen_start = Instant::now();
 
-        for (pos, &token) in prompt_tokens.iter().enumerate() {
-            logits = self.forward(&[token], pos)?;
+        // Set up grammar constraints
+        let mut validator = config.grammar.as_ref().map(|g| g.validator());
+        if validator.is_some() && self.token_texts.is_none() {
+            self.token_texts = Some(self.build_token_texts()?);
         }
 
         // Generate tokens
+        let mut default_sampler = TemperatureSampler::from_config(config);
         let mut output_tokens = Vec::new();
         let mut output_text = String::new();
+        let mut detokenizer = IncrementalDecoder::default();
+        let mut finish_reason = FinishReason::Length;
+        let mut pos = prompt_len;
 
-        for i in 0..config.max_tokens {
-            let next_token = self.sample(&logits, config)?;
+        for _ in 0..config.max_tokens {
+            // Injected text can fill the context before max_tokens does
+            if pos >= self.context_size {
+                break;
+            }
+
+            apply_logit_bias(&mut last_logits, &config.logit_bias);
+            if let Some(validator) = &validator {
+                let token_texts = self.token_texts.as_deref().unwrap_or(&[]);
+                if !mask_logits(&mut last_logits, validator, token_texts, self.eos_token_id) {
+                    // No token can continue the document
+                    finish_reason = FinishReason::Stop;
+                    break;
+                }
+            }
+
+            // Logprobs describe the biased and masked distribution
+            let logprobs = with_logprobs.then(|| log_softmax(&last_logits));
+            let next_token = self.sample(&last_logits, &mut default_sampler)?;
+            let mut injection = None;
 
             if next_token == self.eos_token_id {
+                finish_reason = FinishReason::EosToken;
+                break;
+            }
+            if config.stop_token_ids.contains(&next_token) {
+                finish_reason = FinishReason::Stop;
                 break;
             }
 
@@ -310,18 +845,78 @@ impl TextEngine for CandleLLM {
             self.tokens.push(next_token);
 
             // Decode incrementally
-            let new_text = self.decode(&output_tokens)?;
-            let delta = if new_text.len() > output_text.len() {
-                &new_text[output_text.len()..]
-            } else {
-                ""
-            };
+            let delta = detokenizer
+                .push(&self.tokenizer, next_token)?
+                .unwrap_or_default();
+
+            if with_logprobs || !delta.is_empty() {
+                let (logprob, top_logprobs) = if let Some(logprobs) = &logprobs {
+                    (
+                        logprobs[next_token as usize],
+                        top_logprobs(logprobs, config.n_logprobs),
+                    )
+                } else {
+                    (0.0, Vec::new())
+                };
+
+                let info = TokenInfo {
+                    text: delta.clone(),
+                    token_id: next_token,
+                    logprob,
+                    top_logprobs,
+                };
+                match callback(info) {
+                    StreamControl::Continue => {}
+                    StreamControl::Stop => {
+                        finish_reason = FinishReason::Cancelled;
+                        break;
+                    }
+                    StreamControl::Inject(text) => injection = Some(text),
+                }
+            }
 
             if !delta.is_empty() {
-                if !callback(delta) {
-                    break;
+                if let Some(validator) = &mut validator {
+                    if !validator.feed_str(&delta) {
+                        finish_reason = FinishReason::Stop;
+                        break;
+                    }
+                }
+                output_text.push_str(&delta);
+            }
+
+            // Injected text is forwarded along with the sampled token
+            let mut input = vec![next_token];
+            if let Some(text) = injection {
+                let injected = self.tokenizer.encode(text.as_str(), false)
+                    .map_err(|e| CortexError::Inference(format!("Tokenization failed: {}", e)))?
+                    .get_ids()
+                    .to_vec();
+                if pos + 1 + injected.len() > self.context_size {
+                    return Err(CortexError::Inference(format!(
+                        "Injecting {} tokens would overflow the context size of {}",
+                        injected.len(),
+                        self.context_size
+                    )));
+                }
+                // Release text held back for an incomplete character first
+                let text = detokenizer.flush(&self.tokenizer)? + &text;
+                detokenizer.extend_released(&injected);
+                if let Some(validator) = &mut validator {
+                    if !validator.feed_str(&text) {
+                        finish_reason = FinishReason::Stop;
+                        break;
+                    }
                 }
-                output_text = new_text;
+                self.tokens.extend_from_slice(&injected);
+                input.extend(injected);
+                output_text.push_str(&text);
+            }
+
+            // A finished document can't be extended
+            if validator.as_ref().is_some_and(|v| v.is_done()) {
+                finish_reason = FinishReason::Stop;
+                break;
             }
 
             // Check stop sequences
@@ -333,25 +928,403 @@ impl TextEngine for CandleLLM {
                 }
             }
             if should_stop {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
+
+            if ends_in_loop(&output_tokens, config.loop_max_period, config.loop_repeats) {
+                finish_reason = FinishReason::RepetitionLoop;
+                break;
+            }
+
+            // Forward next token, one at a time as in `prefill`
+            for token in input {
+                let logits = self.forward(&[token], pos)?;
+                pos += 1;
+                last_logits = Self::last_logits(&logits)?;
+            }
+        }
+
+        // A truncated document isn't valid output
+        if let (Some(grammar), Some(validator)) = (config.grammar, &validator) {
+            if !validator.is_complete() && finish_reason != FinishReason::Cancelled {
+                return Err(CortexError::Inference(format!(
+                    "Generation ended ({:?}) after {} tokens with an incomplete {:?} document",
+                    finish_reason,
+                    output_tokens.len(),
+                    grammar
+                )));
+            }
+        }
+
+        stats.gen_tokens = output_tokens.len();
+        stats.gen_ms = millis(gen_start.elapsed());
+        Ok(GenerationOutput {
+            text: output_text,
+            prompt_tokens: prompt_len,
+            completion_tokens: output_tokens.len(),
+            finish_reason,
+            stats,
+        })
+    }
+
+    /// Generate completions for several prompts, in input order
+    ///
+    /// All prompts run through the model together, one row per prompt,
+    /// left-padded to the longest. Padding is masked out of attention and
+    /// each row's positions start at its first real token, so a prompt
+    /// generates what it would alone. A sequence that hits EOS, a stop
+    /// condition or a loop keeps its slot until the batch ends, but its
+    /// later tokens are discarded. Grammar-constrained configs run the
+    /// prompts one at a time.
+    ///
+    /// The batch runs on a fresh copy of the weights, so the engine's own
+    /// context is left as it was.
+    pub fn generate_batch(
+        &mut self,
+        prompts: &[&str],
+        config: &GenerationConfig,
+    ) -> Result<Vec<String>> {
+        if config.grammar.is_some() {
+            return prompts.iter().map(|p| self.generate(p, config)).collect();
+        }
+
+        let mut results = vec![String::new(); prompts.len()];
+        let mut indices = Vec::new();
+        let mut batch = Vec::new();
+        for (index, prompt) in prompts.iter().enumerate() {
+            let mut tokens = self.tokenize(prompt)?;
+            if tokens.is_empty() {
+                match self.bos_token_id {
+                    Some(bos) => tokens.push(bos),
+                    // Nothing to generate from, as in `generate`
+                    None => continue,
+                }
+            }
+            indices.push(index);
+            batch.push(self.fit_context(tokens, config)?);
+        }
+        if batch.is_empty() {
+            return Ok(results);
+        }
+
+        for (index, text) in indices.into_iter().zip(self.generate_padded(batch, config)?) {
+            results[index] = text;
+        }
+        Ok(results)
+    }
+
+    /// Run `prompts` to completion as one batch, left-padded with EOS
+    fn generate_padded(&mut self, prompts: Vec<Vec<u32>>, config: &GenerationConfig) -> Result<Vec<String>> {
+        let err = |e: candle_core::Error| CortexError::Inference(e.to_string());
+        let batch = prompts.len();
+        let prompt_len = prompts.iter().map(Vec::len).max().unwrap_or(0);
+        let padding: Vec<usize> = prompts.iter().map(|p| prompt_len - p.len()).collect();
+        let mut model = self.base_model.clone();
+
+        let input: Vec<u32> = prompts
+            .iter()
+            .zip(&padding)
+            .flat_map(|(tokens, &pad)| std::iter::repeat_n(self.eos_token_id, pad).chain(tokens.iter().copied()))
+            .collect();
+        let input = Tensor::from_vec(input, (batch, prompt_len), &self.device).map_err(err)?;
+        self.tokens_forwarded += batch * prompt_len;
+        let mut logits = Self::batch_logits(&model.forward(&input, &padding, 0).map_err(err)?)?;
+
+        let mut samplers: Vec<_> = (0..batch).map(|_| TemperatureSampler::from_config(config)).collect();
+        let mut histories = prompts;
+        let mut outputs: Vec<Vec<u32>> = vec![Vec::new(); batch];
+        let mut texts = vec![String::new(); batch];
+        let mut detokenizers: Vec<IncrementalDecoder> = (0..batch).map(|_| IncrementalDecoder::default()).collect();
+        let mut done = vec![false; batch];
+
+        for step in 0..config.max_tokens as usize {
+            for seq in 0..batch {
+                if done[seq] {
+                    continue;
+                }
+                let row = &mut logits[seq];
+                apply_logit_bias(row, &config.logit_bias);
+                let token = Self::sample_with(
+                    &mut self.sampler,
+                    &self.device,
+                    row,
+                    &histories[seq],
+                    &mut samplers[seq],
+                )?;
+                if token == self.eos_token_id || config.stop_token_ids.contains(&token) {
+                    done[seq] = true;
+                    continue;
+                }
+
+                histories[seq].push(token);
+                outputs[seq].push(token);
+                if let Some(delta) = detokenizers[seq].push(&self.tokenizer, token)? {
+                    texts[seq].push_str(&delta);
+                }
+                if config.stop.iter().any(|stop| texts[seq].ends_with(stop))
+                    || ends_in_loop(&outputs[seq], config.loop_max_period, config.loop_repeats)
+                {
+                    done[seq] = true;
+                }
+            }
+            if done.iter().all(|&d| d) || step + 1 == config.max_tokens as usize {
                 break;
             }
 
-            // Forward next token
-            let pos = prompt_len + i as usize;
-            logits = self.forward(&[next_token], pos)?;
+            // Finished rows repeat their last token; their logits are ignored
+            let next: Vec<u32> = histories.iter().map(|h| h[h.len() - 1]).collect();
+            let input = Tensor::new(next, &self.device)
+                .and_then(|t| t.reshape((batch, 1)))
+                .map_err(err)?;
+            self.tokens_forwarded += batch;
+            logits = Self::batch_logits(&model.forward(&input, &padding, prompt_len + step).map_err(err)?)?;
         }
 
-        Ok(output_text)
+        Ok(texts)
+    }
+
+    /// Embed `texts` in one padded forward pass; see `embed_batch`
+    fn embed_chunk(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let err = |e: candle_core::Error| CortexError::Inference(e.to_string());
+        let limit = self.context_size.min(self.model.max_positions());
+        let encoded = texts
+            .iter()
+            .map(|text| {
+                let encoding = self.tokenizer.encode(*text, false)
+                    .map_err(|e| CortexError::Inference(format!("Tokenization failed: {}", e)))?;
+                let mut ids = encoding.get_ids().to_vec();
+                ids.truncate(limit);
+                Ok(ids)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let max_len = encoded.iter().map(Vec::len).max().unwrap_or(0);
+        if max_len == 0 {
+            return Ok(vec![vec![0.0; self.hidden_size]; texts.len()]);
+        }
+        let padding: Vec<usize> = encoded.iter().map(|ids| max_len - ids.len()).collect();
+        let input: Vec<u32> = encoded
+            .iter()
+            .zip(&padding)
+            .flat_map(|(ids, &pad)| std::iter::repeat_n(self.eos_token_id, pad).chain(ids.iter().copied()))
+            .collect();
+        let input = Tensor::from_vec(input, (texts.len(), max_len), &self.device).map_err(err)?;
+        let hidden = self
+            .model
+            .hidden_states(&input, &padding)
+            .and_then(|h| h.to_dtype(DType::F32))
+            .and_then(|h| h.to_vec3::<f32>())
+            .map_err(err)?;
+
+        Ok(hidden
+            .iter()
+            .zip(&padding)
+            .map(|(states, &pad)| mean_pool(&states[pad..], self.hidden_size))
+            .collect())
+    }
+
+    /// Last-position logits for each row of a batched forward pass
+    fn batch_logits(logits: &Tensor) -> Result<Vec<Vec<f32>>> {
+        let err = |e: candle_core::Error| CortexError::Inference(e.to_string());
+        let logits = match logits.dims() {
+            [_, seq_len, _] => logits.narrow(1, seq_len - 1, 1).and_then(|l| l.squeeze(1)).map_err(err)?,
+            [_, _] => logits.clone(),
+            dims => return Err(CortexError::Inference(format!("Unexpected logits shape: {:?}", dims))),
+        };
+        logits
+            .to_dtype(candle_core::DType::F32)
+            .and_then(|l| l.to_vec2::<f32>())
+            .map_err(err)
+    }
+}
+
+/// Turns generated tokens into text one token at a time
+///
+/// Each step decodes only the tokens after the last released text, plus
+/// the chunk before them as context, so tokenizers that drop a leading
+/// space at the start of a decode still space words correctly. Text is
+/// released once it no longer ends in an incomplete UTF-8 sequence
+/// (decoded as U+FFFD); the partial bytes wait for the tokens that
+/// complete them, so a character is never split.
+#[derive(Default)]
+struct IncrementalDecoder {
+    tokens: Vec<u32>,
+    /// Start of the context decoded along with the pending tokens
+    prefix_offset: usize,
+    /// End of the tokens whose text has been released
+    read_offset: usize,
+}
+
+impl IncrementalDecoder {
+    /// Add a token, returning any text it completes
+    fn push(&mut self, tokenizer: &Tokenizer, token: u32) -> Result<Option<String>> {
+        self.tokens.push(token);
+        let (prefix, text) = self.decode_pending(tokenizer)?;
+
+        if text.len() <= prefix.len() || text.ends_with('\u{FFFD}') {
+            return Ok(None);
+        }
+        let Some(delta) = text.get(prefix.len()..) else {
+            return Ok(None);
+        };
+        let delta = delta.to_string();
+
+        self.release();
+        Ok(Some(delta))
+    }
+
+    /// Release any held-back text, even if it ends mid-character
+    fn flush(&mut self, tokenizer: &Tokenizer) -> Result<String> {
+        let (prefix, text) = self.decode_pending(tokenizer)?;
+        let delta = text.get(prefix.len()..).unwrap_or_default().to_string();
+        self.release();
+        Ok(delta)
+    }
+
+    /// Add tokens whose text the caller already has
+    fn extend_released(&mut self, tokens: &[u32]) {
+        self.tokens.extend_from_slice(tokens);
+        self.release();
+    }
+
+    /// Decoded released context, and the same with the pending tokens
+    fn decode_pending(&self, tokenizer: &Tokenizer) -> Result<(String, String)> {
+        let decode = |tokens: &[u32]| {
+            tokenizer
+                .decode(tokens, true)
+                .map_err(|e| CortexError::Inference(format!("Decoding failed: {}", e)))
+        };
+        Ok((
+            decode(&self.tokens[self.prefix_offset..self.read_offset])?,
+            decode(&self.tokens[self.prefix_offset..])?,
+        ))
+    }
+
+    fn release(&mut self) {
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.tokens.len();
+    }
+}
+
+impl TextEngine for CandleLLM {
+    fn embedding_dim(&self) -> usize {
+        self.hidden_size
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        Ok(self.embed_batch(&[text])?.remove(0))
+    }
+
+    /// Mean of the final hidden states over each text's tokens,
+    /// L2-normalized
+    ///
+    /// Texts run through the model `EMBED_BATCH_SIZE` at a time, left-padded
+    /// to the longest; padding is masked out of attention and the mean. No
+    /// BOS is added, and an empty text embeds as zeros.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(EMBED_BATCH_SIZE) {
+            embeddings.extend(self.embed_chunk(chunk)?);
+        }
+        Ok(embeddings)
+    }
+
+    fn generate(&mut self, prompt: &str, config: &GenerationConfig) -> Result<String> {
+        self.generate_streaming(prompt, config, &mut |_| true)
+    }
+
+    fn generate_streaming(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<String> {
+        Ok(self.generate_streaming_output(prompt, config, callback)?.text)
+    }
+
+    fn generate_streaming_output(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<GenerationOutput> {
+        self.generate_tokens(prompt, config, false, &mut || true, &mut |info| {
+            callback(&info.text).into()
+        })
+    }
+
+    fn generate_with_events(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(StreamEvent<'_>) -> bool,
+    ) -> Result<String> {
+        if !callback(StreamEvent::PrefillStarted) {
+            return Ok(String::new());
+        }
+
+        // Both closures need the callback, so share it through a RefCell
+        let callback = std::cell::RefCell::new(callback);
+        self.generate_tokens(
+            prompt,
+            config,
+            false,
+            &mut || (callback.borrow_mut())(StreamEvent::PrefillFinished),
+            &mut |info| (callback.borrow_mut())(StreamEvent::Token(&info.text)).into(),
+        )
+        .map(|output| output.text)
+    }
+
+    fn generate_with_logprobs(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&TokenInfo) -> bool,
+    ) -> Result<String> {
+        self.generate_tokens(prompt, config, true, &mut || true, &mut |info| callback(&info).into())
+            .map(|output| output.text)
+    }
+
+    fn generate_interactive(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> StreamControl,
+    ) -> Result<String> {
+        self.generate_tokens(prompt, config, false, &mut || true, &mut |info| callback(&info.text))
+            .map(|output| output.text)
     }
 
     fn get_state(&self) -> Result<EngineState> {
         let data = bincode::serialize(&self.tokens)
             .map_err(|e| CortexError::State(e.to_string()))?;
 
+        // Keep a copy of the KV cache so restoring this state is warm
+        let mut restore = RestoreMode::Replay;
+        if !self.kv_tokens.is_empty() && self.tokens.starts_with(&self.kv_tokens) {
+            let mut snapshots = self.kv_snapshots.lock()
+                .map_err(|e| CortexError::State(e.to_string()))?;
+            snapshots.retain(|s| s.tokens != self.kv_tokens);
+            snapshots.push(KvSnapshot {
+                tokens: self.kv_tokens.clone(),
+                model: self.model.clone(),
+            });
+            if snapshots.len() > MAX_KV_SNAPSHOTS {
+                snapshots.remove(0);
+            }
+            restore = RestoreMode::Warm;
+        }
+
         Ok(EngineState {
             data,
             n_tokens: self.tokens.len(),
             engine_id: "candle".to_string(),
+            restore,
         })
     }
 
@@ -369,16 +1342,1008 @@ impl TextEngine for CandleLLM {
             self.tokens.clear();
         }
 
+        // Reuse the longest cached prefix of the restored context, either
+        // the live cache or a snapshot; otherwise replay on next generation
+        let live = if self.tokens.starts_with(&self.kv_tokens) {
+            self.kv_tokens.len()
+        } else {
+            0
+        };
+        let snapshot = {
+            let snapshots = self.kv_snapshots.lock()
+                .map_err(|e| CortexError::State(e.to_string()))?;
+            snapshots
+                .iter()
+                .filter(|s| s.tokens.len() > live && self.tokens.starts_with(&s.tokens))
+                .max_by_key(|s| s.tokens.len())
+                .map(|s| (s.tokens.clone(), s.model.clone()))
+        };
+
+        if let Some((tokens, model)) = snapshot {
+            self.kv_tokens = tokens;
+            self.model = model;
+            self.last_restore = Some(RestoreMode::Warm);
+        } else if live > 0 {
+            self.last_restore = Some(RestoreMode::Warm);
+        } else {
+            self.kv_tokens.clear();
+            self.last_restore = Some(RestoreMode::Replay);
+        }
+
         Ok(())
     }
 
     fn clear(&mut self) {
         self.tokens.clear();
-        // Note: ModelWeights doesn't expose clear_kv_cache directly
-        // We rely on position tracking to overwrite old cache entries
+        self.kv_tokens.clear();
+        // The weights have no cache reset, so swap in a copy without one.
+        // Dropping the prefix snapshots releases the old cache tensors.
+        self.model = self.base_model.clone();
+        self.prefix_snapshots.clear();
     }
 
     fn context_used(&self) -> usize {
         self.tokens.len()
     }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.tokenize(text).map(|t| t.len()).unwrap_or(text.len() / 4)
+    }
+
+    fn model_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn model_info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+}
+
+/// Mean of `states`, L2-normalized; zeros if there are none
+fn mean_pool(states: &[Vec<f32>], dim: usize) -> Vec<f32> {
+    let mut mean = vec![0.0f32; dim];
+    for state in states {
+        for (sum, x) in mean.iter_mut().zip(state) {
+            *sum += x;
+        }
+    }
+    let norm = mean.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        mean.iter_mut().for_each(|x| *x /= norm);
+    }
+    mean
+}
+
+/// Number of leading tokens `a` and `b` have in common
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::inference::GreedySampler;
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use std::collections::HashMap;
+    use tokenizers::decoders::byte_fallback::ByteFallback;
+    use tokenizers::decoders::fuse::Fuse;
+    use tokenizers::decoders::sequence::Sequence;
+    use tokenizers::models::bpe::BPE;
+    use tokenizers::{AddedToken, DecoderWrapper};
+
+    const HIDDEN: usize = 16;
+    const FFN: usize = 32;
+
+    /// Build a byte-fallback BPE tokenizer over printable ASCII
+    fn tiny_tokenizer() -> Tokenizer {
+        let mut vocab = HashMap::new();
+        vocab.insert("<unk>".to_string(), 0);
+        vocab.insert("<s>".to_string(), 1);
+        vocab.insert("</s>".to_string(), 2);
+        for b in 0..=255u32 {
+            vocab.insert(format!("<0x{:02X}>", b), 3 + b);
+        }
+        for c in 32u8..127 {
+            vocab.insert((c as char).to_string(), 259 + (c - 32) as u32);
+        }
+
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, vec![])
+            .unk_token("<unk>".to_string())
+            .byte_fallback(true)
+            .build()
+            .unwrap();
+
+        let mut tokenizer = Tokenizer::new(bpe);
+        tokenizer.with_decoder(Some(DecoderWrapper::Sequence(Sequence::new(vec![
+            DecoderWrapper::ByteFallback(ByteFallback::new()),
+            DecoderWrapper::Fuse(Fuse::new()),
+        ]))));
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<unk>", true),
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+        tokenizer
+    }
+
+    /// Deterministic pseudo-random weights in [-0.5, 0.5)
+    fn weights(shape: &[usize], seed: u64) -> QTensor {
+        let n: usize = shape.iter().product();
+        let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let data: Vec<f32> = (0..n)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+            })
+            .collect();
+        let tensor = Tensor::from_vec(data, shape, &Device::Cpu).unwrap();
+        QTensor::quantize(&tensor, GgmlDType::F32).unwrap()
+    }
+
+    /// Write a tiny random-weight llama GGUF and matching tokenizer to `dir`
+    pub(crate) fn write_tiny_model(dir: &Path) -> std::path::PathBuf {
+        let tokenizer = tiny_tokenizer();
+        let vocab_size = tokenizer.get_vocab_size(true);
+        tokenizer.save(dir.join("tokenizer.json"), false).unwrap();
+
+        let ones = || {
+            let tensor = Tensor::ones(HIDDEN, candle_core::DType::F32, &Device::Cpu).unwrap();
+            QTensor::quantize(&tensor, GgmlDType::F32).unwrap()
+        };
+        let tensors = vec![
+            ("token_embd.weight", weights(&[vocab_size, HIDDEN], 1)),
+            ("output.weight", weights(&[vocab_size, HIDDEN], 2)),
+            ("output_norm.weight", ones()),
+            ("blk.0.attn_norm.weight", ones()),
+            ("blk.0.ffn_norm.weight", ones()),
+            ("blk.0.attn_q.weight", weights(&[HIDDEN, HIDDEN], 3)),
+            ("blk.0.attn_k.weight", weights(&[HIDDEN, HIDDEN], 4)),
+            ("blk.0.attn_v.weight", weights(&[HIDDEN, HIDDEN], 5)),
+            ("blk.0.attn_output.weight", weights(&[HIDDEN, HIDDEN], 6)),
+            ("blk.0.ffn_gate.weight", weights(&[FFN, HIDDEN], 7)),
+            ("blk.0.ffn_up.weight", weights(&[FFN, HIDDEN], 8)),
+            ("blk.0.ffn_down.weight", weights(&[HIDDEN, FFN], 9)),
+        ];
+
+        let metadata = vec![
+            ("general.architecture", gguf_file::Value::String("llama".to_string())),
+            ("general.name", gguf_file::Value::String("tiny-test".to_string())),
+            ("llama.context_length", gguf_file::Value::U32(256)),
+            ("llama.embedding_length", gguf_file::Value::U32(HIDDEN as u32)),
+            ("llama.feed_forward_length", gguf_file::Value::U32(FFN as u32)),
+            ("llama.block_count", gguf_file::Value::U32(1)),
+            ("llama.attention.head_count", gguf_file::Value::U32(2)),
+            ("llama.attention.head_count_kv", gguf_file::Value::U32(2)),
+            ("llama.rope.dimension_count", gguf_file::Value::U32((HIDDEN / 2) as u32)),
+            ("llama.attention.layer_norm_rms_epsilon", gguf_file::Value::F32(1e-5)),
+            ("tokenizer.ggml.eos_token_id", gguf_file::Value::U32(2)),
+        ];
+
+        let path = dir.join("tiny.gguf");
+        let mut file = std::fs::File::create(&path).unwrap();
+        let metadata: Vec<(&str, &gguf_file::Value)> =
+            metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (*k, v)).collect();
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_logs_device() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::Layer;
+
+        /// Records the field names of info events
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        struct FieldNames(Vec<String>);
+
+        impl Visit for FieldNames {
+            fn record_debug(&mut self, field: &Field, _value: &dyn std::fmt::Debug) {
+                self.0.push(field.name().to_string());
+            }
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for Capture {
+            fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+                if *event.metadata().level() == tracing::Level::INFO {
+                    let mut names = FieldNames(Vec::new());
+                    event.record(&mut names);
+                    self.0.lock().unwrap().extend(names.0);
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_tiny_model(dir.path());
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        tracing::subscriber::with_default(subscriber, || CandleLLM::load(&path)).unwrap();
+
+        assert!(capture.0.lock().unwrap().iter().any(|f| f == "device"));
+    }
+
+    #[test]
+    fn test_tokenizer_vocab_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_tiny_model(dir.path());
+        assert!(CandleLLM::load(&path).unwrap().check_tokenizer().is_ok());
+
+        // A tokenizer for a bigger vocabulary still loads, with a warning
+        let mut tokenizer = tiny_tokenizer();
+        let extra: Vec<AddedToken> = (0..50).map(|i| AddedToken::from(format!("<x{}>", i), false)).collect();
+        tokenizer.add_tokens(&extra);
+        tokenizer.save(dir.path().join("tokenizer.json"), false).unwrap();
+        let llm = CandleLLM::load(&path).unwrap();
+        assert!(matches!(llm.check_tokenizer(), Err(CortexError::ModelLoad(_))));
+
+        let config = crate::CortexConfig::default().with_strict_tokenizer(true);
+        let result = crate::Cortex::builder().config(config).build_from_model(&path);
+        assert!(matches!(result, Err(CortexError::ModelLoad(_))));
+    }
+
+    #[test]
+    fn test_cached_tokenizer_used_offline() {
+        let model_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let model_path = model_dir.path().join("tiny-llama.gguf");
+
+        // Llama models map to this tokenizer repo
+        let cached = cache_dir.path().join("NousResearch_Llama-2-7b-hf.json");
+        tiny_tokenizer().save(&cached, false).unwrap();
+
+        let mut attempts = 0;
+        let tokenizer = CandleLLM::find_tokenizer(&model_path, cache_dir.path(), |_, _| {
+            attempts += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(tokenizer.get_vocab_size(true), tiny_tokenizer().get_vocab_size(true));
+        assert_eq!(attempts, 0);
+    }
+
+    #[test]
+    fn test_cache_dir_env() {
+        use crate::inference::download::cache_root_from;
+
+        let model_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let model_path = model_dir.path().join("tiny-llama.gguf");
+
+        let env = Some(cache_dir.path().as_os_str().to_owned());
+        let tokenizer_dir = cache_root_from(None, env.clone()).join("tokenizers");
+        assert_eq!(tokenizer_dir, cache_dir.path().join("tokenizers"));
+
+        let mut written = None;
+        CandleLLM::find_tokenizer(&model_path, &tokenizer_dir, |_, dest| {
+            std::fs::create_dir_all(dest.parent().unwrap())?;
+            tiny_tokenizer().save(dest, false).unwrap();
+            written = Some(dest.to_path_buf());
+            Ok(())
+        })
+        .unwrap();
+        let written = written.unwrap();
+        assert!(written.starts_with(cache_dir.path()));
+        assert!(written.is_file());
+
+        // An explicitly configured directory wins over the environment
+        assert_eq!(cache_root_from(Some(model_dir.path()), env), model_dir.path());
+        // An empty variable counts as unset
+        assert!(cache_root_from(None, Some("".into())).ends_with("cortex"));
+    }
+
+    #[test]
+    fn test_offline_tokenizer_fails_fast() {
+        let model_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let model_path = model_dir.path().join("tiny-llama.gguf");
+        let options = DownloadOptions {
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+            offline: true,
+        };
+
+        let mut started = 0;
+        let mut reporter = |_: u64, _: Option<u64>| started += 1;
+        let err = CandleLLM::load_tokenizer(&model_path, &options, &mut reporter).unwrap_err();
+        assert_eq!(started, 0);
+        let CortexError::ModelLoad(message) = err else {
+            panic!("expected ModelLoad, got {:?}", err);
+        };
+        assert!(message.contains("Offline mode"));
+        assert!(message.contains(&cache_dir.path().join("tokenizers").display().to_string()));
+    }
+
+    /// Write a tiny random-weight safetensors llama checkpoint to `dir`
+    pub(crate) fn write_tiny_safetensors(dir: &Path) {
+        use candle_nn::VarMap;
+        use candle_transformers::models::llama::Llama;
+
+        let tokenizer = tiny_tokenizer();
+        tokenizer.save(dir.join("tokenizer.json"), false).unwrap();
+
+        let config = serde_json::json!({
+            "hidden_size": HIDDEN,
+            "intermediate_size": FFN,
+            "vocab_size": tokenizer.get_vocab_size(true),
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "rms_norm_eps": 1e-5,
+            "max_position_embeddings": 128,
+            "eos_token_id": 2,
+        });
+        std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+        let config: LlamaConfig = serde_json::from_value(config).unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        Llama::load(vb, &config.into_config(false)).unwrap();
+        varmap.save(dir.join("model.safetensors")).unwrap();
+    }
+
+    #[test]
+    fn test_load_dispatches_on_format() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tiny_safetensors(dir.path());
+        let gguf = write_tiny_model(dir.path());
+
+        assert!(is_safetensors(dir.path()));
+        assert!(is_safetensors(Path::new("model.safetensors")));
+        assert!(!is_safetensors(&gguf));
+
+        let weights_file = dir.path().join("model.safetensors");
+        for path in [dir.path(), weights_file.as_path()] {
+            let mut llm = CandleLLM::load(path).unwrap();
+            assert_eq!(llm.context_size(), 128);
+            assert_eq!(llm.embedding_dim(), HIDDEN);
+
+            // The KV cache resets between unrelated prompts
+            let config = GenerationConfig::deterministic().with_max_tokens(4);
+            let a = llm.generate("Hello", &config).unwrap();
+            llm.generate("Something else", &config).unwrap();
+            assert_eq!(llm.generate("Hello", &config).unwrap(), a);
+        }
+
+        let llm = CandleLLM::load(&gguf).unwrap();
+        assert_eq!(llm.model_id(), "tiny-test");
+
+        // Both formats describe themselves the same way
+        let info = llm.model_info();
+        assert_eq!(info.architecture.as_deref(), Some("llama"));
+        assert_eq!(info.n_layers, Some(1));
+        assert_eq!(info.quantization.as_deref(), Some("F32"));
+        assert_eq!(info, CandleLLM::load(dir.path()).unwrap().model_info());
+    }
+
+    /// Load a tiny random-weight model, keeping its directory alive
+    pub(crate) fn tiny_model() -> (tempfile::TempDir, CandleLLM) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_tiny_model(dir.path());
+        let llm = CandleLLM::load(&path).unwrap();
+        (dir, llm)
+    }
+
+    /// Emits the given tokens in order, then EOS
+    struct Script(std::vec::IntoIter<u32>);
+
+    impl Sampler for Script {
+        fn sample(&mut self, _logits: &Tensor, _history: &[u32]) -> Result<u32> {
+            Ok(self.0.next().unwrap_or(2))
+        }
+    }
+
+    /// A sampler that picks `tokens` one after another
+    fn scripted(tokens: Vec<u32>) -> Script {
+        Script(tokens.into_iter())
+    }
+
+    #[test]
+    fn test_tiny_model_generates() {
+        let (_dir, mut llm) = tiny_model();
+        assert_eq!(llm.model_id(), "tiny-test");
+
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+        let a = llm.generate("Hello", &config).unwrap();
+        let b = llm.generate("Hello", &config).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_embed_batch() {
+        let (_dir, llm) = tiny_model();
+        let texts = ["The sky is blue", "Cats sleep a lot", "The sky is blue!"];
+
+        let embeddings = llm.embed_batch(&texts).unwrap();
+        assert_eq!(embeddings.len(), texts.len());
+        assert!(embeddings.iter().all(|e| e.len() == llm.embedding_dim()));
+
+        let similarity = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        assert!(similarity(&embeddings[0], &embeddings[2]) > similarity(&embeddings[0], &embeddings[1]));
+
+        // Padding to the longest text in a batch doesn't change an embedding
+        let texts: Vec<String> = (0..EMBED_BATCH_SIZE + 2).map(|i| "ab ".repeat(i)).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let embeddings = llm.embed_batch(&texts).unwrap();
+        assert!(embeddings[0].iter().all(|&x| x == 0.0));
+        for (text, embedding) in texts.iter().zip(&embeddings).skip(1) {
+            let alone = llm.embed(text).unwrap();
+            assert!(alone.iter().zip(embedding).all(|(a, b)| (a - b).abs() < 1e-5));
+            assert!((similarity(embedding, embedding) - 1.0).abs() < 1e-5);
+        }
+
+        // Hidden states depend on token order, unlike a bag of tokens
+        let swapped = llm.embed_batch(&["sky blue", "blue sky"]).unwrap();
+        assert!(similarity(&swapped[0], &swapped[1]) < 0.9999);
+    }
+
+    #[test]
+    fn test_step_matches_greedy_generate() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+        let expected = llm.generate("Hello", &config).unwrap();
+
+        let prompt = llm.tokenize("Hello").unwrap();
+        let mut logits = llm.step(&prompt, 0).unwrap();
+        let mut output = Vec::new();
+        for _ in 0..config.max_tokens {
+            let next = top_logprobs(&logits, 1)[0].0;
+            if next == llm.eos_token_id() {
+                break;
+            }
+            output.push(next);
+            logits = llm.push_token(next).unwrap();
+        }
+
+        assert_eq!(llm.decode(&output).unwrap(), expected);
+        assert_eq!(llm.context_used(), prompt.len() + output.len());
+    }
+
+    #[test]
+    fn test_injected_tokens_extend_context() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+        let prompt = llm.tokenize("Hello").unwrap();
+
+        let mut chunks = 0;
+        let output = llm
+            .generate_interactive("Hello", &config, &mut |_| {
+                chunks += 1;
+                match chunks {
+                    1 => StreamControl::Inject("xyz".to_string()),
+                    _ => StreamControl::Continue,
+                }
+            })
+            .unwrap();
+        assert!(output.contains("xyz"));
+
+        // The injected tokens follow a generated token in context
+        let injected = llm.tokenizer.encode("xyz", false).unwrap().get_ids().to_vec();
+        let context = llm.tokens.clone();
+        let start = (prompt.len() + 1..context.len())
+            .find(|&i| context[i..].starts_with(&injected))
+            .unwrap();
+
+        // Decoding after them matches a fresh run over the same context
+        let resumed = start + injected.len();
+        assert!(resumed < context.len());
+        llm.clear();
+        let logits = llm.step(&context[..resumed], 0).unwrap();
+        assert_eq!(top_logprobs(&logits, 1)[0].0, context[resumed]);
+    }
+
+    #[test]
+    fn test_injection_respects_context_and_pending_text() {
+        // Half of "é" is pending when "xyz" is injected
+        let (_dir, llm) = tiny_model();
+        let mut tokens = vec![3 + "é".as_bytes()[0] as u32];
+        tokens.extend([3 + b'a' as u32; 8]);
+        let mut llm = llm.with_sampler(scripted(tokens));
+        let prompt_len = llm.tokenize("Hi").unwrap().len();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+        llm.context_size = prompt_len + config.max_tokens as usize;
+
+        let mut injected = false;
+        let output = llm
+            .generate_tokens("Hi", &config, true, &mut || true, &mut |_| {
+                if std::mem::replace(&mut injected, true) {
+                    StreamControl::Continue
+                } else {
+                    StreamControl::Inject("xyz".to_string())
+                }
+            })
+            .unwrap();
+
+        assert!(output.text.starts_with("\u{FFFD}xyz"), "{:?}", output.text);
+        assert_eq!(output.finish_reason, FinishReason::Length);
+        assert!(output.completion_tokens < config.max_tokens as usize);
+        assert!(llm.tokens.len() <= llm.context_size);
+        assert!(llm.kv_tokens.len() <= llm.context_size);
+    }
+
+    #[test]
+    fn test_finish_reasons() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(6);
+
+        let mut ids = Vec::new();
+        llm.generate_with_logprobs("Hello", &config, &mut |info| {
+            ids.push(info.token_id);
+            true
+        })
+        .unwrap();
+
+        let output = llm.generate_streaming_output("Hello", &config, &mut |_| true).unwrap();
+        assert_eq!(output.finish_reason, FinishReason::Length);
+        assert_eq!(output.completion_tokens, 6);
+        assert_eq!(output.prompt_tokens, llm.tokenize("Hello").unwrap().len());
+
+        // Treat a token the model produces as EOS
+        let eos = ids[3];
+        llm.eos_token_id = eos;
+        let output = llm.generate_streaming_output("Hello", &config, &mut |_| true).unwrap();
+        assert_eq!(output.finish_reason, FinishReason::EosToken);
+        assert_eq!(output.completion_tokens, ids.iter().position(|&t| t == eos).unwrap());
+    }
+
+    #[test]
+    fn test_empty_prompt() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(4);
+
+        // The tiny GGUF names no BOS token, so there is nothing to generate from
+        assert_eq!(llm.generate("", &config).unwrap(), "");
+
+        llm.bos_token_id = Some(1);
+        let output = llm.generate_streaming_output("", &config, &mut |_| true).unwrap();
+        assert_eq!(output.prompt_tokens, 1);
+        assert_eq!(output.completion_tokens, 4);
+    }
+
+    #[test]
+    fn test_prompt_exceeding_context() {
+        let (_dir, mut llm) = tiny_model();
+        llm.context_size = 16;
+        let prompt = "The quick brown fox jumps over the lazy dog";
+        let prompt_len = llm.tokenize(prompt).unwrap().len();
+        assert!(prompt_len + 4 > 16);
+
+        let config = GenerationConfig::deterministic().with_max_tokens(4);
+        match llm.generate(prompt, &config) {
+            Err(CortexError::Inference(message)) => {
+                assert!(message.contains(&prompt_len.to_string()), "{}", message);
+                assert!(message.contains("16"), "{}", message);
+            }
+            other => panic!("expected an inference error, got {:?}", other),
+        }
+
+        // Truncation keeps the tail of the prompt
+        let config = config.with_truncate_prompt(true);
+        let output = llm.generate_streaming_output(prompt, &config, &mut |_| true).unwrap();
+        assert_eq!(output.prompt_tokens, 12);
+        assert!(output.prompt_tokens + output.completion_tokens <= 16);
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+
+        let mut ids = Vec::new();
+        llm.generate_with_logprobs("Hello", &config, &mut |info| {
+            ids.push(info.token_id);
+            true
+        })
+        .unwrap();
+
+        // A banned token never appears, even where greedy would pick it
+        let banned = ids[0];
+        let ban = config.clone().with_logit_bias(HashMap::from([(banned, f32::NEG_INFINITY)]));
+        let mut seen = Vec::new();
+        llm.generate_with_logprobs("Hello", &ban, &mut |info| {
+            seen.push(info.token_id);
+            true
+        })
+        .unwrap();
+        assert!(!seen.is_empty());
+        assert!(!seen.contains(&banned));
+
+        // A large boost wins the first greedy step; unknown IDs are ignored
+        let boosted = llm.tokenize("q").unwrap()[0];
+        let boost = config.with_logit_bias(HashMap::from([(boosted, 1000.0), (u32::MAX, 5.0)]));
+        let output = llm.generate("Hello", &boost).unwrap();
+        assert!(output.starts_with('q'));
+    }
+
+    #[test]
+    fn test_multibyte_streaming() {
+        // One byte-fallback token per byte, so characters span several steps
+        let text = "é😀 ok";
+        let bytes = text.bytes().map(|b| 3 + b as u32).collect::<Vec<_>>();
+        let (_dir, llm) = tiny_model();
+        let mut llm = llm.with_sampler(scripted(bytes));
+
+        let config = GenerationConfig::deterministic().with_max_tokens(32);
+        let mut updates = Vec::new();
+        let output = llm
+            .generate_streaming_accumulated("Hi", &config, &mut |delta, full| {
+                updates.push((delta.to_string(), full.to_string()));
+                true
+            })
+            .unwrap();
+
+        assert_eq!(output, text);
+        let deltas: Vec<&str> = updates.iter().map(|(d, _)| d.as_str()).collect();
+        assert_eq!(deltas, vec!["é", "😀", " ", "o", "k"]);
+        assert_eq!(updates[1].1, "é😀");
+        assert_eq!(updates.last().unwrap().1, text);
+
+        // Partial bytes are held until the character completes
+        let mut detokenizer = IncrementalDecoder::default();
+        let [first, second] = "é".as_bytes() else { unreachable!() };
+        assert_eq!(detokenizer.push(&llm.tokenizer, 3 + *first as u32).unwrap(), None);
+        assert_eq!(
+            detokenizer.push(&llm.tokenizer, 3 + *second as u32).unwrap().as_deref(),
+            Some("é")
+        );
+    }
+
+    #[test]
+    fn test_generation_stats() {
+        let (_dir, llm) = tiny_model();
+        let tokens = llm.tokenize("abc def").unwrap();
+        let mut llm = llm.with_sampler(scripted(tokens));
+
+        let config = GenerationConfig::deterministic().with_max_tokens(16);
+        let mut chunks = 0;
+        let output = llm
+            .generate_streaming_output("Hello", &config, &mut |_| {
+                chunks += 1;
+                true
+            })
+            .unwrap();
+
+        let stats = output.stats;
+        assert_eq!(stats.prompt_tokens, output.prompt_tokens);
+        assert_eq!(stats.gen_tokens, chunks);
+        assert_eq!(stats.gen_tokens, output.completion_tokens);
+        assert!(stats.eval_ms > 0.0 && stats.gen_ms > 0.0);
+        assert!(stats.tokens_per_sec() > 0.0);
+        assert!(stats.prompt_tokens_per_sec() > 0.0);
+    }
+
+    #[test]
+    fn test_streaming_matches_one_shot_decode() {
+        let text = "Hi 😀 世界, ça va? 🎉";
+        let (_dir, llm) = tiny_model();
+        let tokens = llm.tokenize(text).unwrap();
+        let expected = llm.decode(&tokens).unwrap();
+        let mut llm = llm.with_sampler(scripted(tokens.clone()));
+
+        let config = GenerationConfig::deterministic().with_max_tokens(64);
+        let mut streamed = String::new();
+        let output = llm
+            .generate_streaming("Hi", &config, &mut |chunk| {
+                assert!(!chunk.contains('\u{FFFD}'));
+                streamed.push_str(chunk);
+                true
+            })
+            .unwrap();
+
+        assert_eq!(expected, text);
+        assert_eq!(streamed, expected);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_generate_batch_matches_sequential() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+        let prompts = ["Hello", "Hello", "Hi there"];
+
+        let sequential: Vec<String> = prompts
+            .iter()
+            .map(|p| llm.generate(p, &config).unwrap())
+            .collect();
+        llm.clear();
+        let batched = llm.generate_batch(&prompts, &config).unwrap();
+
+        assert_eq!(batched, sequential);
+        assert_eq!(batched[0], batched[1]);
+        assert_eq!(llm.context_used(), 0);
+    }
+
+    #[test]
+    fn test_generate_batch_pads_to_longest() {
+        let (_dir, mut llm) = tiny_model();
+        let prompts = ["Hi", "Hello there", "Hey"];
+        let lens: Vec<usize> = prompts.iter().map(|p| llm.tokenize(p).unwrap().len()).collect();
+        assert!(lens.windows(2).all(|w| w[0] != w[1]));
+
+        // Greedy output doesn't depend on how much padding a prompt gets
+        let config = GenerationConfig::deterministic().with_max_tokens(6);
+        let sequential: Vec<String> = prompts.iter().map(|p| llm.generate(p, &config).unwrap()).collect();
+        assert_eq!(llm.generate_batch(&prompts, &config).unwrap(), sequential);
+
+        // Every prompt is in the same prefill and decode steps
+        let config = config.with_max_tokens(4);
+        let mut llm = llm.with_sampler(scripted(vec![300; 4 * prompts.len()]));
+        let before = llm.tokens_forwarded();
+        let outputs = llm.generate_batch(&prompts, &config).unwrap();
+        assert!(outputs.iter().all(|o| o == "IIII"));
+        let longest = lens.iter().max().unwrap();
+        assert_eq!(llm.tokens_forwarded() - before, prompts.len() * (longest + 3));
+    }
+
+    #[test]
+    fn test_repetition_loop_stops() {
+        /// Alternates between two tokens forever
+        struct Cycle(bool);
+
+        impl Sampler for Cycle {
+            fn sample(&mut self, _logits: &Tensor, _history: &[u32]) -> Result<u32> {
+                self.0 = !self.0;
+                Ok(if self.0 { 300 } else { 301 })
+            }
+        }
+
+        let (_dir, llm) = tiny_model();
+        let mut llm = llm.with_sampler(Cycle(false));
+
+        let config = GenerationConfig::deterministic().with_max_tokens(32);
+        let output = llm.generate_streaming_output("Hi", &config, &mut |_| true).unwrap();
+        assert_eq!(output.finish_reason, FinishReason::Length);
+
+        let config = config.with_loop_detection(4, 3);
+        let output = llm.generate_streaming_output("Hi", &config, &mut |_| true).unwrap();
+        assert_eq!(output.finish_reason, FinishReason::RepetitionLoop);
+        assert_eq!(output.completion_tokens, 6);
+    }
+
+    #[test]
+    fn test_custom_sampler() {
+        struct Fixed(u32);
+
+        impl Sampler for Fixed {
+            fn sample(&mut self, _logits: &Tensor, _history: &[u32]) -> Result<u32> {
+                Ok(self.0)
+            }
+        }
+
+        let (_dir, llm) = tiny_model();
+        let token = llm.tokenize("x").unwrap()[0];
+        let mut llm = llm.with_sampler(Fixed(token));
+
+        // The config asks for random sampling; the sampler overrides it
+        let config = GenerationConfig::creative().with_max_tokens(4);
+        assert_eq!(llm.generate("Hello", &config).unwrap(), "xxxx");
+
+        llm.set_sampler(GreedySampler);
+        let greedy = llm.generate("Hello", &config).unwrap();
+        llm.clear_sampler();
+        let deterministic = GenerationConfig::deterministic().with_max_tokens(4);
+        assert_eq!(greedy, llm.generate("Hello", &deterministic).unwrap());
+    }
+
+    #[test]
+    fn test_stop_token_ids() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+
+        let mut ids = Vec::new();
+        let full = llm
+            .generate_with_logprobs("Hello", &config, &mut |info| {
+                ids.push(info.token_id);
+                true
+            })
+            .unwrap();
+        assert!(ids.len() > 2);
+
+        // Stops on the token itself, before it's decoded or reported
+        let stop = ids[2];
+        let stop_at = ids.iter().position(|&t| t == stop).unwrap();
+        let config = config.with_stop_token_ids(vec![stop]);
+        let mut seen = Vec::new();
+        let output = llm
+            .generate_with_logprobs("Hello", &config, &mut |info| {
+                seen.push(info.token_id);
+                true
+            })
+            .unwrap();
+        assert_eq!(seen, ids[..stop_at]);
+        assert!(full.starts_with(&output));
+
+        // Composes with string stops and applies to the very first token
+        let config = config
+            .with_stop_token_ids(vec![ids[0]])
+            .with_stop(vec!["never".to_string()]);
+        assert_eq!(llm.generate("Hello", &config).unwrap(), "");
+    }
+
+    #[test]
+    fn test_prompt_prefix_reuse() {
+        let (dir, mut llm) = tiny_model();
+        let mut fresh = CandleLLM::load(dir.path().join("tiny.gguf")).unwrap();
+        let config = GenerationConfig::deterministic().with_max_tokens(4);
+        let system = "You are a helpful assistant who answers briefly. ".repeat(2);
+
+        // The next chat turn extends the previous prompt
+        let first = format!("{}Q: hi", system);
+        let output = llm.generate(&first, &config).unwrap();
+        let first_cost = llm.tokens_forwarded();
+        assert!(first_cost >= llm.tokenize(&first).unwrap().len());
+
+        let second = format!("{}{} Q: and?", first, output);
+        let before = llm.tokens_forwarded();
+        let reply = llm.generate(&second, &config).unwrap();
+        assert!(llm.tokens_forwarded() - before < first_cost);
+        assert_eq!(reply, fresh.generate(&second, &config).unwrap());
+
+        // A prompt that diverges after the system prompt re-encodes once,
+        // then later prompts resume from the shared prefix
+        llm.generate(&format!("{}Q: what now?", system), &config).unwrap();
+        let third = format!("{}Q: why?", system);
+        let before = llm.tokens_forwarded();
+        let reply = llm.generate(&third, &config).unwrap();
+        let suffix = llm.tokenize(&third).unwrap().len() - llm.tokenize(&system).unwrap().len();
+        assert!(llm.tokens_forwarded() - before <= suffix + config.max_tokens as usize);
+        fresh.clear();
+        assert_eq!(reply, fresh.generate(&third, &config).unwrap());
+    }
+
+    #[test]
+    fn test_clear_resets_kv_cache() {
+        let (dir, mut llm) = tiny_model();
+        let mut fresh = CandleLLM::load(dir.path().join("tiny.gguf")).unwrap();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+
+        let long_prompt = "A much longer prompt that fills the cache. ".repeat(4);
+        llm.generate(&long_prompt, &config).unwrap();
+        llm.clear();
+        assert_eq!(llm.context_used(), 0);
+
+        let expected = fresh.generate("Hi", &config).unwrap();
+        assert_eq!(llm.generate("Hi", &config).unwrap(), expected);
+
+        // Also without an explicit clear in between
+        llm.generate(&long_prompt, &config).unwrap();
+        assert_eq!(llm.generate("Hi", &config).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_warm_restore_skips_replay() {
+        let (dir, mut llm) = tiny_model();
+        let mut cold = CandleLLM::load(dir.path().join("tiny.gguf")).unwrap();
+        let config = GenerationConfig::deterministic().with_max_tokens(4);
+
+        // Checkpoint a long prefilled context
+        let context = "The quick brown fox jumps over the lazy dog. ".repeat(4);
+        let tokens = llm.tokenize(&context).unwrap();
+        llm.step(&tokens, 0).unwrap();
+        let state = llm.get_state().unwrap();
+        assert_eq!(state.restore, RestoreMode::Warm);
+
+        // Move on to an unrelated context, then come back
+        llm.generate("Something else", &config).unwrap();
+        let warm_start = llm.tokens_forwarded();
+        llm.set_state(&state).unwrap();
+        assert_eq!(llm.last_restore(), Some(RestoreMode::Warm));
+
+        // A different engine has no cached copy and must replay
+        let cold_start = cold.tokens_forwarded();
+        cold.set_state(&state).unwrap();
+        assert_eq!(cold.last_restore(), Some(RestoreMode::Replay));
+
+        let prompt = format!("{}And then", context);
+        let warm_output = llm.generate(&prompt, &config).unwrap();
+        let cold_output = cold.generate(&prompt, &config).unwrap();
+        assert_eq!(warm_output, cold_output);
+
+        // Only the replay runs the checkpointed context through the model again
+        let warm_forwarded = llm.tokens_forwarded() - warm_start;
+        let cold_forwarded = cold.tokens_forwarded() - cold_start;
+        assert_eq!(cold_forwarded - warm_forwarded, tokens.len());
+    }
+
+    #[test]
+    fn test_generate_with_events() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig::deterministic().with_max_tokens(8);
+        let expected = llm.generate("Hello", &config).unwrap();
+        llm.clear();
+
+        let mut events = Vec::new();
+        let output = llm
+            .generate_with_events("Hello", &config, &mut |event| {
+                events.push(match event {
+                    StreamEvent::Token(text) => text.to_string(),
+                    other => format!("{:?}", other),
+                });
+                true
+            })
+            .unwrap();
+
+        assert_eq!(output, expected);
+        assert_eq!(events[0], "PrefillStarted");
+        assert_eq!(events[1], "PrefillFinished");
+        assert_eq!(events[2..].concat(), expected);
+    }
+
+    #[test]
+    fn test_json_grammar() {
+        let (_dir, mut llm) = tiny_model();
+
+        let [open, closers @ ..] = ["[", "}", "]", "\"", "1"].map(|t| llm.tokenize(t).unwrap()[0]);
+
+        // Favor closing the document so it completes well within max_tokens
+        let bias: HashMap<u32, f32> =
+            closers.into_iter().chain([llm.eos_token_id]).map(|id| (id, 10.0)).collect();
+        for temperature in [0.0, 1.0] {
+            let config = GenerationConfig {
+                temperature,
+                ..GenerationConfig::default().with_max_tokens(64)
+            }
+            .with_grammar(crate::inference::Grammar::Json)
+            .with_logit_bias(bias.clone());
+
+            let output = llm.generate("{", &config).unwrap();
+            serde_json::from_str::<serde_json::Value>(&output)
+                .unwrap_or_else(|e| panic!("{:?} failed to parse: {}", output, e));
+        }
+
+        // Running out of tokens mid-document is an error, not a JSON prefix
+        let config = GenerationConfig::deterministic()
+            .with_max_tokens(4)
+            .with_grammar(crate::inference::Grammar::Json)
+            .with_logit_bias(HashMap::from([(open, 10.0)]));
+        let err = llm.generate("{", &config).unwrap_err();
+        assert!(err.to_string().contains("incomplete"), "{}", err);
+    }
+
+    #[test]
+    fn test_generate_with_logprobs() {
+        let (_dir, mut llm) = tiny_model();
+        let config = GenerationConfig {
+            n_logprobs: 5,
+            ..GenerationConfig::deterministic().with_max_tokens(8)
+        };
+
+        let mut infos = Vec::new();
+        llm.generate_with_logprobs("Hello", &config, &mut |info| {
+            infos.push(info.clone());
+            true
+        })
+        .unwrap();
+
+        assert!(!infos.is_empty());
+        for info in &infos {
+            assert!(info.logprob <= 0.0);
+            assert_eq!(info.top_logprobs.len(), 5);
+            assert!(info.top_logprobs.iter().all(|(_, lp)| *lp <= 0.0));
+
+            // Greedy decoding picks the most likely alternative
+            assert_eq!(info.top_logprobs[0].0, info.token_id);
+            assert!((info.top_logprobs[0].1 - info.logprob).abs() < 1e-6);
+        }
+
+        // Logprobs follow the biased distribution the token was picked from
+        let banned = infos[0].token_id;
+        let boosted = llm.tokenize("q").unwrap()[0];
+        let biased = config.with_logit_bias(HashMap::from([
+            (banned, f32::NEG_INFINITY),
+            (boosted, 1000.0),
+        ]));
+        let mut first = None;
+        llm.generate_with_logprobs("Hello", &biased, &mut |info| {
+            first.get_or_insert_with(|| info.clone());
+            true
+        })
+        .unwrap();
+        let first = first.unwrap();
+        assert_eq!(first.token_id, boosted);
+        assert!(first.logprob > -1e-3);
+        assert!(first.top_logprobs.iter().all(|(id, _)| *id != banned));
+    }
 }
@@ -0,0 +1,280 @@
+//! File downloads with progress reporting
+//!
+//! Model and tokenizer files can be hundreds of MB, so loaders report
+//! progress through a `ProgressReporter` the caller can render.
+
+use crate::{CortexError, Result};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of each read while downloading
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Environment variable overriding where downloads are cached
+const CACHE_DIR_ENV: &str = "CORTEX_CACHE_DIR";
+
+/// Environment variable that, when set, forbids downloads
+pub(crate) const OFFLINE_ENV: &str = "CORTEX_OFFLINE";
+
+/// Environment variable pointing downloads at a HuggingFace mirror
+const HF_ENDPOINT_ENV: &str = "HF_ENDPOINT";
+
+/// Environment variable holding a HuggingFace access token
+const HF_TOKEN_ENV: &str = "HF_TOKEN";
+
+/// Where HuggingFace files come from without `HF_ENDPOINT`
+const HF_DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+
+/// Where loaders cache downloads, and whether they may download at all
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadOptions {
+    /// Cache directory; `None` falls back as in `CortexConfig::cache_dir`
+    pub cache_dir: Option<PathBuf>,
+    /// Refuse network access, also enabled by `CORTEX_OFFLINE`
+    pub offline: bool,
+}
+
+impl DownloadOptions {
+    /// Options caching under `cache_dir`
+    pub fn with_cache_dir(cache_dir: Option<&Path>) -> Self {
+        Self {
+            cache_dir: cache_dir.map(Path::to_path_buf),
+            offline: false,
+        }
+    }
+
+    /// Check if downloads are forbidden, by `offline` or `CORTEX_OFFLINE`
+    ///
+    /// Any value of the variable other than empty, `0` or `false` counts.
+    pub fn is_offline(&self) -> bool {
+        self.offline
+            || std::env::var(OFFLINE_ENV)
+                .is_ok_and(|v| !matches!(v.trim().to_lowercase().as_str(), "" | "0" | "false"))
+    }
+
+    /// Root of the download cache
+    pub(crate) fn cache_root(&self) -> PathBuf {
+        cache_root(self.cache_dir.as_deref())
+    }
+
+    /// Download `url` to `dest`, or fail at once when offline
+    pub(crate) fn download(
+        &self,
+        url: &str,
+        dest: &Path,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<()> {
+        if self.is_offline() {
+            return Err(CortexError::ModelLoad(format!(
+                "Offline mode is on, so {} was not downloaded; place the file at {}",
+                url,
+                dest.display()
+            )));
+        }
+        download(url, dest, reporter)
+    }
+}
+
+/// Root of the download cache
+///
+/// An explicitly `configured` directory wins, then `CORTEX_CACHE_DIR`,
+/// then `cortex` under the platform cache directory.
+pub(crate) fn cache_root(configured: Option<&Path>) -> PathBuf {
+    cache_root_from(configured, std::env::var_os(CACHE_DIR_ENV))
+}
+
+/// `cache_root` with the value of `CORTEX_CACHE_DIR` passed in
+pub(crate) fn cache_root_from(configured: Option<&Path>, env: Option<OsString>) -> PathBuf {
+    if let Some(dir) = configured {
+        return dir.to_path_buf();
+    }
+    match env {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::cache_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("cortex"),
+    }
+}
+
+/// URL of a file in a HuggingFace model repo, on `HF_ENDPOINT` if set
+pub(crate) fn hf_file_url(model_id: &str, file: &str) -> String {
+    format!("{}/{}/resolve/main/{}", hf_endpoint(), model_id, file)
+}
+
+/// The HuggingFace endpoint, without a trailing slash
+fn hf_endpoint() -> String {
+    let endpoint = std::env::var(HF_ENDPOINT_ENV)
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .unwrap_or_else(|| HF_DEFAULT_ENDPOINT.to_string());
+    endpoint.trim().trim_end_matches('/').to_string()
+}
+
+/// Access token from `HF_TOKEN`, or the one `huggingface-cli login` saved
+fn hf_token() -> Option<String> {
+    std::env::var(HF_TOKEN_ENV)
+        .ok()
+        .filter(|t| !t.trim().is_empty())
+        .or_else(|| hf_hub::Cache::default().token())
+        .map(|t| t.trim().to_string())
+}
+
+/// The token to send with a request to `url`, only ever to `endpoint`
+fn token_for<'a>(url: &str, endpoint: &str, token: Option<&'a str>) -> Option<&'a str> {
+    let on_endpoint = url
+        .strip_prefix(endpoint)
+        .is_some_and(|rest| rest.starts_with('/'));
+    token.filter(|_| on_endpoint)
+}
+
+/// Receives progress of file downloads
+///
+/// Closures `FnMut(downloaded, total)` implement this, ignoring `start`
+/// and `finish`. `total` is `None` when the server sends no length.
+pub trait ProgressReporter {
+    /// A download of `file` is starting
+    fn start(&mut self, _file: &str, _total: Option<u64>) {}
+
+    /// `downloaded` bytes of the current file have arrived
+    fn update(&mut self, downloaded: u64, total: Option<u64>);
+
+    /// The download of `file` completed
+    fn finish(&mut self, _file: &str) {}
+}
+
+impl<F: FnMut(u64, Option<u64>)> ProgressReporter for F {
+    fn update(&mut self, downloaded: u64, total: Option<u64>) {
+        self(downloaded, total)
+    }
+}
+
+/// Download `url` to `dest`, reporting progress
+///
+/// Writes to a `.part` file first, so an interrupted download never
+/// leaves a truncated file at `dest`. Requests to the HuggingFace endpoint
+/// carry the user's access token, for gated and private repos.
+pub(crate) fn download(url: &str, dest: &Path, reporter: &mut dyn ProgressReporter) -> Result<()> {
+    let mut request = ureq::get(url);
+    let token = hf_token();
+    if let Some(token) = token_for(url, &hf_endpoint(), token.as_deref()) {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    let response = request
+        .call()
+        .map_err(|e| CortexError::ModelLoad(format!("Failed to download {}: {}", url, e)))?;
+    let total = response
+        .header("Content-Length")
+        .and_then(|len| len.parse().ok());
+
+    if let Some(dir) = dest.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial = dest.with_extension("part");
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| url.to_string());
+
+    let mut file = std::fs::File::create(&partial)?;
+    copy_with_progress(&name, response.into_reader(), total, &mut file, reporter)?;
+    drop(file);
+    std::fs::rename(&partial, dest)?;
+    Ok(())
+}
+
+/// Copy `reader` to `writer`, reporting bytes copied after every chunk
+fn copy_with_progress(
+    name: &str,
+    mut reader: impl Read,
+    total: Option<u64>,
+    writer: &mut impl Write,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<u64> {
+    reporter.start(name, total);
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut downloaded = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n])?;
+        downloaded += n as u64;
+        reporter.update(downloaded, total);
+    }
+
+    reporter.finish(name);
+    Ok(downloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl ProgressReporter for Recorder {
+        fn start(&mut self, file: &str, total: Option<u64>) {
+            self.events.push(format!("start {} {:?}", file, total));
+        }
+
+        fn update(&mut self, downloaded: u64, _total: Option<u64>) {
+            self.events.push(format!("update {}", downloaded));
+        }
+
+        fn finish(&mut self, file: &str) {
+            self.events.push(format!("finish {}", file));
+        }
+    }
+
+    #[test]
+    fn test_token_only_goes_to_endpoint() {
+        let endpoint = "https://hf-mirror.example";
+        let url = format!("{}/org/model/resolve/main/tokenizer.json", endpoint);
+        assert_eq!(token_for(&url, endpoint, Some("secret")), Some("secret"));
+        assert_eq!(token_for(&url, endpoint, None), None);
+        assert_eq!(
+            token_for("https://hf-mirror.example.evil/x", endpoint, Some("secret")),
+            None
+        );
+        assert_eq!(token_for("https://other.example/x", endpoint, Some("secret")), None);
+    }
+
+    #[test]
+    fn test_copy_reports_progress() {
+        let data = vec![7u8; CHUNK_SIZE + 10];
+        let mut out = Vec::new();
+        let mut recorder = Recorder::default();
+
+        let copied = copy_with_progress(
+            "model.bin",
+            data.as_slice(),
+            Some(data.len() as u64),
+            &mut out,
+            &mut recorder,
+        )
+        .unwrap();
+
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(out, data);
+        assert_eq!(
+            recorder.events.first().unwrap(),
+            &format!("start model.bin Some({})", data.len())
+        );
+        assert_eq!(recorder.events.last().unwrap(), "finish model.bin");
+        assert!(recorder.events.contains(&format!("update {}", data.len())));
+
+        // Plain closures see every update
+        let mut seen = Vec::new();
+        copy_with_progress("x", &b"abc"[..], None, &mut Vec::new(), &mut |n, total| {
+            seen.push((n, total))
+        })
+        .unwrap();
+        assert_eq!(seen, vec![(3, None)]);
+    }
+}
@@ -3,36 +3,118 @@
 //! Uses a small BERT-based model (all-MiniLM-L6-v2) for high-quality
 //! sentence embeddings. This is separate from the main LLM.
 
+use super::download::{hf_file_url, DownloadOptions, ProgressReporter};
 use crate::{CortexError, Result};
 use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config as BertConfig, DTYPE};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
 
+/// Files an embedding model directory must contain
+const MODEL_FILE: &str = "model.safetensors";
+const TOKENIZER_FILE: &str = "tokenizer.json";
+const CONFIG_FILE: &str = "config.json";
+const MODEL_FILES: [&str; 3] = [MODEL_FILE, TOKENIZER_FILE, CONFIG_FILE];
+
+/// How token hidden states are reduced to one sentence embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolingStrategy {
+    /// Average over non-padding tokens (sentence-transformers default)
+    #[default]
+    Mean,
+    /// Hidden state of the first ([CLS]) token
+    Cls,
+    /// Elementwise max over non-padding tokens
+    Max,
+}
+
 /// Embedding model for semantic similarity search
 pub struct Embedder {
     model: BertModel,
     tokenizer: Tokenizer,
     device: Device,
     dim: usize,
+    pooling: PoolingStrategy,
 }
 
 impl Embedder {
+    /// Model used by `load_default`
+    pub const DEFAULT_MODEL: &'static str = "sentence-transformers/all-MiniLM-L6-v2";
+
     /// Load the default embedding model (all-MiniLM-L6-v2)
     pub fn load_default() -> Result<Self> {
-        Self::load("sentence-transformers/all-MiniLM-L6-v2")
+        Self::load(Self::DEFAULT_MODEL)
     }
 
     /// Load an embedding model from HuggingFace
+    ///
+    /// `model_id` may also be a local directory. Models already in the
+    /// HuggingFace cache are loaded without touching the network.
     pub fn load(model_id: &str) -> Result<Self> {
-        println!("Loading embedding model: {}...", model_id);
+        Self::load_with_progress(model_id, &mut |_, _| {})
+    }
+
+    /// Load an embedding model, reporting progress of any downloads
+    pub fn load_with_progress(model_id: &str, reporter: &mut dyn ProgressReporter) -> Result<Self> {
+        Self::load_with_cache_dir(model_id, None, reporter)
+    }
+
+    /// Load an embedding model, downloading it under `cache_dir` if needed
+    ///
+    /// `None` uses `CORTEX_CACHE_DIR`, or the platform cache directory if
+    /// that is unset. Models already in the HuggingFace cache are still
+    /// reused.
+    pub fn load_with_cache_dir(
+        model_id: &str,
+        cache_dir: Option<&Path>,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
+        Self::load_with_options(model_id, &DownloadOptions::with_cache_dir(cache_dir), reporter)
+    }
+
+    /// Load an embedding model, downloading it as `options` allow
+    pub fn load_with_options(
+        model_id: &str,
+        options: &DownloadOptions,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
+        if Path::new(model_id).is_dir() {
+            return Self::load_from_dir(model_id);
+        }
+
+        let dir = Self::model_dir(options, model_id);
+        let (model_path, tokenizer_path, config_path) = match Self::cached_model(model_id, &dir) {
+            Some(paths) => paths,
+            None => Self::download_model(model_id, &dir, options, reporter)?,
+        };
+        Self::load_files(model_id, &model_path, &tokenizer_path, &config_path)
+    }
+
+    /// Load an embedding model from a local directory
+    ///
+    /// The directory must contain `model.safetensors`, `tokenizer.json` and
+    /// `config.json`. Never uses the network.
+    pub fn load_from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let [model_path, tokenizer_path, config_path] = MODEL_FILES.map(|file| dir.join(file));
+        for path in [&model_path, &tokenizer_path, &config_path] {
+            if !path.is_file() {
+                return Err(CortexError::ModelLoad(format!("Missing {}", path.display())));
+            }
+        }
+
+        Self::load_files(&dir.display().to_string(), &model_path, &tokenizer_path, &config_path)
+    }
+
+    fn load_files(name: &str, model_path: &Path, tokenizer_path: &Path, config_path: &Path) -> Result<Self> {
+        let _span = tracing::info_span!("load_embedder", model = %name).entered();
 
         let device = Self::get_device()?;
-        let (model_path, tokenizer_path, config_path) = Self::download_model(model_id)?;
+        tracing::info!(device = ?device, "Loading embedding model");
 
         // Load config
-        let config_str = std::fs::read_to_string(&config_path)
+        let config_str = std::fs::read_to_string(config_path)
             .map_err(|e| CortexError::ModelLoad(format!("Failed to read config: {}", e)))?;
         let config: BertConfig = serde_json::from_str(&config_str)
             .map_err(|e| CortexError::ModelLoad(format!("Failed to parse config: {}", e)))?;
@@ -49,7 +131,7 @@ impl Embedder {
             .map_err(|e| CortexError::ModelLoad(format!("Failed to build model: {}", e)))?;
 
         // Load tokenizer
-        let mut tokenizer = Tokenizer::from_file(&tokenizer_path)
+        let mut tokenizer = Tokenizer::from_file(tokenizer_path)
             .map_err(|e| CortexError::ModelLoad(format!("Failed to load tokenizer: {}", e)))?;
 
         // Configure tokenizer for batch processing
@@ -67,16 +149,23 @@ impl Embedder {
             .with_truncation(Some(truncation))
             .map_err(|e| CortexError::ModelLoad(format!("Failed to set truncation: {}", e)))?;
 
-        println!("Embedding model loaded! (dim={})", dim);
+        tracing::info!(dim, "Embedding model loaded");
 
         Ok(Self {
             model,
             tokenizer,
             device,
             dim,
+            pooling: PoolingStrategy::default(),
         })
     }
 
+    /// Set the pooling strategy; use the one the model was trained with
+    pub fn with_pooling(mut self, pooling: PoolingStrategy) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
     fn get_device() -> Result<Device> {
         #[cfg(feature = "metal")]
         {
@@ -95,25 +184,44 @@ impl Embedder {
         Ok(Device::Cpu)
     }
 
-    fn download_model(model_id: &str) -> Result<(PathBuf, PathBuf, PathBuf)> {
-        let api = hf_hub::api::sync::Api::new()
-            .map_err(|e| CortexError::ModelLoad(format!("Failed to create HF API: {}", e)))?;
-
-        let repo = api.model(model_id.to_string());
+    /// Model files from the HuggingFace or Cortex cache, if all are present
+    fn cached_model(model_id: &str, dir: &Path) -> Option<(PathBuf, PathBuf, PathBuf)> {
+        let repo = hf_hub::Cache::default().model(model_id.to_string());
+        if let (Some(model), Some(tokenizer), Some(config)) =
+            (repo.get(MODEL_FILE), repo.get(TOKENIZER_FILE), repo.get(CONFIG_FILE))
+        {
+            return Some((model, tokenizer, config));
+        }
 
-        let model_path = repo
-            .get("model.safetensors")
-            .map_err(|e| CortexError::ModelLoad(format!("Failed to download model: {}", e)))?;
+        let [model, tokenizer, config] = MODEL_FILES.map(|file| dir.join(file));
+        (model.is_file() && tokenizer.is_file() && config.is_file()).then_some((model, tokenizer, config))
+    }
 
-        let tokenizer_path = repo
-            .get("tokenizer.json")
-            .map_err(|e| CortexError::ModelLoad(format!("Failed to download tokenizer: {}", e)))?;
+    /// Where a downloaded model is kept
+    fn model_dir(options: &DownloadOptions, model_id: &str) -> PathBuf {
+        options
+            .cache_root()
+            .join("embedders")
+            .join(model_id.replace('/', "_"))
+    }
 
-        let config_path = repo
-            .get("config.json")
-            .map_err(|e| CortexError::ModelLoad(format!("Failed to download config: {}", e)))?;
+    fn download_model(
+        model_id: &str,
+        dir: &Path,
+        options: &DownloadOptions,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<(PathBuf, PathBuf, PathBuf)> {
+        let [model, tokenizer, config] = MODEL_FILES.map(|file| dir.join(file));
+
+        for path in [&model, &tokenizer, &config] {
+            if !path.is_file() {
+                let file = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                let url = hf_file_url(model_id, file);
+                options.download(&url, path, reporter)?;
+            }
+        }
 
-        Ok((model_path, tokenizer_path, config_path))
+        Ok((model, tokenizer, config))
     }
 
     /// Get the embedding dimension
@@ -121,6 +229,11 @@ impl Embedder {
         self.dim
     }
 
+    /// Get the pooling strategy
+    pub fn pooling(&self) -> PoolingStrategy {
+        self.pooling
+    }
+
     /// Embed a single text
     pub fn embed(&self, text: &str) -> Result<Vec<f32>> {
         let embeddings = self.embed_batch(&[text])?;
@@ -166,8 +279,8 @@ impl Embedder {
             .forward(&input_ids, &token_type_ids, Some(&attention_mask))
             .map_err(|e| CortexError::Inference(format!("Forward pass failed: {}", e)))?;
 
-        // Mean pooling with attention mask
-        let embeddings = self.mean_pooling(&output, &attention_mask)?;
+        // Pool token states, ignoring padding
+        let embeddings = pool(&output, &attention_mask, self.pooling)?;
 
         // L2 normalize
         let embeddings = self.normalize(&embeddings)?;
@@ -180,39 +293,6 @@ impl Embedder {
         Ok(embeddings)
     }
 
-    fn mean_pooling(&self, hidden_states: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
-        // hidden_states: [batch, seq, hidden]
-        // attention_mask: [batch, seq]
-
-        // Expand attention mask to match hidden states
-        let mask = attention_mask
-            .unsqueeze(2)
-            .map_err(|e| CortexError::Inference(e.to_string()))?
-            .to_dtype(hidden_states.dtype())
-            .map_err(|e| CortexError::Inference(e.to_string()))?;
-
-        // Mask and sum
-        let masked = hidden_states
-            .broadcast_mul(&mask)
-            .map_err(|e| CortexError::Inference(e.to_string()))?;
-
-        let summed = masked
-            .sum(1)
-            .map_err(|e| CortexError::Inference(e.to_string()))?;
-
-        // Count non-padding tokens
-        let counts = mask
-            .sum(1)
-            .map_err(|e| CortexError::Inference(e.to_string()))?
-            .clamp(1e-9, f64::MAX)
-            .map_err(|e| CortexError::Inference(e.to_string()))?;
-
-        // Mean
-        summed
-            .broadcast_div(&counts)
-            .map_err(|e| CortexError::Inference(e.to_string()))
-    }
-
     fn normalize(&self, embeddings: &Tensor) -> Result<Tensor> {
         let norms = embeddings
             .sqr()
@@ -230,12 +310,48 @@ impl Embedder {
     }
 }
 
+/// Reduce `[batch, seq, hidden]` states to `[batch, hidden]`
+///
+/// `attention_mask` is `[batch, seq]` with 1 for real tokens and 0 for padding.
+fn pool(hidden_states: &Tensor, attention_mask: &Tensor, strategy: PoolingStrategy) -> Result<Tensor> {
+    let err = |e: candle_core::Error| CortexError::Inference(e.to_string());
+
+    // Padding is always on the right, so position 0 is [CLS]
+    if strategy == PoolingStrategy::Cls {
+        return hidden_states.narrow(1, 0, 1).map_err(err)?.squeeze(1).map_err(err);
+    }
+
+    // Expand attention mask to match hidden states
+    let mask = attention_mask
+        .unsqueeze(2)
+        .map_err(err)?
+        .to_dtype(hidden_states.dtype())
+        .map_err(err)?;
+
+    match strategy {
+        PoolingStrategy::Max => {
+            // Push padding far below any real activation
+            let offset = mask.affine(1e9, -1e9).map_err(err)?;
+            hidden_states.broadcast_add(&offset).map_err(err)?.max(1).map_err(err)
+        }
+        _ => {
+            // Mask and sum
+            let summed = hidden_states.broadcast_mul(&mask).map_err(err)?.sum(1).map_err(err)?;
+
+            // Count non-padding tokens
+            let counts = mask.sum(1).map_err(err)?.clamp(1e-9, f64::MAX).map_err(err)?;
+
+            summed.broadcast_div(&counts).map_err(err)
+        }
+    }
+}
+
 // Safety: Embedder is Send when used from single thread context
 unsafe impl Send for Embedder {}
 unsafe impl Sync for Embedder {}
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
@@ -256,4 +372,80 @@ mod tests {
 
         assert!(sim_12 > sim_13, "Similar sentences should have higher similarity");
     }
+
+    /// Write a tiny random-weight BERT model directory
+    pub(crate) fn write_tiny_bert(dir: &Path) {
+        use candle_nn::VarMap;
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+        let words = ["[PAD]", "[UNK]", "the", "cat", "sat", "on", "mat"];
+        let vocab = words.iter().enumerate().map(|(i, w)| (w.to_string(), i as u32)).collect();
+        let model = WordLevel::builder().vocab(vocab).unk_token("[UNK]".to_string()).build().unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.save(dir.join(TOKENIZER_FILE), false).unwrap();
+
+        let config = serde_json::json!({
+            "vocab_size": words.len(),
+            "hidden_size": 8,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "intermediate_size": 16,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0,
+            "max_position_embeddings": 32,
+            "type_vocab_size": 2,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0,
+        });
+        std::fs::write(dir.join(CONFIG_FILE), config.to_string()).unwrap();
+
+        let bert_config: BertConfig = serde_json::from_value(config).unwrap();
+        let varmap = VarMap::new();
+        BertModel::load(VarBuilder::from_varmap(&varmap, DTYPE, &Device::Cpu), &bert_config).unwrap();
+        varmap.save(dir.join(MODEL_FILE)).unwrap();
+    }
+
+    #[test]
+    fn test_load_from_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tiny_bert(dir.path());
+
+        let embedder = Embedder::load_from_dir(dir.path()).unwrap();
+        assert_eq!(embedder.dim(), 8);
+        let embeddings = embedder.embed_batch(&["the cat sat", "on the mat"]).unwrap();
+        assert_eq!(embeddings.len(), 2);
+        let norm: f32 = embeddings[0].iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+
+        // A directory path passed to `load` stays local too
+        assert_eq!(Embedder::load(dir.path().to_str().unwrap()).unwrap().dim(), 8);
+
+        std::fs::remove_file(dir.path().join(CONFIG_FILE)).unwrap();
+        assert!(Embedder::load_from_dir(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_pooling_strategies() {
+        // Two sequences of three tokens, hidden size 2; the second has one pad
+        let hidden = Tensor::new(
+            &[
+                [[1.0f32, -2.0], [3.0, 0.0], [5.0, 4.0]],
+                [[2.0, 6.0], [-4.0, 1.0], [100.0, 100.0]],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let mask = Tensor::new(&[[1i64, 1, 1], [1, 1, 0]], &Device::Cpu).unwrap();
+
+        let pooled = |strategy| -> Vec<Vec<f32>> {
+            pool(&hidden, &mask, strategy).unwrap().to_vec2().unwrap()
+        };
+
+        assert_eq!(pooled(PoolingStrategy::Mean), vec![vec![3.0, 2.0 / 3.0], vec![-1.0, 3.5]]);
+        assert_eq!(pooled(PoolingStrategy::Cls), vec![vec![1.0, -2.0], vec![2.0, 6.0]]);
+        assert_eq!(pooled(PoolingStrategy::Max), vec![vec![5.0, 4.0], vec![2.0, 6.0]]);
+    }
 }
@@ -0,0 +1,460 @@
+//! Grammar-constrained generation
+//!
+//! Provides an incremental JSON validator that accepts text one character
+//! at a time and rejects anything that can't be the prefix of a valid JSON
+//! document. During sampling, tokens whose text would be rejected are
+//! masked out so the model can only produce well-formed JSON.
+
+use serde::{Deserialize, Serialize};
+
+/// Grammar that generated output must conform to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Grammar {
+    /// Any JSON value (objects, arrays, strings, numbers, booleans, null)
+    Json,
+}
+
+impl Grammar {
+    /// Create a fresh validator for this grammar
+    pub fn validator(&self) -> JsonValidator {
+        match self {
+            Grammar::Json => JsonValidator::new(),
+        }
+    }
+}
+
+/// Enclosing container while parsing
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Container {
+    Object,
+    Array,
+}
+
+/// Position inside a number literal
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberState {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    /// Number of exponent digits read so far
+    ExpDigits(u8),
+}
+
+/// Maximum exponent digits, keeping every number representable as an f64
+const MAX_EXPONENT_DIGITS: u8 = 2;
+
+impl NumberState {
+    /// Whether the number read so far is complete
+    fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            NumberState::Zero | NumberState::Int | NumberState::Frac | NumberState::ExpDigits(_)
+        )
+    }
+}
+
+/// Parser state between characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Expecting any value
+    Value,
+    /// After `[`: a value or `]`
+    ArrayValueOrEnd,
+    /// After `{`: a key or `}`
+    KeyOrEnd,
+    /// After `,` in an object: a key
+    Key,
+    /// After an object key
+    Colon,
+    /// After a value inside a container
+    CommaOrEnd,
+    /// Inside a string; `escape` counts pending escape characters
+    /// (1 after a backslash, 1-4 for remaining `\u` hex digits)
+    String { is_key: bool, escape: u8, unicode: bool },
+    /// Inside a number
+    Number(NumberState),
+    /// Inside `true`, `false` or `null`
+    Literal { word: &'static str, matched: usize },
+    /// The top-level value is complete
+    Done,
+}
+
+/// Incremental validator for JSON prefixes
+#[derive(Debug, Clone)]
+pub struct JsonValidator {
+    stack: Vec<Container>,
+    state: State,
+}
+
+impl JsonValidator {
+    /// Create a validator expecting a single JSON value
+    pub fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            state: State::Value,
+        }
+    }
+
+    /// Feed a string, returning false if it makes the prefix invalid
+    ///
+    /// On failure the validator is left in an unspecified state; clone it
+    /// first when probing.
+    pub fn feed_str(&mut self, text: &str) -> bool {
+        text.chars().all(|c| self.feed(c))
+    }
+
+    /// Feed a single character, returning false if it's invalid here
+    pub fn feed(&mut self, c: char) -> bool {
+        match self.state {
+            State::String { is_key, escape, unicode } => {
+                self.feed_string(c, is_key, escape, unicode)
+            }
+            State::Number(num) => match Self::next_number(num, c) {
+                Some(next) => {
+                    self.state = State::Number(next);
+                    true
+                }
+                None if num.is_terminal() => {
+                    // The number ended; reprocess the character after it
+                    self.end_value();
+                    self.feed(c)
+                }
+                None => false,
+            },
+            State::Literal { word, matched } => {
+                if word[matched..].starts_with(c) {
+                    if matched + 1 == word.len() {
+                        self.end_value();
+                    } else {
+                        self.state = State::Literal { word, matched: matched + 1 };
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            // JSON whitespace is narrower than `char::is_ascii_whitespace`
+            _ if matches!(c, ' ' | '\t' | '\n' | '\r') => true,
+            State::Value => self.start_value(c),
+            State::ArrayValueOrEnd => {
+                if c == ']' {
+                    self.close(Container::Array)
+                } else {
+                    self.start_value(c)
+                }
+            }
+            State::KeyOrEnd | State::Key => match c {
+                '"' => {
+                    self.state = State::String { is_key: true, escape: 0, unicode: false };
+                    true
+                }
+                '}' if self.state == State::KeyOrEnd => self.close(Container::Object),
+                _ => false,
+            },
+            State::Colon => {
+                if c == ':' {
+                    self.state = State::Value;
+                    true
+                } else {
+                    false
+                }
+            }
+            State::CommaOrEnd => match (c, self.stack.last()) {
+                (',', Some(Container::Object)) => {
+                    self.state = State::Key;
+                    true
+                }
+                (',', Some(Container::Array)) => {
+                    self.state = State::Value;
+                    true
+                }
+                ('}', _) => self.close(Container::Object),
+                (']', _) => self.close(Container::Array),
+                _ => false,
+            },
+            State::Done => false,
+        }
+    }
+
+    /// Reset to `other`'s state, reusing this validator's allocation
+    fn copy_from(&mut self, other: &Self) {
+        self.stack.clone_from(&other.stack);
+        self.state = other.state;
+    }
+
+    /// Whether the text so far is a complete JSON document
+    pub fn is_complete(&self) -> bool {
+        match self.state {
+            State::Done => true,
+            State::Number(num) => self.stack.is_empty() && num.is_terminal(),
+            _ => false,
+        }
+    }
+
+    /// Whether the document is complete and can't be extended any further
+    ///
+    /// A top-level number is complete but could still gain more digits.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn start_value(&mut self, c: char) -> bool {
+        self.state = match c {
+            '{' => {
+                self.stack.push(Container::Object);
+                State::KeyOrEnd
+            }
+            '[' => {
+                self.stack.push(Container::Array);
+                State::ArrayValueOrEnd
+            }
+            '"' => State::String { is_key: false, escape: 0, unicode: false },
+            '-' => State::Number(NumberState::Minus),
+            '0' => State::Number(NumberState::Zero),
+            '1'..='9' => State::Number(NumberState::Int),
+            't' => State::Literal { word: "true", matched: 1 },
+            'f' => State::Literal { word: "false", matched: 1 },
+            'n' => State::Literal { word: "null", matched: 1 },
+            _ => return false,
+        };
+        true
+    }
+
+    fn feed_string(&mut self, c: char, is_key: bool, escape: u8, unicode: bool) -> bool {
+        let next = if unicode {
+            if !c.is_ascii_hexdigit() {
+                return false;
+            }
+            State::String { is_key, escape: escape - 1, unicode: escape > 1 }
+        } else if escape > 0 {
+            match c {
+                'u' => State::String { is_key, escape: 4, unicode: true },
+                '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't' => {
+                    State::String { is_key, escape: 0, unicode: false }
+                }
+                _ => return false,
+            }
+        } else {
+            match c {
+                '"' => {
+                    if is_key {
+                        self.state = State::Colon;
+                    } else {
+                        self.end_value();
+                    }
+                    return true;
+                }
+                '\\' => State::String { is_key, escape: 1, unicode: false },
+                c if (c as u32) < 0x20 => return false,
+                _ => State::String { is_key, escape: 0, unicode: false },
+            }
+        };
+        self.state = next;
+        true
+    }
+
+    fn next_number(num: NumberState, c: char) -> Option<NumberState> {
+        use NumberState::*;
+        match (num, c) {
+            (Minus, '0') => Some(Zero),
+            (Minus, '1'..='9') => Some(Int),
+            (Int, '0'..='9') => Some(Int),
+            (Zero | Int, '.') => Some(Dot),
+            (Dot | Frac, '0'..='9') => Some(Frac),
+            (Zero | Int | Frac, 'e' | 'E') => Some(Exp),
+            (Exp, '+' | '-') => Some(ExpSign),
+            (Exp | ExpSign, '0'..='9') => Some(ExpDigits(1)),
+            (ExpDigits(n), '0'..='9') if n < MAX_EXPONENT_DIGITS => Some(ExpDigits(n + 1)),
+            _ => None,
+        }
+    }
+
+    fn close(&mut self, container: Container) -> bool {
+        if self.stack.last() != Some(&container) {
+            return false;
+        }
+        self.stack.pop();
+        self.end_value();
+        true
+    }
+
+    fn end_value(&mut self) {
+        self.state = if self.stack.is_empty() {
+            State::Done
+        } else {
+            State::CommaOrEnd
+        };
+    }
+}
+
+impl Default for JsonValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Mask logits of tokens that would make the output invalid
+///
+/// `token_texts` holds the decoded text of each token ID. The EOS token is
+/// only allowed once the document is complete. Returns false when no token
+/// is allowed at all.
+///
+/// Candidates are probed on one scratch validator, and tokens whose first
+/// ASCII character is already known to be rejected are skipped.
+pub(crate) fn mask_logits(
+    logits: &mut [f32],
+    validator: &JsonValidator,
+    token_texts: &[String],
+    eos_token_id: u32,
+) -> bool {
+    let mut any_allowed = false;
+    let mut scratch = validator.clone();
+    let mut first_allowed = [None::<bool>; 128];
+    let mut probe = |text: &str| {
+        scratch.copy_from(validator);
+        scratch.feed_str(text)
+    };
+
+    for (id, logit) in logits.iter_mut().enumerate() {
+        let allowed = if id as u32 == eos_token_id {
+            validator.is_complete()
+        } else {
+            match token_texts.get(id) {
+                Some(text) if !text.is_empty() && !text.contains('\u{FFFD}') => {
+                    let first = text.as_bytes()[0] as usize;
+                    let first_ok = match first_allowed.get_mut(first) {
+                        Some(cached) => *cached.get_or_insert_with(|| probe(&text[..1])),
+                        None => true,
+                    };
+                    first_ok && probe(text)
+                }
+                _ => false,
+            }
+        };
+
+        if allowed {
+            any_allowed = true;
+        } else {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+
+    any_allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(text: &str) -> bool {
+        let mut v = JsonValidator::new();
+        v.feed_str(text) && v.is_complete()
+    }
+
+    #[test]
+    fn test_validator() {
+        assert!(accepts(r#"{"a": [1, -2.5e3, true, null], "b": {"c": "x\"yé"}}"#));
+        assert!(accepts("[]"));
+        assert!(accepts("42"));
+        assert!(accepts("\"hi\""));
+
+        assert!(!accepts(r#"{"a": 1"#)); // valid prefix, incomplete
+        assert!(!accepts(r#"{"a" 1}"#));
+        assert!(!accepts("[1,]"));
+        assert!(!accepts("01"));
+        assert!(!accepts("{} {}"));
+        assert!(!accepts("tru"));
+        assert!(!accepts("1e999")); // out of f64 range
+        assert!(!accepts("[1,\u{c}2]")); // form feed isn't JSON whitespace
+    }
+
+    #[test]
+    fn test_mask_matches_probing() {
+        let vocab: Vec<String> = [
+            "{", "}", "[", "]", "\"", ":", ",", " ", "1", "12", "-", ".5", "e", "true", "tr",
+            "null", "\"a", "a\"", "\\", "\\u", "00", "é", "é\"", "",
+        ]
+        .iter()
+        .map(|t| t.to_string())
+        .collect();
+        let eos = vocab.len() as u32 - 1;
+
+        for prefix in ["", "{", "{\"k", "{\"k\"", "{\"k\":", "[1", "[1,", "\"\\", "-", "[tr", "7"] {
+            let mut validator = JsonValidator::new();
+            assert!(validator.feed_str(prefix));
+
+            let mut logits = vec![0.0; vocab.len()];
+            mask_logits(&mut logits, &validator, &vocab, eos);
+            for (id, text) in vocab.iter().enumerate() {
+                let expected = if id as u32 == eos {
+                    validator.is_complete()
+                } else {
+                    !text.is_empty() && validator.clone().feed_str(text)
+                };
+                assert_eq!(logits[id].is_finite(), expected, "{:?} after {:?}", text, prefix);
+            }
+        }
+    }
+
+    #[test]
+    fn test_masked_sampling_always_parses() {
+        // Toy vocabulary of JSON fragments
+        let vocab: Vec<String> = [
+            "{", "}", "[", "]", ",", ":", "\"", "a", "b", " ", "1", "0", "-", ".", "e", "true",
+            "null", "false", "\"k\"", "\\", "n", "u", "l", "ull", "x}",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .chain(std::iter::once(String::new())) // EOS slot
+        .collect();
+        let eos = (vocab.len() - 1) as u32;
+
+        let mut seed = 7u64;
+        let mut next_rand = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as f32 / (1u64 << 31) as f32
+        };
+
+        let mut completed = 0;
+        for _ in 0..200 {
+            let mut validator = JsonValidator::new();
+            let mut output = String::new();
+
+            for step in 0..200 {
+                // Random logits that increasingly favor closing the document
+                let mut logits: Vec<f32> = vocab
+                    .iter()
+                    .map(|t| {
+                        let closing = matches!(t.as_str(), "}" | "]" | "\"" | "1");
+                        next_rand() + if closing { step as f32 / 20.0 } else { 0.0 }
+                    })
+                    .collect();
+                logits[eos as usize] += step as f32 / 10.0;
+
+                assert!(mask_logits(&mut logits, &validator, &vocab, eos), "{:?}", output);
+                let best = super::super::sampling::top_logprobs(&logits, 1)[0].0;
+                if best == eos {
+                    break;
+                }
+
+                let text = &vocab[best as usize];
+                assert!(validator.feed_str(text));
+                output.push_str(text);
+                if validator.is_done() {
+                    break;
+                }
+            }
+
+            if validator.is_complete() {
+                completed += 1;
+                serde_json::from_str::<serde_json::Value>(&output)
+                    .unwrap_or_else(|e| panic!("{:?} failed to parse: {}", output, e));
+            }
+        }
+        assert!(completed > 100);
+    }
+}
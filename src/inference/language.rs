@@ -0,0 +1,78 @@
+//! Lightweight language/script detection
+//!
+//! A simple heuristic that counts characters per Unicode script and maps
+//! the dominant script to a language tag. It's not a real language
+//! identifier (all Latin text is reported as "en"), but it's enough to
+//! pick formatting hints for multilingual chat.
+
+/// Detect the most likely language of a text from its script
+///
+/// Returns a BCP 47 style tag such as `"en"`, `"zh"`, `"ja"` or `"ru"`,
+/// or `"und"` when the text has no letters.
+pub fn detect_language(text: &str) -> &'static str {
+    let mut latin = 0usize;
+    let mut han = 0usize;
+    let mut kana = 0usize;
+    let mut hangul = 0usize;
+    let mut cyrillic = 0usize;
+    let mut arabic = 0usize;
+    let mut hebrew = 0usize;
+    let mut greek = 0usize;
+    let mut devanagari = 0usize;
+    let mut thai = 0usize;
+
+    for c in text.chars() {
+        match c as u32 {
+            0x0041..=0x005A | 0x0061..=0x007A | 0x00C0..=0x024F => latin += 1,
+            0x0370..=0x03FF => greek += 1,
+            0x0400..=0x04FF => cyrillic += 1,
+            0x0590..=0x05FF => hebrew += 1,
+            0x0600..=0x06FF => arabic += 1,
+            0x0900..=0x097F => devanagari += 1,
+            0x0E00..=0x0E7F => thai += 1,
+            0x1100..=0x11FF | 0xAC00..=0xD7AF => hangul += 1,
+            0x3040..=0x30FF => kana += 1,
+            0x3400..=0x4DBF | 0x4E00..=0x9FFF => han += 1,
+            _ => {}
+        }
+    }
+
+    // Japanese mixes kana with Han characters, so any kana wins over Han
+    if kana > 0 && kana + han >= latin {
+        return "ja";
+    }
+
+    let counts = [
+        (latin, "en"),
+        (han, "zh"),
+        (hangul, "ko"),
+        (cyrillic, "ru"),
+        (arabic, "ar"),
+        (hebrew, "he"),
+        (greek, "el"),
+        (devanagari, "hi"),
+        (thai, "th"),
+    ];
+
+    counts
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .max_by_key(|(count, _)| *count)
+        .map(|(_, tag)| *tag)
+        .unwrap_or("und")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(detect_language("What color is the sky?"), "en");
+        assert_eq!(detect_language("天空是什么颜色的？"), "zh");
+        assert_eq!(detect_language("空は何色ですか？"), "ja");
+        assert_eq!(detect_language("하늘은 무슨 색이에요?"), "ko");
+        assert_eq!(detect_language("Какого цвета небо?"), "ru");
+        assert_eq!(detect_language("12345 ?!"), "und");
+    }
+}
@@ -8,23 +8,85 @@
 //! The Candle backend provides pure-Rust implementations.
 
 mod candle_llm;
+mod download;
 mod embedder;
+mod grammar;
+mod language;
+mod model_info;
+mod replay;
+mod sampler;
+mod sampling;
+mod transformer;
 
 pub use candle_llm::CandleLLM;
-pub use embedder::Embedder;
+pub use download::{DownloadOptions, ProgressReporter};
+pub use embedder::{Embedder, PoolingStrategy};
+pub use grammar::{Grammar, JsonValidator};
+pub use language::detect_language;
+pub use model_info::ModelInfo;
+pub use replay::{GenerationRecord, RecordingEngine, ReplayEngine};
+pub use sampler::{GreedySampler, Sampler, TemperatureSampler};
+
+#[cfg(test)]
+pub(crate) use embedder::tests::write_tiny_bert;
 
 use crate::config::GenerationConfig;
-use crate::Result;
+use crate::{CortexError, Result};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 /// Engine state for checkpointing
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct EngineState {
     /// Serialized KV cache or other state
+    ///
+    /// Base64-encoded in human-readable formats such as JSON.
+    #[serde(with = "base64_bytes")]
     pub data: Vec<u8>,
     /// Number of tokens in context
     pub n_tokens: usize,
     /// Engine identifier
     pub engine_id: String,
+    /// How restoring this state will rebuild the KV cache
+    ///
+    /// Only meaningful within the engine that produced it; never
+    /// serialized, so states loaded from disk always replay.
+    #[serde(skip)]
+    pub restore: RestoreMode,
+}
+
+/// Serde adapter writing bytes as base64 in human-readable formats
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer)
+        }
+    }
+}
+
+impl EngineState {
+    /// Holds no engine context, as with `EngineState::default()`
+    ///
+    /// Serialized state is never empty bytes, so an engine that had no
+    /// tokens in context counts as empty too.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty() || self.n_tokens == 0
+    }
 }
 
 impl Default for EngineState {
@@ -33,6 +95,139 @@ impl Default for EngineState {
             data: vec![],
             n_tokens: 0,
             engine_id: "none".to_string(),
+            restore: RestoreMode::Replay,
+        }
+    }
+}
+
+/// How an engine rebuilds its KV cache when restoring state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestoreMode {
+    /// A cached copy of the KV cache is reused; nothing is recomputed
+    Warm,
+    /// The context is re-run through the model on the next generation
+    #[default]
+    Replay,
+}
+
+/// A generated token with its log probability
+///
+/// Logprobs are taken after logit bias and grammar masking, so banned and
+/// masked tokens have a logprob of negative infinity. Temperature, top-p and
+/// custom `Sampler`s are not reflected.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TokenInfo {
+    /// Decoded text contributed by this token (may be empty)
+    pub text: String,
+    /// Sampled token ID
+    pub token_id: u32,
+    /// Log probability of the sampled token (always <= 0)
+    pub logprob: f32,
+    /// Most likely alternatives as `(token_id, logprob)`, most likely first
+    ///
+    /// Contains `GenerationConfig::n_logprobs` entries, or the whole
+    /// vocabulary if it is smaller.
+    pub top_logprobs: Vec<(u32, f32)>,
+}
+
+/// Why a generation ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum FinishReason {
+    /// A stop sequence or stop token was produced, or the grammar completed
+    Stop,
+    /// `max_tokens` was reached
+    Length,
+    /// The model emitted its end-of-sequence token
+    EosToken,
+    /// The callback asked to stop
+    Cancelled,
+    /// The output fell into a repeating token cycle
+    RepetitionLoop,
+}
+
+/// Generated text with token accounting
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GenerationOutput {
+    /// Generated text
+    pub text: String,
+    /// Tokens in the prompt
+    pub prompt_tokens: usize,
+    /// Tokens generated
+    pub completion_tokens: usize,
+    /// Why generation ended
+    pub finish_reason: FinishReason,
+    /// Timing of the prompt and generation phases
+    #[serde(default)]
+    pub stats: GenerationStats,
+}
+
+/// Token counts and timings of one generation
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GenerationStats {
+    /// Tokens in the prompt
+    pub prompt_tokens: usize,
+    /// Milliseconds spent processing the prompt
+    pub eval_ms: f64,
+    /// Tokens generated
+    pub gen_tokens: usize,
+    /// Milliseconds spent generating
+    pub gen_ms: f64,
+}
+
+impl GenerationStats {
+    /// Prompt tokens processed per second, 0 if nothing was timed
+    pub fn prompt_tokens_per_sec(&self) -> f64 {
+        per_sec(self.prompt_tokens, self.eval_ms)
+    }
+
+    /// Tokens generated per second, 0 if nothing was timed
+    pub fn tokens_per_sec(&self) -> f64 {
+        per_sec(self.gen_tokens, self.gen_ms)
+    }
+}
+
+/// A duration in fractional milliseconds
+pub(crate) fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn per_sec(tokens: usize, ms: f64) -> f64 {
+    if ms > 0.0 {
+        tokens as f64 * 1000.0 / ms
+    } else {
+        0.0
+    }
+}
+
+/// Event emitted by `TextEngine::generate_with_events`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamEvent<'a> {
+    /// Sent before any work on the prompt, so UIs can show progress at once
+    PrefillStarted,
+    /// The prompt has been processed; tokens follow
+    PrefillFinished,
+    /// A chunk of generated text
+    Token(&'a str),
+}
+
+/// What a `TextEngine::generate_interactive` callback wants next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamControl {
+    /// Keep generating
+    Continue,
+    /// End generation
+    Stop,
+    /// Append text to the context, then keep generating after it
+    Inject(String),
+}
+
+impl From<bool> for StreamControl {
+    /// `true` continues and `false` stops, as in `generate_streaming`
+    fn from(keep_going: bool) -> Self {
+        if keep_going {
+            StreamControl::Continue
+        } else {
+            StreamControl::Stop
         }
     }
 }
@@ -53,6 +248,14 @@ pub trait TextEngine: Send {
     /// Get embedding for text (for memory/RAG)
     fn embed(&self, text: &str) -> Result<Vec<f32>>;
 
+    /// Get embeddings for several texts
+    ///
+    /// The default embeds one at a time; engines that can share work
+    /// across texts override it.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        texts.iter().map(|text| self.embed(text)).collect()
+    }
+
     /// Generate text completion
     fn generate(&mut self, prompt: &str, config: &GenerationConfig) -> Result<String>;
 
@@ -64,6 +267,156 @@ pub trait TextEngine: Send {
         callback: &mut dyn FnMut(&str) -> bool,
     ) -> Result<String>;
 
+    /// Generate with streaming callback, reporting token counts and why
+    /// generation ended
+    ///
+    /// The default wraps `generate_streaming`, counting prompt tokens with
+    /// `count_tokens` and each streamed chunk as one completion token, and
+    /// infers the finish reason from those counts. Prompt evaluation is
+    /// timed up to the first chunk.
+    fn generate_streaming_output(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<GenerationOutput> {
+        let mut completion_tokens = 0;
+        let mut cancelled = false;
+        let start = Instant::now();
+        let mut first_token = None;
+        let text = self.generate_streaming(prompt, config, &mut |token| {
+            first_token.get_or_insert_with(Instant::now);
+            completion_tokens += 1;
+            cancelled = !callback(token);
+            !cancelled
+        })?;
+        let end = Instant::now();
+        let first_token = first_token.unwrap_or(end);
+
+        let finish_reason = if cancelled {
+            FinishReason::Cancelled
+        } else if completion_tokens >= config.max_tokens as usize {
+            FinishReason::Length
+        } else {
+            FinishReason::EosToken
+        };
+        let prompt_tokens = self.count_tokens(prompt);
+        Ok(GenerationOutput {
+            text,
+            prompt_tokens,
+            completion_tokens,
+            finish_reason,
+            stats: GenerationStats {
+                prompt_tokens,
+                eval_ms: millis(first_token - start),
+                gen_tokens: completion_tokens,
+                gen_ms: millis(end - first_token),
+            },
+        })
+    }
+
+    /// Generate with a callback receiving each new chunk and the full text
+    ///
+    /// The callback gets `(delta, text_so_far)`, for UIs that redraw the
+    /// whole response rather than appending.
+    fn generate_streaming_accumulated(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str, &str) -> bool,
+    ) -> Result<String> {
+        let mut text = String::new();
+        self.generate_streaming(prompt, config, &mut |delta| {
+            text.push_str(delta);
+            callback(delta, &text)
+        })
+    }
+
+    /// Generate with a callback receiving prefill progress and tokens
+    ///
+    /// `PrefillStarted` is always the first event and is sent before the
+    /// prompt is tokenized. The default implementation reports
+    /// `PrefillFinished` just before the first token.
+    fn generate_with_events(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(StreamEvent<'_>) -> bool,
+    ) -> Result<String> {
+        if !callback(StreamEvent::PrefillStarted) {
+            return Ok(String::new());
+        }
+
+        let mut prefilled = false;
+        self.generate_streaming(prompt, config, &mut |token| {
+            if !prefilled {
+                prefilled = true;
+                if !callback(StreamEvent::PrefillFinished) {
+                    return false;
+                }
+            }
+            callback(StreamEvent::Token(token))
+        })
+    }
+
+    /// Generate with a callback that can stop generation or inject text
+    ///
+    /// Injected text goes into the context as if the model had written it
+    /// right after the chunk that asked for it, and is part of the returned
+    /// text. Chunks count against `max_tokens`. The default restarts
+    /// generation on the prompt plus everything so far, which engines that
+    /// reuse a cached prefix make cheap.
+    fn generate_interactive(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> StreamControl,
+    ) -> Result<String> {
+        let mut output = String::new();
+        let mut config = config.clone();
+        loop {
+            let mut streamed = String::new();
+            let mut chunks = 0;
+            let mut injected = None;
+            let context = format!("{}{}", prompt, output);
+            let text = self.generate_streaming(&context, &config, &mut |chunk| {
+                streamed.push_str(chunk);
+                chunks += 1;
+                match callback(chunk) {
+                    StreamControl::Continue => true,
+                    StreamControl::Stop => false,
+                    StreamControl::Inject(text) => {
+                        injected = Some(text);
+                        false
+                    }
+                }
+            })?;
+
+            let Some(injected) = injected else {
+                output.push_str(&text);
+                return Ok(output);
+            };
+            output.push_str(&streamed);
+            output.push_str(&injected);
+            config.max_tokens = config.max_tokens.saturating_sub(chunks);
+            if config.max_tokens == 0 {
+                return Ok(output);
+            }
+        }
+    }
+
+    /// Generate with a callback receiving per-token log probabilities
+    fn generate_with_logprobs(
+        &mut self,
+        _prompt: &str,
+        _config: &GenerationConfig,
+        _callback: &mut dyn FnMut(&TokenInfo) -> bool,
+    ) -> Result<String> {
+        Err(CortexError::Inference(
+            "Log probabilities are not supported by this engine".to_string(),
+        ))
+    }
+
     /// Get current state for checkpointing
     fn get_state(&self) -> Result<EngineState>;
 
@@ -75,19 +428,81 @@ pub trait TextEngine: Send {
 
     /// Get number of tokens currently in context
     fn context_used(&self) -> usize;
+
+    /// Count the tokens in a text
+    ///
+    /// The default is a rough estimate of four bytes per token.
+    fn count_tokens(&self, text: &str) -> usize {
+        text.len() / 4
+    }
+
+    /// Identifier of the loaded model
+    ///
+    /// Used to tag portable checkpoints so they are only restored
+    /// against the model that produced them.
+    fn model_id(&self) -> String {
+        "unknown".to_string()
+    }
+
+    /// Architecture and size of the loaded model, as far as known
+    fn model_info(&self) -> ModelInfo {
+        ModelInfo::default()
+    }
 }
 
 /// Chat message formatting
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum ChatTemplate {
     #[default]
     Llama3,
     ChatML,
     Phi3,
     Gemma,
+    /// ChatML with Qwen's default system message
+    Qwen,
+    /// Cohere Command-R turn tokens
+    CommandR,
+    /// Mistral `[INST]` blocks, with system messages folded into them
+    Mistral,
     Raw,
 }
 
+impl std::str::FromStr for ChatTemplate {
+    type Err = CortexError;
+
+    /// Parse a template name such as `llama3` or `chatml`, ignoring case
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "llama3" => Ok(ChatTemplate::Llama3),
+            "chatml" => Ok(ChatTemplate::ChatML),
+            "phi3" => Ok(ChatTemplate::Phi3),
+            "gemma" => Ok(ChatTemplate::Gemma),
+            "qwen" => Ok(ChatTemplate::Qwen),
+            "command-r" => Ok(ChatTemplate::CommandR),
+            "mistral" => Ok(ChatTemplate::Mistral),
+            "raw" => Ok(ChatTemplate::Raw),
+            _ => Err(CortexError::Config(format!(
+                "Unknown chat template '{}'; expected one of llama3, chatml, phi3, \
+                 gemma, qwen, command-r, mistral, raw",
+                name
+            ))),
+        }
+    }
+}
+
+impl ChatTemplate {
+    /// Extend a formatted prompt with the start of the assistant's reply
+    ///
+    /// Every template but `Raw` ends with an open assistant turn, so the
+    /// prefix follows it directly; `Raw` puts the prefix on its own line.
+    pub fn append_assistant_prefix(self, prompt: &mut String, prefix: &str) {
+        if matches!(self, ChatTemplate::Raw) && !prompt.is_empty() {
+            prompt.push('\n');
+        }
+        prompt.push_str(prefix);
+    }
+}
+
 /// Format a chat conversation into a prompt string
 pub fn format_chat_prompt(messages: &[crate::Message], template: ChatTemplate) -> String {
     match template {
@@ -95,6 +510,9 @@ pub fn format_chat_prompt(messages: &[crate::Message], template: ChatTemplate) -
         ChatTemplate::ChatML => format_chatml(messages),
         ChatTemplate::Phi3 => format_phi3(messages),
         ChatTemplate::Gemma => format_gemma(messages),
+        ChatTemplate::Qwen => format_qwen(messages),
+        ChatTemplate::CommandR => format_command_r(messages),
+        ChatTemplate::Mistral => format_mistral(messages),
         ChatTemplate::Raw => format_raw(messages),
     }
 }
@@ -173,6 +591,61 @@ fn format_gemma(messages: &[crate::Message]) -> String {
     prompt
 }
 
+/// System message Qwen models were trained with when none is given
+const QWEN_DEFAULT_SYSTEM: &str = "You are a helpful assistant.";
+
+fn format_qwen(messages: &[crate::Message]) -> String {
+    if messages.first().is_some_and(|m| m.role == crate::Role::System) {
+        return format_chatml(messages);
+    }
+    let mut with_system = vec![crate::Message::system(QWEN_DEFAULT_SYSTEM)];
+    with_system.extend_from_slice(messages);
+    format_chatml(&with_system)
+}
+
+fn format_command_r(messages: &[crate::Message]) -> String {
+    let mut prompt = String::from("<BOS_TOKEN>");
+    for msg in messages {
+        // Tool results go in system turns
+        let role = match msg.role {
+            crate::Role::System | crate::Role::Tool => "<|SYSTEM_TOKEN|>",
+            crate::Role::User => "<|USER_TOKEN|>",
+            crate::Role::Assistant => "<|CHATBOT_TOKEN|>",
+        };
+        prompt.push_str(&format!(
+            "<|START_OF_TURN_TOKEN|>{}{}<|END_OF_TURN_TOKEN|>",
+            role, msg.content
+        ));
+    }
+    prompt.push_str("<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>");
+    prompt
+}
+
+fn format_mistral(messages: &[crate::Message]) -> String {
+    let mut prompt = String::from("<s>");
+    // Mistral has no system role; system text opens the next instruction
+    let mut system = String::new();
+    for msg in messages {
+        match msg.role {
+            crate::Role::System => {
+                system.push_str(&msg.content);
+                system.push_str("\n\n");
+            }
+            crate::Role::User | crate::Role::Tool => {
+                prompt.push_str(&format!("[INST] {}{} [/INST]", system, msg.content));
+                system.clear();
+            }
+            crate::Role::Assistant => {
+                prompt.push_str(&format!(" {}</s>", msg.content));
+            }
+        }
+    }
+    if !system.is_empty() {
+        prompt.push_str(&format!("[INST] {} [/INST]", system.trim_end()));
+    }
+    prompt
+}
+
 fn format_raw(messages: &[crate::Message]) -> String {
     messages
         .iter()
@@ -191,6 +664,9 @@ pub struct StubEngine {
     context_size: usize,
     context_used: usize,
     response_prefix: String,
+    model_id: String,
+    /// Canned responses returned in order before falling back to the default
+    script: VecDeque<String>,
 }
 
 impl StubEngine {
@@ -200,13 +676,40 @@ impl StubEngine {
             context_size: 8192,
             context_used: 0,
             response_prefix: "".to_string(),
+            model_id: "stub".to_string(),
+            script: VecDeque::new(),
         }
     }
 
+    /// Answer the next generations with these responses, in order
+    pub fn with_script<I, S>(mut self, responses: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.script = responses.into_iter().map(Into::into).collect();
+        self
+    }
+
     pub fn with_response_prefix(mut self, prefix: impl Into<String>) -> Self {
         self.response_prefix = prefix.into();
         self
     }
+
+    pub fn with_model_id(mut self, model_id: impl Into<String>) -> Self {
+        self.model_id = model_id.into();
+        self
+    }
+
+    pub fn with_context_size(mut self, context_size: usize) -> Self {
+        self.context_size = context_size;
+        self
+    }
+
+    pub fn with_embedding_dim(mut self, embedding_dim: usize) -> Self {
+        self.embedding_dim = embedding_dim;
+        self
+    }
 }
 
 impl Default for StubEngine {
@@ -270,13 +773,30 @@ impl TextEngine for StubEngine {
         config: &GenerationConfig,
         callback: &mut dyn FnMut(&str) -> bool,
     ) -> Result<String> {
-        let response = format!(
-            "{}[Stub response for: \"{}\", temp={}, max={}]",
-            self.response_prefix,
-            prompt.chars().take(30).collect::<String>(),
-            config.temperature,
-            config.max_tokens
-        );
+        // Like a model with nothing to condition on, say nothing
+        if prompt.is_empty() {
+            return Ok(String::new());
+        }
+
+        let response = match self.script.pop_front() {
+            Some(scripted) => scripted,
+            None => {
+                let response = format!(
+                    "{}[Stub response for: \"{}\", temp={}, max={}]",
+                    self.response_prefix,
+                    prompt.chars().take(30).collect::<String>(),
+                    config.temperature,
+                    config.max_tokens
+                );
+
+                // Honor grammar constraints by wrapping the text in a JSON object
+                if config.grammar == Some(Grammar::Json) {
+                    serde_json::json!({ "response": response }).to_string()
+                } else {
+                    response
+                }
+            }
+        };
 
         for word in response.split_inclusive(' ') {
             if !callback(word) {
@@ -288,11 +808,35 @@ impl TextEngine for StubEngine {
         Ok(response)
     }
 
+    fn generate_with_logprobs(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&TokenInfo) -> bool,
+    ) -> Result<String> {
+        // Every word is "certain" in the stub, so each has logprob 0 and
+        // the other alternatives are impossible
+        let mut token_id = 0u32;
+        self.generate_streaming(prompt, config, &mut |word| {
+            let alternatives =
+                (1..config.n_logprobs as u32).map(|i| (token_id + i, f32::NEG_INFINITY));
+            let info = TokenInfo {
+                text: word.to_string(),
+                token_id,
+                logprob: 0.0,
+                top_logprobs: std::iter::once((token_id, 0.0)).chain(alternatives).collect(),
+            };
+            token_id += 1;
+            callback(&info)
+        })
+    }
+
     fn get_state(&self) -> Result<EngineState> {
         Ok(EngineState {
             data: bincode::serialize(&self.context_used).unwrap_or_default(),
             n_tokens: self.context_used,
             engine_id: "stub".to_string(),
+            restore: RestoreMode::Replay,
         })
     }
 
@@ -308,4 +852,122 @@ impl TextEngine for StubEngine {
     fn context_used(&self) -> usize {
         self.context_used
     }
+
+    fn model_id(&self) -> String {
+        self.model_id.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            Message::system("Be brief."),
+            Message::user("Hi"),
+            Message::assistant("Hello!"),
+        ]
+    }
+
+    #[test]
+    fn test_qwen_template() {
+        assert_eq!(
+            format_chat_prompt(&conversation(), ChatTemplate::Qwen),
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+
+        // Without a system message, Qwen's default is used
+        assert_eq!(
+            format_chat_prompt(&[Message::user("Hi")], ChatTemplate::Qwen),
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_command_r_template() {
+        assert_eq!(
+            format_chat_prompt(&conversation(), ChatTemplate::CommandR),
+            "<BOS_TOKEN>\
+             <|START_OF_TURN_TOKEN|><|SYSTEM_TOKEN|>Be brief.<|END_OF_TURN_TOKEN|>\
+             <|START_OF_TURN_TOKEN|><|USER_TOKEN|>Hi<|END_OF_TURN_TOKEN|>\
+             <|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>Hello!<|END_OF_TURN_TOKEN|>\
+             <|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>"
+        );
+    }
+
+    #[test]
+    fn test_interactive_injection_restarts_with_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("run.jsonl");
+        let mut engine = RecordingEngine::new(StubEngine::new(), &log).unwrap();
+
+        let mut injected = false;
+        let output = engine
+            .generate_interactive("Q:", &GenerationConfig::default(), &mut |_| {
+                if injected {
+                    return StreamControl::Continue;
+                }
+                injected = true;
+                StreamControl::Inject("<result>42</result>".to_string())
+            })
+            .unwrap();
+        assert!(output.starts_with("[Stub <result>42</result>[Stub response"));
+
+        // The second generation saw the injected text in its prompt
+        let log = std::fs::read_to_string(&log).unwrap();
+        let prompts: Vec<String> = log
+            .lines()
+            .map(|line| serde_json::from_str::<GenerationRecord>(line).unwrap().prompt)
+            .collect();
+        assert_eq!(prompts, vec!["Q:", "Q:[Stub <result>42</result>"]);
+    }
+
+    #[test]
+    fn test_stub_logprobs() {
+        let config = GenerationConfig {
+            n_logprobs: 3,
+            ..GenerationConfig::default()
+        };
+        let mut infos = Vec::new();
+        StubEngine::new()
+            .generate_with_logprobs("Hi", &config, &mut |info| {
+                infos.push(info.clone());
+                true
+            })
+            .unwrap();
+
+        assert!(!infos.is_empty());
+        for info in &infos {
+            assert_eq!(info.top_logprobs.len(), 3);
+            assert_eq!(info.top_logprobs[0], (info.token_id, 0.0));
+        }
+    }
+
+    #[test]
+    fn test_mistral_template() {
+        let mut messages = conversation();
+        messages.push(Message::user("Bye"));
+        assert_eq!(
+            format_chat_prompt(&messages, ChatTemplate::Mistral),
+            "<s>[INST] Be brief.\n\nHi [/INST] Hello!</s>[INST] Bye [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!("llama3".parse::<ChatTemplate>().unwrap(), ChatTemplate::Llama3);
+        assert_eq!("ChatML".parse::<ChatTemplate>().unwrap(), ChatTemplate::ChatML);
+        assert_eq!("command-r".parse::<ChatTemplate>().unwrap(), ChatTemplate::CommandR);
+        assert!(matches!(
+            "vicuna".parse::<ChatTemplate>(),
+            Err(CortexError::Config(_))
+        ));
+    }
 }
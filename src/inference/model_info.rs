@@ -0,0 +1,199 @@
+//! Descriptive model metadata for display
+//!
+//! Every field is optional: checkpoints differ in which GGUF keys they
+//! carry, and a missing key shows as "unknown" rather than a guess.
+
+use candle_core::quantized::gguf_file::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Architecture and size of a loaded model
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    /// Architecture name, e.g. `"llama"`
+    pub architecture: Option<String>,
+    /// Weight quantization, e.g. `"Q4_K_M"`
+    pub quantization: Option<String>,
+    /// Number of transformer blocks
+    pub n_layers: Option<usize>,
+    /// Number of attention heads
+    pub n_heads: Option<usize>,
+    /// Tokenizer vocabulary size
+    pub vocab_size: Option<usize>,
+    /// Number of parameters, exact or estimated from the dimensions
+    pub param_count: Option<u64>,
+}
+
+impl ModelInfo {
+    /// Read what GGUF metadata says about the model
+    ///
+    /// Architecture-specific keys are looked up under
+    /// `general.architecture`. The parameter count is estimated from the
+    /// layer dimensions, assuming a Llama-style block.
+    pub fn from_gguf_metadata(metadata: &HashMap<String, Value>) -> Self {
+        let architecture = match metadata.get("general.architecture") {
+            Some(Value::String(arch)) => Some(arch.clone()),
+            _ => None,
+        };
+        let arch = architecture.as_deref().unwrap_or("llama");
+        let arch_uint = |key: &str| uint(metadata, &format!("{}.{}", arch, key));
+
+        let vocab_size = match metadata.get("tokenizer.ggml.tokens") {
+            Some(Value::Array(tokens)) => Some(tokens.len()),
+            _ => arch_uint("vocab_size"),
+        };
+        let n_layers = arch_uint("block_count");
+        let n_heads = arch_uint("attention.head_count");
+
+        let param_count = match (
+            vocab_size,
+            arch_uint("embedding_length"),
+            n_layers,
+            arch_uint("feed_forward_length"),
+            n_heads,
+        ) {
+            (Some(vocab), Some(hidden), Some(layers), Some(ffn), Some(heads)) if heads > 0 => {
+                let kv_heads = arch_uint("attention.head_count_kv").unwrap_or(heads);
+                Some(estimate_params(
+                    vocab,
+                    hidden,
+                    layers,
+                    ffn,
+                    hidden / heads * kv_heads,
+                ))
+            }
+            _ => None,
+        };
+
+        Self {
+            architecture,
+            quantization: uint(metadata, "general.file_type").map(file_type_name),
+            n_layers,
+            n_heads,
+            vocab_size,
+            param_count,
+        }
+    }
+}
+
+impl fmt::Display for ModelInfo {
+    /// One `Name: value` line per field
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn show<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map_or_else(|| "unknown".to_string(), |v| v.to_string())
+        }
+
+        writeln!(f, "Architecture: {}", show(&self.architecture))?;
+        writeln!(f, "Quantization: {}", show(&self.quantization))?;
+        writeln!(f, "Layers: {}", show(&self.n_layers))?;
+        writeln!(f, "Attention heads: {}", show(&self.n_heads))?;
+        writeln!(f, "Vocab size: {}", show(&self.vocab_size))?;
+        write!(
+            f,
+            "Parameters: {}",
+            show(&self.param_count.map(format_params))
+        )
+    }
+}
+
+/// An unsigned integer metadata value of any width
+fn uint(metadata: &HashMap<String, Value>, key: &str) -> Option<usize> {
+    match metadata.get(key)? {
+        Value::U8(n) => Some(*n as usize),
+        Value::U16(n) => Some(*n as usize),
+        Value::U32(n) => Some(*n as usize),
+        Value::U64(n) => Some(*n as usize),
+        Value::I32(n) => usize::try_from(*n).ok(),
+        Value::I64(n) => usize::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+/// Parameters in a Llama-style model with untied input and output embeddings
+fn estimate_params(vocab: usize, hidden: usize, layers: usize, ffn: usize, kv_dim: usize) -> u64 {
+    let attention = 2 * hidden * hidden + 2 * hidden * kv_dim;
+    let mlp = 3 * hidden * ffn;
+    let norms = 2 * hidden;
+    (2 * vocab * hidden + layers * (attention + mlp + norms) + hidden) as u64
+}
+
+/// llama.cpp's name for a `general.file_type` value
+fn file_type_name(file_type: usize) -> String {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        32 => "BF16",
+        other => return format!("type {}", other),
+    };
+    name.to_string()
+}
+
+/// Human-readable parameter count, e.g. `"6.74B"`
+fn format_params(count: u64) -> String {
+    match count {
+        n if n >= 1_000_000_000 => format!("{:.2}B", n as f64 / 1e9),
+        n if n >= 1_000_000 => format!("{:.1}M", n as f64 / 1e6),
+        n if n >= 1_000 => format!("{:.1}K", n as f64 / 1e3),
+        n => n.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_gguf_metadata() {
+        // Llama 2 7B dimensions
+        let metadata = HashMap::from([
+            (
+                "general.architecture".to_string(),
+                Value::String("llama".to_string()),
+            ),
+            ("general.file_type".to_string(), Value::U32(15)),
+            ("llama.block_count".to_string(), Value::U32(32)),
+            ("llama.attention.head_count".to_string(), Value::U32(32)),
+            ("llama.embedding_length".to_string(), Value::U32(4096)),
+            ("llama.feed_forward_length".to_string(), Value::U32(11008)),
+            (
+                "tokenizer.ggml.tokens".to_string(),
+                Value::Array(vec![Value::String(String::new()); 32000]),
+            ),
+        ]);
+
+        let info = ModelInfo::from_gguf_metadata(&metadata);
+        assert_eq!(info.architecture.as_deref(), Some("llama"));
+        assert_eq!(info.quantization.as_deref(), Some("Q4_K_M"));
+        assert_eq!(info.n_layers, Some(32));
+        assert_eq!(info.n_heads, Some(32));
+        assert_eq!(info.vocab_size, Some(32000));
+        assert_eq!(info.param_count, Some(6_738_415_616));
+        assert!(info.to_string().contains("Parameters: 6.74B"));
+
+        // Missing keys stay unknown instead of falling back to defaults
+        let sparse = HashMap::from([("llama.block_count".to_string(), Value::U32(2))]);
+        let info = ModelInfo::from_gguf_metadata(&sparse);
+        assert_eq!(info.n_layers, Some(2));
+        assert_eq!(info.param_count, None);
+        let text = info.to_string();
+        assert!(text.contains("Architecture: unknown"));
+        assert!(text.contains("Vocab size: unknown"));
+    }
+}
@@ -0,0 +1,293 @@
+//! Record/replay harness for reproducing generation runs
+//!
+//! `RecordingEngine` wraps any `TextEngine` and appends every generation's
+//! prompt, config and output to a JSONL log. `ReplayEngine` reads that log
+//! and serves the recorded outputs in order without loading a model, so
+//! agent runs can be reproduced deterministically.
+
+use crate::config::GenerationConfig;
+use crate::{CortexError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use super::{EngineState, GenerationOutput, ModelInfo, StubEngine, TextEngine, TokenInfo};
+
+/// One recorded generation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationRecord {
+    /// Prompt passed to the engine
+    pub prompt: String,
+    /// Generation parameters
+    pub config: GenerationConfig,
+    /// Text the engine returned
+    pub output: String,
+}
+
+/// Engine wrapper that logs every generation to a JSONL file
+pub struct RecordingEngine<E: TextEngine> {
+    inner: E,
+    log: BufWriter<File>,
+}
+
+impl<E: TextEngine> RecordingEngine<E> {
+    /// Wrap `inner`, creating (or truncating) the log at `path`
+    pub fn new(inner: E, path: impl AsRef<Path>) -> Result<Self> {
+        let log = BufWriter::new(File::create(path)?);
+        Ok(Self { inner, log })
+    }
+
+    /// Unwrap the recorded engine
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    fn record(&mut self, prompt: &str, config: &GenerationConfig, output: &str) -> Result<()> {
+        let record = GenerationRecord {
+            prompt: prompt.to_string(),
+            config: config.clone(),
+            output: output.to_string(),
+        };
+        let line = serde_json::to_string(&record)
+            .map_err(|e| CortexError::Serialization(e.to_string()))?;
+
+        // Flush per record so the log survives a crash mid-run
+        writeln!(self.log, "{}", line)?;
+        self.log.flush()?;
+        Ok(())
+    }
+}
+
+impl<E: TextEngine> TextEngine for RecordingEngine<E> {
+    fn embedding_dim(&self) -> usize {
+        self.inner.embedding_dim()
+    }
+
+    fn context_size(&self) -> usize {
+        self.inner.context_size()
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.inner.embed(text)
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        self.inner.embed_batch(texts)
+    }
+
+    fn generate(&mut self, prompt: &str, config: &GenerationConfig) -> Result<String> {
+        let output = self.inner.generate(prompt, config)?;
+        self.record(prompt, config, &output)?;
+        Ok(output)
+    }
+
+    fn generate_streaming(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<String> {
+        let output = self.inner.generate_streaming(prompt, config, callback)?;
+        self.record(prompt, config, &output)?;
+        Ok(output)
+    }
+
+    fn generate_streaming_output(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<GenerationOutput> {
+        let output = self.inner.generate_streaming_output(prompt, config, callback)?;
+        self.record(prompt, config, &output.text)?;
+        Ok(output)
+    }
+
+    fn generate_with_logprobs(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&TokenInfo) -> bool,
+    ) -> Result<String> {
+        let output = self.inner.generate_with_logprobs(prompt, config, callback)?;
+        self.record(prompt, config, &output)?;
+        Ok(output)
+    }
+
+    fn get_state(&self) -> Result<EngineState> {
+        self.inner.get_state()
+    }
+
+    fn set_state(&mut self, state: &EngineState) -> Result<()> {
+        self.inner.set_state(state)
+    }
+
+    fn clear(&mut self) {
+        self.inner.clear()
+    }
+
+    fn context_used(&self) -> usize {
+        self.inner.context_used()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.inner.count_tokens(text)
+    }
+
+    fn model_id(&self) -> String {
+        self.inner.model_id()
+    }
+
+    fn model_info(&self) -> ModelInfo {
+        self.inner.model_info()
+    }
+}
+
+/// Engine that serves outputs from a recorded log, in order
+///
+/// Each generation must use the same prompt as the recording; a mismatch
+/// means the run has diverged and is reported as an error. Embeddings and
+/// state come from a `StubEngine`, since no model is loaded.
+pub struct ReplayEngine {
+    records: VecDeque<GenerationRecord>,
+    replayed: usize,
+    fallback: StubEngine,
+}
+
+impl ReplayEngine {
+    /// Load a log written by `RecordingEngine`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut records = VecDeque::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line)
+                .map_err(|e| CortexError::Serialization(e.to_string()))?;
+            records.push_back(record);
+        }
+
+        Ok(Self {
+            records,
+            replayed: 0,
+            fallback: StubEngine::new(),
+        })
+    }
+
+    /// Number of recorded generations not yet replayed
+    pub fn remaining(&self) -> usize {
+        self.records.len()
+    }
+
+    fn next_output(&mut self, prompt: &str) -> Result<String> {
+        let record = self.records.pop_front().ok_or_else(|| {
+            CortexError::Inference(format!(
+                "Replay log exhausted after {} generations",
+                self.replayed
+            ))
+        })?;
+
+        if record.prompt != prompt {
+            return Err(CortexError::Inference(format!(
+                "Replay diverged at generation {}: prompt differs from the recording",
+                self.replayed
+            )));
+        }
+
+        self.replayed += 1;
+        Ok(record.output)
+    }
+}
+
+impl TextEngine for ReplayEngine {
+    fn embedding_dim(&self) -> usize {
+        self.fallback.embedding_dim()
+    }
+
+    fn context_size(&self) -> usize {
+        self.fallback.context_size()
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.fallback.embed(text)
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        self.fallback.embed_batch(texts)
+    }
+
+    fn generate(&mut self, prompt: &str, _config: &GenerationConfig) -> Result<String> {
+        self.next_output(prompt)
+    }
+
+    fn generate_streaming(
+        &mut self,
+        prompt: &str,
+        _config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<String> {
+        let output = self.next_output(prompt)?;
+        callback(&output);
+        Ok(output)
+    }
+
+    fn get_state(&self) -> Result<EngineState> {
+        self.fallback.get_state()
+    }
+
+    fn set_state(&mut self, state: &EngineState) -> Result<()> {
+        self.fallback.set_state(state)
+    }
+
+    fn clear(&mut self) {
+        self.fallback.clear()
+    }
+
+    fn context_used(&self) -> usize {
+        self.fallback.context_used()
+    }
+
+    fn model_id(&self) -> String {
+        "replay".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.jsonl");
+        let config = GenerationConfig::default();
+
+        let mut recorder = RecordingEngine::new(StubEngine::new(), &path).unwrap();
+        let first = recorder.generate("What is 2+2?", &config).unwrap();
+        let second = recorder
+            .generate_streaming("And 3+3?", &config, &mut |_| true)
+            .unwrap();
+        drop(recorder);
+
+        let mut replay = ReplayEngine::load(&path).unwrap();
+        assert_eq!(replay.remaining(), 2);
+        assert_eq!(replay.generate("What is 2+2?", &config).unwrap(), first);
+
+        let mut streamed = String::new();
+        let output = replay
+            .generate_streaming("And 3+3?", &config, &mut |chunk| {
+                streamed.push_str(chunk);
+                true
+            })
+            .unwrap();
+        assert_eq!(output, second);
+        assert_eq!(streamed, second);
+
+        // The log is exhausted; there's no model to fall back on
+        assert!(replay.generate("And 4+4?", &config).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Pluggable token selection
+//!
+//! `CandleLLM` picks each next token through a `Sampler`. Without one it
+//! uses a `TemperatureSampler` built from the `GenerationConfig`.
+
+use super::sampling::apply_min_p;
+use crate::config::GenerationConfig;
+use crate::{CortexError, Result};
+use candle_core::Tensor;
+use candle_transformers::generation::LogitsProcessor;
+
+/// Chooses the next token from the last position's logits
+///
+/// Grammar masking has already been applied to `logits`. `history` holds
+/// every token in context, prompt included.
+pub trait Sampler: Send {
+    /// Pick the next token
+    fn sample(&mut self, logits: &Tensor, history: &[u32]) -> Result<u32>;
+}
+
+/// Always picks the most likely token
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GreedySampler;
+
+impl Sampler for GreedySampler {
+    fn sample(&mut self, logits: &Tensor, _history: &[u32]) -> Result<u32> {
+        logits
+            .argmax(0)
+            .and_then(|t| t.to_scalar::<u32>())
+            .map_err(|e| CortexError::Inference(e.to_string()))
+    }
+}
+
+/// Temperature and nucleus (top-p) sampling, with an optional min-p filter
+///
+/// A temperature of 0 is greedy.
+pub struct TemperatureSampler {
+    temperature: f32,
+    top_p: f32,
+    min_p: f32,
+    processor: LogitsProcessor,
+}
+
+impl TemperatureSampler {
+    /// Create a sampler with a random seed
+    pub fn new(temperature: f32, top_p: f32) -> Self {
+        Self {
+            temperature,
+            top_p,
+            min_p: 0.0,
+            processor: LogitsProcessor::new(
+                rand::random(),
+                Some(temperature as f64),
+                Some(top_p as f64),
+            ),
+        }
+    }
+
+    /// The sampler `generate` uses for `config`
+    pub fn from_config(config: &GenerationConfig) -> Self {
+        Self::new(config.temperature, config.top_p).with_min_p(config.min_p)
+    }
+
+    /// Seed the random number generator for reproducible output
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.processor =
+            LogitsProcessor::new(seed, Some(self.temperature as f64), Some(self.top_p as f64));
+        self
+    }
+
+    /// Drop tokens less likely than `min_p` times the top token
+    pub fn with_min_p(mut self, min_p: f32) -> Self {
+        self.min_p = min_p;
+        self
+    }
+}
+
+impl Sampler for TemperatureSampler {
+    fn sample(&mut self, logits: &Tensor, _history: &[u32]) -> Result<u32> {
+        let filtered;
+        let logits = if self.min_p > 0.0 {
+            let mut values = logits
+                .to_vec1::<f32>()
+                .map_err(|e| CortexError::Inference(e.to_string()))?;
+            apply_min_p(&mut values, self.min_p);
+            filtered = Tensor::new(values.as_slice(), logits.device())
+                .map_err(|e| CortexError::Inference(e.to_string()))?;
+            &filtered
+        } else {
+            logits
+        };
+
+        self.processor
+            .sample(logits)
+            .map_err(|e| CortexError::Inference(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_builtin_samplers() {
+        let logits = Tensor::new(&[0.5f32, 3.0, 1.0, 2.9], &Device::Cpu).unwrap();
+        assert_eq!(GreedySampler.sample(&logits, &[]).unwrap(), 1);
+        assert_eq!(
+            TemperatureSampler::new(0.0, 1.0)
+                .sample(&logits, &[])
+                .unwrap(),
+            1
+        );
+
+        // min-p leaves only the two near-top tokens
+        let mut sampler = TemperatureSampler::new(1.0, 1.0)
+            .with_min_p(0.5)
+            .with_seed(7);
+        for _ in 0..20 {
+            assert!(matches!(sampler.sample(&logits, &[]).unwrap(), 1 | 3));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Logit processing helpers used during sampling
+//!
+//! These operate on plain `f32` slices (the last-position logits) so they
+//! can be tested without a model.
+
+use std::collections::HashMap;
+
+/// Biases at or below this ban a token outright
+pub const LOGIT_BIAS_BAN: f32 = -100.0;
+
+/// Compute log-softmax over a logit vector
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return vec![f32::NEG_INFINITY; logits.len()];
+    }
+
+    let sum_exp: f32 = logits.iter().map(|&l| (l - max).exp()).sum();
+    let log_sum = max + sum_exp.ln();
+    logits.iter().map(|&l| l - log_sum).collect()
+}
+
+/// Return the `n` most likely tokens as `(token_id, logprob)`, most likely first
+pub fn top_logprobs(logprobs: &[f32], n: usize) -> Vec<(u32, f32)> {
+    let mut indexed: Vec<(u32, f32)> = logprobs
+        .iter()
+        .enumerate()
+        .map(|(i, &lp)| (i as u32, lp))
+        .collect();
+
+    indexed.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    indexed.truncate(n);
+    indexed
+}
+
+/// Mask tokens whose probability is below `min_p` times the top probability
+///
+/// Operates on the untempered distribution, so the set of surviving tokens
+/// doesn't grow at high temperatures. A `min_p` of 0 disables the filter.
+pub fn apply_min_p(logits: &mut [f32], min_p: f32) {
+    if min_p <= 0.0 {
+        return;
+    }
+
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return;
+    }
+
+    // p_i >= min_p * p_max  <=>  logit_i >= max_logit + ln(min_p)
+    let threshold = max + min_p.min(1.0).ln();
+    for logit in logits.iter_mut() {
+        if *logit < threshold {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Add per-token biases to the logits
+///
+/// Biases of `LOGIT_BIAS_BAN` or below set the logit to -inf, so a banned
+/// token can't win however large its logit. Out-of-range IDs are skipped.
+pub fn apply_logit_bias(logits: &mut [f32], bias: &HashMap<u32, f32>) {
+    for (&token, &b) in bias {
+        if let Some(logit) = logits.get_mut(token as usize) {
+            if b <= LOGIT_BIAS_BAN {
+                *logit = f32::NEG_INFINITY;
+            } else {
+                *logit += b;
+            }
+        }
+    }
+}
+
+/// Whether `tokens` ends with a cycle of at most `max_period` tokens
+/// repeated `repeats` times in a row
+///
+/// A `max_period` of 0 disables the check.
+pub fn ends_in_loop(tokens: &[u32], max_period: usize, repeats: usize) -> bool {
+    (1..=max_period).any(|period| {
+        let span = period * repeats;
+        span > 0
+            && tokens.len() >= span
+            && {
+                let tail = &tokens[tokens.len() - span..];
+                tail.iter().skip(period).zip(tail).all(|(a, b)| a == b)
+            }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_softmax() {
+        let logprobs = log_softmax(&[1.0, 2.0, 3.0]);
+        let total: f32 = logprobs.iter().map(|lp| lp.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        assert!(logprobs.iter().all(|&lp| lp <= 0.0));
+
+        let top = top_logprobs(&logprobs, 2);
+        assert_eq!(top[0].0, 2);
+        assert_eq!(top[1].0, 1);
+    }
+
+    #[test]
+    fn test_min_p() {
+        // Probabilities relative to the top token: 1.0, ~0.95, ~0.37, ~0.05
+        let mut logits = vec![5.0, 4.95, 4.0, 2.0];
+        apply_min_p(&mut logits, 0.9);
+        assert_eq!(logits[0], 5.0);
+        assert_eq!(logits[1], 4.95);
+        assert_eq!(logits[2], f32::NEG_INFINITY);
+        assert_eq!(logits[3], f32::NEG_INFINITY);
+
+        // Disabled leaves everything untouched
+        let mut logits = vec![5.0, 4.0, 2.0];
+        apply_min_p(&mut logits, 0.0);
+        assert_eq!(logits, vec![5.0, 4.0, 2.0]);
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        let mut logits = vec![1.0, 2.0, 3.0];
+        let bias = HashMap::from([(0, 5.0), (2, -100.0), (99, 1.0)]);
+        apply_logit_bias(&mut logits, &bias);
+        assert_eq!(logits, vec![6.0, 2.0, f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn test_ends_in_loop() {
+        assert!(ends_in_loop(&[5, 1, 2, 1, 2, 1, 2], 4, 3));
+        assert!(!ends_in_loop(&[5, 1, 2, 1, 2, 1, 2], 4, 4));
+        assert!(!ends_in_loop(&[1, 2, 3, 1, 2, 4], 4, 2));
+        assert!(ends_in_loop(&[7, 7, 7], 1, 3));
+        assert!(!ends_in_loop(&[7, 7, 7], 0, 3));
+    }
+}
@@ -0,0 +1,590 @@
+//! Llama-family decoder forward pass
+//!
+//! A port of candle's `quantized_llama` that also runs unquantized
+//! safetensors checkpoints. Unlike candle's models it takes left-padded
+//! batches, masking each row's padding and counting positions from its
+//! first real token, and it can return the final hidden states instead of
+//! logits.
+
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{Embedding, VarBuilder};
+use candle_transformers::models::llama::{Config, Llama3RopeType};
+use candle_transformers::models::quantized_llama::MAX_SEQ_LEN;
+use candle_transformers::utils::repeat_kv;
+use std::f32::consts::PI;
+use std::io::{Read, Seek};
+
+/// Root-mean-square layer norm
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    weight: Tensor,
+    eps: f32,
+}
+
+impl RmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        candle_nn::ops::rms_norm(x, &self.weight, self.eps)
+    }
+}
+
+/// Gated feed-forward layer
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate: QMatMul,
+    up: QMatMul,
+    down: QMatMul,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let gate = candle_nn::ops::silu(&self.gate.forward(x)?)?;
+        self.down.forward(&(gate * self.up.forward(x)?)?)
+    }
+}
+
+/// A block's feed-forward layer, dense or mixture-of-experts
+#[derive(Debug, Clone)]
+enum FeedForward {
+    Mlp(Mlp),
+    MoE {
+        n_expert_used: usize,
+        gate_inp: QMatMul,
+        experts: Vec<Mlp>,
+    },
+}
+
+impl FeedForward {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let (n_expert_used, gate_inp, experts) = match self {
+            Self::Mlp(mlp) => return mlp.forward(x),
+            Self::MoE {
+                n_expert_used,
+                gate_inp,
+                experts,
+            } => (*n_expert_used, gate_inp, experts),
+        };
+
+        let (batch, seq_len, hidden) = x.dims3()?;
+        let x = x.reshape(((), hidden))?;
+        let routing = candle_nn::ops::softmax_last_dim(&gate_inp.forward(&x)?)?
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?;
+
+        // Rows routed to each expert, with their renormalized weights
+        let mut rows = vec![vec![]; experts.len()];
+        let mut weights = vec![vec![]; experts.len()];
+        for (row, scores) in routing.iter().enumerate() {
+            let mut ranked: Vec<usize> = (0..scores.len()).collect();
+            ranked.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+            let chosen = &ranked[..n_expert_used.min(ranked.len())];
+            let total: f32 = chosen.iter().map(|&e| scores[e]).sum();
+            for &expert in chosen {
+                rows[expert].push(row as u32);
+                weights[expert].push(scores[expert] / total);
+            }
+        }
+
+        let mut y = x.zeros_like()?;
+        for (expert, mlp) in experts.iter().enumerate() {
+            if rows[expert].is_empty() {
+                continue;
+            }
+            let index = Tensor::new(rows[expert].as_slice(), x.device())?;
+            let weight = Tensor::new(weights[expert].as_slice(), x.device())?.reshape(((), 1))?;
+            let out = mlp.forward(&x.index_select(&index, 0)?)?.broadcast_mul(&weight)?;
+            y = y.index_add(&index, &out, 0)?;
+        }
+        y.reshape((batch, seq_len, hidden))
+    }
+}
+
+/// Rotary position embedding tables
+#[derive(Debug, Clone)]
+struct Rope {
+    cos: Tensor,
+    sin: Tensor,
+    /// GGUF checkpoints rotate interleaved pairs; Hugging Face ones rotate
+    /// the two halves of each head
+    interleaved: bool,
+}
+
+impl Rope {
+    fn new(inv_freq: &[f32], max_positions: usize, interleaved: bool, device: &Device) -> Result<Self> {
+        let inv_freq = Tensor::new(inv_freq, device)?;
+        let angles = Tensor::arange(0, max_positions as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((max_positions, 1))?
+            .matmul(&inv_freq.reshape((1, ()))?)?;
+        Ok(Self {
+            cos: angles.cos()?,
+            sin: angles.sin()?,
+            interleaved,
+        })
+    }
+
+    /// Rotate `x` (`[batch, heads, seq, head_dim]`) for the columns after
+    /// `index_pos`
+    ///
+    /// Row `b`'s positions start at its first real token, `padding[b]`.
+    fn apply(&self, x: &Tensor, padding: &[usize], index_pos: usize) -> Result<Tensor> {
+        let (_, _, seq_len, _) = x.dims4()?;
+        let x = x.contiguous()?;
+        if padding.iter().all(|&pad| pad == 0) {
+            let cos = self.cos.narrow(0, index_pos, seq_len)?;
+            let sin = self.sin.narrow(0, index_pos, seq_len)?;
+            return self.rotate(&x, &cos, &sin);
+        }
+
+        let rows = padding
+            .iter()
+            .enumerate()
+            .map(|(row, &pad)| {
+                let positions: Vec<u32> = (index_pos..index_pos + seq_len)
+                    .map(|column| column.saturating_sub(pad) as u32)
+                    .collect();
+                let positions = Tensor::new(positions, x.device())?;
+                let cos = self.cos.index_select(&positions, 0)?;
+                let sin = self.sin.index_select(&positions, 0)?;
+                self.rotate(&x.narrow(0, row, 1)?, &cos, &sin)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&rows, 0)
+    }
+
+    fn rotate(&self, x: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        if self.interleaved {
+            candle_nn::rotary_emb::rope_i(x, cos, sin)
+        } else {
+            candle_nn::rotary_emb::rope(x, cos, sin)
+        }
+    }
+}
+
+/// Weights and KV cache of one decoder block
+#[derive(Debug, Clone)]
+struct Block {
+    attn_q: QMatMul,
+    attn_k: QMatMul,
+    attn_v: QMatMul,
+    attn_output: QMatMul,
+    attn_norm: RmsNorm,
+    ffn: FeedForward,
+    ffn_norm: RmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Block {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        rope: &Rope,
+        padding: &[usize],
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let attn = self.attention(&self.attn_norm.forward(x)?, mask, rope, padding, index_pos)?;
+        let x = (attn + x)?;
+        let ffn = self.ffn.forward(&self.ffn_norm.forward(&x)?)?;
+        ffn + x
+    }
+
+    fn attention(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        rope: &Rope,
+        padding: &[usize],
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let (batch, seq_len, _) = x.dims3()?;
+        let heads = |proj: &QMatMul, n_head: usize| {
+            proj.forward(x)?
+                .reshape((batch, seq_len, n_head, self.head_dim))?
+                .transpose(1, 2)
+        };
+        let q = rope.apply(&heads(&self.attn_q, self.n_head)?, padding, index_pos)?;
+        let k = rope.apply(&heads(&self.attn_k, self.n_kv_head)?, padding, index_pos)?;
+        let v = heads(&self.attn_v, self.n_kv_head)?.contiguous()?;
+
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) if index_pos > 0 => {
+                (Tensor::cat(&[k_cache, &k], 2)?, Tensor::cat(&[v_cache, &v], 2)?)
+            }
+            _ => (k, v),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let n_rep = self.n_head / self.n_kv_head;
+        let k = repeat_kv(k, n_rep)?;
+        let v = repeat_kv(v, n_rep)?;
+        let scores = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let scores = match mask {
+            Some(mask) => {
+                let blocked = Tensor::new(f32::NEG_INFINITY, scores.device())?
+                    .broadcast_as(scores.shape())?;
+                mask.broadcast_as(scores.shape())?.where_cond(&blocked, &scores)?
+            }
+            None => scores,
+        };
+        let y = candle_nn::ops::softmax_last_dim(&scores)?.matmul(&v.contiguous()?)?;
+        let y = y.transpose(1, 2)?.reshape((batch, seq_len, self.n_head * self.head_dim))?;
+        self.attn_output.forward(&y)
+    }
+}
+
+/// Attention mask for `seq_len` new columns after `index_pos` cached ones
+///
+/// Shaped `[batch, 1, seq_len, index_pos + seq_len]`, 1 where attention is
+/// blocked: later columns, and a row's padding as seen from its real
+/// tokens. Padding still sees earlier padding, so no softmax row is empty.
+/// `None` when nothing needs masking.
+fn attention_mask(
+    padding: &[usize],
+    seq_len: usize,
+    index_pos: usize,
+    device: &Device,
+) -> Result<Option<Tensor>> {
+    if seq_len == 1 && padding.iter().all(|&pad| pad == 0) {
+        return Ok(None);
+    }
+    let total = index_pos + seq_len;
+    let mask: Vec<u8> = padding
+        .iter()
+        .flat_map(|&pad| {
+            (index_pos..total).flat_map(move |q| {
+                (0..total).map(move |k| u8::from(k > q || (k < pad && q >= pad)))
+            })
+        })
+        .collect();
+    Tensor::from_vec(mask, (padding.len(), 1, seq_len, total), device).map(Some)
+}
+
+/// Inverse rotary frequencies for heads of `head_dim`
+fn inv_freq(head_dim: usize, base: f32) -> Vec<f32> {
+    (0..head_dim)
+        .step_by(2)
+        .map(|i| 1.0 / base.powf(i as f32 / head_dim as f32))
+        .collect()
+}
+
+/// Reads named tensors out of a GGUF file
+struct GgufTensors<'a, R> {
+    ct: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: &'a Device,
+}
+
+impl<R: Read + Seek> GgufTensors<'_, R> {
+    fn get(&mut self, name: &str) -> Result<QTensor> {
+        self.ct.tensor(self.reader, name, self.device)
+    }
+
+    fn linear(&mut self, name: &str) -> Result<QMatMul> {
+        QMatMul::from_qtensor(self.get(name)?)
+    }
+
+    fn norm(&mut self, name: &str, eps: f32) -> Result<RmsNorm> {
+        Ok(RmsNorm {
+            weight: self.get(name)?.dequantize(self.device)?,
+            eps,
+        })
+    }
+
+    /// The feed-forward weights of block `prefix`; `suffix` picks an expert
+    fn mlp(&mut self, prefix: &str, suffix: &str) -> Result<Mlp> {
+        Ok(Mlp {
+            gate: self.linear(&format!("{prefix}.ffn_gate{suffix}.weight"))?,
+            up: self.linear(&format!("{prefix}.ffn_up{suffix}.weight"))?,
+            down: self.linear(&format!("{prefix}.ffn_down{suffix}.weight"))?,
+        })
+    }
+}
+
+/// A Llama-family decoder with its KV cache
+///
+/// Cloning only bumps reference counts on the weight and cache tensors.
+#[derive(Debug, Clone)]
+pub(crate) struct Transformer {
+    embeddings: Embedding,
+    blocks: Vec<Block>,
+    norm: RmsNorm,
+    output: QMatMul,
+    rope: Rope,
+}
+
+impl Transformer {
+    /// Load a GGUF checkpoint, as candle's `quantized_llama` does
+    pub(crate) fn from_gguf<R: Read + Seek>(
+        ct: &gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md = |key: &str| match ct.metadata.get(key) {
+            Some(value) => Ok(value),
+            None => candle_core::bail!("cannot find {key} in metadata"),
+        };
+        let n_expert = md("llama.expert_count").and_then(|v| v.to_u32()).unwrap_or(0) as usize;
+        let n_expert_used =
+            md("llama.expert_used_count").and_then(|v| v.to_u32()).unwrap_or(0) as usize;
+        let n_head = md("llama.attention.head_count")?.to_u32()? as usize;
+        let n_kv_head = md("llama.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md("llama.block_count")?.to_u32()? as usize;
+        let hidden = md("llama.embedding_length")?.to_u32()? as usize;
+        let rope_dim = md("llama.rope.dimension_count")?.to_u32()? as usize;
+        let eps = md("llama.attention.layer_norm_rms_epsilon")?.to_f32()?;
+        let rope_base = md("llama.rope.freq_base").and_then(|v| v.to_f32()).unwrap_or(10000.0);
+
+        let mut gguf = GgufTensors { ct, reader, device };
+
+        let token_embd = gguf.get("token_embd.weight")?;
+        let embeddings = Embedding::new(token_embd.dequantize(device)?, hidden);
+        let output = match gguf.get("output.weight") {
+            Ok(output) => output,
+            Err(_) => token_embd,
+        };
+
+        let mut blocks = Vec::with_capacity(block_count);
+        for i in 0..block_count {
+            let prefix = format!("blk.{i}");
+            let ffn = if n_expert <= 1 {
+                FeedForward::Mlp(gguf.mlp(&prefix, "")?)
+            } else {
+                FeedForward::MoE {
+                    n_expert_used,
+                    experts: (0..n_expert)
+                        .map(|e| gguf.mlp(&prefix, &format!(".{e}")))
+                        .collect::<Result<_>>()?,
+                    gate_inp: gguf.linear(&format!("{prefix}.ffn_gate_inp.weight"))?,
+                }
+            };
+            blocks.push(Block {
+                attn_q: gguf.linear(&format!("{prefix}.attn_q.weight"))?,
+                attn_k: gguf.linear(&format!("{prefix}.attn_k.weight"))?,
+                attn_v: gguf.linear(&format!("{prefix}.attn_v.weight"))?,
+                attn_output: gguf.linear(&format!("{prefix}.attn_output.weight"))?,
+                attn_norm: gguf.norm(&format!("{prefix}.attn_norm.weight"), eps)?,
+                ffn,
+                ffn_norm: gguf.norm(&format!("{prefix}.ffn_norm.weight"), eps)?,
+                n_head,
+                n_kv_head,
+                head_dim: hidden / n_head,
+                kv_cache: None,
+            });
+        }
+
+        Ok(Self {
+            embeddings,
+            blocks,
+            norm: gguf.norm("output_norm.weight", eps)?,
+            output: QMatMul::from_qtensor(output)?,
+            rope: Rope::new(&inv_freq(rope_dim, rope_base), MAX_SEQ_LEN, true, device)?,
+        })
+    }
+
+    /// Load a Hugging Face Llama checkpoint, as candle's `llama` does
+    pub(crate) fn from_safetensors(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let hidden = config.hidden_size;
+        let head_dim = hidden / config.num_attention_heads;
+        let q_size = head_dim * config.num_attention_heads;
+        let kv_size = head_dim * config.num_key_value_heads;
+        let linear = |vb: VarBuilder, out: usize, inp: usize| -> Result<QMatMul> {
+            Ok(QMatMul::Tensor(vb.get((out, inp), "weight")?))
+        };
+        let norm = |vb: VarBuilder| -> Result<RmsNorm> {
+            Ok(RmsNorm {
+                weight: vb.get(hidden, "weight")?,
+                eps: config.rms_norm_eps as f32,
+            })
+        };
+
+        let embed_tokens = vb.get((config.vocab_size, hidden), "model.embed_tokens.weight")?;
+        let output = if config.tie_word_embeddings {
+            QMatMul::Tensor(embed_tokens.clone())
+        } else {
+            linear(vb.pp("lm_head"), config.vocab_size, hidden)?
+        };
+
+        let blocks = (0..config.num_hidden_layers)
+            .map(|i| {
+                let vb = vb.pp(format!("model.layers.{i}"));
+                let attn = vb.pp("self_attn");
+                let mlp = vb.pp("mlp");
+                Ok(Block {
+                    attn_q: linear(attn.pp("q_proj"), q_size, hidden)?,
+                    attn_k: linear(attn.pp("k_proj"), kv_size, hidden)?,
+                    attn_v: linear(attn.pp("v_proj"), kv_size, hidden)?,
+                    attn_output: linear(attn.pp("o_proj"), hidden, q_size)?,
+                    attn_norm: norm(vb.pp("input_layernorm"))?,
+                    ffn: FeedForward::Mlp(Mlp {
+                        gate: linear(mlp.pp("gate_proj"), config.intermediate_size, hidden)?,
+                        up: linear(mlp.pp("up_proj"), config.intermediate_size, hidden)?,
+                        down: linear(mlp.pp("down_proj"), hidden, config.intermediate_size)?,
+                    }),
+                    ffn_norm: norm(vb.pp("post_attention_layernorm"))?,
+                    n_head: config.num_attention_heads,
+                    n_kv_head: config.num_key_value_heads,
+                    head_dim,
+                    kv_cache: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Llama 3 stretches the low frequencies for long contexts
+        let mut freqs = inv_freq(head_dim, config.rope_theta);
+        if let Some(scaling) = config
+            .rope_scaling
+            .as_ref()
+            .filter(|s| !matches!(s.rope_type, Llama3RopeType::Default))
+        {
+            let original = scaling.original_max_position_embeddings as f32;
+            let low_freq_wavelen = original / scaling.low_freq_factor;
+            let high_freq_wavelen = original / scaling.high_freq_factor;
+            for freq in &mut freqs {
+                let wavelen = 2.0 * PI / *freq;
+                if wavelen > low_freq_wavelen {
+                    *freq /= scaling.factor;
+                } else if wavelen >= high_freq_wavelen {
+                    let smooth = (original / wavelen - scaling.low_freq_factor)
+                        / (scaling.high_freq_factor - scaling.low_freq_factor);
+                    *freq = (1.0 - smooth) * *freq / scaling.factor + smooth * *freq;
+                }
+            }
+        }
+
+        Ok(Self {
+            embeddings: Embedding::new(embed_tokens, hidden),
+            blocks,
+            norm: norm(vb.pp("model.norm"))?,
+            output,
+            rope: Rope::new(&freqs, config.max_position_embeddings, false, vb.device())?,
+        })
+    }
+
+    /// Number of positions the rotary tables cover
+    pub(crate) fn max_positions(&self) -> usize {
+        self.rope.cos.dims()[0]
+    }
+
+    /// Run `input` (`[batch, seq]`) after `index_pos` cached columns and
+    /// return each row's last-position logits (`[batch, vocab]`)
+    ///
+    /// Row `b` starts with `padding[b]` filler tokens that real tokens never
+    /// attend to. `index_pos == 0` starts a fresh KV cache; otherwise the
+    /// cache must come from earlier calls with the same padding.
+    pub(crate) fn forward(
+        &mut self,
+        input: &Tensor,
+        padding: &[usize],
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let (_, seq_len) = input.dims2()?;
+        let hidden = self.run(input, padding, index_pos)?;
+        self.output.forward(&hidden.i((.., seq_len - 1, ..))?.contiguous()?)
+    }
+
+    /// Final normalized hidden states (`[batch, seq, hidden]`) for a fresh
+    /// `input`, leaving the KV cache as it was
+    pub(crate) fn hidden_states(&self, input: &Tensor, padding: &[usize]) -> Result<Tensor> {
+        self.clone().run(input, padding, 0)
+    }
+
+    fn run(&mut self, input: &Tensor, padding: &[usize], index_pos: usize) -> Result<Tensor> {
+        let (batch, seq_len) = input.dims2()?;
+        if padding.len() != batch {
+            candle_core::bail!("{} padding lengths for a batch of {}", padding.len(), batch);
+        }
+        let mask = attention_mask(padding, seq_len, index_pos, input.device())?;
+        let mut x = self.embeddings.forward(input)?;
+        for block in &mut self.blocks {
+            x = block.forward(&x, mask.as_ref(), &self.rope, padding, index_pos)?;
+        }
+        self.norm.forward(&x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::candle_llm::tests::{write_tiny_model, write_tiny_safetensors};
+    use candle_transformers::models::llama::{Cache, Llama, LlamaConfig};
+    use candle_transformers::models::quantized_llama::ModelWeights;
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b).unwrap().abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar().unwrap()
+    }
+
+    fn input(tokens: &[u32]) -> Tensor {
+        Tensor::new(tokens, &Device::Cpu).unwrap().unsqueeze(0).unwrap()
+    }
+
+    #[test]
+    fn test_matches_quantized_llama() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_tiny_model(dir.path());
+        let mut file = std::fs::File::open(&path).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let mut ours = Transformer::from_gguf(&content, &mut file, &Device::Cpu).unwrap();
+        let mut theirs = ModelWeights::from_gguf(content, &mut file, &Device::Cpu).unwrap();
+
+        let prompt = input(&[300, 301, 302, 303, 304]);
+        let expected = theirs.forward(&prompt, 0).unwrap();
+        assert!(max_diff(&ours.forward(&prompt, &[0], 0).unwrap(), &expected) < 1e-4);
+        let expected = theirs.forward(&input(&[305]), 5).unwrap();
+        assert!(max_diff(&ours.forward(&input(&[305]), &[0], 5).unwrap(), &expected) < 1e-4);
+    }
+
+    #[test]
+    fn test_matches_llama() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tiny_safetensors(dir.path());
+        let config: LlamaConfig =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("config.json")).unwrap()).unwrap();
+        let config = config.into_config(false);
+        let files = [dir.path().join("model.safetensors")];
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files, DType::F32, &Device::Cpu).unwrap() };
+        let mut ours = Transformer::from_safetensors(vb.clone(), &config).unwrap();
+        let theirs = Llama::load(vb, &config).unwrap();
+        let mut cache = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+
+        let prompt = input(&[300, 301, 302, 303, 304]);
+        let expected = theirs.forward(&prompt, 0, &mut cache).unwrap();
+        assert!(max_diff(&ours.forward(&prompt, &[0], 0).unwrap(), &expected) < 1e-4);
+        let expected = theirs.forward(&input(&[305]), 5, &mut cache).unwrap();
+        assert!(max_diff(&ours.forward(&input(&[305]), &[0], 5).unwrap(), &expected) < 1e-4);
+    }
+
+    #[test]
+    fn test_padding_is_masked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_tiny_model(dir.path());
+        let mut file = std::fs::File::open(&path).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let model = Transformer::from_gguf(&content, &mut file, &Device::Cpu).unwrap();
+
+        // The short row is left-padded with arbitrary tokens
+        let long = [300, 301, 302, 303, 304];
+        let short = [310, 311, 312];
+        let batch = Tensor::new(&[long, [2, 9, 310, 311, 312]], &Device::Cpu).unwrap();
+        let padding = [0, 2];
+
+        let hidden = model.hidden_states(&batch, &padding).unwrap();
+        let alone = model.hidden_states(&input(&short), &[0]).unwrap();
+        assert!(max_diff(&hidden.i((1..2, 2..)).unwrap(), &alone) < 1e-5);
+        let alone = model.hidden_states(&input(&long), &[0]).unwrap();
+        assert!(max_diff(&hidden.i(0..1).unwrap(), &alone) < 1e-5);
+
+        // Decoding continues each row from its own positions
+        let mut batched = model.clone();
+        let mut single = model.clone();
+        batched.forward(&batch, &padding, 0).unwrap();
+        single.forward(&input(&short), &[0], 0).unwrap();
+        let next = Tensor::new(&[[305u32], [313]], &Device::Cpu).unwrap();
+        let logits = batched.forward(&next, &padding, 5).unwrap();
+        let expected = single.forward(&input(&[313]), &[0], 3).unwrap();
+        assert!(max_diff(&logits.i(1..2).unwrap(), &expected) < 1e-5);
+    }
+}
@@ -13,20 +13,35 @@
 //!
 //! No Pinecone. No Redis. No LangChain. One binary. Just run.
 
+#[cfg(feature = "async")]
+pub mod async_runtime;
+mod clock;
 pub mod config;
+pub mod handle;
 pub mod inference;
 pub mod memory;
 pub mod runtime;
+pub mod server;
 pub mod session;
 pub mod state;
+pub mod tools;
 
 // Re-exports for convenience
+#[cfg(feature = "async")]
+pub use async_runtime::{AsyncCortex, TokenStream};
 pub use config::{CortexConfig, GenerationConfig};
-pub use inference::{CandleLLM, ChatTemplate, Embedder, EngineState, StubEngine, TextEngine};
+pub use handle::CortexHandle;
+pub use inference::{
+    CandleLLM, ChatTemplate, DownloadOptions, Embedder, EngineState, FinishReason, GenerationOutput,
+    GenerationStats, Grammar, ModelInfo, PoolingStrategy, ProgressReporter, RestoreMode, Sampler,
+    StreamControl, StreamEvent, StubEngine, TextEngine, TokenInfo,
+};
 pub use memory::Memory;
-pub use runtime::Cortex;
+pub use runtime::{Cortex, CortexBuilder, RestoreReport};
+pub use server::Server;
 pub use session::Session;
-pub use state::{Branch, Checkpoint};
+pub use state::{Branch, Checkpoint, CheckpointSort, MergeStrategy};
+pub use tools::{ToolCall, ToolRegistry};
 
 /// Message role in a conversation
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
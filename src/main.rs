@@ -3,7 +3,9 @@
 //! A command-line interface for the Cortex AI runtime.
 
 use clap::{Parser, Subcommand};
-use cortex::{Cortex, GenerationConfig, Message, Session};
+use cortex::{
+    ChatTemplate, Cortex, Embedder, GenerationConfig, Message, ProgressReporter, Server, Session,
+};
 use std::io::{self, BufRead, Write};
 use std::path::PathBuf;
 
@@ -20,7 +22,7 @@ struct Cli {
 enum Commands {
     /// Start an interactive chat session
     Chat {
-        /// Path to the model file (GGUF format)
+        /// Path to a GGUF model file or safetensors checkpoint directory
         #[arg(short, long)]
         model: PathBuf,
 
@@ -43,11 +45,16 @@ enum Commands {
         /// Enable semantic memory (downloads embedding model on first use)
         #[arg(long)]
         memory: bool,
+
+        /// Chat template: llama3, chatml, phi3, gemma, qwen, command-r, mistral,
+        /// raw, or auto for the loader's default
+        #[arg(long, default_value = "auto", value_parser = parse_template)]
+        template: TemplateArg,
     },
 
     /// Generate a single completion
     Generate {
-        /// Path to the model file (GGUF format)
+        /// Path to a GGUF model file or safetensors checkpoint directory
         #[arg(short, long)]
         model: PathBuf,
 
@@ -61,6 +68,54 @@ enum Commands {
         /// Maximum tokens
         #[arg(long, default_value = "256")]
         max_tokens: u32,
+
+        /// Chat template to wrap the prompt in as a user turn; auto completes
+        /// the prompt as-is
+        #[arg(long, default_value = "auto", value_parser = parse_template)]
+        template: TemplateArg,
+    },
+
+    /// Print embeddings for text (one input per line on stdin if none given)
+    Embed {
+        /// Path to a GGUF model file or safetensors checkpoint directory
+        #[arg(short, long)]
+        model: PathBuf,
+
+        /// Use the dedicated embedding model (downloads on first use)
+        #[arg(long)]
+        use_embedder: bool,
+
+        /// Print newline-separated floats instead of JSON
+        #[arg(long)]
+        raw: bool,
+
+        /// Texts to embed
+        text: Vec<String>,
+    },
+
+    /// Serve an OpenAI-compatible HTTP API
+    Serve {
+        /// Path to a GGUF model file or safetensors checkpoint directory
+        #[arg(short, long)]
+        model: PathBuf,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Port to listen on
+        #[arg(short, long, default_value = "8080")]
+        port: u16,
+
+        /// Serve embeddings from the dedicated embedding model (downloads on first use)
+        #[arg(long)]
+        embedder: bool,
+    },
+
+    /// Inspect a session's stored memory
+    Memory {
+        #[command(subcommand)]
+        command: MemoryCommands,
     },
 
     /// List all sessions
@@ -80,9 +135,43 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum MemoryCommands {
+    /// Search a session's memory by similarity
+    Search {
+        /// Session ID
+        session_id: String,
+
+        /// Text to search for
+        query: String,
+
+        /// Number of results
+        #[arg(short, long, default_value = "5")]
+        k: usize,
+
+        /// Minimum similarity score (default: no minimum)
+        #[arg(long)]
+        threshold: Option<f32>,
+
+        /// Embed the query with this model (default: the stub engine sessions use)
+        #[arg(short, long)]
+        model: Option<PathBuf>,
+
+        /// Embed the query with the dedicated embedding model
+        #[arg(long)]
+        use_embedder: bool,
+    },
+
+    /// List the keys and contents of a session's memory
+    List {
+        /// Session ID
+        session_id: String,
+    },
+}
+
 fn main() -> anyhow::Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    // Log to stderr so command output on stdout stays clean
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
 
     let cli = Cli::parse();
 
@@ -94,8 +183,9 @@ fn main() -> anyhow::Result<()> {
             temperature,
             max_tokens,
             memory,
+            template,
         } => {
-            run_chat(model, session, system, temperature, max_tokens, memory)?;
+            run_chat(model, session, system, temperature, max_tokens, memory, template)?;
         }
 
         Commands::Generate {
@@ -103,10 +193,45 @@ fn main() -> anyhow::Result<()> {
             prompt,
             temperature,
             max_tokens,
+            template,
         } => {
-            run_generate(model, prompt, temperature, max_tokens)?;
+            run_generate(model, prompt, temperature, max_tokens, template)?;
         }
 
+        Commands::Embed {
+            model,
+            use_embedder,
+            raw,
+            text,
+        } => {
+            run_embed(model, use_embedder, raw, text)?;
+        }
+
+        Commands::Serve {
+            model,
+            host,
+            port,
+            embedder,
+        } => {
+            run_serve(model, host, port, embedder)?;
+        }
+
+        Commands::Memory { command } => match command {
+            MemoryCommands::Search {
+                session_id,
+                query,
+                k,
+                threshold,
+                model,
+                use_embedder,
+            } => {
+                search_memory(&session_id, &query, k, threshold, model, use_embedder)?;
+            }
+            MemoryCommands::List { session_id } => {
+                list_memory(&session_id)?;
+            }
+        },
+
         Commands::Sessions => {
             list_sessions()?;
         }
@@ -123,6 +248,19 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// A `--template` value: a chat template, or `None` for `auto`
+type TemplateArg = Option<ChatTemplate>;
+
+/// Parse a `--template` value
+///
+/// `auto` keeps whatever template the model was loaded with.
+fn parse_template(name: &str) -> Result<TemplateArg, String> {
+    if name.eq_ignore_ascii_case("auto") {
+        return Ok(None);
+    }
+    name.parse().map(Some).map_err(|e: cortex::CortexError| e.to_string())
+}
+
 fn run_chat(
     model: PathBuf,
     session_id: Option<String>,
@@ -130,6 +268,7 @@ fn run_chat(
     temperature: f32,
     max_tokens: u32,
     enable_memory: bool,
+    template: TemplateArg,
 ) -> anyhow::Result<()> {
     let config = GenerationConfig {
         temperature,
@@ -143,22 +282,29 @@ fn run_chat(
         // TODO: Load real model once llama-cpp is integrated
         let _ = model; // Suppress unused warning for now
         let mut session = Session::new(&session_id)?;
+        if let Some(template) = template {
+            session.runtime_mut().set_template(template);
+        }
 
         if let Some(sys) = system {
             session.set_system(sys);
         }
 
-        println!("Session loaded. Type 'quit' to exit, 'save' to save, 'clear' to clear.\n");
+        println!("Session loaded. Type 'quit' to exit, 'save' to save, 'clear' to clear.");
+        println!("Wrap multi-line input in \"\"\".\n");
         run_chat_loop_session(&mut session, &config)?;
     } else {
         // One-off chat
         println!("Loading model...");
-        let mut ctx = Cortex::load(&model)?;
+        let mut ctx = Cortex::load_with_progress(&model, &mut DownloadProgress::default())?;
+        if let Some(template) = template {
+            ctx = ctx.with_template(template);
+        }
 
         // Enable semantic memory if requested
         if enable_memory {
             println!("Loading embedding model for semantic memory...");
-            ctx = ctx.with_embedder()?;
+            ctx = ctx.with_embedder_progress(Embedder::DEFAULT_MODEL, &mut DownloadProgress::default())?;
             println!("Memory enabled. Use /remember and /recall commands.\n");
         }
 
@@ -167,17 +313,64 @@ fn run_chat(
         }
 
         let help_msg = if enable_memory {
-            "Model loaded. Commands: quit, /remember <text>, /recall <query>\n"
+            "Model loaded. Commands: quit, /regenerate, /remember <text>, /recall <query>"
         } else {
-            "Model loaded. Type 'quit' to exit.\n"
+            "Model loaded. Type 'quit' to exit, '/regenerate' to retry the last answer."
         };
         println!("{}", help_msg);
+        println!("Wrap multi-line input in \"\"\".\n");
         run_chat_loop(&mut ctx, &config, enable_memory)?;
     }
 
     Ok(())
 }
 
+/// Delimiter opening and closing a multi-line block in the chat loop
+const MULTILINE_DELIMITER: &str = "\"\"\"";
+
+/// Read one chat input, or `None` at end of input
+///
+/// A line starting with `"""` opens a block that runs until a line ending
+/// with `"""`, so pasted text and code keep their newlines. Text on the
+/// delimiter lines is part of the block.
+fn read_input(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let first = line.trim_end_matches(['\r', '\n']);
+    let Some(rest) = first.trim_start().strip_prefix(MULTILINE_DELIMITER) else {
+        return Ok(Some(first.to_string()));
+    };
+    if let Some(body) = rest.strip_suffix(MULTILINE_DELIMITER) {
+        return Ok(Some(body.to_string()));
+    }
+
+    let mut lines = vec![rest.to_string()];
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let text = line.trim_end_matches(['\r', '\n']);
+        if let Some(last) = text.trim_end().strip_suffix(MULTILINE_DELIMITER) {
+            lines.push(last.to_string());
+            break;
+        }
+        lines.push(text.to_string());
+    }
+
+    // Drop the empty lines left by delimiters on lines of their own
+    if lines.first().is_some_and(|l| l.trim().is_empty()) {
+        lines.remove(0);
+    }
+    if lines.last().is_some_and(|l| l.trim().is_empty()) {
+        lines.pop();
+    }
+    Ok(Some(lines.join("\n")))
+}
+
 fn run_chat_loop(ctx: &mut Cortex, config: &GenerationConfig, memory_enabled: bool) -> anyhow::Result<()> {
     let stdin = io::stdin();
     let mut stdout = io::stdout();
@@ -187,8 +380,10 @@ fn run_chat_loop(ctx: &mut Cortex, config: &GenerationConfig, memory_enabled: bo
         print!("You: ");
         stdout.flush()?;
 
-        let mut input = String::new();
-        stdin.lock().read_line(&mut input)?;
+        let input = match read_input(&mut stdin.lock())? {
+            Some(input) => input,
+            None => break,
+        };
         let input = input.trim();
 
         if input.is_empty() {
@@ -199,6 +394,14 @@ fn run_chat_loop(ctx: &mut Cortex, config: &GenerationConfig, memory_enabled: bo
             break;
         }
 
+        if input == "/regenerate" {
+            match ctx.regenerate_last(config) {
+                Ok(response) => println!("AI: {}\n", response),
+                Err(e) => println!("Error regenerating: {}\n", e),
+            }
+            continue;
+        }
+
         // Handle memory commands if enabled
         if memory_enabled {
             if let Some(text) = input.strip_prefix("/remember ") {
@@ -264,8 +467,10 @@ fn run_chat_loop_session(session: &mut Session, _config: &GenerationConfig) -> a
         print!("You: ");
         stdout.flush()?;
 
-        let mut input = String::new();
-        stdin.lock().read_line(&mut input)?;
+        let input = match read_input(&mut stdin.lock())? {
+            Some(input) => input,
+            None => break,
+        };
         let input = input.trim();
 
         if input.is_empty() {
@@ -308,9 +513,10 @@ fn run_generate(
     prompt: String,
     temperature: f32,
     max_tokens: u32,
+    template: TemplateArg,
 ) -> anyhow::Result<()> {
     println!("Loading model...");
-    let mut ctx = Cortex::load(&model)?;
+    let mut ctx = Cortex::load_with_progress(&model, &mut DownloadProgress::default())?;
 
     let config = GenerationConfig {
         temperature,
@@ -321,25 +527,155 @@ fn run_generate(
     println!("Generating...\n");
 
     let mut stdout = io::stdout();
-    let _response = ctx.generate_streaming(&prompt, &config, &mut |token| {
+    let mut print_token = |token: &str| {
         print!("{}", token);
         stdout.flush().ok();
         true
-    })?;
+    };
+    match template {
+        Some(template) => {
+            ctx = ctx.with_template(template);
+            ctx.chat_streaming(&[Message::user(prompt)], &config, &mut print_token)?;
+        }
+        None => {
+            ctx.generate_streaming(&prompt, &config, &mut print_token)?;
+        }
+    }
 
     println!("\n");
     Ok(())
 }
 
+fn run_embed(model: PathBuf, use_embedder: bool, raw: bool, text: Vec<String>) -> anyhow::Result<()> {
+    let inputs = if text.is_empty() {
+        io::stdin()
+            .lock()
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .collect::<io::Result<Vec<_>>>()?
+    } else {
+        text
+    };
+
+    let mut ctx = Cortex::load_with_progress(&model, &mut DownloadProgress::default())?;
+    if use_embedder {
+        ctx = ctx.with_embedder_progress(Embedder::DEFAULT_MODEL, &mut DownloadProgress::default())?;
+    }
+
+    println!("{}", format_embeddings(&ctx, &inputs, raw)?);
+    Ok(())
+}
+
+/// Embed `inputs` and format them as JSON, or as raw floats with a blank
+/// line between inputs
+fn format_embeddings(ctx: &Cortex, inputs: &[String], raw: bool) -> anyhow::Result<String> {
+    let texts: Vec<&str> = inputs.iter().map(String::as_str).collect();
+    let embeddings = ctx.embed_batch(&texts)?;
+
+    if raw {
+        let blocks: Vec<String> = embeddings
+            .iter()
+            .map(|e| e.iter().map(f32::to_string).collect::<Vec<_>>().join("\n"))
+            .collect();
+        return Ok(blocks.join("\n\n"));
+    }
+
+    let data: Vec<_> = inputs
+        .iter()
+        .zip(&embeddings)
+        .map(|(input, embedding)| serde_json::json!({ "input": input, "embedding": embedding }))
+        .collect();
+    let output = serde_json::json!({ "dim": ctx.embedding_dim(), "data": data });
+    Ok(serde_json::to_string_pretty(&output)?)
+}
+
+fn run_serve(model: PathBuf, host: String, port: u16, embedder: bool) -> anyhow::Result<()> {
+    println!("Loading model...");
+    let mut ctx = Cortex::load_with_progress(&model, &mut DownloadProgress::default())?;
+
+    if embedder {
+        println!("Loading embedding model...");
+        ctx = ctx.with_embedder_progress(Embedder::DEFAULT_MODEL, &mut DownloadProgress::default())?;
+    }
+
+    let name = model
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| ctx.model_id());
+
+    println!("Serving {} on http://{}:{}/v1", name, host, port);
+    Server::new(ctx).with_model_name(name).run((host.as_str(), port))?;
+    Ok(())
+}
+
+fn search_memory(
+    session_id: &str,
+    query: &str,
+    k: usize,
+    threshold: Option<f32>,
+    model: Option<PathBuf>,
+    use_embedder: bool,
+) -> anyhow::Result<()> {
+    let memory = cortex::session::load_session_memory(session_id)?;
+
+    // The LLM is only needed if the session's memories were embedded with it
+    let mut ctx = match model {
+        Some(model) => Cortex::load_with_progress(&model, &mut DownloadProgress::default())?,
+        None => Cortex::new(),
+    };
+    if use_embedder {
+        ctx = ctx.with_embedder_progress(Embedder::DEFAULT_MODEL, &mut DownloadProgress::default())?;
+    }
+
+    let query_embedding = ctx.embed(query)?;
+    if !memory.is_empty() && memory.embedding_dim() != query_embedding.len() {
+        anyhow::bail!(
+            "Query embedding has dimension {} but session memory uses {}; \
+             pass the --model or --use-embedder the memories were created with",
+            query_embedding.len(),
+            memory.embedding_dim()
+        );
+    }
+
+    let results = memory.search_with_threshold(&query_embedding, k, threshold.unwrap_or(f32::MIN));
+    if results.is_empty() {
+        println!("No memories found for: \"{}\"", query);
+    } else {
+        for result in results {
+            println!("{:.3}  {}: {}", result.score, result.entry.key, result.entry.content);
+        }
+    }
+
+    Ok(())
+}
+
+fn list_memory(session_id: &str) -> anyhow::Result<()> {
+    let memory = cortex::session::load_session_memory(session_id)?;
+
+    if memory.is_empty() {
+        println!("Session '{}' has no memories.", session_id);
+    } else {
+        for entry in memory.entries() {
+            println!("{}: {}", entry.key, entry.content);
+        }
+    }
+
+    Ok(())
+}
+
 fn list_sessions() -> anyhow::Result<()> {
-    let sessions = cortex::session::list_sessions()?;
+    let sessions = cortex::session::list_sessions_meta()?;
 
     if sessions.is_empty() {
         println!("No sessions found.");
     } else {
         println!("Sessions:");
         for session in sessions {
-            println!("  - {}", session);
+            if session.title == session.id {
+                println!("  - {}", session.id);
+            } else {
+                println!("  - {} ({})", session.id, session.title);
+            }
         }
     }
 
@@ -352,14 +688,117 @@ fn delete_session(session_id: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Renders model download progress on stderr
+#[derive(Default)]
+struct DownloadProgress {
+    file: String,
+    /// Last percentage (or MB, when the size is unknown) drawn
+    shown: Option<u64>,
+}
+
+impl ProgressReporter for DownloadProgress {
+    fn start(&mut self, file: &str, _total: Option<u64>) {
+        self.file = file.to_string();
+        self.shown = None;
+    }
+
+    fn update(&mut self, downloaded: u64, total: Option<u64>) {
+        let mb = |bytes: u64| bytes as f64 / 1_000_000.0;
+        let step = match total {
+            Some(total) if total > 0 => downloaded * 100 / total,
+            _ => downloaded / 1_000_000,
+        };
+        if self.shown == Some(step) {
+            return;
+        }
+        self.shown = Some(step);
+
+        match total {
+            Some(total) if total > 0 => {
+                let filled = (step / 5).min(20) as usize;
+                eprint!(
+                    "\rDownloading {} [{}{}] {:>3}% {:.1}/{:.1} MB",
+                    self.file,
+                    "#".repeat(filled),
+                    " ".repeat(20 - filled),
+                    step,
+                    mb(downloaded),
+                    mb(total)
+                );
+            }
+            _ => eprint!("\rDownloading {} {:.1} MB", self.file, mb(downloaded)),
+        }
+    }
+
+    fn finish(&mut self, _file: &str) {
+        eprintln!();
+    }
+}
+
 fn show_info(model: PathBuf) -> anyhow::Result<()> {
     println!("Loading model...");
-    let ctx = Cortex::load(&model)?;
+    let ctx = Cortex::load_with_progress(&model, &mut DownloadProgress::default())?;
 
     println!("\nModel Information:");
+    for line in ctx.model_info().to_string().lines() {
+        println!("  {}", line);
+    }
     println!("  Context size: {} tokens", ctx.context_size());
     println!("  Embedding dim: {}", ctx.embedding_dim());
     println!("  Memory entries: {}", ctx.memory.len());
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_input() {
+        let script = "hello\n\"\"\"\nfn main() {\n\n    println!();\n}\n\"\"\"\n\"\"\"inline\"\"\"\nquit\n";
+        let mut reader = io::Cursor::new(script);
+
+        let mut inputs = vec![];
+        while let Some(input) = read_input(&mut reader).unwrap() {
+            inputs.push(input);
+        }
+
+        assert_eq!(
+            inputs,
+            vec![
+                "hello",
+                "fn main() {\n\n    println!();\n}",
+                "inline",
+                "quit",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!(parse_template("auto").unwrap(), None);
+        assert_eq!(parse_template("phi3").unwrap(), Some(ChatTemplate::Phi3));
+        assert_eq!(parse_template("Mistral").unwrap(), Some(ChatTemplate::Mistral));
+
+        let err = parse_template("alpaca").unwrap_err();
+        assert!(err.contains("alpaca") && err.contains("chatml"));
+    }
+
+    #[test]
+    fn test_format_embeddings() {
+        let ctx = Cortex::new();
+        let inputs = vec!["first".to_string(), "second".to_string()];
+
+        let json: serde_json::Value =
+            serde_json::from_str(&format_embeddings(&ctx, &inputs, false).unwrap()).unwrap();
+        assert_eq!(json["dim"], ctx.embedding_dim());
+        assert_eq!(json["data"][1]["input"], "second");
+        let embedding = json["data"][0]["embedding"].as_array().unwrap();
+        assert_eq!(embedding.len(), ctx.embedding_dim());
+
+        let raw = format_embeddings(&ctx, &inputs[..1], true).unwrap();
+        assert_eq!(raw.lines().count(), ctx.embedding_dim());
+        assert!(raw.lines().all(|l| l.parse::<f32>().is_ok()));
+    }
+}
@@ -0,0 +1,174 @@
+//! Splitting long documents into chunks for memory
+//!
+//! Chunks are slices of the source text that fit a token budget, cut at
+//! paragraph or sentence breaks where possible, with optional overlap so
+//! context spanning a boundary appears in both neighbours.
+
+/// Options for `Cortex::ingest_document`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkOptions {
+    /// Maximum tokens per chunk
+    pub max_tokens: usize,
+    /// Tokens repeated from the end of one chunk at the start of the next
+    pub overlap: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            overlap: 32,
+        }
+    }
+}
+
+/// How good a place to cut the text is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Break {
+    Word,
+    Sentence,
+    Paragraph,
+}
+
+/// Split `text` into chunks of at most `max_tokens` tokens
+///
+/// `count_tokens` measures a piece of text, typically the engine's
+/// `count_tokens`. Chunks end at a paragraph break when one falls in their
+/// second half, else at a sentence break, else between words. With
+/// `overlap == 0` the chunks concatenate back to `text` exactly. A single
+/// word longer than `max_tokens` becomes a chunk of its own.
+pub fn chunk_text(
+    text: &str,
+    max_tokens: usize,
+    overlap: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<&str> {
+    let pieces = split_pieces(text);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < pieces.len() {
+        // Grow the chunk one word at a time while it fits
+        let mut end = start + 1;
+        while end < pieces.len() && count_tokens(slice(text, &pieces, start, end + 1)) <= max_tokens
+        {
+            end += 1;
+        }
+
+        if end < pieces.len() {
+            end = best_cut(&pieces, start, end);
+        }
+        chunks.push(slice(text, &pieces, start, end));
+        if end == pieces.len() {
+            break;
+        }
+
+        // Step back over whole words for the overlap, always moving forward
+        let mut next = end;
+        while next > start + 1 && count_tokens(slice(text, &pieces, next - 1, end)) <= overlap {
+            next -= 1;
+        }
+        start = next;
+    }
+
+    chunks
+}
+
+/// Byte range of each word with its trailing whitespace, and the break after it
+fn split_pieces(text: &str) -> Vec<(usize, usize, Break)> {
+    let mut pieces = Vec::new();
+    let mut piece_start = 0;
+    let mut in_space = false;
+
+    for (i, c) in text.char_indices() {
+        if in_space && !c.is_whitespace() {
+            pieces.push(piece(text, piece_start, i));
+            piece_start = i;
+        }
+        in_space = c.is_whitespace();
+    }
+    if piece_start < text.len() {
+        pieces.push(piece(text, piece_start, text.len()));
+    }
+
+    pieces
+}
+
+fn piece(text: &str, start: usize, end: usize) -> (usize, usize, Break) {
+    let piece = &text[start..end];
+    let word = piece.trim_end();
+    let kind = if piece[word.len()..].matches('\n').count() >= 2 {
+        Break::Paragraph
+    } else if word.ends_with(['.', '!', '?']) {
+        Break::Sentence
+    } else {
+        Break::Word
+    };
+    (start, end, kind)
+}
+
+/// Text covering pieces `from..to`
+fn slice<'a>(text: &'a str, pieces: &[(usize, usize, Break)], from: usize, to: usize) -> &'a str {
+    &text[pieces[from].0..pieces[to - 1].1]
+}
+
+/// Where to end a full chunk spanning pieces `start..end`
+fn best_cut(pieces: &[(usize, usize, Break)], start: usize, end: usize) -> usize {
+    // Only cut at a paragraph break if it keeps at least half the chunk
+    let half = start + (end - start).div_ceil(2);
+    let last_break = |kind: Break, from: usize| {
+        (from..end)
+            .rev()
+            .find(|&i| pieces[i].2 >= kind)
+            .map(|i| i + 1)
+    };
+
+    last_break(Break::Paragraph, half.saturating_sub(1).max(start))
+        .or_else(|| last_break(Break::Sentence, start))
+        .unwrap_or(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One token per word
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    const DOC: &str = "Rust is a systems language. It is fast and safe.\n\n\
+        Memory safety comes from ownership. Borrowing lets code share data \
+        without copies. Lifetimes track how long references live.\n\n\
+        Cargo builds projects and fetches crates from the registry.";
+
+    #[test]
+    fn test_chunks_fit_and_reassemble() {
+        let chunks = chunk_text(DOC, 12, 0, words);
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|c| words(c) <= 12));
+        assert_eq!(chunks.concat(), DOC);
+
+        // Cuts land on sentence or paragraph ends
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.trim_end().ends_with('.'), "{:?}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_overlap_is_shared() {
+        let chunks = chunk_text(DOC, 12, 3, words);
+        assert!(chunks.iter().all(|c| words(c) <= 12));
+
+        for pair in chunks.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            // The longest suffix of `a` that starts `b`
+            let shared = (0..a.len())
+                .filter(|&i| a.is_char_boundary(i))
+                .map(|i| &a[i..])
+                .find(|suffix| b.starts_with(suffix))
+                .unwrap_or("");
+            assert!(words(shared) > 0 && words(shared) <= 3, "{:?} / {:?}", a, b);
+        }
+    }
+}
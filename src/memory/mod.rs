@@ -3,18 +3,33 @@
 //! Provides built-in vector storage with:
 //! - Key-value storage with vector embeddings
 //! - Similarity search
+//! - Metadata filtering
 //! - Optional disk persistence
 
+mod chunk;
+mod query;
+mod query_cache;
+mod schema;
 mod vector;
 
-pub use vector::VectorStore;
+pub use chunk::{chunk_text, ChunkOptions};
+pub use query::MemoryQuery;
+pub(crate) use query_cache::QueryCache;
+pub use schema::MEMORY_FORMAT_VERSION;
+pub use vector::{cosine_similarity, normalize, VectorStore};
 
-use crate::config::MemoryConfig;
+use crate::clock::now_unix_secs;
+use crate::config::{EmbeddingStorage, MemoryConfig};
 use crate::{CortexError, Result};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
 
+/// Namespace used when none is given
+pub const DEFAULT_NAMESPACE: &str = "default";
+
 /// Memory entry with embedding and metadata
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryEntry {
@@ -28,8 +43,29 @@ pub struct MemoryEntry {
     pub metadata: HashMap<String, String>,
     /// Timestamp (unix epoch)
     pub created_at: u64,
+    /// Namespace the entry belongs to
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+}
+
+impl MemoryEntry {
+    /// Cosine similarity between this entry's embedding and `query`
+    ///
+    /// Matches the scores `Memory::search` computes. Returns 0.0 for a
+    /// dimension mismatch, or for borrowed entries of an int8 store, whose
+    /// `embedding` is empty.
+    pub fn similarity(&self, query: &[f32]) -> f32 {
+        cosine_similarity(query, &self.embedding)
+    }
+}
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
 }
 
+/// Callback computing an embedding for some text
+type EmbedFn<'a> = &'a dyn Fn(&str) -> Result<Vec<f32>>;
+
 /// Search result from memory
 #[derive(Debug, Clone)]
 pub struct SearchResult {
@@ -39,6 +75,61 @@ pub struct SearchResult {
     pub score: f32,
 }
 
+/// What a search looked at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchStats {
+    /// Entries considered
+    pub scanned: usize,
+    /// Entries skipped because their embedding dimension differs from the
+    /// query's
+    pub skipped_dim_mismatch: usize,
+    /// Results returned
+    pub returned: usize,
+}
+
+/// Size of a memory, for sizing `max_entries`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Entries stored
+    pub entries: usize,
+    /// Entries allowed before eviction
+    pub capacity: usize,
+    /// Approximate bytes held by embeddings, keys and contents
+    pub approx_bytes: usize,
+}
+
+/// Unfiltered search results with the threshold `search` would apply
+#[derive(Debug, Clone)]
+pub struct SearchDebug {
+    /// Top results, best first, regardless of the threshold
+    pub results: Vec<SearchResult>,
+    /// Configured similarity threshold
+    pub threshold: f32,
+}
+
+impl SearchDebug {
+    /// Number of results `search` would have returned
+    pub fn passing(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| r.score >= self.threshold)
+            .count()
+    }
+
+    /// Why `search` came back empty, or `None` if it found something
+    pub fn diagnosis(&self) -> Option<String> {
+        let top = match self.results.first() {
+            Some(top) if top.score >= self.threshold => return None,
+            Some(top) => top.score,
+            None => return Some("Memory has no entries to search".to_string()),
+        };
+        Some(format!(
+            "Top result scored {:.2} but the similarity threshold is {:.2}",
+            top, self.threshold
+        ))
+    }
+}
+
 /// Memory interface
 ///
 /// This is the main interface for memory operations.
@@ -46,22 +137,60 @@ pub struct SearchResult {
 pub struct Memory {
     store: VectorStore,
     config: MemoryConfig,
+    /// Nesting depth of open write batches
+    batch_depth: usize,
+    /// Mutations happened inside a batch and haven't been persisted
+    dirty: bool,
 }
 
 impl Memory {
     /// Create new memory with config
     pub fn new(config: MemoryConfig) -> Self {
-        let store = VectorStore::new(config.embedding_dim, config.max_entries);
-        Self { store, config }
+        let store = VectorStore::new(config.embedding_dim, config.max_entries)
+            .with_eviction(config.eviction)
+            .with_storage(config.embedding_storage);
+        Self {
+            store,
+            config,
+            batch_depth: 0,
+            dirty: false,
+        }
+    }
+
+    /// Create memory with config, restoring entries from `persist_path`
+    /// if that file exists
+    ///
+    /// Fails if the stored embedding dimension differs from the config's.
+    pub fn open(config: MemoryConfig) -> Result<Self> {
+        let path = match &config.persist_path {
+            Some(path) if path.exists() => path.clone(),
+            _ => return Ok(Self::new(config)),
+        };
+
+        let state = schema::decode_state(&std::fs::read(&path)?)?;
+        if state.embedding_dim != config.embedding_dim {
+            return Err(CortexError::Memory(format!(
+                "{} holds {}-dimensional embeddings, expected {}",
+                path.display(),
+                state.embedding_dim,
+                config.embedding_dim
+            )));
+        }
+
+        let mut memory = Self::new(config);
+        for entry in state.entries {
+            memory.store.insert(entry);
+        }
+        Ok(memory)
     }
 
     /// Load memory from disk
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let data = std::fs::read(path.as_ref())?;
-        let state: MemoryState =
-            bincode::deserialize(&data).map_err(|e| CortexError::Serialization(e.to_string()))?;
+        let state = schema::decode_state(&data)?;
 
-        let mut store = VectorStore::new(state.embedding_dim, state.max_entries);
+        let mut store = VectorStore::new(state.embedding_dim, state.max_entries)
+            .with_storage(state.storage);
         for entry in state.entries {
             store.insert(entry);
         }
@@ -71,9 +200,12 @@ impl Memory {
             config: MemoryConfig {
                 embedding_dim: state.embedding_dim,
                 max_entries: state.max_entries,
+                embedding_storage: state.storage,
                 persist_path: Some(path.as_ref().to_path_buf()),
                 ..Default::default()
             },
+            batch_depth: 0,
+            dirty: false,
         })
     }
 
@@ -81,33 +213,7 @@ impl Memory {
     ///
     /// If the key exists, it will be updated.
     pub fn write(&mut self, key: impl Into<String>, content: impl Into<String>, embedding: Vec<f32>) -> Result<()> {
-        let key = key.into();
-        let content = content.into();
-
-        if embedding.len() != self.config.embedding_dim {
-            return Err(CortexError::Memory(format!(
-                "Embedding dimension mismatch: expected {}, got {}",
-                self.config.embedding_dim,
-                embedding.len()
-            )));
-        }
-
-        let entry = MemoryEntry {
-            key: key.clone(),
-            content,
-            embedding,
-            metadata: HashMap::new(),
-            created_at: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-        };
-
-        // Remove existing entry with same key
-        self.store.remove(&key);
-        self.store.insert(entry);
-
-        Ok(())
+        self.insert(DEFAULT_NAMESPACE, key.into(), content.into(), embedding, HashMap::new())
     }
 
     /// Write with metadata
@@ -118,9 +224,28 @@ impl Memory {
         embedding: Vec<f32>,
         metadata: HashMap<String, String>,
     ) -> Result<()> {
-        let key = key.into();
-        let content = content.into();
+        self.insert(DEFAULT_NAMESPACE, key.into(), content.into(), embedding, metadata)
+    }
 
+    /// Write to a namespace
+    pub fn write_in(
+        &mut self,
+        namespace: &str,
+        key: impl Into<String>,
+        content: impl Into<String>,
+        embedding: Vec<f32>,
+    ) -> Result<()> {
+        self.insert(namespace, key.into(), content.into(), embedding, HashMap::new())
+    }
+
+    fn insert(
+        &mut self,
+        namespace: &str,
+        key: String,
+        content: String,
+        embedding: Vec<f32>,
+        metadata: HashMap<String, String>,
+    ) -> Result<()> {
         if embedding.len() != self.config.embedding_dim {
             return Err(CortexError::Memory(format!(
                 "Embedding dimension mismatch: expected {}, got {}",
@@ -134,26 +259,60 @@ impl Memory {
             content,
             embedding,
             metadata,
-            created_at: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            created_at: now_unix_secs(),
+            namespace: namespace.to_string(),
         };
 
-        self.store.remove(&key);
+        // Remove existing entry with same key in this namespace
+        self.store.remove_in(namespace, &key);
         self.store.insert(entry);
 
-        Ok(())
+        self.write_through()
     }
 
     /// Read by key
-    pub fn read(&self, key: &str) -> Option<&MemoryEntry> {
-        self.store.get(key)
+    pub fn read(&self, key: &str) -> Option<Cow<'_, MemoryEntry>> {
+        self.read_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Read by key from a namespace
+    ///
+    /// Entries always carry their full embedding; with int8 storage that
+    /// means a dequantized copy.
+    pub fn read_in(&self, namespace: &str, key: &str) -> Option<Cow<'_, MemoryEntry>> {
+        self.store.get_in(namespace, key)
     }
 
     /// Delete by key
     pub fn delete(&mut self, key: &str) -> bool {
-        self.store.remove(key)
+        self.delete_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Delete by key from a namespace
+    pub fn delete_in(&mut self, namespace: &str, key: &str) -> bool {
+        let removed = self.store.remove_in(namespace, key);
+        if removed {
+            self.write_through_or_warn();
+        }
+        removed
+    }
+
+    /// Delete every entry whose metadata contains all the given pairs
+    ///
+    /// Looks across all namespaces. Returns the number of entries removed;
+    /// an empty filter removes everything.
+    pub fn delete_matching(&mut self, filter: &HashMap<String, String>) -> usize {
+        let query = MemoryQuery::from(filter);
+        let removed = self.store.retain(|entry| !query.matches(entry));
+        if removed > 0 {
+            self.write_through_or_warn();
+        }
+        removed
+    }
+
+    /// Namespaces that currently hold entries, sorted
+    pub fn namespaces(&self) -> Vec<String> {
+        self.store.namespaces()
     }
 
     /// Search by similarity
@@ -165,6 +324,44 @@ impl Memory {
             .collect()
     }
 
+    /// Search by similarity, also counting what was scanned and skipped
+    pub fn search_with_stats(
+        &self,
+        query_embedding: &[f32],
+        k: usize,
+    ) -> (Vec<SearchResult>, SearchStats) {
+        let (mut results, mut stats) = self.store.search_with_stats(query_embedding, k);
+        results.retain(|r| r.score >= self.config.similarity_threshold);
+        stats.returned = results.len();
+        (results, stats)
+    }
+
+    /// Search without the threshold, for diagnosing empty results
+    ///
+    /// Returns the top `k` entries whatever their scores, together with the
+    /// threshold `search` would have filtered them by.
+    pub fn search_debug(&self, query_embedding: &[f32], k: usize) -> SearchDebug {
+        SearchDebug {
+            results: self.store.search(query_embedding, k),
+            threshold: self.config.similarity_threshold,
+        }
+    }
+
+    /// Suggest a similarity threshold from representative query embeddings
+    ///
+    /// Picks a threshold just below the weakest best match among the
+    /// queries, so each of them would return at least one result. `None`
+    /// if there are no queries or no entries.
+    pub fn suggest_threshold(&self, sample_queries: &[Vec<f32>]) -> Option<f32> {
+        const MARGIN: f32 = 0.05;
+
+        sample_queries
+            .iter()
+            .filter_map(|query| self.store.search(query, 1).first().map(|r| r.score))
+            .min_by(|a, b| a.total_cmp(b))
+            .map(|weakest| (weakest - MARGIN).clamp(0.0, 1.0))
+    }
+
     /// Search with custom threshold
     pub fn search_with_threshold(
         &self,
@@ -179,11 +376,107 @@ impl Memory {
             .collect()
     }
 
-    /// Get all entries
-    pub fn entries(&self) -> Vec<&MemoryEntry> {
+    /// Search for relevant but mutually diverse entries
+    ///
+    /// Re-ranks candidates with Maximal Marginal Relevance; see
+    /// `VectorStore::search_mmr`. Candidates below the similarity threshold
+    /// are dropped.
+    pub fn search_mmr(&self, query_embedding: &[f32], k: usize, lambda: f32) -> Vec<SearchResult> {
+        self.store
+            .search_mmr(query_embedding, k, lambda)
+            .into_iter()
+            .filter(|r| r.score >= self.config.similarity_threshold)
+            .collect()
+    }
+
+    /// Search with scores decayed by entry age
+    ///
+    /// Each score is `similarity * exp(-recency_lambda * age_seconds)`, and
+    /// the similarity threshold applies to the decayed score. Entries dated
+    /// in the future (clock skew) are treated as brand new.
+    pub fn search_with_recency(&self, query_embedding: &[f32], k: usize) -> Vec<SearchResult> {
+        let lambda = self.config.recency_lambda as f64;
+        let now = now_unix_secs();
+
+        self.store
+            .search_scored(query_embedding, k, |_| true, |entry, similarity| {
+                let age = now.saturating_sub(entry.created_at) as f64;
+                (similarity as f64 * (-lambda * age).exp()) as f32
+            })
+            .into_iter()
+            .filter(|r| r.score >= self.config.similarity_threshold)
+            .collect()
+    }
+
+    /// Search only entries whose metadata contains every given pair
+    ///
+    /// Filtering happens before top-k truncation, so up to `k` results are
+    /// returned from the matching subset.
+    pub fn search_filtered(
+        &self,
+        query_embedding: &[f32],
+        k: usize,
+        filter: &HashMap<String, String>,
+    ) -> Vec<SearchResult> {
+        self.search_query(query_embedding, k, &MemoryQuery::from(filter))
+    }
+
+    /// Search only entries matching a `MemoryQuery`
+    pub fn search_query(
+        &self,
+        query_embedding: &[f32],
+        k: usize,
+        query: &MemoryQuery,
+    ) -> Vec<SearchResult> {
+        self.store
+            .search_filtered(query_embedding, k, |entry| query.matches(entry))
+            .into_iter()
+            .filter(|r| r.score >= self.config.similarity_threshold)
+            .collect()
+    }
+
+    /// Search within a single namespace
+    ///
+    /// Uses the namespace's similarity threshold if one is set, otherwise
+    /// the global threshold.
+    pub fn search_in(&self, namespace: &str, query_embedding: &[f32], k: usize) -> Vec<SearchResult> {
+        let threshold = self.namespace_threshold(namespace);
+        self.store
+            .search_filtered(query_embedding, k, |entry| entry.namespace == namespace)
+            .into_iter()
+            .filter(|r| r.score >= threshold)
+            .collect()
+    }
+
+    /// Override the similarity threshold for a namespace
+    pub fn set_namespace_threshold(&mut self, namespace: impl Into<String>, threshold: f32) {
+        self.config.namespace_thresholds.insert(namespace.into(), threshold);
+    }
+
+    /// Similarity threshold for a namespace, falling back to the global one
+    pub fn namespace_threshold(&self, namespace: &str) -> f32 {
+        self.config
+            .namespace_thresholds
+            .get(namespace)
+            .copied()
+            .unwrap_or(self.config.similarity_threshold)
+    }
+
+    /// Get all entries, with full embeddings
+    pub fn entries(&self) -> Vec<Cow<'_, MemoryEntry>> {
         self.store.entries()
     }
 
+    /// Iterate over entries in insertion order without collecting them
+    pub fn entries_iter(&self) -> impl Iterator<Item = Cow<'_, MemoryEntry>> {
+        self.store.entries_iter()
+    }
+
+    /// Dimension of the embeddings this memory stores
+    pub fn embedding_dim(&self) -> usize {
+        self.config.embedding_dim
+    }
+
     /// Get number of entries
     pub fn len(&self) -> usize {
         self.store.len()
@@ -194,19 +487,92 @@ impl Memory {
         self.store.is_empty()
     }
 
+    /// Maximum number of entries; writing more evicts old ones
+    pub fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    /// Check if the next new entry will evict one
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    /// Entry count, capacity and approximate size
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            entries: self.len(),
+            capacity: self.capacity(),
+            approx_bytes: self.store.approx_bytes(),
+        }
+    }
+
     /// Clear all entries
     pub fn clear(&mut self) {
         self.store.clear();
+        self.write_through_or_warn();
+    }
+
+    /// Start a write batch
+    ///
+    /// While a batch is open, auto-persistence is deferred; the store is
+    /// written once when the outermost batch ends. Prefer `batch()`, which
+    /// ends the batch automatically.
+    pub fn begin_batch(&mut self) {
+        self.batch_depth += 1;
+    }
+
+    /// End a write batch, persisting once if anything changed
+    pub fn end_batch(&mut self) -> Result<()> {
+        self.batch_depth = self.batch_depth.saturating_sub(1);
+        if self.batch_depth == 0 && self.dirty {
+            self.dirty = false;
+            return self.write_through();
+        }
+        Ok(())
+    }
+
+    /// Open a write batch that ends when the guard is dropped
+    ///
+    /// ```rust,ignore
+    /// let mut batch = memory.batch();
+    /// for (key, content, embedding) in docs {
+    ///     batch.write(key, content, embedding)?;
+    /// }
+    /// batch.commit()?; // single persist
+    /// ```
+    pub fn batch(&mut self) -> MemoryBatch<'_> {
+        self.begin_batch();
+        MemoryBatch {
+            memory: self,
+            finished: false,
+        }
+    }
+
+    /// Persist to the configured path if auto-persistence is enabled
+    fn write_through(&mut self) -> Result<()> {
+        if !self.config.auto_persist {
+            return Ok(());
+        }
+        if self.batch_depth > 0 {
+            self.dirty = true;
+            return Ok(());
+        }
+        match &self.config.persist_path {
+            Some(path) => self.persist(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Like `write_through`, for mutations that can't return an error
+    fn write_through_or_warn(&mut self) {
+        if let Err(e) = self.write_through() {
+            tracing::warn!("Failed to persist memory: {}", e);
+        }
     }
 
     /// Persist to disk
     pub fn persist(&self, path: impl AsRef<Path>) -> Result<()> {
-        let state = MemoryState {
-            embedding_dim: self.config.embedding_dim,
-            max_entries: self.config.max_entries,
-            entries: self.store.entries().into_iter().cloned().collect(),
-        };
-
+        let state = self.get_state();
         let data =
             bincode::serialize(&state).map_err(|e| CortexError::Serialization(e.to_string()))?;
 
@@ -214,30 +580,248 @@ impl Memory {
         Ok(())
     }
 
+    /// Export the whole store as human-readable JSON
+    ///
+    /// Includes embeddings, so the file can be imported without a model.
+    pub fn export_json(&self, path: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.get_state())
+            .map_err(|e| CortexError::Serialization(e.to_string()))?;
+        std::fs::write(path.as_ref(), data)?;
+        Ok(())
+    }
+
+    /// Import a store written by `export_json`
+    ///
+    /// Entries whose embedding length doesn't match the file's
+    /// `embedding_dim` (e.g. after hand edits) are skipped with a warning.
+    /// Use `import_json_with` to re-embed them instead.
+    pub fn import_json(path: impl AsRef<Path>) -> Result<Self> {
+        Self::import_json_inner(path.as_ref(), None)
+    }
+
+    /// Import a JSON store, re-embedding entries with mismatched vectors
+    pub fn import_json_with<F>(path: impl AsRef<Path>, embed: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Result<Vec<f32>>,
+    {
+        Self::import_json_inner(path.as_ref(), Some(&embed))
+    }
+
+    fn import_json_inner(
+        path: &Path,
+        embed: Option<EmbedFn<'_>>,
+    ) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        let state: MemoryState = serde_json::from_slice(&data)
+            .map_err(|e| CortexError::Serialization(e.to_string()))?;
+
+        let dim = state.embedding_dim;
+        let mut memory = Self::new(MemoryConfig {
+            embedding_dim: dim,
+            max_entries: state.max_entries,
+            embedding_storage: state.storage,
+            ..Default::default()
+        });
+
+        for mut entry in state.entries {
+            if entry.embedding.len() != dim {
+                match embed {
+                    Some(embed) => entry.embedding = embed(&entry.content)?,
+                    None => {
+                        tracing::warn!(
+                            "Skipping memory '{}': embedding has {} dimensions, expected {}",
+                            entry.key,
+                            entry.embedding.len(),
+                            dim
+                        );
+                        continue;
+                    }
+                }
+                if entry.embedding.len() != dim {
+                    return Err(CortexError::Memory(format!(
+                        "Re-embedded '{}' has {} dimensions, expected {}",
+                        entry.key,
+                        entry.embedding.len(),
+                        dim
+                    )));
+                }
+            }
+            memory.store.insert(entry);
+        }
+
+        Ok(memory)
+    }
+
+    /// Export keys and embeddings as parallel arrays, in insertion order
+    ///
+    /// Intended for bulk-loading external ANN indexes (FAISS, Qdrant, ...).
+    pub fn export_vectors(&self) -> (Vec<String>, Vec<Vec<f32>>) {
+        self.store
+            .to_entries()
+            .into_iter()
+            .map(|e| (e.key, e.embedding))
+            .unzip()
+    }
+
+    /// Dump all embeddings to a raw binary file
+    ///
+    /// Layout is `[n: u32][dim: u32][n * dim f32]`, all little-endian, in
+    /// the same order as `export_vectors`. Load with numpy via
+    /// `np.fromfile(path, dtype="<f4", offset=8).reshape(n, dim)`. Entries
+    /// whose embedding isn't `embedding_dim` long are skipped with a
+    /// warning, and `n` counts only the vectors written.
+    pub fn export_vectors_raw(&self, path: impl AsRef<Path>) -> Result<()> {
+        let dim = self.config.embedding_dim;
+        let (entries, skipped): (Vec<_>, Vec<_>) = self
+            .store
+            .to_entries()
+            .into_iter()
+            .partition(|e| e.embedding.len() == dim);
+        if !skipped.is_empty() {
+            tracing::warn!(
+                "Skipped {} entries whose embeddings aren't {} dimensions",
+                skipped.len(),
+                dim
+            );
+        }
+
+        let mut data = Vec::with_capacity(8 + entries.len() * dim * 4);
+        data.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(dim as u32).to_le_bytes());
+        for entry in entries {
+            for value in &entry.embedding {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        std::fs::write(path.as_ref(), data)?;
+        Ok(())
+    }
+
+    /// Recompute every embedding from its content at a new dimension
+    ///
+    /// For switching embedding models: vectors from the old model can't be
+    /// compared with queries from the new one. `embed` receives batches of
+    /// contents and returns one vector per content. Fails without changing
+    /// anything if a vector doesn't have `embedding_dim` dimensions.
+    pub fn reembed<F>(&mut self, embedding_dim: usize, mut embed: F) -> Result<()>
+    where
+        F: FnMut(&[&str]) -> Result<Vec<Vec<f32>>>,
+    {
+        let mut state = self.get_state();
+        let contents: Vec<&str> = state.entries.iter().map(|e| e.content.as_str()).collect();
+        let embeddings = embed(&contents)?;
+        if embeddings.len() != state.entries.len() {
+            return Err(CortexError::Memory(format!(
+                "Re-embedding returned {} vectors for {} entries",
+                embeddings.len(),
+                state.entries.len()
+            )));
+        }
+
+        for (entry, embedding) in state.entries.iter_mut().zip(embeddings) {
+            if embedding.len() != embedding_dim {
+                return Err(CortexError::Memory(format!(
+                    "Re-embedded '{}' has {} dimensions, expected {}",
+                    entry.key,
+                    embedding.len(),
+                    embedding_dim
+                )));
+            }
+            entry.embedding = embedding;
+        }
+
+        self.config.embedding_dim = embedding_dim;
+        self.store = VectorStore::new(embedding_dim, state.max_entries)
+            .with_eviction(self.config.eviction)
+            .with_storage(self.config.embedding_storage);
+        for entry in state.entries {
+            self.store.insert(entry);
+        }
+        self.write_through()
+    }
+
     /// Get serializable state
     pub fn get_state(&self) -> MemoryState {
         MemoryState {
+            version: MEMORY_FORMAT_VERSION,
             embedding_dim: self.config.embedding_dim,
             max_entries: self.config.max_entries,
-            entries: self.store.entries().into_iter().cloned().collect(),
+            entries: self.store.to_entries(),
+            storage: self.config.embedding_storage,
         }
     }
 
     /// Restore from state
     pub fn set_state(&mut self, state: MemoryState) {
-        self.store = VectorStore::new(state.embedding_dim, state.max_entries);
+        self.store = VectorStore::new(state.embedding_dim, state.max_entries)
+            .with_eviction(self.config.eviction)
+            .with_storage(self.config.embedding_storage);
         for entry in state.entries {
             self.store.insert(entry);
         }
+        self.write_through_or_warn();
+    }
+}
+
+/// Guard for a memory write batch
+///
+/// Derefs to `Memory`. Persists once on `commit`, or on drop (where errors
+/// are logged rather than returned).
+pub struct MemoryBatch<'a> {
+    memory: &'a mut Memory,
+    finished: bool,
+}
+
+impl MemoryBatch<'_> {
+    /// End the batch, persisting once if anything changed
+    pub fn commit(mut self) -> Result<()> {
+        self.finished = true;
+        self.memory.end_batch()
+    }
+}
+
+impl Deref for MemoryBatch<'_> {
+    type Target = Memory;
+
+    fn deref(&self) -> &Memory {
+        self.memory
+    }
+}
+
+impl DerefMut for MemoryBatch<'_> {
+    fn deref_mut(&mut self) -> &mut Memory {
+        self.memory
+    }
+}
+
+impl Drop for MemoryBatch<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.memory.end_batch() {
+                tracing::warn!("Failed to persist memory batch: {}", e);
+            }
+        }
     }
 }
 
 /// Serializable memory state
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryState {
+    /// Format version, see `MEMORY_FORMAT_VERSION`
+    #[serde(default = "current_format_version")]
+    pub version: u32,
     pub embedding_dim: usize,
     pub max_entries: usize,
+    /// Entries always hold full f32 embeddings
     pub entries: Vec<MemoryEntry>,
+    /// How the store held embeddings; restored on load
+    #[serde(default)]
+    pub storage: EmbeddingStorage,
+}
+
+fn current_format_version() -> u32 {
+    MEMORY_FORMAT_VERSION
 }
 
 #[cfg(test)]
@@ -248,6 +832,95 @@ mod tests {
         (0..dim).map(|i| (i as f32 * seed).sin()).collect()
     }
 
+    #[test]
+    fn test_public_similarity_matches_search() {
+        let mut mem = Memory::new(MemoryConfig {
+            embedding_dim: 64,
+            ..Default::default()
+        });
+        mem.write("a", "first", make_embedding(64, 0.1)).unwrap();
+        mem.write("b", "second", make_embedding(64, 0.7)).unwrap();
+
+        let query = make_embedding(64, 0.3);
+        for result in mem.search(&query, 2) {
+            let entry = mem.read(&result.entry.key).unwrap();
+            assert!((entry.similarity(&query) - result.score).abs() < 1e-6);
+            assert!((cosine_similarity(&query, &entry.embedding) - result.score).abs() < 1e-6);
+        }
+
+        let unit = normalize(&query);
+        assert!((unit.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-5);
+
+        // Zero vectors and dimension mismatches score 0 instead of NaN
+        let zero = vec![0.0; 64];
+        assert_eq!(normalize(&zero), zero);
+        assert_eq!(cosine_similarity(&zero, &query), 0.0);
+        assert_eq!(mem.read("a").unwrap().similarity(&query[..10]), 0.0);
+    }
+
+    #[test]
+    fn test_search_debug_below_threshold() {
+        let config = MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: 0.99,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+        assert!(mem.search_debug(&make_embedding(64, 1.0), 3).diagnosis().is_some());
+
+        for i in 0..5 {
+            let emb = make_embedding(64, i as f32 + 1.0);
+            mem.write(format!("entry_{}", i), format!("Content {}", i), emb)
+                .unwrap();
+        }
+
+        let query = make_embedding(64, 2.5);
+        assert!(mem.search(&query, 3).is_empty());
+
+        let debug = mem.search_debug(&query, 3);
+        assert_eq!(debug.results.len(), 3);
+        assert_eq!(debug.passing(), 0);
+        assert_eq!(debug.threshold, 0.99);
+        assert!(debug.results[0].score >= debug.results[1].score);
+        let message = debug.diagnosis().unwrap();
+        assert!(message.contains("threshold is 0.99"), "{}", message);
+
+        // The suggestion lets every sample query find its best match
+        let samples = vec![query.clone(), make_embedding(64, 4.5)];
+        let suggested = mem.suggest_threshold(&samples).unwrap();
+        assert!(suggested < debug.results[0].score);
+        assert!(!mem.search_with_threshold(&samples[1], 1, suggested).is_empty());
+        assert_eq!(mem.suggest_threshold(&[]), None);
+    }
+
+    #[test]
+    fn test_reembed_changes_dimension() {
+        let mut memory = Memory::new(MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: 0.5,
+            ..Default::default()
+        });
+        memory.write("a", "alpha", make_embedding(64, 0.1)).unwrap();
+        memory.write("b", "beta", make_embedding(64, 0.7)).unwrap();
+
+        // A query from the new model matches nothing until re-embedding
+        let seed = |content: &str| if content == "alpha" { 0.3 } else { 0.9 };
+        let query = make_embedding(32, 0.3);
+        assert!(memory.search(&query, 1).is_empty());
+
+        let embed = |texts: &[&str]| Ok(texts.iter().map(|t| make_embedding(32, seed(t))).collect());
+        memory.reembed(32, embed).unwrap();
+        assert_eq!(memory.embedding_dim(), 32);
+        assert_eq!(memory.len(), 2);
+        assert_eq!(memory.search(&query, 1)[0].entry.key, "a");
+        assert_eq!(memory.search(&make_embedding(32, 0.9), 1)[0].entry.key, "b");
+
+        // Vectors of the wrong size leave memory as it was
+        let wrong_size = |texts: &[&str]| Ok(texts.iter().map(|_| vec![0.0; 8]).collect());
+        assert!(memory.reembed(16, wrong_size).is_err());
+        assert_eq!(memory.embedding_dim(), 32);
+    }
+
     #[test]
     fn test_write_read() {
         let config = MemoryConfig {
@@ -286,4 +959,408 @@ mod tests {
         assert_eq!(results.len(), 3);
         assert_eq!(results[0].entry.key, "entry_5"); // Should be exact match
     }
+
+    #[test]
+    fn test_batch_persists_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.bin");
+        let config = MemoryConfig {
+            embedding_dim: 64,
+            persist_path: Some(path.clone()),
+            auto_persist: true,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+
+        let mut batch = mem.batch();
+        for i in 0..10 {
+            let emb = make_embedding(64, i as f32);
+            batch.write(format!("entry_{}", i), format!("Content {}", i), emb).unwrap();
+        }
+        // Nothing is written until the batch ends
+        assert!(!path.exists());
+        batch.commit().unwrap();
+
+        assert_eq!(Memory::load(&path).unwrap().len(), 10);
+
+        // Outside a batch, every write goes straight to disk
+        mem.write("extra", "More content", make_embedding(64, 11.0)).unwrap();
+        assert_eq!(Memory::load(&path).unwrap().len(), 11);
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_search_filtered() {
+        let config = MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: 0.0,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+
+        // Bob's entries are the closest matches, but must be filtered out
+        for i in 0..6 {
+            let user = if i < 3 { "bob" } else { "alice" };
+            let mut meta = metadata(&[("user", user)]);
+            if i == 5 {
+                meta.insert("kind".to_string(), "fact".to_string());
+            }
+            let emb = make_embedding(64, 1.0 + i as f32 * 0.001);
+            mem.write_with_metadata(format!("key_{}", i), "content", emb, meta).unwrap();
+        }
+        mem.write("untagged", "content", make_embedding(64, 1.0)).unwrap();
+
+        let query = make_embedding(64, 1.0);
+        let results = mem.search_filtered(&query, 2, &metadata(&[("user", "alice")]));
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.entry.metadata["user"] == "alice"));
+
+        // Entries missing a key never match, even when the rest does
+        let filter = metadata(&[("user", "alice"), ("kind", "fact")]);
+        let results = mem.search_filtered(&query, 10, &filter);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].entry.key, "key_5");
+        assert!(mem.search_filtered(&query, 10, &metadata(&[("team", "x")])).is_empty());
+    }
+
+    #[test]
+    fn test_search_skips_dim_mismatch() {
+        let mut mem = Memory::new(MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: 0.0,
+            ..Default::default()
+        });
+        mem.write("good", "content", make_embedding(64, 1.0)).unwrap();
+
+        // Writes reject the wrong dimension, so inject one through state
+        let mut state = mem.get_state();
+        let mut stale = state.entries[0].clone();
+        stale.key = "stale".to_string();
+        stale.embedding = make_embedding(32, 1.0);
+        state.entries.push(stale);
+        mem.set_state(state);
+
+        let (results, stats) = mem.search_with_stats(&make_embedding(64, 1.0), 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].entry.key, "good");
+        assert_eq!(
+            stats,
+            SearchStats {
+                scanned: 2,
+                skipped_dim_mismatch: 1,
+                returned: 1,
+            }
+        );
+        assert!(mem.search(&make_embedding(64, 1.0), 10).iter().all(|r| r.entry.key != "stale"));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut mem = Memory::new(MemoryConfig {
+            embedding_dim: 64,
+            max_entries: 3,
+            ..Default::default()
+        });
+        assert_eq!(mem.capacity(), 3);
+        for i in 0..3 {
+            assert!(!mem.is_full());
+            mem.write(format!("k{}", i), "content", make_embedding(64, 1.0)).unwrap();
+        }
+        assert!(mem.is_full());
+        mem.write("k3", "content", make_embedding(64, 1.0)).unwrap();
+        assert!(mem.is_full());
+        assert_eq!(mem.len(), 3);
+        mem.delete("k3");
+        assert!(!mem.is_full());
+    }
+
+    #[test]
+    fn test_stats_scale_with_dim() {
+        let stats = |dim: usize, entries: usize| {
+            let mut mem = Memory::new(MemoryConfig {
+                embedding_dim: dim,
+                ..Default::default()
+            });
+            for i in 0..entries {
+                mem.write(format!("k{:02}", i), "content", make_embedding(dim, 1.0)).unwrap();
+            }
+            mem.stats()
+        };
+
+        let small = stats(64, 10);
+        assert_eq!(small.entries, 10);
+        assert_eq!(small.capacity, MemoryConfig::default().max_entries);
+        assert_eq!(stats(128, 10).approx_bytes - small.approx_bytes, 10 * 64 * 4);
+        assert_eq!(stats(64, 20).approx_bytes, 2 * small.approx_bytes);
+    }
+
+    #[test]
+    fn test_delete_matching() {
+        let mut mem = Memory::new(MemoryConfig {
+            embedding_dim: 64,
+            ..Default::default()
+        });
+        let docs = [("a", "x"), ("b", "y"), ("c", "x")];
+        for (key, doc_id) in docs {
+            let meta = metadata(&[("doc_id", doc_id)]);
+            mem.write_with_metadata(key, "content", make_embedding(64, 1.0), meta).unwrap();
+        }
+        mem.write("untagged", "content", make_embedding(64, 1.0)).unwrap();
+
+        assert_eq!(mem.delete_matching(&metadata(&[("doc_id", "x")])), 2);
+        let mut keys: Vec<String> = mem.entries_iter().map(|e| e.key.clone()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["b", "untagged"]);
+        assert_eq!(mem.delete_matching(&metadata(&[("doc_id", "x")])), 0);
+    }
+
+    #[test]
+    fn test_memory_query() {
+        let config = MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: 0.0,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+        for (i, kind) in ["note", "fact", "todo"].iter().enumerate() {
+            let meta = metadata(&[("kind", kind)]);
+            mem.write_with_metadata(format!("key_{}", i), "content", make_embedding(64, 1.0), meta)
+                .unwrap();
+        }
+        mem.write("untagged", "content", make_embedding(64, 1.0)).unwrap();
+
+        let query = make_embedding(64, 1.0);
+        let one_of = MemoryQuery::new().one_of("kind", ["note", "todo"]);
+        let mut keys: Vec<String> =
+            mem.search_query(&query, 10, &one_of).into_iter().map(|r| r.entry.key).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["key_0", "key_2"]);
+
+        assert_eq!(mem.search_query(&query, 10, &MemoryQuery::new().exists("kind")).len(), 3);
+        assert_eq!(mem.search_query(&query, 10, &MemoryQuery::new()).len(), 4);
+    }
+
+    #[test]
+    fn test_search_mmr() {
+        let config = MemoryConfig {
+            embedding_dim: 3,
+            similarity_threshold: 0.0,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+        mem.write("dup_a", "The sky is blue", vec![1.0, 0.0, 0.0]).unwrap();
+        mem.write("dup_b", "The sky is blue!", vec![0.99, 0.01, 0.0]).unwrap();
+        mem.write("dup_c", "the sky is blue", vec![0.98, 0.02, 0.0]).unwrap();
+        mem.write("outlier_a", "Grass is green", vec![0.7, 0.714, 0.0]).unwrap();
+        mem.write("outlier_b", "Snow is white", vec![0.7, 0.0, 0.714]).unwrap();
+
+        let query = [1.0, 0.0, 0.0];
+        let keys = |results: Vec<SearchResult>| -> Vec<String> {
+            results.into_iter().map(|r| r.entry.key).collect()
+        };
+
+        assert_eq!(keys(mem.search(&query, 3)), vec!["dup_a", "dup_b", "dup_c"]);
+        assert_eq!(keys(mem.search_mmr(&query, 3, 1.0)), vec!["dup_a", "dup_b", "dup_c"]);
+
+        let diverse = keys(mem.search_mmr(&query, 3, 0.3));
+        assert_eq!(diverse[0], "dup_a");
+        assert!(diverse.contains(&"outlier_a".to_string()));
+        assert!(diverse.contains(&"outlier_b".to_string()));
+    }
+
+    #[test]
+    fn test_search_with_recency() {
+        let config = MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: 0.0,
+            recency_lambda: 0.001,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+
+        let now = now_unix_secs();
+        let embedding = make_embedding(64, 1.0);
+        let entry = |key: &str, created_at: u64| MemoryEntry {
+            key: key.to_string(),
+            content: key.to_string(),
+            embedding: embedding.clone(),
+            metadata: HashMap::new(),
+            created_at,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+        };
+        mem.set_state(MemoryState {
+            version: MEMORY_FORMAT_VERSION,
+            embedding_dim: 64,
+            max_entries: 100,
+            entries: vec![
+                entry("old", now - 3600),
+                entry("recent", now - 60),
+                entry("future", now + 3600),
+            ],
+            storage: EmbeddingStorage::F32,
+        });
+
+        let results = mem.search_with_recency(&embedding, 3);
+        let keys: Vec<&str> = results.iter().map(|r| r.entry.key.as_str()).collect();
+        assert_eq!(keys, vec!["future", "recent", "old"]);
+
+        // Future timestamps are clamped to age zero, never boosted
+        assert!((results[0].score - 1.0).abs() < 1e-4);
+        assert!(results[2].score < 0.05);
+
+        // Plain search is unaffected
+        assert!(mem.search(&embedding, 3).iter().all(|r| (r.score - 1.0).abs() < 1e-4));
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let config = MemoryConfig {
+            embedding_dim: 16,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+        mem.write_with_metadata(
+            "fact",
+            "The sky is blue",
+            make_embedding(16, 1.0),
+            metadata(&[("source", "observation")]),
+        )
+        .unwrap();
+        mem.write_in("code", "snippet", "fn main() {}", make_embedding(16, 2.0)).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.json");
+        mem.export_json(&path).unwrap();
+
+        let imported = Memory::import_json(&path).unwrap();
+        assert_eq!(imported.len(), 2);
+        for original in mem.entries() {
+            let entry = imported.read_in(&original.namespace, &original.key).unwrap();
+            assert_eq!(entry.content, original.content);
+            assert_eq!(entry.metadata, original.metadata);
+            assert_eq!(entry.created_at, original.created_at);
+            assert_eq!(entry.namespace, original.namespace);
+            assert_eq!(entry.embedding, original.embedding);
+        }
+
+        // Hand-edited vectors of the wrong size are rejected or re-embedded
+        let mut json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        json["entries"][0]["embedding"] = serde_json::json!([1.0, 2.0]);
+        std::fs::write(&path, serde_json::to_vec(&json).unwrap()).unwrap();
+
+        assert_eq!(Memory::import_json(&path).unwrap().len(), 1);
+        let reembedded = Memory::import_json_with(&path, |_| Ok(vec![0.5; 16])).unwrap();
+        assert_eq!(reembedded.len(), 2);
+        assert_eq!(reembedded.read("fact").unwrap().embedding, vec![0.5; 16]);
+    }
+
+    #[test]
+    fn test_export_vectors() {
+        let config = MemoryConfig {
+            embedding_dim: 32,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+        for i in 0..3 {
+            mem.write(format!("key_{}", i), "content", make_embedding(32, i as f32)).unwrap();
+        }
+
+        let (keys, vectors) = mem.export_vectors();
+        assert_eq!(keys, vec!["key_0", "key_1", "key_2"]);
+        assert_eq!(vectors.len(), 3);
+        assert!(vectors.iter().all(|v| v.len() == 32));
+        assert_eq!(vectors[1], mem.read("key_1").unwrap().embedding);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors.bin");
+        mem.export_vectors_raw(&path).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(data[0..4].try_into().unwrap()), 3);
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 32);
+        assert_eq!(data.len(), 8 + 3 * 32 * 4);
+        let first = f32::from_le_bytes(data[8..12].try_into().unwrap());
+        assert_eq!(first, vectors[0][0]);
+
+        // Entries of another dimension are left out of the raw dump
+        let mut state = mem.get_state();
+        let mut stray = state.entries[0].clone();
+        stray.key = "stray".to_string();
+        stray.embedding = make_embedding(16, 1.0);
+        state.entries.push(stray);
+        mem.set_state(state);
+        assert_eq!(mem.len(), 4);
+        mem.export_vectors_raw(&path).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(data[0..4].try_into().unwrap()), 3);
+        assert_eq!(data.len(), 8 + 3 * 32 * 4);
+    }
+
+    #[test]
+    fn test_namespaces_are_isolated() {
+        let config = MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: -1.0,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+
+        // Same key and embedding in both namespaces
+        for i in 0..4 {
+            let emb = make_embedding(64, i as f32 + 1.0);
+            mem.write_in("facts", format!("key_{}", i), "a fact", emb.clone()).unwrap();
+            mem.write_in("docs", format!("key_{}", i), "a doc chunk", emb).unwrap();
+        }
+        assert_eq!(mem.len(), 8);
+        assert_eq!(mem.namespaces(), vec!["docs", "facts"]);
+        assert_eq!(mem.read_in("facts", "key_0").unwrap().content, "a fact");
+        assert_eq!(mem.read_in("docs", "key_0").unwrap().content, "a doc chunk");
+        assert!(mem.read("key_0").is_none());
+
+        for i in 0..4 {
+            let query = make_embedding(64, i as f32 + 1.0);
+            let results = mem.search_in("facts", &query, 8);
+            assert_eq!(results.len(), 4);
+            assert!(results.iter().all(|r| r.entry.namespace == "facts"));
+        }
+
+        assert!(mem.delete_in("docs", "key_0"));
+        assert!(mem.read_in("facts", "key_0").is_some());
+        assert!(mem.read_in("docs", "key_0").is_none());
+    }
+
+    #[test]
+    fn test_namespace_thresholds() {
+        let config = MemoryConfig {
+            embedding_dim: 64,
+            similarity_threshold: 0.0,
+            ..Default::default()
+        };
+        let mut mem = Memory::new(config);
+        mem.set_namespace_threshold("code", 0.99);
+
+        for i in 0..5 {
+            let emb = make_embedding(64, i as f32 + 1.0);
+            mem.write_in("code", format!("code_{}", i), "fn main() {}", emb.clone()).unwrap();
+            mem.write_in("prose", format!("prose_{}", i), "Once upon a time", emb).unwrap();
+        }
+
+        let query = make_embedding(64, 3.0);
+
+        // Strict namespace only keeps the exact match
+        let code = mem.search_in("code", &query, 5);
+        assert_eq!(code.len(), 1);
+        assert_eq!(code[0].entry.key, "code_2");
+
+        // Unset namespace falls back to the global threshold
+        assert_eq!(mem.namespace_threshold("prose"), 0.0);
+        let prose = mem.search_in("prose", &query, 5);
+        assert!(prose.len() > 1);
+        assert!(prose.iter().all(|r| r.entry.namespace == "prose"));
+    }
 }
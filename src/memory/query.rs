@@ -0,0 +1,87 @@
+//! Metadata predicates for memory search
+//!
+//! A `MemoryQuery` is a conjunction of conditions on an entry's metadata.
+//! It's applied before similarity ranking, so top-k results come from the
+//! matching subset only.
+
+use super::MemoryEntry;
+use std::collections::HashMap;
+
+/// A single metadata condition
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    /// Key is present with exactly this value
+    Equals(String, String),
+    /// Key is present with any value
+    Exists(String),
+    /// Key is present with one of these values
+    OneOf(String, Vec<String>),
+}
+
+/// Builder for metadata filters
+///
+/// An entry matches when it satisfies every condition. An empty query
+/// matches everything.
+///
+/// ```rust,ignore
+/// let query = MemoryQuery::new()
+///     .equals("user", "alice")
+///     .one_of("kind", ["note", "fact"])
+///     .exists("source");
+/// let results = memory.search_query(&embedding, 5, &query);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryQuery {
+    conditions: Vec<Condition>,
+}
+
+impl MemoryQuery {
+    /// Create an empty query
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require `key` to equal `value`
+    pub fn equals(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.conditions.push(Condition::Equals(key.into(), value.into()));
+        self
+    }
+
+    /// Require `key` to be present
+    pub fn exists(mut self, key: impl Into<String>) -> Self {
+        self.conditions.push(Condition::Exists(key.into()));
+        self
+    }
+
+    /// Require `key` to equal any of `values`
+    pub fn one_of<I, S>(mut self, key: impl Into<String>, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let values = values.into_iter().map(Into::into).collect();
+        self.conditions.push(Condition::OneOf(key.into(), values));
+        self
+    }
+
+    /// Check whether an entry satisfies every condition
+    pub fn matches(&self, entry: &MemoryEntry) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Equals(key, value) => entry.metadata.get(key) == Some(value),
+            Condition::Exists(key) => entry.metadata.contains_key(key),
+            Condition::OneOf(key, values) => entry
+                .metadata
+                .get(key)
+                .is_some_and(|v| values.contains(v)),
+        })
+    }
+}
+
+impl From<&HashMap<String, String>> for MemoryQuery {
+    /// Require every key/value pair in the map
+    fn from(filter: &HashMap<String, String>) -> Self {
+        filter
+            .iter()
+            .fold(Self::new(), |query, (k, v)| query.equals(k.as_str(), v.as_str()))
+    }
+}
@@ -0,0 +1,79 @@
+//! LRU cache of query embeddings
+//!
+//! Interactive sessions embed the same queries over and over; `Cortex`
+//! keeps their embeddings here so exact repeats skip the embedding model.
+
+use std::collections::HashMap;
+
+/// Embeddings of recent queries, evicting the least recently used
+#[derive(Debug, Default)]
+pub(crate) struct QueryCache {
+    capacity: usize,
+    entries: HashMap<String, (Vec<f32>, u64)>,
+    tick: u64,
+}
+
+impl QueryCache {
+    /// Create a cache holding up to `capacity` queries; 0 disables it
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    /// Cached embedding of `query`, marking it recently used
+    pub(crate) fn get(&mut self, query: &str) -> Option<Vec<f32>> {
+        self.tick += 1;
+        let (embedding, used) = self.entries.get_mut(query)?;
+        *used = self.tick;
+        Some(embedding.clone())
+    }
+
+    /// Cache the embedding of `query`
+    pub(crate) fn insert(&mut self, query: &str, embedding: Vec<f32>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(query) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.entries
+            .insert(query.to_string(), (embedding, self.tick));
+    }
+
+    /// Drop every cached embedding
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = QueryCache::new(2);
+        cache.insert("a", vec![1.0]);
+        cache.insert("b", vec![2.0]);
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+
+        cache.insert("c", vec![3.0]);
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+
+        let mut disabled = QueryCache::new(0);
+        disabled.insert("a", vec![1.0]);
+        assert_eq!(disabled.get("a"), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Versioned on-disk memory format
+//!
+//! Bincode isn't self-describing, so new `MemoryEntry` fields can't just
+//! be defaulted when reading old files. Persisted `MemoryState`s start with
+//! a format version; older layouts are decoded into their original structs
+//! and upgraded, filling defaults for fields they lack.
+
+use super::{MemoryEntry, MemoryState, DEFAULT_NAMESPACE};
+use crate::config::EmbeddingStorage;
+use crate::{CortexError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Current memory file format version
+///
+/// - 1: unversioned, entries without namespaces
+/// - 2: version header, entry namespaces
+/// - 3: embedding storage mode
+pub const MEMORY_FORMAT_VERSION: u32 = 3;
+
+/// Memory state as written before versioning
+#[derive(Debug, Serialize, Deserialize)]
+struct MemoryStateV1 {
+    embedding_dim: usize,
+    max_entries: usize,
+    entries: Vec<MemoryEntryV1>,
+}
+
+/// Memory state before the embedding storage mode was recorded
+#[derive(Debug, Serialize, Deserialize)]
+struct MemoryStateV2 {
+    version: u32,
+    embedding_dim: usize,
+    max_entries: usize,
+    entries: Vec<MemoryEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MemoryEntryV1 {
+    key: String,
+    content: String,
+    embedding: Vec<f32>,
+    metadata: HashMap<String, String>,
+    created_at: u64,
+}
+
+impl From<MemoryStateV1> for MemoryState {
+    fn from(state: MemoryStateV1) -> Self {
+        MemoryState {
+            version: MEMORY_FORMAT_VERSION,
+            embedding_dim: state.embedding_dim,
+            max_entries: state.max_entries,
+            entries: state.entries.into_iter().map(MemoryEntry::from).collect(),
+            storage: EmbeddingStorage::F32,
+        }
+    }
+}
+
+impl From<MemoryStateV2> for MemoryState {
+    fn from(state: MemoryStateV2) -> Self {
+        MemoryState {
+            version: MEMORY_FORMAT_VERSION,
+            embedding_dim: state.embedding_dim,
+            max_entries: state.max_entries,
+            entries: state.entries,
+            storage: EmbeddingStorage::F32,
+        }
+    }
+}
+
+impl From<MemoryEntryV1> for MemoryEntry {
+    fn from(entry: MemoryEntryV1) -> Self {
+        MemoryEntry {
+            key: entry.key,
+            content: entry.content,
+            embedding: entry.embedding,
+            metadata: entry.metadata,
+            created_at: entry.created_at,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+        }
+    }
+}
+
+/// Decode a persisted memory file of any known version
+pub(crate) fn decode_state(data: &[u8]) -> Result<MemoryState> {
+    // Versioned files start with the version as a little-endian u32.
+    // Unversioned (v1) files start with the embedding dimension instead.
+    let version = data
+        .get(..4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .unwrap_or(0);
+
+    if version == MEMORY_FORMAT_VERSION {
+        if let Ok(state) = bincode::deserialize::<MemoryState>(data) {
+            return Ok(state);
+        }
+    }
+    if version == 2 {
+        if let Ok(state) = bincode::deserialize::<MemoryStateV2>(data) {
+            return Ok(state.into());
+        }
+    }
+
+    // Anything else is either a v1 file or corrupt
+    bincode::deserialize::<MemoryStateV1>(data)
+        .map(MemoryState::from)
+        .map_err(|e| CortexError::Serialization(format!("Unrecognized memory file: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_load_v1_file() {
+        let v1 = MemoryStateV1 {
+            embedding_dim: 4,
+            max_entries: 10,
+            entries: vec![MemoryEntryV1 {
+                key: "fact".to_string(),
+                content: "The sky is blue".to_string(),
+                embedding: vec![1.0, 0.0, 0.0, 0.0],
+                metadata: HashMap::from([("source".to_string(), "test".to_string())]),
+                created_at: 42,
+            }],
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.bin");
+        std::fs::write(&path, bincode::serialize(&v1).unwrap()).unwrap();
+
+        let mem = Memory::load(&path).unwrap();
+        let entry = mem.read("fact").unwrap();
+        assert_eq!(entry.content, "The sky is blue");
+        assert_eq!(entry.metadata["source"], "test");
+        assert_eq!(entry.created_at, 42);
+        assert_eq!(entry.namespace, DEFAULT_NAMESPACE);
+
+        // Re-persisting upgrades the file to the current version
+        mem.persist(&path).unwrap();
+        let state = decode_state(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(state.version, MEMORY_FORMAT_VERSION);
+        assert_eq!(state.entries.len(), 1);
+    }
+}
@@ -6,20 +6,83 @@
 //!
 //! Optimized for the common case of < 10k memories per session.
 
-use super::{MemoryEntry, SearchResult};
-use std::collections::HashMap;
+use super::{MemoryEntry, SearchResult, SearchStats, DEFAULT_NAMESPACE};
+use crate::config::{EmbeddingStorage, EvictionPolicy};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+/// Number of recent queries remembered for `EvictionPolicy::LeastRelevant`
+const RECENT_QUERIES: usize = 16;
 
 /// Vector store with similarity search
 pub struct VectorStore {
-    /// Entries by key
+    /// Entries by slot (see `slot`)
     entries: HashMap<String, MemoryEntry>,
-    /// Ordered list of keys for iteration
+    /// Ordered list of slots for iteration
     keys: Vec<String>,
     /// Embedding dimension
     #[allow(dead_code)]
     dim: usize,
     /// Maximum entries
     max_entries: usize,
+    /// How to pick an entry to evict at capacity
+    eviction: EvictionPolicy,
+    /// Logical clock for access tracking
+    clock: Cell<u64>,
+    /// Last access time of each slot (LRU only)
+    last_access: RefCell<HashMap<String, u64>>,
+    /// Recent normalized queries (least-relevant only)
+    recent_queries: RefCell<VecDeque<Vec<f32>>>,
+    /// How embeddings are held
+    storage: EmbeddingStorage,
+    /// Int8 embeddings by slot; their stored entries keep an empty
+    /// `embedding`, which the read APIs fill back in
+    quantized: HashMap<String, QuantizedEmbedding>,
+    /// Norms of the f32 embeddings by slot, so search only takes dot products
+    norms: HashMap<String, f32>,
+}
+
+/// An embedding scalar-quantized to int8 with a per-vector scale
+#[derive(Debug, Clone)]
+struct QuantizedEmbedding {
+    values: Vec<i8>,
+    scale: f32,
+    /// Norm of `values`, unscaled
+    norm: f32,
+}
+
+impl QuantizedEmbedding {
+    /// Quantize symmetrically so the largest magnitude maps to 127
+    fn new(v: &[f32]) -> Self {
+        let max_abs = v.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        let scale = if max_abs > 0.0 { max_abs / 127.0 } else { 1.0 };
+        let values = v
+            .iter()
+            .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8)
+            .collect::<Vec<_>>();
+        let norm = (values.iter().map(|&x| x as i64 * x as i64).sum::<i64>() as f32).sqrt();
+        Self {
+            values,
+            scale,
+            norm,
+        }
+    }
+
+    fn dequantize(&self) -> Vec<f32> {
+        self.values.iter().map(|&x| x as f32 * self.scale).collect()
+    }
+
+    /// Cosine similarity with a unit-length query; the scale cancels out
+    fn cosine_similarity(&self, query: &[f32]) -> f32 {
+        if query.len() != self.values.len() || self.norm == 0.0 {
+            return 0.0;
+        }
+
+        let dot: f32 = query.iter().zip(&self.values).map(|(&q, &v)| q * v as f32).sum();
+        dot / self.norm
+    }
 }
 
 impl VectorStore {
@@ -30,32 +93,152 @@ impl VectorStore {
             keys: Vec::new(),
             dim,
             max_entries,
+            eviction: EvictionPolicy::Fifo,
+            clock: Cell::new(0),
+            last_access: RefCell::new(HashMap::new()),
+            recent_queries: RefCell::new(VecDeque::new()),
+            storage: EmbeddingStorage::F32,
+            quantized: HashMap::new(),
+            norms: HashMap::new(),
         }
     }
 
+    /// Set how embeddings are held; call before inserting
+    pub fn with_storage(mut self, storage: EmbeddingStorage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Set the eviction policy used at capacity
+    pub fn with_eviction(mut self, eviction: EvictionPolicy) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
     /// Insert an entry
-    pub fn insert(&mut self, entry: MemoryEntry) {
-        // If at capacity, remove oldest entry
+    pub fn insert(&mut self, mut entry: MemoryEntry) {
+        // If at capacity, make room according to the eviction policy
         if self.entries.len() >= self.max_entries {
-            if let Some(oldest_key) = self.keys.first().cloned() {
-                self.remove(&oldest_key);
+            if let Some(victim) = self.eviction_candidate() {
+                self.remove_slot(&victim);
             }
         }
 
-        let key = entry.key.clone();
+        let key = slot(&entry.namespace, &entry.key);
+        self.touch(&key);
+        if self.storage == EmbeddingStorage::Int8 {
+            let quantized = QuantizedEmbedding::new(&entry.embedding);
+            entry.embedding = Vec::new();
+            self.quantized.insert(key.clone(), quantized);
+        } else {
+            self.norms.insert(key.clone(), norm(&entry.embedding));
+        }
         self.entries.insert(key.clone(), entry);
         self.keys.push(key);
     }
 
-    /// Get entry by key
-    pub fn get(&self, key: &str) -> Option<&MemoryEntry> {
-        self.entries.get(key)
+    /// Pick the entry to evict, ties going to the oldest insertion
+    fn eviction_candidate(&self) -> Option<String> {
+        let queries = self.recent_queries.borrow();
+        match self.eviction {
+            EvictionPolicy::Fifo => self.keys.first().cloned(),
+            EvictionPolicy::LeastRelevant if queries.is_empty() => self.keys.first().cloned(),
+            EvictionPolicy::Lru => {
+                let last_access = self.last_access.borrow();
+                self.keys
+                    .iter()
+                    .min_by_key(|k| last_access.get(*k).copied().unwrap_or(0))
+                    .cloned()
+            }
+            EvictionPolicy::LeastRelevant => {
+                let relevance = |key: &String| {
+                    queries
+                        .iter()
+                        .map(|q| self.similarity(key, &self.entries[key], q))
+                        .fold(f32::NEG_INFINITY, f32::max)
+                };
+                self.keys
+                    .iter()
+                    .map(|k| (k, relevance(k)))
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+                    .map(|(k, _)| k.clone())
+            }
+        }
+    }
+
+    /// Record an access to a slot for LRU eviction
+    fn touch(&self, key: &str) {
+        if self.eviction == EvictionPolicy::Lru {
+            let now = self.clock.get() + 1;
+            self.clock.set(now);
+            self.last_access.borrow_mut().insert(key.to_string(), now);
+        }
+    }
+
+    /// Get entry by key in the default namespace
+    pub fn get(&self, key: &str) -> Option<Cow<'_, MemoryEntry>> {
+        self.get_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Get entry by namespace and key
+    ///
+    /// Quantized embeddings are dequantized into an owned copy.
+    pub fn get_in(&self, namespace: &str, key: &str) -> Option<Cow<'_, MemoryEntry>> {
+        let slot = slot(namespace, key);
+        let entry = self.entries.get(&slot)?;
+        self.touch(&slot);
+        Some(self.full_entry(&slot, entry))
     }
 
-    /// Remove entry by key
+    /// Remove entry by key in the default namespace
     pub fn remove(&mut self, key: &str) -> bool {
+        self.remove_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Remove entry by namespace and key
+    pub fn remove_in(&mut self, namespace: &str, key: &str) -> bool {
+        self.remove_slot(&slot(namespace, key))
+    }
+
+    /// Distinct namespaces with at least one entry, sorted
+    pub fn namespaces(&self) -> Vec<String> {
+        let mut namespaces: Vec<String> =
+            self.entries.values().map(|e| e.namespace.clone()).collect();
+        namespaces.sort();
+        namespaces.dedup();
+        namespaces
+    }
+
+    /// Keep only entries for which `keep` returns true
+    ///
+    /// Returns the number of entries removed. Under int8 storage the
+    /// entries passed to `keep` have an empty `embedding`.
+    pub fn retain<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&MemoryEntry) -> bool,
+    {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| keep(entry));
+        if self.entries.len() == before {
+            return 0;
+        }
+
+        let entries = &self.entries;
+        self.keys.retain(|k| entries.contains_key(k));
+        self.quantized.retain(|k, _| entries.contains_key(k));
+        self.norms.retain(|k, _| entries.contains_key(k));
+        self.last_access
+            .borrow_mut()
+            .retain(|k, _| entries.contains_key(k));
+        before - self.entries.len()
+    }
+
+    fn remove_slot(&mut self, key: &str) -> bool {
         if self.entries.remove(key).is_some() {
+            self.quantized.remove(key);
+            self.norms.remove(key);
             self.keys.retain(|k| k != key);
+            self.last_access.borrow_mut().remove(key);
             true
         } else {
             false
@@ -64,43 +247,268 @@ impl VectorStore {
 
     /// Search by similarity (cosine similarity)
     pub fn search(&self, query: &[f32], k: usize) -> Vec<SearchResult> {
+        self.search_filtered(query, k, |_| true)
+    }
+
+    /// Search by similarity, also counting what was scanned and skipped
+    pub fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<SearchResult>, SearchStats) {
+        self.search_scored_with_stats(query, k, |_| true, |_, similarity| similarity)
+    }
+
+    /// Search only among entries matching a predicate
+    ///
+    /// The filter is applied before top-k truncation, so up to `k` results
+    /// are returned from the matching subset.
+    pub fn search_filtered<F>(&self, query: &[f32], k: usize, filter: F) -> Vec<SearchResult>
+    where
+        F: Fn(&MemoryEntry) -> bool,
+    {
+        self.search_scored(query, k, filter, |_, similarity| similarity)
+    }
+
+    /// Search with a custom score derived from each entry's similarity
+    ///
+    /// `score` maps `(entry, cosine similarity)` to the value used for
+    /// ranking and reported in the results.
+    ///
+    /// Entries whose embedding has a different dimension than the query
+    /// can't be compared and are skipped.
+    pub fn search_scored<F, S>(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: F,
+        score: S,
+    ) -> Vec<SearchResult>
+    where
+        F: Fn(&MemoryEntry) -> bool,
+        S: Fn(&MemoryEntry, f32) -> f32,
+    {
+        self.search_scored_with_stats(query, k, filter, score).0
+    }
+
+    fn search_scored_with_stats<F, S>(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: F,
+        score: S,
+    ) -> (Vec<SearchResult>, SearchStats)
+    where
+        F: Fn(&MemoryEntry) -> bool,
+        S: Fn(&MemoryEntry, f32) -> f32,
+    {
+        let mut stats = SearchStats::default();
         if self.entries.is_empty() || k == 0 {
-            return vec![];
+            return (vec![], stats);
         }
 
-        // Normalize query
-        let query_norm = normalize(query);
+        let query_norm = self.prepare_query(query);
 
         // Calculate similarities
-        let mut scored: Vec<(&MemoryEntry, f32)> = self
-            .entries
-            .values()
-            .map(|entry| {
-                let score = cosine_similarity(&query_norm, &entry.embedding);
-                (entry, score)
-            })
-            .collect();
+        let mut scored: Vec<(&String, &MemoryEntry, f32)> = Vec::new();
+        for (slot, entry) in self.slots_iter().filter(|(_, entry)| filter(entry)) {
+            stats.scanned += 1;
+            if self.stored_dim(slot, entry) != query.len() {
+                stats.skipped_dim_mismatch += 1;
+                continue;
+            }
+            let similarity = self.similarity(slot, entry, &query_norm);
+            scored.push((slot, entry, score(entry, similarity)));
+        }
+        warn_dim_mismatch(&stats, query.len());
 
-        // Sort by score descending
-        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        // Sort by score descending, ties by key so results are reproducible
+        scored.sort_by(|a, b| rank(b.2, b.1, a.2, a.1));
 
         // Take top k
-        scored
+        scored.truncate(k);
+        stats.returned = scored.len();
+        (self.to_results(scored), stats)
+    }
+
+    /// Search keeping only the best `k` candidates in memory
+    ///
+    /// `score` maps `(entry, cosine similarity)` to a ranking score, or
+    /// `None` to skip the entry. Results equal those of `search_scored`
+    /// with the same filter and score, but peak memory is O(k) rather than
+    /// O(entries).
+    pub fn search_streaming<S>(&self, query: &[f32], k: usize, score: S) -> Vec<SearchResult>
+    where
+        S: Fn(&MemoryEntry, f32) -> Option<f32>,
+    {
+        if self.entries.is_empty() || k == 0 {
+            return vec![];
+        }
+
+        let query_norm = self.prepare_query(query);
+
+        // Min-heap of the best candidates so far; the root is the worst
+        let mut heap: BinaryHeap<Reverse<Candidate>> = BinaryHeap::with_capacity(k + 1);
+        let candidates = self
+            .slots_iter()
+            .filter(|(slot, entry)| self.stored_dim(slot, entry) == query.len())
+            .filter_map(|(slot, entry)| {
+                let similarity = self.similarity(slot, entry, &query_norm);
+                score(entry, similarity).map(|score| Candidate { score, slot, entry })
+            });
+        for candidate in candidates {
+            if heap.len() < k {
+                heap.push(Reverse(candidate));
+            } else if heap.peek().is_some_and(|worst| candidate > worst.0) {
+                heap.pop();
+                heap.push(Reverse(candidate));
+            }
+        }
+
+        let best = heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(c)| (c.slot, c.entry, c.score));
+        self.to_results(best)
+    }
+
+    /// Normalize a query, remembering it for least-relevant eviction
+    fn prepare_query(&self, query: &[f32]) -> Vec<f32> {
+        let query_norm = normalize(query);
+
+        if self.eviction == EvictionPolicy::LeastRelevant {
+            let mut queries = self.recent_queries.borrow_mut();
+            if queries.len() == RECENT_QUERIES {
+                queries.pop_front();
+            }
+            queries.push_back(query_norm.clone());
+        }
+
+        query_norm
+    }
+
+    /// Turn ranked `(slot, entry, score)` triples into results
+    fn to_results<'a>(
+        &'a self,
+        ranked: impl IntoIterator<Item = (&'a String, &'a MemoryEntry, f32)>,
+    ) -> Vec<SearchResult> {
+        ranked
             .into_iter()
-            .take(k)
-            .map(|(entry, score)| SearchResult {
-                entry: entry.clone(),
-                score,
+            .map(|(slot, entry, score)| {
+                self.touch(slot);
+                SearchResult {
+                    entry: self.full_entry(slot, entry).into_owned(),
+                    score,
+                }
             })
             .collect()
     }
 
-    /// Get all entries
-    pub fn entries(&self) -> Vec<&MemoryEntry> {
+    /// Dimension of a stored entry's embedding
+    fn stored_dim(&self, slot: &str, entry: &MemoryEntry) -> usize {
+        self.quantized
+            .get(slot)
+            .map_or(entry.embedding.len(), |quantized| quantized.values.len())
+    }
+
+    /// Cosine similarity between a normalized query and a stored entry
+    fn similarity(&self, slot: &str, entry: &MemoryEntry, query_norm: &[f32]) -> f32 {
+        if let Some(quantized) = self.quantized.get(slot) {
+            return quantized.cosine_similarity(query_norm);
+        }
+
+        let norm = self.norms.get(slot).copied().unwrap_or(0.0);
+        if query_norm.len() != entry.embedding.len() || norm == 0.0 {
+            return 0.0;
+        }
+        let dot: f32 = query_norm.iter().zip(&entry.embedding).map(|(q, e)| q * e).sum();
+        dot / norm
+    }
+
+    /// An entry with its full embedding, cloned only if stored quantized
+    fn full_entry<'a>(&self, slot: &str, entry: &'a MemoryEntry) -> Cow<'a, MemoryEntry> {
+        match self.quantized.get(slot) {
+            Some(quantized) => Cow::Owned(MemoryEntry {
+                embedding: quantized.dequantize(),
+                ..entry.clone()
+            }),
+            None => Cow::Borrowed(entry),
+        }
+    }
+
+    /// Search with Maximal Marginal Relevance re-ranking
+    ///
+    /// Over-fetches `MMR_OVERFETCH * k` candidates by similarity, then
+    /// greedily picks the one maximizing
+    /// `lambda * sim(query, c) - (1 - lambda) * max sim(c, selected)`.
+    /// `lambda = 1.0` is plain similarity search; `0.0` maximizes diversity.
+    /// Result scores are still the query similarity.
+    pub fn search_mmr(&self, query: &[f32], k: usize, lambda: f32) -> Vec<SearchResult> {
+        let mut candidates = self.search(query, k.saturating_mul(MMR_OVERFETCH));
+        let mut selected: Vec<SearchResult> = Vec::with_capacity(k.min(candidates.len()));
+
+        while selected.len() < k && !candidates.is_empty() {
+            let mmr = |c: &SearchResult| {
+                let redundancy = selected
+                    .iter()
+                    .map(|s| cosine_similarity(&c.entry.embedding, &s.entry.embedding))
+                    .fold(f32::NEG_INFINITY, f32::max);
+                let redundancy = if selected.is_empty() { 0.0 } else { redundancy };
+                lambda * c.score - (1.0 - lambda) * redundancy
+            };
+
+            // Candidates are sorted by similarity, so ties keep the closer one
+            let mut best = 0;
+            let mut best_score = mmr(&candidates[0]);
+            for (i, candidate) in candidates.iter().enumerate().skip(1) {
+                let score = mmr(candidate);
+                if score > best_score {
+                    best = i;
+                    best_score = score;
+                }
+            }
+            selected.push(candidates.remove(best));
+        }
+
+        selected
+    }
+
+    /// Get all entries, with full embeddings
+    pub fn entries(&self) -> Vec<Cow<'_, MemoryEntry>> {
+        self.entries_iter().collect()
+    }
+
+    /// Iterate over entries in insertion order without collecting them
+    ///
+    /// Quantized embeddings are dequantized into owned copies.
+    pub fn entries_iter(&self) -> impl Iterator<Item = Cow<'_, MemoryEntry>> {
+        self.slots_iter().map(|(slot, entry)| self.full_entry(slot, entry))
+    }
+
+    /// Iterate over `(slot, entry)` pairs in insertion order
+    fn slots_iter(&self) -> impl Iterator<Item = (&String, &MemoryEntry)> {
         self.keys
             .iter()
-            .filter_map(|k| self.entries.get(k))
-            .collect()
+            .filter_map(|k| self.entries.get(k).map(|entry| (k, entry)))
+    }
+
+    /// Maximum number of entries before eviction
+    pub fn capacity(&self) -> usize {
+        self.max_entries
+    }
+
+    /// Approximate bytes held by embeddings, keys and contents
+    pub fn approx_bytes(&self) -> usize {
+        self.slots_iter()
+            .map(|(slot, entry)| {
+                let embedding = match self.quantized.get(slot) {
+                    Some(quantized) => quantized.values.len() + std::mem::size_of::<f32>(),
+                    None => entry.embedding.len() * std::mem::size_of::<f32>(),
+                };
+                embedding + entry.key.len() + entry.content.len()
+            })
+            .sum()
+    }
+
+    /// Clone all entries in insertion order, with full embeddings
+    pub fn to_entries(&self) -> Vec<MemoryEntry> {
+        self.entries_iter().map(Cow::into_owned).collect()
     }
 
     /// Get number of entries
@@ -117,11 +525,76 @@ impl VectorStore {
     pub fn clear(&mut self) {
         self.entries.clear();
         self.keys.clear();
+        self.quantized.clear();
+        self.norms.clear();
+        self.last_access.borrow_mut().clear();
     }
 }
 
+/// Storage key for an entry, unique per (namespace, key) pair
+///
+/// The namespace is length-prefixed so no two pairs map to the same slot.
+fn slot(namespace: &str, key: &str) -> String {
+    format!("{}:{}{}", namespace.len(), namespace, key)
+}
+
+/// Log entries a search skipped for having the wrong dimension
+fn warn_dim_mismatch(stats: &SearchStats, query_dim: usize) {
+    if stats.skipped_dim_mismatch > 0 {
+        tracing::warn!(
+            skipped = stats.skipped_dim_mismatch,
+            query_dim,
+            "Search skipped entries whose embedding dimension differs from the query"
+        );
+    }
+}
+
+/// Order two scored entries, lower score first
+///
+/// Equal scores order by key, then namespace, with the smaller ranking
+/// higher, so tied results come back in the same order on every run.
+fn rank(a_score: f32, a: &MemoryEntry, b_score: f32, b: &MemoryEntry) -> Ordering {
+    a_score
+        .partial_cmp(&b_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| b.key.cmp(&a.key))
+        .then_with(|| b.namespace.cmp(&a.namespace))
+}
+
+/// A scored entry in `search_streaming`'s heap, ordered by `rank`
+struct Candidate<'a> {
+    score: f32,
+    slot: &'a String,
+    entry: &'a MemoryEntry,
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        rank(self.score, self.entry, other.score, other.entry)
+    }
+}
+
+/// Candidates fetched per requested result before MMR re-ranking
+const MMR_OVERFETCH: usize = 4;
+
 /// Compute cosine similarity between two vectors
-fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+///
+/// Returns 0.0 if the lengths differ or either vector is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     if a.len() != b.len() {
         return 0.0;
     }
@@ -137,9 +610,16 @@ fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     dot / (norm_a * norm_b)
 }
 
+/// Euclidean length of a vector
+fn norm(v: &[f32]) -> f32 {
+    v.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
 /// Normalize a vector to unit length
-fn normalize(v: &[f32]) -> Vec<f32> {
-    let norm: f32 = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+///
+/// A zero vector is returned unchanged.
+pub fn normalize(v: &[f32]) -> Vec<f32> {
+    let norm = norm(v);
     if norm == 0.0 {
         v.to_vec()
     } else {
@@ -158,6 +638,7 @@ mod tests {
             embedding,
             metadata: Default::default(),
             created_at: 0,
+            namespace: "default".to_string(),
         }
     }
 
@@ -188,6 +669,61 @@ mod tests {
         assert!((results[0].score - 1.0).abs() < 1e-6);
     }
 
+    #[test]
+    fn test_cached_norms_match_cosine_similarity() {
+        let vector = |seed: f32| -> Vec<f32> { (0..16).map(|i| (i as f32 * seed).sin() * seed).collect() };
+        let mut store = VectorStore::new(16, 100);
+        for (i, seed) in [0.3, 1.1, 2.7, 4.2, 5.9].iter().enumerate() {
+            store.insert(make_entry(&format!("k{}", i), vector(*seed)));
+        }
+        store.insert(make_entry("zero", vec![0.0; 16]));
+
+        let query = vector(1.7);
+        let results = store.search(&query, 10);
+        assert_eq!(results.len(), 6);
+        for result in results {
+            let expected = cosine_similarity(&query, &result.entry.embedding);
+            assert!((result.score - expected).abs() < 1e-5, "{}", result.entry.key);
+        }
+
+        // Stored embeddings are returned as inserted, not normalized
+        assert_eq!(store.get("k4").unwrap().embedding, vector(5.9));
+    }
+
+    #[test]
+    fn test_ties_sort_by_key() {
+        let mut store = VectorStore::new(3, 100);
+        for key in ["delta", "alpha", "charlie", "bravo"] {
+            store.insert(make_entry(key, vec![1.0, 1.0, 0.0]));
+        }
+        store.insert(make_entry("best", vec![1.0, 0.0, 0.0]));
+
+        let keys = |results: Vec<SearchResult>| -> Vec<String> {
+            results.into_iter().map(|r| r.entry.key).collect()
+        };
+        let query = [1.0, 0.0, 0.0];
+        let expected = vec!["best", "alpha", "bravo", "charlie", "delta"];
+        assert_eq!(keys(store.search(&query, 5)), expected);
+        assert_eq!(keys(store.search_streaming(&query, 3, |_, s| Some(s))), expected[..3]);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut store = VectorStore::new(3, 100).with_storage(EmbeddingStorage::Int8);
+        for key in ["a", "b", "c", "d"] {
+            store.insert(make_entry(key, vec![1.0, 0.0, 0.0]));
+        }
+
+        assert_eq!(store.retain(|e| e.key == "b" || e.key == "d"), 2);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.keys.len(), 2);
+        assert_eq!(store.quantized.len(), 2);
+        let keys: Vec<String> = store.entries_iter().map(|e| e.key.clone()).collect();
+        assert_eq!(keys, vec!["b", "d"]);
+        assert_eq!(store.search(&[1.0, 0.0, 0.0], 5).len(), 2);
+        assert_eq!(store.retain(|_| true), 0);
+    }
+
     #[test]
     fn test_capacity() {
         let mut store = VectorStore::new(3, 2);
@@ -203,4 +739,101 @@ mod tests {
         assert!(store.get("b").is_some());
         assert!(store.get("c").is_some());
     }
+
+    #[test]
+    fn test_lru_eviction() {
+        let fill = |policy| {
+            let mut store = VectorStore::new(3, 2).with_eviction(policy);
+            store.insert(make_entry("a", vec![1.0, 0.0, 0.0]));
+            store.insert(make_entry("b", vec![0.0, 1.0, 0.0]));
+            store.get("a");
+            store.insert(make_entry("c", vec![0.0, 0.0, 1.0]));
+            store
+        };
+
+        // FIFO drops "a" even though it was just read
+        let fifo = fill(EvictionPolicy::Fifo);
+        assert!(fifo.get("a").is_none());
+        assert!(fifo.get("b").is_some());
+
+        // LRU keeps it and drops "b" instead
+        let lru = fill(EvictionPolicy::Lru);
+        assert!(lru.get("a").is_some());
+        assert!(lru.get("b").is_none());
+        assert!(lru.get("c").is_some());
+    }
+
+    #[test]
+    fn test_least_relevant_eviction() {
+        let mut store = VectorStore::new(3, 2).with_eviction(EvictionPolicy::LeastRelevant);
+        store.insert(make_entry("a", vec![1.0, 0.0, 0.0]));
+        store.insert(make_entry("b", vec![0.0, 1.0, 0.0]));
+
+        // Queries only ever look like "a", so "b" goes first
+        store.search(&[0.9, 0.1, 0.0], 1);
+        store.insert(make_entry("c", vec![0.0, 0.0, 1.0]));
+        assert!(store.get("a").is_some());
+        assert!(store.get("b").is_none());
+    }
+
+    #[test]
+    fn test_streaming_search_matches_sort() {
+        let dim = 8;
+        let mut store = VectorStore::new(dim, 1000);
+        for i in 0..200 {
+            // Every value repeats, so ties must break the same way too
+            let seed = (i % 50) as f32;
+            let embedding = (0..dim).map(|d| (seed * 0.37 + d as f32).sin()).collect();
+            store.insert(make_entry(&format!("k{}", i), embedding));
+        }
+        let query: Vec<f32> = (0..dim).map(|d| (d as f32 * 0.5).cos()).collect();
+
+        let keys = |results: Vec<SearchResult>| -> Vec<(String, f32)> {
+            results.into_iter().map(|r| (r.entry.key, r.score)).collect()
+        };
+        for k in [1, 7, 50, 300] {
+            let sorted = keys(store.search(&query, k));
+            let streamed = keys(store.search_streaming(&query, k, |_, s| Some(s)));
+            assert_eq!(streamed, sorted);
+        }
+
+        let even = |e: &MemoryEntry| e.key[1..].parse::<usize>().unwrap() % 2 == 0;
+        let sorted = keys(store.search_filtered(&query, 10, even));
+        let streamed = keys(store.search_streaming(&query, 10, |e, s| even(e).then_some(s)));
+        assert_eq!(streamed, sorted);
+
+        assert_eq!(store.entries_iter().count(), 200);
+        assert_eq!(store.entries_iter().next().unwrap().key, "k0");
+    }
+
+    #[test]
+    fn test_int8_storage_matches_f32() {
+        let dim = 32;
+        let vector = |seed: f32| -> Vec<f32> { (0..dim).map(|i| (i as f32 * seed).sin()).collect() };
+
+        let mut full = VectorStore::new(dim, 100);
+        let mut quantized = VectorStore::new(dim, 100).with_storage(EmbeddingStorage::Int8);
+        for (i, seed) in [0.3, 1.1, 2.7, 4.2].iter().enumerate() {
+            full.insert(make_entry(&format!("k{}", i), vector(*seed)));
+            quantized.insert(make_entry(&format!("k{}", i), vector(*seed)));
+        }
+
+        for seed in [0.3, 1.1, 2.7, 4.2] {
+            let expected = &full.search(&vector(seed), 1)[0];
+            let actual = &quantized.search(&vector(seed), 1)[0];
+            assert_eq!(actual.entry.key, expected.entry.key);
+            assert!((actual.score - expected.score).abs() < 0.01);
+            assert_eq!(actual.entry.embedding.len(), dim);
+        }
+
+        // Every read API hands back the dequantized vector
+        let original = vector(0.3);
+        let read = quantized.get("k0").unwrap();
+        assert!(matches!(read, Cow::Owned(_)));
+        assert!(read.embedding.iter().zip(&original).all(|(a, b)| (a - b).abs() < 0.01));
+        assert!(read.similarity(&original) > 0.99);
+        assert!(quantized.entries_iter().all(|e| e.embedding.len() == dim));
+        assert_eq!(quantized.to_entries()[0].embedding, read.embedding);
+        assert!(matches!(full.get("k0").unwrap(), Cow::Borrowed(_)));
+    }
 }
@@ -2,13 +2,38 @@
 //!
 //! The runtime layer that provides memory, state, and execution primitives.
 
-use crate::config::{CortexConfig, GenerationConfig};
-use crate::inference::{format_chat_prompt, CandleLLM, ChatTemplate, Embedder, StubEngine, TextEngine};
-use crate::memory::Memory;
-use crate::state::{Branch, Checkpoint, CheckpointManager, RuntimeState, StateStore};
-use crate::{Message, Result};
-
-use std::path::Path;
+use crate::config::{ContextPolicy, CortexConfig, GenerationConfig, MemoryConfig};
+use crate::inference::{
+    detect_language, format_chat_prompt, CandleLLM, ChatTemplate, Embedder, EngineState,
+    GenerationOutput, Grammar, ModelInfo, ProgressReporter, StreamControl, StreamEvent, StubEngine,
+    TextEngine,
+};
+use crate::memory::{
+    chunk_text, cosine_similarity, normalize, ChunkOptions, Memory, MemoryStats, QueryCache,
+};
+use crate::state::{
+    Branch, Checkpoint, CheckpointManager, CheckpointSort, MergeStrategy, PortableCheckpoint,
+    RuntimeState, StateStore,
+};
+use crate::tools::{ToolCall, ToolRegistry};
+use crate::{CortexError, Message, Result, Role};
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of recent generations used for throughput estimates
+const THROUGHPUT_WINDOW: usize = 16;
+
+/// Assumed throughput before any generation has been measured
+const DEFAULT_TOKENS_PER_SECOND: f64 = 5.0;
+
+/// `Message::name` marking the running summary of folded-away history
+const SUMMARY_NAME: &str = "summary";
+
+/// Token budget for each summary of old messages
+const SUMMARY_MAX_TOKENS: u32 = 256;
 
 /// The Cortex runtime
 ///
@@ -47,6 +72,9 @@ pub struct Cortex {
     /// Memory subsystem
     pub memory: Memory,
 
+    /// Embeddings of recent recall queries
+    query_cache: Mutex<QueryCache>,
+
     /// State store for checkpoints
     state_store: StateStore,
 
@@ -56,10 +84,47 @@ pub struct Cortex {
     /// Conversation history
     messages: Vec<Message>,
 
+    /// System prompt sent ahead of the history, never part of it
+    system_prompt: Option<String>,
+
+    /// Tool instructions sent after the system prompt while `run_agent`
+    /// runs, never part of the history
+    tool_prompt: Option<String>,
+
     /// Chat template to use
     chat_template: ChatTemplate,
+
+    /// Language detected in the most recent user message
+    detected_language: Option<String>,
+
+    /// Maps a detected language tag to an optional system prompt hint
+    language_hook: Option<LanguageHook>,
+
+    /// Recent generations as (tokens processed, elapsed time)
+    timings: VecDeque<(usize, Duration)>,
+
+    /// Messages sent to `chat` since the last checkpoint
+    messages_since_checkpoint: usize,
+
+    /// Automatic checkpoints taken so far, for naming them
+    auto_checkpoints: usize,
+}
+
+/// What a checkpoint restore brought back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Messages in the restored history
+    pub messages_restored: usize,
+    /// Entries in the restored memory
+    pub memory_entries: usize,
+    /// The checkpoint carried engine context; when false the engine starts
+    /// cold and callers may want to re-prime it
+    pub engine_warm: bool,
 }
 
+/// Hook turning a detected language tag into a system prompt hint
+pub type LanguageHook = Box<dyn Fn(&str) -> Option<String> + Send>;
+
 impl Cortex {
     /// Create a new runtime with stub engine
     ///
@@ -68,59 +133,136 @@ impl Cortex {
         Self::with_engine(StubEngine::new())
     }
 
+    /// Start building a runtime
+    pub fn builder() -> CortexBuilder {
+        CortexBuilder::new()
+    }
+
     /// Create runtime with a custom text engine
+    ///
+    /// Memory is sized to the engine's embedding dimension.
     pub fn with_engine<E: TextEngine + 'static>(engine: E) -> Self {
-        let config = CortexConfig::default();
-        let memory = Memory::new(config.memory.clone());
-        let state_store = StateStore::new(
-            config.state.directory.clone(),
-            config.state.max_checkpoints,
-        );
-        let checkpoint_manager = CheckpointManager::new(config.state.max_checkpoints);
-
-        Self {
-            config,
-            engine: Box::new(engine),
-            embedder: None,
-            memory,
-            state_store,
-            checkpoint_manager,
-            messages: Vec::new(),
-            chat_template: ChatTemplate::default(),
-        }
+        CortexBuilder::new()
+            .engine(engine)
+            .build()
+            .expect("the default config fits any engine")
     }
 
     /// Create runtime with config and engine
+    ///
+    /// A default `memory.embedding_dim` is replaced by the engine's
+    /// embedding dimension. Any other value that disagrees with the engine
+    /// is a `Config` error, since every `remember` would fail. Memory is
+    /// restored from `memory.persist_path` if that file exists.
     pub fn with_config_and_engine<E: TextEngine + 'static>(
         config: CortexConfig,
         engine: E,
-    ) -> Self {
-        let memory = Memory::new(config.memory.clone());
+    ) -> Result<Self> {
+        CortexBuilder::new().config(config).engine(engine).build()
+    }
+
+    /// Memory is embedded by `embedder` if given, otherwise by `engine`
+    fn from_config(
+        mut config: CortexConfig,
+        engine: Box<dyn TextEngine>,
+        embedder: Option<Embedder>,
+    ) -> Result<Self> {
+        let (source, source_dim) = match &embedder {
+            Some(embedder) => ("embedder", embedder.dim()),
+            None => ("engine", engine.embedding_dim()),
+        };
+        let configured = config.memory.embedding_dim;
+        if configured != source_dim {
+            if configured != MemoryConfig::default().embedding_dim {
+                return Err(CortexError::Config(format!(
+                    "memory.embedding_dim is {} but the {} produces {}-dimensional embeddings",
+                    configured, source, source_dim
+                )));
+            }
+            tracing::info!(
+                "Sizing memory to {} embedding dimension {} (config default was {})",
+                source,
+                source_dim,
+                configured
+            );
+            config.memory.embedding_dim = source_dim;
+        }
+
+        let memory = Memory::open(config.memory.clone())?;
+        let mut ctx = Self::from_parts(config, memory, engine);
+        ctx.embedder = embedder;
+        Ok(ctx)
+    }
+
+    fn from_parts(config: CortexConfig, memory: Memory, engine: Box<dyn TextEngine>) -> Self {
+        let query_cache = Mutex::new(QueryCache::new(config.memory.query_cache_size));
         let state_store = StateStore::new(
             config.state.directory.clone(),
             config.state.max_checkpoints,
-        );
+        )
+        .with_compression(config.state.compress)
+        .with_format(config.state.format);
         let checkpoint_manager = CheckpointManager::new(config.state.max_checkpoints);
 
         Self {
             config,
-            engine: Box::new(engine),
+            engine,
             embedder: None,
             memory,
+            query_cache,
             state_store,
             checkpoint_manager,
             messages: Vec::new(),
+            system_prompt: None,
+            tool_prompt: None,
             chat_template: ChatTemplate::default(),
+            detected_language: None,
+            language_hook: None,
+            timings: VecDeque::new(),
+            messages_since_checkpoint: 0,
+            auto_checkpoints: 0,
         }
     }
 
-    /// Load a model from a GGUF file
+    /// Load a model from a GGUF file or safetensors checkpoint
     ///
-    /// Uses CandleLLM for inference with quantized models.
+    /// Uses CandleLLM for inference. Directories and `.safetensors` files
+    /// load as unquantized Llama checkpoints; anything else as GGUF.
     pub fn load(model_path: impl AsRef<Path>) -> Result<Self> {
-        let config = CortexConfig::for_model(model_path.as_ref());
-        let engine = CandleLLM::load(model_path)?;
-        Ok(Self::with_config_and_engine(config, engine))
+        Self::load_with_progress(model_path, &mut |_, _| {})
+    }
+
+    /// Load a model, reporting progress of any tokenizer download
+    pub fn load_with_progress(
+        model_path: impl AsRef<Path>,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
+        CortexBuilder::new().build_from_model_with_progress(model_path, reporter)
+    }
+
+    /// Replace the text engine, keeping history, memory and checkpoints
+    ///
+    /// The next generation rebuilds the new engine's context from the
+    /// history. Without a dedicated embedder, memory is embedded by the
+    /// engine, so the new engine must produce embeddings of the same
+    /// dimension; otherwise the swap fails and the current engine stays.
+    pub fn set_engine<E: TextEngine + 'static>(&mut self, engine: E) -> Result<()> {
+        if self.embedder.is_none() && engine.embedding_dim() != self.memory.embedding_dim() {
+            return Err(CortexError::Config(format!(
+                "Engine embedding dimension {} doesn't match memory dimension {}",
+                engine.embedding_dim(),
+                self.memory.embedding_dim()
+            )));
+        }
+
+        self.engine = Box::new(engine);
+        self.engine.clear();
+        if self.embedder.is_none() {
+            self.clear_query_cache();
+        }
+        // Throughput of the old model says nothing about the new one
+        self.timings.clear();
+        Ok(())
     }
 
     /// Set the chat template
@@ -129,6 +271,38 @@ impl Cortex {
         self
     }
 
+    /// Change the chat template used to format prompts
+    pub fn set_template(&mut self, template: ChatTemplate) {
+        self.chat_template = template;
+    }
+
+    /// Set a hook that adjusts the system prompt for the detected language
+    ///
+    /// The hook receives a language tag (e.g. `"zh"`) and may return a hint
+    /// such as `"Respond in Chinese."`, which is added as a system message
+    /// when formatting the prompt. Enables language detection.
+    ///
+    /// ```rust,ignore
+    /// let ctx = Cortex::new().with_language_hook(|lang| {
+    ///     (lang != "en").then(|| format!("Respond in {}.", lang))
+    /// });
+    /// ```
+    pub fn with_language_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + 'static,
+    {
+        self.config.detect_language = true;
+        self.language_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Language tag detected in the most recent user message
+    ///
+    /// Only populated when language detection is enabled.
+    pub fn detected_language(&self) -> Option<&str> {
+        self.detected_language.as_deref()
+    }
+
     // ==================== Generation ====================
 
     /// Generate a completion for raw text
@@ -142,7 +316,22 @@ impl Cortex {
         prompt: &str,
         config: &GenerationConfig,
     ) -> Result<String> {
-        self.engine.generate(prompt, config)
+        config.validate()?;
+        self.run_timed(prompt, |engine| engine.generate(prompt, config))
+    }
+
+    /// Generate a JSON value
+    ///
+    /// Sampling is constrained to well-formed JSON. Fails if generation ends
+    /// before the document is complete (e.g. `max_tokens` was reached).
+    pub fn generate_json(&mut self, prompt: &str) -> Result<serde_json::Value> {
+        let config = self.config.generation.clone().with_grammar(Grammar::Json);
+        config.validate()?;
+        let output = self.run_timed(prompt, |engine| engine.generate(prompt, &config))?;
+
+        serde_json::from_str(&output).map_err(|e| {
+            CortexError::Inference(format!("Generated output is not valid JSON: {}", e))
+        })
     }
 
     /// Generate with streaming
@@ -152,7 +341,31 @@ impl Cortex {
         config: &GenerationConfig,
         callback: &mut dyn FnMut(&str) -> bool,
     ) -> Result<String> {
-        self.engine.generate_streaming(prompt, config, callback)
+        Ok(self.generate_streaming_output(prompt, config, callback)?.text)
+    }
+
+    /// Generate with a callback that can stop generation or inject text
+    ///
+    /// See `TextEngine::generate_interactive`.
+    pub fn generate_interactive(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> StreamControl,
+    ) -> Result<String> {
+        config.validate()?;
+        self.run_timed(prompt, |engine| engine.generate_interactive(prompt, config, callback))
+    }
+
+    /// Generate with streaming, returning token counts and the finish reason
+    pub fn generate_streaming_output(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<GenerationOutput> {
+        config.validate()?;
+        self.run_timed_output(|engine| engine.generate_streaming_output(prompt, config, callback))
     }
 
     /// Chat with message history
@@ -166,21 +379,154 @@ impl Cortex {
         messages: &[Message],
         config: &GenerationConfig,
     ) -> Result<String> {
+        config.validate()?;
+
         // Add new messages to history
         self.messages.extend(messages.iter().cloned());
 
         // Format prompt
-        let prompt = format_chat_prompt(&self.messages, self.chat_template);
+        let prompt = self.build_prompt(config)?;
 
         // Generate response
-        let response = self.engine.generate(&prompt, config)?;
+        let response = self.run_timed(&prompt, |engine| engine.generate(&prompt, config))?;
 
         // Add assistant response to history
         self.messages.push(Message::assistant(&response));
+        self.count_sent_messages(messages.len());
+
+        Ok(response)
+    }
+
+    /// Chat, having the model continue an assistant reply that starts
+    /// with `assistant_prefix`
+    ///
+    /// The prompt ends with the open assistant turn followed by the prefix,
+    /// so the model completes it rather than starting fresh; useful for
+    /// forcing a format. The returned reply, and the one stored in the
+    /// history, include the prefix.
+    pub fn chat_continue(
+        &mut self,
+        messages: &[Message],
+        assistant_prefix: &str,
+        config: &GenerationConfig,
+    ) -> Result<String> {
+        config.validate()?;
+
+        self.messages.extend(messages.iter().cloned());
+        let mut prompt = self.build_prompt(config)?;
+        self.chat_template
+            .append_assistant_prefix(&mut prompt, assistant_prefix);
+
+        let completion = self.run_timed(&prompt, |engine| engine.generate(&prompt, config))?;
+        let response = format!("{}{}", assistant_prefix, completion);
+
+        self.messages.push(Message::assistant(&response));
+        self.count_sent_messages(messages.len());
 
         Ok(response)
     }
 
+    /// Chat, running the tools the model asks for until it answers
+    ///
+    /// Each step generates a reply. A reply containing a tool call (see
+    /// `crate::tools`) runs the tool and sends its result back as a
+    /// `Role::Tool` message; any other reply is the final answer. Unknown
+    /// tools, tool failures and malformed calls are reported to the model
+    /// as tool results so it can correct itself. Fails with a `Tool` error
+    /// if there's no final answer after `max_steps` generations.
+    ///
+    /// The tool descriptions go into the prompt like the system prompt
+    /// does, so they are never stored in the history.
+    pub fn run_agent(
+        &mut self,
+        messages: &[Message],
+        registry: &ToolRegistry,
+        max_steps: usize,
+    ) -> Result<String> {
+        self.tool_prompt = (!registry.is_empty()).then(|| registry.system_prompt());
+        let result = self.run_agent_steps(messages, registry, max_steps);
+        self.tool_prompt = None;
+        result
+    }
+
+    fn run_agent_steps(
+        &mut self,
+        messages: &[Message],
+        registry: &ToolRegistry,
+        max_steps: usize,
+    ) -> Result<String> {
+        let config = self.config.generation.clone();
+        let mut pending = messages.to_vec();
+
+        for _ in 0..max_steps {
+            let reply = self.chat_with_config(&pending, &config)?;
+            let result = match ToolCall::parse(&reply) {
+                Ok(None) => return Ok(reply),
+                Ok(Some(call)) => {
+                    let output = registry.call(&call).unwrap_or_else(|e| e.to_string());
+                    Message::tool(output, call.name)
+                }
+                Err(e) => Message::tool(e.to_string(), "invalid"),
+            };
+            pending = vec![result];
+        }
+
+        Err(CortexError::Tool(format!(
+            "No final answer after {} steps",
+            max_steps
+        )))
+    }
+
+    /// Count messages sent to `chat`, checkpointing once the configured
+    /// interval is reached
+    ///
+    /// A failed automatic checkpoint is logged rather than failing the chat
+    /// that triggered it.
+    fn count_sent_messages(&mut self, count: usize) {
+        let interval = self.config.state.auto_checkpoint_interval;
+        if interval == 0 {
+            return;
+        }
+
+        self.messages_since_checkpoint += count;
+        if self.messages_since_checkpoint >= interval {
+            self.auto_checkpoints += 1;
+            let name = format!("auto-{}", self.auto_checkpoints);
+            if let Err(e) = self.checkpoint_named(&name) {
+                tracing::warn!("Automatic checkpoint '{}' failed: {}", name, e);
+            }
+        }
+    }
+
+    /// Replace the last assistant reply with a fresh generation
+    ///
+    /// The prompt is rebuilt from the history before that reply, so the
+    /// discarded answer never reaches the engine; engines that cache context
+    /// (like `CandleLLM`) reuse only the shared prefix.
+    pub fn regenerate_last(&mut self, config: &GenerationConfig) -> Result<String> {
+        config.validate()?;
+
+        if self.messages.last().map(|m| &m.role) != Some(&Role::Assistant) {
+            return Err(CortexError::State(
+                "No assistant message to regenerate".to_string(),
+            ));
+        }
+        let discarded = self.messages.pop();
+
+        let prompt = self.build_prompt(config)?;
+        match self.run_timed(&prompt, |engine| engine.generate(&prompt, config)) {
+            Ok(response) => {
+                self.messages.push(Message::assistant(&response));
+                Ok(response)
+            }
+            Err(e) => {
+                // Keep the old reply rather than leave the history dangling
+                self.messages.extend(discarded);
+                Err(e)
+            }
+        }
+    }
+
     /// Chat with streaming
     pub fn chat_streaming(
         &mut self,
@@ -188,24 +534,308 @@ impl Cortex {
         config: &GenerationConfig,
         callback: &mut dyn FnMut(&str) -> bool,
     ) -> Result<String> {
+        Ok(self.chat_streaming_output(messages, config, callback)?.text)
+    }
+
+    /// Chat with streaming, returning token counts and the finish reason
+    ///
+    /// Prompt tokens cover the whole formatted history, not just `messages`.
+    pub fn chat_streaming_output(
+        &mut self,
+        messages: &[Message],
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<GenerationOutput> {
+        config.validate()?;
+        self.messages.extend(messages.iter().cloned());
+        let prompt = self.build_prompt(config)?;
+        let output =
+            self.run_timed_output(|engine| engine.generate_streaming_output(&prompt, config, callback))?;
+        self.messages.push(Message::assistant(&output.text));
+        self.count_sent_messages(messages.len());
+        Ok(output)
+    }
+
+    /// Chat with streaming, reporting prefill progress
+    ///
+    /// The callback gets `StreamEvent::PrefillStarted` before any prompt
+    /// processing, so UIs can show a spinner until the first token arrives.
+    pub fn chat_with_events(
+        &mut self,
+        messages: &[Message],
+        config: &GenerationConfig,
+        callback: &mut dyn FnMut(StreamEvent<'_>) -> bool,
+    ) -> Result<String> {
+        config.validate()?;
         self.messages.extend(messages.iter().cloned());
-        let prompt = format_chat_prompt(&self.messages, self.chat_template);
-        let response = self.engine.generate_streaming(&prompt, config, callback)?;
+        let prompt = self.build_prompt(config)?;
+        let response =
+            self.run_timed(&prompt, |engine| engine.generate_with_events(&prompt, config, callback))?;
         self.messages.push(Message::assistant(&response));
+        self.count_sent_messages(messages.len());
         Ok(response)
     }
 
+    /// Estimate how long a generation will take
+    ///
+    /// Based on the average throughput of recent generations, or a
+    /// conservative default before any have run. Prompt and generated
+    /// tokens are weighted equally since prompts are fed one token at a time.
+    pub fn estimate_generation_time(&self, prompt_tokens: usize, max_tokens: usize) -> Duration {
+        let (tokens, elapsed) = self
+            .timings
+            .iter()
+            .fold((0usize, Duration::ZERO), |(t, e), (tokens, elapsed)| (t + tokens, e + *elapsed));
+
+        let tokens_per_second = if tokens > 0 && !elapsed.is_zero() {
+            tokens as f64 / elapsed.as_secs_f64()
+        } else {
+            DEFAULT_TOKENS_PER_SECOND
+        };
+
+        Duration::from_secs_f64((prompt_tokens + max_tokens) as f64 / tokens_per_second)
+    }
+
+    /// Run a generation and record its throughput
+    fn run_timed<F>(&mut self, prompt: &str, generate: F) -> Result<String>
+    where
+        F: FnOnce(&mut dyn TextEngine) -> Result<String>,
+    {
+        let start = Instant::now();
+        let output = generate(self.engine.as_mut())?;
+        let tokens = self.engine.count_tokens(prompt) + self.engine.count_tokens(&output);
+        self.record_timing(tokens, start.elapsed());
+        Ok(output)
+    }
+
+    /// `run_timed` for generations that report token counts
+    fn run_timed_output<F>(&mut self, generate: F) -> Result<GenerationOutput>
+    where
+        F: FnOnce(&mut dyn TextEngine) -> Result<GenerationOutput>,
+    {
+        let start = Instant::now();
+        let output = generate(self.engine.as_mut())?;
+        self.record_timing(output.prompt_tokens + output.completion_tokens, start.elapsed());
+        Ok(output)
+    }
+
+    fn record_timing(&mut self, tokens: usize, elapsed: Duration) {
+        if self.timings.len() == THROUGHPUT_WINDOW {
+            self.timings.pop_front();
+        }
+        self.timings.push_back((tokens, elapsed));
+    }
+
+    /// Format the system prompt and conversation history into a prompt
+    ///
+    /// Applies the context policy first. Runs language detection on the
+    /// latest user message when enabled and injects the hook's hint after
+    /// any leading system messages.
+    fn build_prompt(&mut self, config: &GenerationConfig) -> Result<String> {
+        self.compact_history(config.max_tokens as usize)?;
+
+        let (prompt, lang) = self.render_prompt(&self.messages);
+        if let Some(lang) = lang {
+            self.detected_language = Some(lang.to_string());
+        }
+        Ok(prompt)
+    }
+
+    /// The prompt `chat` would send after adding `messages`, without
+    /// generating or changing the history
+    ///
+    /// Applies the system prompt, language hint and chat template. A
+    /// `Summarize` context policy is not applied, since summarizing needs
+    /// a generation; nor is any truncation the engine does to fit its
+    /// context window.
+    pub fn preview_prompt(&self, messages: &[Message]) -> String {
+        let history: Vec<Message> = self.messages.iter().chain(messages).cloned().collect();
+        self.render_prompt(&history).0
+    }
+
+    /// Format the system prompt and `history` into a prompt
+    ///
+    /// Also returns the language of the latest user message when detection
+    /// is enabled.
+    fn render_prompt(&self, history: &[Message]) -> (String, Option<&'static str>) {
+        let mut messages = self.prompt_messages(history);
+
+        let last_user = history.iter().rev().find(|m| m.role == Role::User);
+        let lang = last_user
+            .filter(|_| self.config.detect_language)
+            .map(|m| detect_language(&m.content));
+        if let Some(hint) = lang.and_then(|lang| self.language_hook.as_ref()?(lang)) {
+            let pos = messages
+                .iter()
+                .position(|m| m.role != Role::System)
+                .unwrap_or(messages.len());
+            messages.insert(pos, Message::system(hint));
+        }
+
+        (format_chat_prompt(&messages, self.chat_template), lang)
+    }
+
+    /// The system prompt and tool instructions, if any, followed by
+    /// `history`
+    fn prompt_messages(&self, history: &[Message]) -> Vec<Message> {
+        self.system_prompt
+            .iter()
+            .chain(&self.tool_prompt)
+            .map(Message::system)
+            .chain(history.iter().cloned())
+            .collect()
+    }
+
+    /// Fold old messages into a summary until the history fits the window
+    /// with room left for `max_tokens` of reply
+    ///
+    /// Only acts under `ContextPolicy::Summarize`. The leading system prompt
+    /// and the latest message are never folded; a previous summary is folded
+    /// into the next one. Fails if nothing is left to fold or a summary
+    /// doesn't shrink the prompt, rather than summarizing forever.
+    fn compact_history(&mut self, max_tokens: usize) -> Result<()> {
+        let batch = match self.config.context_policy {
+            ContextPolicy::Full => return Ok(()),
+            ContextPolicy::Summarize { batch } => batch.max(1),
+        };
+        let window = self.engine.context_size().saturating_sub(max_tokens);
+        let history_tokens = |ctx: &Self| {
+            ctx.engine
+                .count_tokens(&format_chat_prompt(&ctx.prompt_messages(&ctx.messages), ctx.chat_template))
+        };
+
+        let mut tokens = history_tokens(self);
+        while tokens > window {
+            let start = self
+                .messages
+                .iter()
+                .position(|m| m.role != Role::System || m.name.as_deref() == Some(SUMMARY_NAME))
+                .unwrap_or(self.messages.len());
+            let has_summary = self.messages.get(start).and_then(|m| m.name.as_deref())
+                == Some(SUMMARY_NAME);
+            let first_new = start + has_summary as usize;
+            let end = (first_new + batch).min(self.messages.len().saturating_sub(1));
+            if end <= first_new {
+                return Err(CortexError::Inference(
+                    "Conversation exceeds the context window even after summarization"
+                        .to_string(),
+                ));
+            }
+
+            let transcript = self.messages[start..end]
+                .iter()
+                .map(|m| {
+                    let role = match m.role {
+                        Role::System => "system",
+                        Role::User => "user",
+                        Role::Assistant => "assistant",
+                        Role::Tool => "tool",
+                    };
+                    format!("{}: {}", role, m.content)
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let prompt = self.config.summary_prompt.replace("{conversation}", &transcript);
+            let config = GenerationConfig::deterministic().with_max_tokens(SUMMARY_MAX_TOKENS);
+            let summary = self.run_timed(&prompt, |engine| engine.generate(&prompt, &config))?;
+
+            let summary = Message {
+                role: Role::System,
+                content: format!("Summary of the earlier conversation: {}", summary.trim()),
+                name: Some(SUMMARY_NAME.to_string()),
+            };
+            self.messages.splice(start..end, [summary]);
+
+            let new_tokens = history_tokens(self);
+            if new_tokens >= tokens {
+                return Err(CortexError::Inference(
+                    "Summarizing the conversation did not make it fit the context window"
+                        .to_string(),
+                ));
+            }
+            tokens = new_tokens;
+        }
+
+        Ok(())
+    }
+
     /// Get conversation history
     pub fn messages(&self) -> &[Message] {
         &self.messages
     }
 
+    /// Set the system prompt sent ahead of the conversation
+    ///
+    /// It isn't part of `messages()`, so clearing, editing or summarizing
+    /// the history always keeps it. Nothing is generated.
+    pub fn set_system_prompt(&mut self, prompt: impl Into<String>) {
+        self.system_prompt = Some(prompt.into());
+    }
+
+    /// Remove the system prompt
+    pub fn clear_system_prompt(&mut self) {
+        self.system_prompt = None;
+    }
+
+    /// The system prompt, if one is set
+    pub fn system_prompt(&self) -> Option<&str> {
+        self.system_prompt.as_deref()
+    }
+
     /// Clear conversation history
     pub fn clear_messages(&mut self) {
         self.messages.clear();
         self.engine.clear();
     }
 
+    /// Replace the content of the message at `index`
+    ///
+    /// Resets the engine context so the next generation sees the edit.
+    pub fn edit_message(&mut self, index: usize, new_content: impl Into<String>) -> Result<()> {
+        self.check_message_index(index)?;
+        self.messages[index].content = new_content.into();
+        self.engine.clear();
+        Ok(())
+    }
+
+    /// Remove and return the message at `index`
+    ///
+    /// Resets the engine context so the next generation sees the removal.
+    pub fn remove_message(&mut self, index: usize) -> Result<Message> {
+        self.check_message_index(index)?;
+        let message = self.messages.remove(index);
+        self.engine.clear();
+        Ok(message)
+    }
+
+    /// Keep only the first `len` messages
+    ///
+    /// Resets the engine context so the next generation sees the shorter
+    /// history. Fails if `len` exceeds the number of messages.
+    pub fn truncate_messages(&mut self, len: usize) -> Result<()> {
+        if len > self.messages.len() {
+            return Err(CortexError::State(format!(
+                "Cannot truncate {} messages to {}",
+                self.messages.len(),
+                len
+            )));
+        }
+        self.messages.truncate(len);
+        self.engine.clear();
+        Ok(())
+    }
+
+    fn check_message_index(&self, index: usize) -> Result<()> {
+        if index >= self.messages.len() {
+            return Err(CortexError::State(format!(
+                "Message index {} out of range ({} messages)",
+                index,
+                self.messages.len()
+            )));
+        }
+        Ok(())
+    }
+
     // ==================== Memory ====================
 
     /// Enable the dedicated embedding model for semantic search
@@ -213,30 +843,37 @@ impl Cortex {
     /// Downloads and loads a BERT-based model (all-MiniLM-L6-v2) that provides
     /// high-quality semantic embeddings. This is recommended for production use.
     ///
-    /// Note: This will reinitialize memory with the correct embedding dimension.
-    pub fn with_embedder(mut self) -> Result<Self> {
-        let embedder = Embedder::load_default()?;
-        let dim = embedder.dim();
-        self.embedder = Some(embedder);
-
-        // Reinitialize memory with correct dimension
-        let mut memory_config = self.config.memory.clone();
-        memory_config.embedding_dim = dim;
-        self.memory = Memory::new(memory_config);
-
-        Ok(self)
+    /// Note: This will reinitialize memory with the embedder's dimension.
+    /// Existing entries are re-embedded; an empty memory is reloaded from
+    /// the configured persist path, if there is one.
+    pub fn with_embedder(self) -> Result<Self> {
+        self.with_embedder_progress(Embedder::DEFAULT_MODEL, &mut |_, _| {})
     }
 
     /// Enable embedder with a custom model
-    pub fn with_embedder_model(mut self, model_id: &str) -> Result<Self> {
-        let embedder = Embedder::load(model_id)?;
-        let dim = embedder.dim();
-        self.embedder = Some(embedder);
+    pub fn with_embedder_model(self, model_id: &str) -> Result<Self> {
+        self.with_embedder_progress(model_id, &mut |_, _| {})
+    }
 
-        // Reinitialize memory with correct dimension
-        let mut memory_config = self.config.memory.clone();
-        memory_config.embedding_dim = dim;
-        self.memory = Memory::new(memory_config);
+    /// Enable embedder, reporting progress of any model download
+    pub fn with_embedder_progress(
+        mut self,
+        model_id: &str,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Self> {
+        let embedder =
+            Embedder::load_with_options(model_id, &self.config.download_options(), reporter)?;
+        if self.memory.is_empty() {
+            let mut memory_config = self.config.memory.clone();
+            memory_config.embedding_dim = embedder.dim();
+            self.memory = Memory::open(memory_config)?;
+        } else {
+            // Existing entries were embedded by the engine; re-embed them
+            self.memory
+                .reembed(embedder.dim(), |contents| embedder.embed_batch(contents))?;
+        }
+        self.embedder = Some(embedder);
+        self.clear_query_cache();
 
         Ok(self)
     }
@@ -247,7 +884,7 @@ impl Cortex {
     }
 
     /// Get embedding for text (uses embedder if available, falls back to engine)
-    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>> {
         if let Some(ref embedder) = self.embedder {
             embedder.embed(text)
         } else {
@@ -255,6 +892,81 @@ impl Cortex {
         }
     }
 
+    /// Embed a recall query, reusing the embedding of an identical
+    /// recent query
+    fn embed_query(&self, query: &str) -> Result<Vec<f32>> {
+        let mut cache = self.query_cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(embedding) = cache.get(query) {
+            return Ok(embedding);
+        }
+        let embedding = self.embed(query)?;
+        cache.insert(query, embedding.clone());
+        Ok(embedding)
+    }
+
+    /// Forget cached query embeddings, e.g. after the embedding model changes
+    fn clear_query_cache(&mut self) {
+        self.query_cache
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    /// Embed several texts in one batch
+    pub fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        if let Some(ref embedder) = self.embedder {
+            embedder.embed_batch(texts)
+        } else {
+            self.engine.embed_batch(texts)
+        }
+    }
+
+    /// Cosine similarity between the embeddings of two texts
+    pub fn similarity(&self, a: &str, b: &str) -> Result<f32> {
+        let embeddings = self.embed_batch(&[a, b])?;
+        Ok(cosine_similarity(&embeddings[0], &embeddings[1]))
+    }
+
+    /// Pairwise cosine similarities between texts, embedded in one batch
+    ///
+    /// Entry `[i][j]` is the similarity of `texts[i]` and `texts[j]`.
+    pub fn similarity_matrix(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let embeddings = self.embed_batch(texts)?;
+        let mut matrix = vec![vec![0.0; texts.len()]; texts.len()];
+        for i in 0..embeddings.len() {
+            for j in i..embeddings.len() {
+                let score = cosine_similarity(&embeddings[i], &embeddings[j]);
+                matrix[i][j] = score;
+                matrix[j][i] = score;
+            }
+        }
+        Ok(matrix)
+    }
+
+    /// One vector standing for the whole conversation
+    ///
+    /// The normalized mean of the message embeddings, useful for finding
+    /// similar conversations. An empty history gives a zero vector.
+    pub fn conversation_embedding(&self) -> Result<Vec<f32>> {
+        let mut mean = vec![0.0f32; self.memory.embedding_dim()];
+        if self.messages.is_empty() {
+            return Ok(mean);
+        }
+
+        let contents: Vec<&str> = self.messages.iter().map(|m| m.content.as_str()).collect();
+        for embedding in self.embed_batch(&contents)? {
+            for (sum, x) in mean.iter_mut().zip(&embedding) {
+                *sum += x;
+            }
+        }
+        Ok(normalize(&mean))
+    }
+
+    /// Memory entry count, capacity and approximate size
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.memory.stats()
+    }
+
     /// Write to memory with auto-embedding
     pub fn remember(&mut self, key: impl Into<String>, content: impl Into<String>) -> Result<()> {
         let content = content.into();
@@ -264,64 +976,243 @@ impl Cortex {
 
     /// Search memory by text query
     pub fn recall(&self, query: &str, k: usize) -> Result<Vec<String>> {
-        let query_embedding = self.embed(query)?;
+        let results = self.recall_scored(query, k)?;
+        Ok(results.into_iter().map(|(content, _)| content).collect())
+    }
+
+    /// Search memory by text query, returning contents with their scores
+    ///
+    /// Results are best first and all meet the similarity threshold.
+    pub fn recall_scored(&self, query: &str, k: usize) -> Result<Vec<(String, f32)>> {
+        let query_embedding = self.embed_query(query)?;
         let results = self.memory.search(&query_embedding, k);
-        Ok(results.into_iter().map(|r| r.entry.content).collect())
+        Ok(results.into_iter().map(|r| (r.entry.content, r.score)).collect())
     }
 
-    // ==================== State ====================
+    /// Search memory by text query with a threshold for this call only
+    pub fn recall_with_threshold(
+        &self,
+        query: &str,
+        k: usize,
+        threshold: f32,
+    ) -> Result<Vec<String>> {
+        let results = self.recall_scored_with_threshold(query, k, threshold)?;
+        Ok(results.into_iter().map(|(content, _)| content).collect())
+    }
 
-    /// Create a checkpoint of current state
-    pub fn checkpoint(&mut self) -> Result<Checkpoint> {
-        let state = RuntimeState::new(
-            self.messages.clone(),
-            self.memory.get_state(),
-            self.engine.get_state()?,
-        );
+    /// Search memory by text query with a threshold for this call only,
+    /// returning contents with their scores
+    pub fn recall_scored_with_threshold(
+        &self,
+        query: &str,
+        k: usize,
+        threshold: f32,
+    ) -> Result<Vec<(String, f32)>> {
+        let query_embedding = self.embed_query(query)?;
+        let results = self.memory.search_with_threshold(&query_embedding, k, threshold);
+        Ok(results.into_iter().map(|r| (r.entry.content, r.score)).collect())
+    }
 
-        let checkpoint = Checkpoint::from_state(&state);
-        self.state_store.save(state)?;
-        self.checkpoint_manager.record(checkpoint.clone());
+    /// Split a long document into chunks and remember each one
+    ///
+    /// Chunks are embedded in one batch and stored under keys
+    /// `"<doc_id>#<n>"` with `doc_id` and `chunk_index` metadata. Chunks
+    /// from an earlier ingest of the same document are replaced. Returns
+    /// the number of chunks stored.
+    pub fn ingest_document(
+        &mut self,
+        doc_id: &str,
+        text: &str,
+        options: &ChunkOptions,
+    ) -> Result<usize> {
+        let chunks = chunk_text(text, options.max_tokens, options.overlap, |t| {
+            self.engine.count_tokens(t)
+        });
+        let embeddings = self.embed_batch(&chunks)?;
+
+        let stale: Vec<(String, String)> = self
+            .memory
+            .entries_iter()
+            .filter(|e| e.metadata.get("doc_id").map(String::as_str) == Some(doc_id))
+            .map(|e| (e.namespace.clone(), e.key.clone()))
+            .collect();
+
+        let mut batch = self.memory.batch();
+        for (namespace, key) in &stale {
+            batch.delete_in(namespace, key);
+        }
+        for (index, (chunk, embedding)) in chunks.iter().zip(embeddings).enumerate() {
+            let metadata = HashMap::from([
+                ("doc_id".to_string(), doc_id.to_string()),
+                ("chunk_index".to_string(), index.to_string()),
+            ]);
+            batch.write_with_metadata(format!("{}#{}", doc_id, index), *chunk, embedding, metadata)?;
+        }
+        batch.commit()?;
 
-        Ok(checkpoint)
+        Ok(chunks.len())
     }
 
-    /// Create a named checkpoint
-    pub fn checkpoint_named(&mut self, name: impl Into<String>) -> Result<Checkpoint> {
-        let state = RuntimeState::new(
-            self.messages.clone(),
-            self.memory.get_state(),
-            self.engine.get_state()?,
-        )
-        .with_name(name);
+    /// Write to a memory namespace with auto-embedding
+    pub fn remember_in(
+        &mut self,
+        namespace: &str,
+        key: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Result<()> {
+        let content = content.into();
+        let embedding = self.embed(&content)?;
+        self.memory.write_in(namespace, key, content, embedding)
+    }
+
+    /// Search a single memory namespace by text query
+    ///
+    /// Applies the namespace's similarity threshold when one is configured.
+    pub fn recall_in(&self, namespace: &str, query: &str, k: usize) -> Result<Vec<String>> {
+        let query_embedding = self.embed_query(query)?;
+        let results = self.memory.search_in(namespace, &query_embedding, k);
+        Ok(results.into_iter().map(|r| r.entry.content).collect())
+    }
+
+    /// Delete a memory by key, returning whether it existed
+    pub fn forget(&mut self, key: &str) -> bool {
+        self.memory.delete(key)
+    }
+
+    /// Delete every memory
+    pub fn forget_all(&mut self) {
+        self.memory.clear();
+    }
+
+    /// Delete the memories, in any namespace, whose metadata matches `filter`
+    ///
+    /// Returns the number of entries removed. Persistence, if enabled,
+    /// happens once for the whole removal.
+    pub fn forget_matching<F>(&mut self, filter: F) -> Result<usize>
+    where
+        F: Fn(&HashMap<String, String>) -> bool,
+    {
+        let matching: Vec<(String, String)> = self
+            .memory
+            .entries_iter()
+            .filter(|e| filter(&e.metadata))
+            .map(|e| (e.namespace.clone(), e.key.clone()))
+            .collect();
+
+        let mut batch = self.memory.batch();
+        for (namespace, key) in &matching {
+            batch.delete_in(namespace, key);
+        }
+        batch.commit()?;
+
+        Ok(matching.len())
+    }
+
+    // ==================== State ====================
+
+    /// Snapshot the history, system prompt, memory and engine context
+    pub(crate) fn runtime_state(&self) -> Result<RuntimeState> {
+        let mut state = RuntimeState::new(
+            self.messages.clone(),
+            self.memory.get_state(),
+            self.engine.get_state()?,
+        );
+        state.system_prompt = self.system_prompt.clone();
+        Ok(state)
+    }
+
+    /// Create a checkpoint of current state
+    pub fn checkpoint(&mut self) -> Result<Checkpoint> {
+        let state = self.runtime_state()?;
+
+        let checkpoint = Checkpoint::from_state(&state);
+        self.state_store.save(state)?;
+        self.checkpoint_manager.record(checkpoint.clone());
+        self.messages_since_checkpoint = 0;
+
+        Ok(checkpoint)
+    }
+
+    /// Create a named checkpoint
+    ///
+    /// Names needn't be unique; `restore_named` picks the most recent.
+    pub fn checkpoint_named(&mut self, name: impl Into<String>) -> Result<Checkpoint> {
+        let name = name.into();
+        if name.trim().is_empty() {
+            return Err(CortexError::InvalidCheckpoint(
+                "Checkpoint name must not be empty".to_string(),
+            ));
+        }
+
+        let state = self.runtime_state()?.with_name(name);
 
         let checkpoint = Checkpoint::from_state(&state);
         self.state_store.save(state)?;
         self.checkpoint_manager.record(checkpoint.clone());
+        self.messages_since_checkpoint = 0;
 
         Ok(checkpoint)
     }
 
+    /// Snapshot the engine's context without creating a checkpoint
+    pub fn engine_state(&self) -> Result<EngineState> {
+        self.engine.get_state()
+    }
+
+    /// Restore engine context saved by `engine_state`
+    ///
+    /// Fails without touching the engine if the state came from a
+    /// different kind of engine.
+    pub fn set_engine_state(&mut self, state: &EngineState) -> Result<()> {
+        let engine_id = self.engine.get_state()?.engine_id;
+        if state.engine_id != engine_id && state.engine_id != "none" {
+            return Err(CortexError::State(format!(
+                "Engine state is from '{}', but the current engine is '{}'",
+                state.engine_id, engine_id
+            )));
+        }
+        self.engine.set_state(state)
+    }
+
     /// Restore from a checkpoint
-    pub fn restore(&mut self, checkpoint: &Checkpoint) -> Result<()> {
+    ///
+    /// The report says whether engine context came back with it; if not,
+    /// the next generation re-encodes the whole history.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> Result<RestoreReport> {
         let state = self.state_store.load(&checkpoint.id)?;
 
-        self.messages = state.messages;
-        self.memory.set_state(state.memory);
-        self.engine.set_state(&state.engine_state)?;
-
-        Ok(())
+        self.apply_state(state)
     }
 
     /// Restore from checkpoint ID
-    pub fn restore_id(&mut self, id: &str) -> Result<()> {
+    pub fn restore_id(&mut self, id: &str) -> Result<RestoreReport> {
         let state = self.state_store.load(id)?;
 
+        self.apply_state(state)
+    }
+
+    /// Restore the most recent checkpoint with the given name
+    pub fn restore_named(&mut self, name: &str) -> Result<RestoreReport> {
+        let state = self.state_store.find_by_name(name)?.ok_or_else(|| {
+            CortexError::InvalidCheckpoint(format!("No checkpoint named '{}'", name))
+        })?;
+
+        self.apply_state(state)
+    }
+
+    fn apply_state(&mut self, state: RuntimeState) -> Result<RestoreReport> {
+        let report = RestoreReport {
+            messages_restored: state.messages.len(),
+            memory_entries: state.memory.entries.len(),
+            engine_warm: !state.engine_state.is_empty(),
+        };
+
         self.messages = state.messages;
+        self.system_prompt = state.system_prompt;
         self.memory.set_state(state.memory);
         self.engine.set_state(&state.engine_state)?;
 
-        Ok(())
+        Ok(report)
     }
 
     /// Create a branch from current state
@@ -331,6 +1222,23 @@ impl Cortex {
         Ok(Branch::new(checkpoint.id, state))
     }
 
+    /// Merge a branch back into a checkpoint
+    ///
+    /// The merged state is stored as a new checkpoint, which is returned;
+    /// pass it to `restore` to activate it.
+    pub fn merge(
+        &mut self,
+        base: &Checkpoint,
+        branch: Branch,
+        strategy: MergeStrategy,
+    ) -> Result<Checkpoint> {
+        let base = self.state_store.load(&base.id)?;
+        let merged = self.checkpoint_manager.merge(&base, branch, strategy);
+        let checkpoint = Checkpoint::from_state(&merged);
+        self.state_store.save(merged)?;
+        Ok(checkpoint)
+    }
+
     /// Get the latest checkpoint
     pub fn latest_checkpoint(&self) -> Option<&Checkpoint> {
         self.checkpoint_manager.latest()
@@ -341,6 +1249,41 @@ impl Cortex {
         self.checkpoint_manager.list()
     }
 
+    /// A page of checkpoints in the given order
+    pub fn checkpoints_sorted(
+        &self,
+        by: CheckpointSort,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<&Checkpoint> {
+        self.checkpoint_manager.list_sorted(by, offset, limit)
+    }
+
+    /// Export a checkpoint to a portable JSON file
+    ///
+    /// The file is tagged with the current model's identity so it can be
+    /// imported on another machine running the same model.
+    pub fn export_checkpoint(&self, checkpoint: &Checkpoint, path: impl AsRef<Path>) -> Result<()> {
+        let state = self.state_store.load(&checkpoint.id)?;
+        PortableCheckpoint::new(state, self.engine.model_id()).export_portable(path)
+    }
+
+    /// Import a portable checkpoint file
+    ///
+    /// Fails if the checkpoint was exported from a different model.
+    /// The imported checkpoint is stored but not restored; pass the returned
+    /// handle to `restore` to activate it.
+    pub fn import_checkpoint(&mut self, path: impl AsRef<Path>) -> Result<Checkpoint> {
+        let bundle = PortableCheckpoint::import_portable(path)?;
+        bundle.check_model(&self.engine.model_id())?;
+
+        let checkpoint = Checkpoint::from_state(&bundle.state);
+        self.state_store.save(bundle.state)?;
+        self.checkpoint_manager.record(checkpoint.clone());
+
+        Ok(checkpoint)
+    }
+
     // ==================== Info ====================
 
     /// Get context window size
@@ -353,6 +1296,21 @@ impl Cortex {
         self.engine.context_used()
     }
 
+    /// Identifier of the loaded model
+    pub fn model_id(&self) -> String {
+        self.engine.model_id()
+    }
+
+    /// Architecture and size of the loaded model
+    pub fn model_info(&self) -> ModelInfo {
+        self.engine.model_info()
+    }
+
+    /// Count tokens in text using the engine's tokenizer
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.engine.count_tokens(text)
+    }
+
     /// Get embedding dimension
     pub fn embedding_dim(&self) -> usize {
         if let Some(ref embedder) = self.embedder {
@@ -374,6 +1332,132 @@ impl Default for Cortex {
     }
 }
 
+/// Step-by-step construction of a `Cortex`
+///
+/// ```rust,ignore
+/// let ctx = Cortex::builder()
+///     .template(ChatTemplate::ChatML)
+///     .memory_persist("memory.bin")
+///     .state_dir("checkpoints")
+///     .build_from_model("model.gguf")?;
+/// ```
+///
+/// Without an engine, `build` uses a `StubEngine`.
+#[derive(Default)]
+pub struct CortexBuilder {
+    config: Option<CortexConfig>,
+    engine: Option<Box<dyn TextEngine>>,
+    template: Option<ChatTemplate>,
+    embedder: Option<String>,
+    memory_persist: Option<PathBuf>,
+    state_dir: Option<PathBuf>,
+}
+
+impl CortexBuilder {
+    /// Create a builder with the default config
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Text engine to generate and embed with
+    pub fn engine<E: TextEngine + 'static>(mut self, engine: E) -> Self {
+        self.engine = Some(Box::new(engine));
+        self
+    }
+
+    /// Base configuration; `memory_persist` and `state_dir` apply on top
+    pub fn config(mut self, config: CortexConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Chat template for formatting conversations
+    pub fn template(mut self, template: ChatTemplate) -> Self {
+        self.template = Some(template);
+        self
+    }
+
+    /// Dedicated embedding model for memory, loaded on build
+    pub fn embedder(mut self, model_id: impl Into<String>) -> Self {
+        self.embedder = Some(model_id.into());
+        self
+    }
+
+    /// Load memory from and persist it to `path`
+    pub fn memory_persist(mut self, path: impl Into<PathBuf>) -> Self {
+        self.memory_persist = Some(path.into());
+        self
+    }
+
+    /// Directory for persisted checkpoints
+    pub fn state_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(path.into());
+        self
+    }
+
+    /// Build the runtime
+    pub fn build(mut self) -> Result<Cortex> {
+        let engine = self
+            .engine
+            .take()
+            .unwrap_or_else(|| Box::new(StubEngine::new()));
+        self.finish(engine)
+    }
+
+    /// Load a model with `CandleLLM` and build a runtime around it
+    ///
+    /// Replaces any engine set with `engine`.
+    pub fn build_from_model(self, model_path: impl AsRef<Path>) -> Result<Cortex> {
+        self.build_from_model_with_progress(model_path, &mut |_, _| {})
+    }
+
+    /// Like `build_from_model`, reporting progress of any tokenizer download
+    pub fn build_from_model_with_progress(
+        mut self,
+        model_path: impl AsRef<Path>,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<Cortex> {
+        let model_path = model_path.as_ref();
+        let config = self.config.get_or_insert_with(CortexConfig::default);
+        config.model_path = model_path.to_path_buf();
+        let engine =
+            CandleLLM::load_with_options(model_path, &config.download_options(), reporter)?;
+        if config.strict_tokenizer {
+            engine.check_tokenizer()?;
+        }
+        self.finish(Box::new(engine))
+    }
+
+    fn finish(self, engine: Box<dyn TextEngine>) -> Result<Cortex> {
+        let mut config = self.config.unwrap_or_else(|| {
+            // Nothing to reconcile: size memory to the engine
+            let mut config = CortexConfig::default();
+            config.memory.embedding_dim = engine.embedding_dim();
+            config
+        });
+        if let Some(path) = self.memory_persist {
+            config = config.with_memory_persistence(path);
+        }
+        if let Some(dir) = self.state_dir {
+            config = config.with_state_dir(dir);
+        }
+
+        // Loaded first so persisted memory is checked against its dimension
+        let embedder = self
+            .embedder
+            .as_deref()
+            .map(|model_id| {
+                Embedder::load_with_options(model_id, &config.download_options(), &mut |_, _| {})
+            })
+            .transpose()?;
+        let mut ctx = Cortex::from_config(config, engine, embedder)?;
+        if let Some(template) = self.template {
+            ctx = ctx.with_template(template);
+        }
+        Ok(ctx)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -426,13 +1510,810 @@ mod tests {
         let mut ctx = Cortex::new();
 
         ctx.remember("before", "original value").unwrap();
+        ctx.chat(&[Message::user("Hello")]).unwrap();
         let snap = ctx.checkpoint().unwrap();
 
         ctx.remember("after", "new value").unwrap();
         assert_eq!(ctx.memory.len(), 2);
 
-        ctx.restore(&snap).unwrap();
+        let report = ctx.restore(&snap).unwrap();
+        assert_eq!(ctx.memory.len(), 1);
+        assert_eq!(report.memory_entries, 1);
+        assert!(report.engine_warm);
+    }
+
+    #[test]
+    fn test_restore_without_engine_state() {
+        let mut ctx = Cortex::new();
+        ctx.remember("fact", "The sky is blue").unwrap();
+        let state = RuntimeState::new(
+            vec![Message::user("Hello"), Message::assistant("Hi")],
+            ctx.memory.get_state(),
+            EngineState::default(),
+        );
+        let id = state.id.clone();
+        ctx.state_store.save(state).unwrap();
+
+        let report = ctx.restore_id(&id).unwrap();
+        assert_eq!(
+            report,
+            RestoreReport {
+                messages_restored: 2,
+                memory_entries: 1,
+                engine_warm: false,
+            }
+        );
+
+        // A serialized but empty engine context isn't warm either
+        let mut fresh = Cortex::new();
+        let checkpoint = fresh.checkpoint().unwrap();
+        assert!(!fresh.engine_state().unwrap().data.is_empty());
+        assert!(!fresh.restore(&checkpoint).unwrap().engine_warm);
+    }
+
+    #[test]
+    fn test_portable_checkpoint_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snap.json");
+
+        let mut ctx = Cortex::new();
+        ctx.remember("fact", "The sky is blue").unwrap();
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        let snap = ctx.checkpoint_named("portable").unwrap();
+        ctx.export_checkpoint(&snap, &path).unwrap();
+
+        // Import on a "different machine" running the same model
+        let mut other = Cortex::new();
+        let imported = other.import_checkpoint(&path).unwrap();
+        assert_eq!(imported.id, snap.id);
+        assert_eq!(imported.name.as_deref(), Some("portable"));
+
+        other.restore(&imported).unwrap();
+        assert_eq!(other.messages().len(), 2);
+        assert_eq!(other.memory.read("fact").unwrap().content, "The sky is blue");
+        assert_eq!(other.context_used(), ctx.context_used());
+    }
+
+    #[test]
+    fn test_portable_checkpoint_model_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snap.json");
+
+        let mut ctx = Cortex::new();
+        let snap = ctx.checkpoint().unwrap();
+        ctx.export_checkpoint(&snap, &path).unwrap();
+
+        let mut other = Cortex::with_engine(StubEngine::new().with_model_id("other-model"));
+        let err = other.import_checkpoint(&path).unwrap_err();
+        assert!(matches!(err, crate::CortexError::InvalidCheckpoint(_)));
+        assert!(other.checkpoints().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_config_rejected_before_chat() {
+        let mut ctx = Cortex::new();
+        let config = GenerationConfig::default().with_temperature(-1.0);
+
+        let err = ctx.chat_with_config(&[Message::user("Hi")], &config).unwrap_err();
+        assert!(matches!(err, CortexError::Config(_)));
+        assert!(ctx.messages().is_empty());
+    }
+
+    #[test]
+    fn test_regenerate_last() {
+        let mut ctx = Cortex::new();
+        assert!(ctx.regenerate_last(&GenerationConfig::default()).is_err());
+
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        let config = GenerationConfig::default().with_temperature(0.3);
+        let response = ctx.regenerate_last(&config).unwrap();
+
+        assert_eq!(ctx.messages().len(), 2);
+        let last = ctx.messages().last().unwrap();
+        assert_eq!(last.role, Role::Assistant);
+        assert_eq!(last.content, response);
+        assert!(response.contains("temp=0.3"));
+    }
+
+    #[test]
+    fn test_edit_and_truncate_messages() {
+        let mut ctx = Cortex::new();
+        ctx.chat(&[Message::user("What is 2+2?")]).unwrap();
+        ctx.chat(&[Message::user("And 3+3?")]).unwrap();
+        ctx.remember("fact", "The sky is blue").unwrap();
+
+        ctx.edit_message(2, "And 4+4?").unwrap();
+        assert_eq!(ctx.messages()[2].content, "And 4+4?");
+        assert_eq!(ctx.messages().len(), 4);
+        assert!(ctx.edit_message(4, "nope").is_err());
+
+        let removed = ctx.remove_message(3).unwrap();
+        assert_eq!(removed.role, Role::Assistant);
+        assert_eq!(ctx.messages().len(), 3);
+
+        ctx.truncate_messages(2).unwrap();
+        assert_eq!(ctx.messages()[1].role, Role::Assistant);
+        assert_eq!(ctx.messages().len(), 2);
+        assert!(ctx.truncate_messages(3).is_err());
+
+        // Memory is untouched
+        assert_eq!(ctx.memory.len(), 1);
+    }
+
+    #[test]
+    fn test_summarize_old_context() {
+        let policy = ContextPolicy::Summarize { batch: 4 };
+        let config = CortexConfig::default().with_context_policy(policy);
+        let engine = StubEngine::new().with_context_size(150);
+        let mut ctx = Cortex::with_config_and_engine(config, engine).unwrap();
+
+        ctx.messages.push(Message::system("You are a helpful assistant."));
+        for i in 0..6 {
+            ctx.messages.push(Message::user(format!("Question number {} about the weather", i)));
+            ctx.messages.push(Message::assistant(format!("Answer number {} about the weather", i)));
+        }
+        let before = ctx.messages.len() + 1;
+
+        let config = GenerationConfig::default().with_max_tokens(20);
+        ctx.chat_with_config(&[Message::user("What did we discuss?")], &config).unwrap();
+
+        // One summary replaced at least a batch of turns
+        assert!(ctx.messages.len() + 1 < before);
+        assert_eq!(ctx.messages[0].content, "You are a helpful assistant.");
+        assert_eq!(ctx.messages[1].name.as_deref(), Some(SUMMARY_NAME));
+        assert!(ctx.messages[1].content.contains("Stub response for: \"Summarize"));
+        let last_user = &ctx.messages[ctx.messages.len() - 2];
+        assert_eq!(last_user.content, "What did we discuss?");
+
+        // A window too small for even a summary errors instead of looping
+        let config = CortexConfig::default().with_context_policy(policy);
+        let engine = StubEngine::new().with_context_size(10);
+        let mut ctx = Cortex::with_config_and_engine(config, engine).unwrap();
+        ctx.messages.push(Message::system("You are a helpful assistant."));
+        assert!(ctx.chat(&[Message::user("Hello there, how are you?")]).is_err());
+    }
+
+    #[test]
+    fn test_summarize_reserves_reply_room() {
+        let policy = ContextPolicy::Summarize { batch: 2 };
+        let config = CortexConfig::default().with_context_policy(policy);
+        let engine = StubEngine::new().with_context_size(150);
+        let mut ctx = Cortex::with_config_and_engine(config, engine).unwrap();
+        for i in 0..2 {
+            ctx.messages.push(Message::user(format!("Question number {} about the weather", i)));
+            ctx.messages.push(Message::assistant(format!("Answer number {} about the weather", i)));
+        }
+
+        // The history fits the window, but not with a 50-token reply
+        let next = [Message::user("And tomorrow?")];
+        let tokens = ctx.engine.count_tokens(&ctx.preview_prompt(&next));
+        assert!(tokens <= 150 && tokens + 50 > 150, "{}", tokens);
+
+        let config = GenerationConfig::default().with_max_tokens(50);
+        ctx.chat_with_config(&next, &config).unwrap();
+        assert_eq!(ctx.messages[0].name.as_deref(), Some(SUMMARY_NAME));
+        let sent = &ctx.messages[..ctx.messages.len() - 1];
+        let prompt = format_chat_prompt(&ctx.prompt_messages(sent), ctx.chat_template);
+        assert!(ctx.engine.count_tokens(&prompt) + 50 <= 150);
+    }
+
+    #[test]
+    fn test_chat_continue() {
+        use crate::inference::{GenerationRecord, RecordingEngine};
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("run.jsonl");
+        let engine = StubEngine::new().with_script(["\"blue\"}"]);
+        let mut ctx = Cortex::with_engine(RecordingEngine::new(engine, &log).unwrap())
+            .with_template(ChatTemplate::ChatML);
+
+        let config = GenerationConfig::default();
+        let reply = ctx
+            .chat_continue(&[Message::user("Sky color as JSON?")], "{\"color\": ", &config)
+            .unwrap();
+        assert_eq!(reply, "{\"color\": \"blue\"}");
+        assert_eq!(ctx.messages().last().unwrap().content, reply);
+
+        let record: GenerationRecord =
+            serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+        assert!(record
+            .prompt
+            .ends_with("<|im_start|>assistant\n{\"color\": "));
+    }
+
+    #[test]
+    fn test_preview_prompt() {
+        use crate::inference::{GenerationRecord, RecordingEngine};
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("run.jsonl");
+        let engine = RecordingEngine::new(StubEngine::new(), &log).unwrap();
+        let mut ctx = Cortex::with_engine(engine)
+            .with_template(ChatTemplate::Llama3)
+            .with_language_hook(|lang| (lang == "en").then(|| "Answer in English.".to_string()));
+        ctx.set_system_prompt("You are terse.");
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+
+        let next = [Message::user("What is the capital of France?")];
+        let preview = ctx.preview_prompt(&next);
+        assert_eq!(ctx.messages().len(), 2);
+        ctx.chat(&next).unwrap();
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        let sent: GenerationRecord = serde_json::from_str(log.lines().last().unwrap()).unwrap();
+        assert_eq!(preview, sent.prompt);
+        assert!(preview.contains("You are terse.") && preview.contains("Answer in English."));
+    }
+
+    #[test]
+    fn test_system_prompt() {
+        let mut ctx = Cortex::new().with_template(ChatTemplate::ChatML);
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        ctx.set_system_prompt("You are terse.");
+
+        assert_eq!(ctx.messages().len(), 2);
+        assert!(ctx.messages().iter().all(|m| m.role != Role::System));
+        let prompt = ctx.build_prompt(&GenerationConfig::default()).unwrap();
+        assert!(prompt.starts_with("<|im_start|>system\nYou are terse."));
+        assert!(prompt.contains("Hello"));
+
+        // Clearing the history keeps the system prompt
+        ctx.clear_messages();
+        assert_eq!(ctx.system_prompt(), Some("You are terse."));
+        assert!(ctx.build_prompt(&GenerationConfig::default()).unwrap().contains("You are terse."));
+
+        ctx.clear_system_prompt();
+        assert!(!ctx.build_prompt(&GenerationConfig::default()).unwrap().contains("You are terse."));
+    }
+
+    #[test]
+    fn test_checkpoint_keeps_system_prompt() {
+        let mut ctx = Cortex::new();
+        ctx.set_system_prompt("You are terse.");
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        let checkpoint = ctx.checkpoint_named("terse").unwrap();
+
+        ctx.clear_system_prompt();
+        ctx.restore(&checkpoint).unwrap();
+        assert_eq!(ctx.system_prompt(), Some("You are terse."));
+
+        ctx.set_system_prompt("You are chatty.");
+        ctx.restore_named("terse").unwrap();
+        assert_eq!(ctx.system_prompt(), Some("You are terse."));
+    }
+
+    #[test]
+    fn test_set_engine() {
+        let mut ctx = Cortex::with_engine(StubEngine::new().with_model_id("draft"));
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        ctx.remember("fact", "The sky is blue").unwrap();
+        let checkpoint = ctx.checkpoint().unwrap();
+
+        ctx.set_engine(StubEngine::new().with_model_id("final").with_response_prefix("FINAL "))
+            .unwrap();
+        assert_eq!(ctx.model_id(), "final");
+        let response = ctx.chat(&[Message::user("Again")]).unwrap();
+        assert!(response.starts_with("FINAL "));
+        assert_eq!(ctx.messages().len(), 4);
+        assert_eq!(ctx.memory.len(), 1);
+        ctx.restore(&checkpoint).unwrap();
+        assert_eq!(ctx.messages().len(), 2);
+    }
+
+    #[test]
+    fn test_chat_streaming_output() {
+        use crate::inference::FinishReason;
+
+        let mut ctx = Cortex::new();
+        let config = GenerationConfig::default();
+        let output = ctx
+            .chat_streaming_output(&[Message::user("Hello")], &config, &mut |_| true)
+            .unwrap();
+        assert_eq!(output.finish_reason, FinishReason::EosToken);
+        assert!(output.prompt_tokens > 0 && output.completion_tokens > 0);
+        assert_eq!(ctx.messages().last().unwrap().content, output.text);
+        assert_eq!(output.stats.prompt_tokens, output.prompt_tokens);
+        assert_eq!(output.stats.gen_tokens, output.completion_tokens);
+
+        let short = config.clone().with_max_tokens(2);
+        let output = ctx
+            .chat_streaming_output(&[Message::user("Again")], &short, &mut |_| true)
+            .unwrap();
+        assert_eq!(output.finish_reason, FinishReason::Length);
+
+        let output = ctx
+            .chat_streaming_output(&[Message::user("Stop")], &config, &mut |_| false)
+            .unwrap();
+        assert_eq!(output.finish_reason, FinishReason::Cancelled);
+    }
+
+    #[test]
+    fn test_recall_scored() {
+        let mut ctx = Cortex::new();
+        ctx.remember("sky", "The sky is blue").unwrap();
+        ctx.remember("sky2", "The sky is blue and clear").unwrap();
+        ctx.remember("code", "Rust compiles programs quickly").unwrap();
+
+        let results = ctx.recall_scored("The sky is blue today", 3).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(results.iter().all(|(_, score)| (0.7..=1.0 + 1e-6).contains(score)));
+        assert!(results.iter().all(|(content, _)| content.contains("sky")));
+
+        let contents: Vec<String> = results.into_iter().map(|(content, _)| content).collect();
+        assert_eq!(ctx.recall("The sky is blue today", 3).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_recall_with_threshold() {
+        let mut ctx = Cortex::new();
+        ctx.remember("sky", "The sky was grey yesterday").unwrap();
+        ctx.remember("code", "Rust compiles programs quickly").unwrap();
+
+        let query = "The sky is blue today";
+        assert!(ctx.recall_with_threshold(query, 3, 0.9).unwrap().is_empty());
+        let loose = ctx.recall_scored_with_threshold(query, 3, 0.1).unwrap();
+        assert_eq!(loose.len(), 1);
+        assert_eq!(loose[0].0, "The sky was grey yesterday");
+        assert!(loose[0].1 < 0.9);
+
+        // The configured threshold is left alone
+        assert!(ctx.recall(query, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_adopts_engine_dim() {
+        let mut ctx = Cortex::with_engine(StubEngine::new().with_embedding_dim(384));
+        assert_eq!(ctx.memory.embedding_dim(), 384);
+        ctx.remember("fact", "The sky is blue").unwrap();
+
+        let ctx = Cortex::with_config_and_engine(
+            CortexConfig::default(),
+            StubEngine::new().with_embedding_dim(384),
+        )
+        .unwrap();
+        assert_eq!(ctx.memory.embedding_dim(), 384);
+
+        // An explicit dimension the engine can't produce is rejected
+        let mut config = CortexConfig::default();
+        config.memory.embedding_dim = 768;
+        let result = Cortex::with_config_and_engine(config, StubEngine::new().with_embedding_dim(384));
+        assert!(matches!(result.err(), Some(CortexError::Config(_))));
+    }
+
+    #[test]
+    fn test_conversation_embedding() {
+        let conversation = |turns: &[&str]| {
+            let mut ctx = Cortex::new();
+            ctx.messages = turns.iter().map(|t| Message::user(*t)).collect();
+            ctx.conversation_embedding().unwrap()
+        };
+
+        let empty = conversation(&[]);
+        assert_eq!(empty.len(), Cortex::new().memory.embedding_dim());
+        assert!(empty.iter().all(|&x| x == 0.0));
+
+        let hiking = conversation(&["Where should I go hiking", "hiking trails near mountain lakes"]);
+        let more_hiking = conversation(&["best mountain hiking trails", "hiking boots for trails"]);
+        let taxes = conversation(&["How do I file my taxes", "quarterly tax deductions and receipts"]);
+        let norm: f32 = hiking.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+        assert!(cosine_similarity(&hiking, &more_hiking) > cosine_similarity(&hiking, &taxes));
+    }
+
+    #[test]
+    fn test_similarity() {
+        let ctx = Cortex::new();
+        let text = "The quick brown fox jumps over the lazy dog";
+        let other = "Quarterly revenue grew by twelve percent";
+
+        assert!((ctx.similarity(text, text).unwrap() - 1.0).abs() < 1e-4);
+        assert!(ctx.similarity(text, other).unwrap() < 0.99);
+
+        let matrix = ctx.similarity_matrix(&[text, other]).unwrap();
+        assert!((matrix[0][0] - 1.0).abs() < 1e-4);
+        assert!((matrix[1][1] - 1.0).abs() < 1e-4);
+        assert_eq!(matrix[0][1], matrix[1][0]);
+        assert!(matrix[0][1] < matrix[0][0]);
+    }
+
+    #[test]
+    fn test_memory_persists_across_runtimes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CortexConfig::default().with_memory_persistence(dir.path().join("memory.bin"));
+
+        let mut ctx = Cortex::with_config_and_engine(config.clone(), StubEngine::new()).unwrap();
+        ctx.remember("fact", "The sky is blue").unwrap();
+        ctx.remember("other", "Grass is green").unwrap();
+        assert!(ctx.forget("other"));
+        drop(ctx);
+
+        let ctx = Cortex::with_config_and_engine(config, StubEngine::new()).unwrap();
+        assert_eq!(ctx.memory.len(), 1);
+        assert_eq!(ctx.memory.read("fact").unwrap().content, "The sky is blue");
+    }
+
+    #[test]
+    fn test_builder() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = Cortex::builder()
+            .engine(StubEngine::new().with_embedding_dim(64))
+            .template(ChatTemplate::ChatML)
+            .memory_persist(dir.path().join("memory.bin"))
+            .state_dir(dir.path().join("state"))
+            .build()
+            .unwrap();
+
+        assert_eq!(ctx.memory.embedding_dim(), 64);
+        assert_eq!(ctx.config.state.directory, Some(dir.path().join("state")));
+
+        ctx.remember("fact", "The sky is blue").unwrap();
+        assert!(dir.path().join("memory.bin").exists());
+
+        // The stub echoes the start of the prompt, formatted with the template
+        let response = ctx.chat(&[Message::user("Hi")]).unwrap();
+        assert!(response.contains("<|im_start|>"), "{}", response);
+    }
+
+    #[test]
+    #[ignore] // Requires model download
+    fn test_with_default_embedder() {
+        let ctx = Cortex::new().with_embedder().unwrap();
+        assert!(ctx.has_embedder());
+        assert_eq!(ctx.memory.embedding_dim(), 384);
+    }
+
+    #[test]
+    fn test_with_embedder_model() {
+        let dir = tempfile::tempdir().unwrap();
+        crate::inference::write_tiny_bert(dir.path());
+        let model_id = dir.path().to_str().unwrap();
+
+        let mut ctx = Cortex::new();
+        ctx.remember("fact", "the cat sat").unwrap();
+        let mut ctx = ctx.with_embedder_model(model_id).unwrap();
+        assert!(ctx.embedder.is_some());
+        assert_eq!(ctx.memory.embedding_dim(), 8);
+
+        // The existing entry was re-embedded, and new ones use the embedder
+        assert_eq!(ctx.memory.read("fact").unwrap().embedding.len(), 8);
+        ctx.remember("other", "on the mat").unwrap();
+        assert_eq!(ctx.memory.len(), 2);
+
+        let ctx = Cortex::builder().embedder(model_id).build().unwrap();
+        assert!(ctx.has_embedder());
+        assert_eq!(ctx.memory.embedding_dim(), 8);
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut ctx = Cortex::new();
+        ctx.remember("a", "Alpha").unwrap();
+        ctx.remember("b", "Beta").unwrap();
+
+        assert!(ctx.forget("a"));
+        assert!(!ctx.forget("a"));
+        assert!(!ctx.forget("missing"));
+        assert!(ctx.memory.read("a").is_none());
         assert_eq!(ctx.memory.len(), 1);
+
+        ctx.ingest_document("doc", "One. Two.", &ChunkOptions::default())
+            .unwrap();
+        assert_eq!(
+            ctx.forget_matching(|meta| meta.get("doc_id").map(String::as_str) == Some("doc"))
+                .unwrap(),
+            1
+        );
+        assert!(ctx.memory.read("b").is_some());
+
+        ctx.forget_all();
+        assert!(ctx.memory.is_empty());
+    }
+
+    #[test]
+    fn test_query_embedding_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// Stub engine counting how many texts it embeds
+        struct Counting(StubEngine, Arc<AtomicUsize>);
+
+        impl TextEngine for Counting {
+            fn embedding_dim(&self) -> usize {
+                self.0.embedding_dim()
+            }
+            fn context_size(&self) -> usize {
+                self.0.context_size()
+            }
+            fn embed(&self, text: &str) -> Result<Vec<f32>> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.embed(text)
+            }
+            fn generate(&mut self, prompt: &str, config: &GenerationConfig) -> Result<String> {
+                self.0.generate(prompt, config)
+            }
+            fn generate_streaming(
+                &mut self,
+                prompt: &str,
+                config: &GenerationConfig,
+                callback: &mut dyn FnMut(&str) -> bool,
+            ) -> Result<String> {
+                self.0.generate_streaming(prompt, config, callback)
+            }
+            fn get_state(&self) -> Result<EngineState> {
+                self.0.get_state()
+            }
+            fn set_state(&mut self, state: &EngineState) -> Result<()> {
+                self.0.set_state(state)
+            }
+            fn clear(&mut self) {
+                self.0.clear()
+            }
+            fn context_used(&self) -> usize {
+                self.0.context_used()
+            }
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut ctx = Cortex::with_engine(Counting(StubEngine::new(), calls.clone()));
+        ctx.remember("fact", "The sky is blue").unwrap();
+        calls.store(0, Ordering::SeqCst);
+
+        let first = ctx.recall_scored("What color is the sky?", 3).unwrap();
+        let second = ctx.recall_scored("What color is the sky?", 3).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // A new embedding model invalidates the cache
+        let other = Arc::new(AtomicUsize::new(0));
+        ctx.set_engine(Counting(StubEngine::new(), other.clone())).unwrap();
+        ctx.recall("What color is the sky?", 3).unwrap();
+        assert_eq!(other.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_run_agent() {
+        use crate::inference::{GenerationRecord, RecordingEngine};
+
+        let registry = ToolRegistry::new().with_tool("add", "Add numbers a and b", |args| {
+            let arg = |name: &str| {
+                args[name]
+                    .as_i64()
+                    .ok_or_else(|| CortexError::Tool(format!("missing '{}'", name)))
+            };
+            Ok((arg("a")? + arg("b")?).to_string())
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("run.jsonl");
+        let engine = StubEngine::new().with_script([
+            r#"<tool_call>{"name": "add", "arguments": {"a": 2, "b": 3}}</tool_call>"#,
+            "2 + 3 = 5",
+        ]);
+        let mut ctx = Cortex::with_engine(RecordingEngine::new(engine, &log).unwrap());
+        let answer = ctx
+            .run_agent(&[Message::user("What is 2 + 3?")], &registry, 4)
+            .unwrap();
+        assert_eq!(answer, "2 + 3 = 5");
+
+        // The tool prompt is sent every step but never stored
+        let roles: Vec<Role> = ctx.messages().iter().map(|m| m.role.clone()).collect();
+        assert_eq!(roles, vec![Role::User, Role::Assistant, Role::Tool, Role::Assistant]);
+        assert_eq!(ctx.messages()[2].content, "5");
+        assert_eq!(ctx.messages()[2].name.as_deref(), Some("add"));
+        let log = std::fs::read_to_string(&log).unwrap();
+        for line in log.lines() {
+            let record: GenerationRecord = serde_json::from_str(line).unwrap();
+            assert_eq!(record.prompt.matches("- add: Add numbers a and b").count(), 1);
+        }
+        assert!(!ctx.preview_prompt(&[]).contains("- add:"));
+
+        // Malformed calls are reported back; a model that never answers hits the limit
+        let engine = StubEngine::new().with_script(["<tool_call>{oops</tool_call>"; 3]);
+        let mut ctx = Cortex::with_engine(engine);
+        let result = ctx.run_agent(&[Message::user("Hi")], &registry, 3);
+        assert!(matches!(result, Err(CortexError::Tool(_))));
+        assert!(ctx.messages()[2].content.starts_with("Tool error: Malformed tool call"));
+    }
+
+    #[test]
+    fn test_ingest_document() {
+        let mut ctx = Cortex::new();
+        let text = "First sentence here. Second one follows. Third closes it.";
+        let options = ChunkOptions {
+            max_tokens: 8,
+            overlap: 0,
+        };
+
+        let count = ctx.ingest_document("doc", text, &options).unwrap();
+        assert!(count > 1);
+        assert_eq!(ctx.memory.len(), count);
+
+        let first = ctx.memory.read("doc#0").unwrap();
+        assert_eq!(first.metadata["doc_id"], "doc");
+        assert_eq!(first.metadata["chunk_index"], "0");
+
+        // Re-ingesting replaces the old chunks
+        assert_eq!(ctx.ingest_document("doc", "Short now.", &options).unwrap(), 1);
+        assert_eq!(ctx.memory.len(), 1);
+    }
+
+    #[test]
+    fn test_reingest_replaces_chunks_in_other_namespaces() {
+        let mut ctx = Cortex::new();
+        let text = "First sentence here. Second one follows. Third closes it.";
+        let options = ChunkOptions {
+            max_tokens: 8,
+            overlap: 0,
+        };
+        ctx.ingest_document("doc", text, &options).unwrap();
+
+        // Move the chunks to another namespace
+        let mut state = ctx.memory.get_state();
+        for entry in &mut state.entries {
+            entry.namespace = "archive".to_string();
+        }
+        ctx.memory.set_state(state);
+        ctx.remember("doc#1", "Unrelated entry").unwrap();
+
+        assert_eq!(ctx.ingest_document("doc", "Short now.", &options).unwrap(), 1);
+        assert_eq!(ctx.memory.len(), 2);
+        assert!(ctx.memory.read_in("archive", "doc#0").is_none());
+        assert_eq!(ctx.memory.read("doc#1").unwrap().content, "Unrelated entry");
+    }
+
+    #[test]
+    fn test_auto_checkpoint() {
+        let mut config = CortexConfig::default();
+        config.state.auto_checkpoint_interval = 2;
+        config.state.max_checkpoints = 2;
+        let mut ctx = Cortex::with_config_and_engine(config, StubEngine::new()).unwrap();
+
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        assert!(ctx.checkpoints().is_empty());
+        ctx.chat(&[Message::user("Again")]).unwrap();
+        assert_eq!(ctx.checkpoints().len(), 1);
+        assert_eq!(ctx.checkpoints()[0].name.as_deref(), Some("auto-1"));
+
+        let state = ctx.state_store.find_by_name("auto-1").unwrap().unwrap();
+        let contents = |messages: &[Message]| -> Vec<String> {
+            messages.iter().map(|m| m.content.clone()).collect()
+        };
+        assert_eq!(contents(&state.messages), contents(ctx.messages()));
+        assert_eq!(state.memory.entries.len(), ctx.memory.len());
+
+        // Older automatic checkpoints are evicted like any other
+        for i in 0..4 {
+            ctx.chat(&[Message::user(format!("Turn {}", i))]).unwrap();
+        }
+        let names: Vec<_> = ctx.checkpoints().iter().map(|c| c.name.clone().unwrap()).collect();
+        assert_eq!(names, vec!["auto-2", "auto-3"]);
+    }
+
+    #[test]
+    fn test_restore_named() {
+        let mut ctx = Cortex::new();
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        ctx.checkpoint_named("one").unwrap();
+        ctx.chat(&[Message::user("Again")]).unwrap();
+        ctx.checkpoint_named("two").unwrap();
+        ctx.chat(&[Message::user("Once more")]).unwrap();
+
+        ctx.restore_named("one").unwrap();
+        assert_eq!(ctx.messages().len(), 2);
+        ctx.restore_named("two").unwrap();
+        assert_eq!(ctx.messages().len(), 4);
+
+        // Reusing a name shadows the older checkpoint
+        ctx.restore_named("one").unwrap();
+        ctx.chat(&[Message::user("Branch")]).unwrap();
+        ctx.checkpoint_named("one").unwrap();
+        ctx.clear_messages();
+        ctx.restore_named("one").unwrap();
+        assert_eq!(ctx.messages().len(), 4);
+
+        let err = ctx.restore_named("missing").unwrap_err();
+        assert!(matches!(err, crate::CortexError::InvalidCheckpoint(_)));
+        assert!(ctx.checkpoint_named("  ").is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_diff() {
+        let mut ctx = Cortex::new();
+        ctx.chat(&[Message::user("Hello")]).unwrap();
+        let before = ctx.checkpoint().unwrap();
+
+        ctx.remember("fact", "The sky is blue").unwrap();
+        let after = ctx.checkpoint().unwrap();
+
+        let before = ctx.state_store.load(&before.id).unwrap();
+        let after = ctx.state_store.load(&after.id).unwrap();
+        let diff = before.diff(&after);
+
+        assert_eq!(diff.added_keys, vec!["fact"]);
+        assert!(diff.removed_keys.is_empty());
+        assert!(diff.changed_keys.is_empty());
+        assert_eq!(diff.message_delta, 0);
+        assert!(diff.appended_messages.is_empty());
+        assert!(serde_json::to_string(&diff).unwrap().contains("\"fact\""));
+
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn test_language_hook() {
+        let mut ctx = Cortex::new()
+            .with_template(ChatTemplate::Raw)
+            .with_language_hook(|lang| (lang != "en").then(|| format!("Respond in {}", lang)));
+        assert_eq!(ctx.detected_language(), None);
+
+        ctx.chat(&[Message::user("Hello there")]).unwrap();
+        assert_eq!(ctx.detected_language(), Some("en"));
+
+        // Raw template lets the stub echo the start of the prompt
+        let response = ctx.chat(&[Message::user("你好")]).unwrap();
+        assert_eq!(ctx.detected_language(), Some("zh"));
+        assert!(response.contains("Respond in zh"));
+
+        // The hint is never stored in the history
+        assert!(ctx.messages().iter().all(|m| m.role != Role::System));
+    }
+
+    #[test]
+    fn test_generate_json() {
+        let mut ctx = Cortex::new();
+        let value = ctx.generate_json("Describe the sky").unwrap();
+        assert!(value["response"].is_string());
+    }
+
+    #[test]
+    fn test_chat_with_events() {
+        let mut ctx = Cortex::new();
+        let config = GenerationConfig::default();
+
+        let mut events = Vec::new();
+        let response = ctx
+            .chat_with_events(&[Message::user("Hello")], &config, &mut |event| {
+                events.push(match event {
+                    StreamEvent::Token(text) => text.to_string(),
+                    other => format!("{:?}", other),
+                });
+                true
+            })
+            .unwrap();
+
+        // The early signal comes before the prompt is processed
+        assert_eq!(events[0], "PrefillStarted");
+        assert_eq!(events[1], "PrefillFinished");
+        assert_eq!(events[2..].concat(), response);
+    }
+
+    #[test]
+    fn test_estimate_generation_time() {
+        let mut ctx = Cortex::new();
+
+        // Conservative default before any measurements
+        let estimate = ctx.estimate_generation_time(50, 50);
+        assert_eq!(estimate, Duration::from_secs_f64(100.0 / DEFAULT_TOKENS_PER_SECOND));
+
+        // 100 tokens/sec
+        for _ in 0..4 {
+            ctx.record_timing(100, Duration::from_secs(1));
+        }
+        let estimate = ctx.estimate_generation_time(50, 50);
+        assert!((estimate.as_secs_f64() - 1.0).abs() < 1e-6);
+
+        // Old samples roll out of the window once throughput changes
+        for _ in 0..THROUGHPUT_WINDOW {
+            ctx.record_timing(400, Duration::from_secs(1));
+        }
+        let estimate = ctx.estimate_generation_time(100, 100);
+        assert!((estimate.as_secs_f64() - 0.5).abs() < 1e-6);
+
+        // Real generations are recorded too
+        ctx.generate("Hello").unwrap();
+        assert_eq!(ctx.timings.len(), THROUGHPUT_WINDOW);
+        assert_ne!(ctx.timings.back().unwrap().0, 400);
     }
 
     #[test]
@@ -0,0 +1,490 @@
+//! OpenAI-compatible HTTP server
+//!
+//! Exposes a `Cortex` runtime over `/v1/chat/completions` and
+//! `/v1/embeddings`, so existing OpenAI clients can talk to a local model.
+//! Each connection gets its own thread, while generation is serialized
+//! through a `CortexHandle`, since they all share a single model.
+
+use crate::clock::now_unix_secs;
+use crate::config::GenerationConfig;
+use crate::inference::FinishReason;
+use crate::{Cortex, CortexError, CortexHandle, Message, Result, Role};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// Largest request body accepted, in bytes
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// HTTP server wrapping a `Cortex` runtime
+#[derive(Clone)]
+pub struct Server {
+    handle: CortexHandle,
+    model_name: String,
+    /// Defaults for requests that don't set sampling parameters
+    generation: GenerationConfig,
+}
+
+/// Body of a `/v1/chat/completions` request
+#[derive(Debug, Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    stop: Option<OneOrMany>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+    name: Option<String>,
+}
+
+/// Body of a `/v1/embeddings` request
+#[derive(Debug, Deserialize)]
+struct EmbeddingRequest {
+    input: OneOrMany,
+}
+
+/// A string or list of strings, as accepted by `stop` and `input`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+/// A parsed HTTP request
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// How a handler answered
+enum Reply {
+    /// Send this JSON body with the given status
+    Json(u16, Value),
+    /// The handler already wrote the response
+    Streamed,
+}
+
+impl Server {
+    /// Serve the given runtime, moving it onto a worker thread
+    pub fn new(ctx: Cortex) -> Self {
+        let model_name = ctx.model_id();
+        let generation = ctx.config().generation.clone();
+        Self {
+            handle: CortexHandle::spawn(ctx),
+            model_name,
+            generation,
+        }
+    }
+
+    /// Set the model name reported in responses
+    pub fn with_model_name(mut self, name: impl Into<String>) -> Self {
+        self.model_name = name.into();
+        self
+    }
+
+    /// Bind to `addr` and serve until the process exits
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serve connections from an already-bound listener
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    tracing::warn!("Failed to handle request: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let request = match read_request(&mut stream)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        let reply = match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/v1/chat/completions") => self.chat_completions(&request.body, &mut stream),
+            ("POST", "/v1/embeddings") => self.embeddings(&request.body),
+            _ => Ok(Reply::Json(
+                404,
+                error_body(&format!(
+                    "Unknown endpoint: {} {}",
+                    request.method, request.path
+                )),
+            )),
+        };
+
+        match reply {
+            Ok(Reply::Json(status, body)) => write_json(&mut stream, status, &body),
+            Ok(Reply::Streamed) => Ok(()),
+            Err(e) => {
+                let status = match e {
+                    CortexError::Config(_) | CortexError::Serialization(_) => 400,
+                    _ => 500,
+                };
+                write_json(&mut stream, status, &error_body(&e.to_string()))
+            }
+        }
+    }
+
+    fn chat_completions(&self, body: &[u8], stream: &mut TcpStream) -> Result<Reply> {
+        let request: ChatCompletionRequest =
+            serde_json::from_slice(body).map_err(|e| CortexError::Serialization(e.to_string()))?;
+
+        let mut config = self.generation.clone();
+        if let Some(temperature) = request.temperature {
+            config.temperature = temperature;
+        }
+        if let Some(top_p) = request.top_p {
+            config.top_p = top_p;
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            config.max_tokens = max_tokens;
+        }
+        if let Some(stop) = request.stop {
+            config.stop = stop.into_vec();
+        }
+        // Validate up front: a streamed response can't change status later
+        config.validate()?;
+
+        let messages = request
+            .messages
+            .into_iter()
+            .map(to_message)
+            .collect::<Result<Vec<_>>>()?;
+
+        let id = format!("chatcmpl-{}", uuid::Uuid::new_v4());
+        let created = now_unix_secs();
+
+        // Each request carries the whole conversation
+        let tokens = self.handle.stream(move |ctx, callback| {
+            ctx.clear_messages();
+            ctx.chat_streaming_output(&messages, &config, callback)
+        })?;
+
+        if !request.stream {
+            let output = tokens.finish()?;
+            let (prompt_tokens, completion_tokens) = (output.prompt_tokens, output.completion_tokens);
+
+            return Ok(Reply::Json(
+                200,
+                json!({
+                    "id": id,
+                    "object": "chat.completion",
+                    "created": created,
+                    "model": self.model_name,
+                    "choices": [{
+                        "index": 0,
+                        "message": { "role": "assistant", "content": output.text },
+                        "finish_reason": finish_reason(output.finish_reason),
+                    }],
+                    "usage": {
+                        "prompt_tokens": prompt_tokens,
+                        "completion_tokens": completion_tokens,
+                        "total_tokens": prompt_tokens + completion_tokens,
+                    },
+                }),
+            ));
+        }
+
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Transfer-Encoding: chunked\r\n\
+              Connection: close\r\n\r\n",
+        )?;
+
+        let chunk = |delta: Value, finish_reason: Option<&str>| {
+            json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": self.model_name,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            })
+        };
+
+        write_event(
+            stream,
+            &chunk(json!({ "role": "assistant" }), None).to_string(),
+        )?;
+
+        // Dropping the receiver stops generation if the client goes away
+        let mut tokens = tokens;
+        for token in tokens.by_ref() {
+            let event = chunk(json!({ "content": token }), None).to_string();
+            if write_event(stream, &event).is_err() {
+                return Ok(Reply::Streamed);
+            }
+        }
+
+        match tokens.finish() {
+            Ok(output) => {
+                let reason = finish_reason(output.finish_reason);
+                write_event(stream, &chunk(json!({}), Some(reason)).to_string())?;
+            }
+            Err(e) => write_event(stream, &error_body(&e.to_string()).to_string())?,
+        }
+        write_event(stream, "[DONE]")?;
+        stream.write_all(b"0\r\n\r\n")?;
+        stream.flush()?;
+
+        Ok(Reply::Streamed)
+    }
+
+    fn embeddings(&self, body: &[u8]) -> Result<Reply> {
+        let request: EmbeddingRequest =
+            serde_json::from_slice(body).map_err(|e| CortexError::Serialization(e.to_string()))?;
+
+        let inputs = request.input.into_vec();
+        let (embeddings, tokens) = self.handle.call(move |ctx| {
+            let texts: Vec<&str> = inputs.iter().map(String::as_str).collect();
+            let tokens: usize = texts.iter().map(|t| ctx.count_tokens(t)).sum();
+            Ok((ctx.embed_batch(&texts)?, tokens))
+        })?;
+
+        let data: Vec<Value> = embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| {
+                json!({ "object": "embedding", "index": index, "embedding": embedding })
+            })
+            .collect();
+
+        Ok(Reply::Json(
+            200,
+            json!({
+                "object": "list",
+                "data": data,
+                "model": self.model_name,
+                "usage": { "prompt_tokens": tokens, "total_tokens": tokens },
+            }),
+        ))
+    }
+}
+
+fn to_message(message: ChatMessage) -> Result<Message> {
+    let role = match message.role.as_str() {
+        "system" | "developer" => Role::System,
+        "user" => Role::User,
+        "assistant" => Role::Assistant,
+        "tool" => Role::Tool,
+        other => {
+            return Err(CortexError::Config(format!(
+                "Unknown message role: {}",
+                other
+            )));
+        }
+    };
+    Ok(Message {
+        role,
+        content: message.content,
+        name: message.name,
+    })
+}
+
+/// OpenAI's name for a finish reason: "length" or "stop"
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length => "length",
+        _ => "stop",
+    }
+}
+
+fn error_body(message: &str) -> Value {
+    json!({ "error": { "message": message, "type": "invalid_request_error" } })
+}
+
+/// Read one request; `None` if the client closed without sending one
+fn read_request(stream: &mut TcpStream) -> Result<Option<Request>> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| {
+                    CortexError::Serialization(format!("Invalid Content-Length: {}", value.trim()))
+                })?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(CortexError::Serialization(format!(
+            "Request body too large: {} bytes",
+            content_length
+        )));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(Request { method, path, body }))
+}
+
+fn write_json(stream: &mut TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Write one server-sent event as an HTTP chunk
+fn write_event(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+    let event = format!("data: {}\n\n", data);
+    write!(stream, "{:x}\r\n{}\r\n", event.len(), event)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a stub-backed server on a free port and return its base URL
+    fn start_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || Server::new(Cortex::new()).serve(listener));
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_chat_completions() {
+        let base = start_server();
+        let url = format!("{}/v1/chat/completions", base);
+        let request = json!({
+            "model": "stub",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hello" },
+            ],
+        });
+
+        let response: Value = ureq::post(&url)
+            .send_json(&request)
+            .unwrap()
+            .into_json()
+            .unwrap();
+        assert_eq!(response["object"], "chat.completion");
+        assert_eq!(response["choices"][0]["message"]["role"], "assistant");
+        let content = response["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap();
+        assert!(content.contains("Stub response"));
+        assert!(response["usage"]["total_tokens"].as_u64().unwrap() > 0);
+
+        // Streaming yields several content deltas, then [DONE]
+        let mut request = request;
+        request["stream"] = json!(true);
+        let response = ureq::post(&url).send_json(&request).unwrap();
+        assert_eq!(response.content_type(), "text/event-stream");
+        let body = response.into_string().unwrap();
+        let events: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+
+        let chunks: Vec<Value> = events[..events.len() - 1]
+            .iter()
+            .map(|e| serde_json::from_str(e).unwrap())
+            .collect();
+        assert!(chunks
+            .iter()
+            .all(|c| c["object"] == "chat.completion.chunk"));
+        let deltas: Vec<&str> = chunks
+            .iter()
+            .filter_map(|c| c["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert!(deltas.len() > 1);
+        assert_eq!(deltas.concat(), content);
+        assert_eq!(
+            chunks.last().unwrap()["choices"][0]["finish_reason"],
+            "stop"
+        );
+
+        // Invalid sampling parameters are rejected before generating
+        let request = json!({ "messages": [{ "role": "user", "content": "Hi" }], "top_p": 2.0 });
+        match ureq::post(&url).send_json(&request) {
+            Err(ureq::Error::Status(400, _)) => {}
+            other => panic!("expected 400, got {:?}", other.map(|r| r.status())),
+        }
+    }
+
+    #[test]
+    fn test_embeddings() {
+        let base = start_server();
+        let request = json!({ "model": "stub", "input": ["first", "second"] });
+
+        let response: Value = ureq::post(&format!("{}/v1/embeddings", base))
+            .send_json(&request)
+            .unwrap()
+            .into_json()
+            .unwrap();
+        assert_eq!(response["object"], "list");
+        let data = response["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[1]["index"], 1);
+        assert!(!data[0]["embedding"].as_array().unwrap().is_empty());
+    }
+}
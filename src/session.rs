@@ -18,13 +18,87 @@
 //! // Automatically restored!
 //! ```
 
+use crate::clock::now_unix_secs;
 use crate::config::GenerationConfig;
-use crate::inference::{EngineState, StubEngine, TextEngine};
+use crate::inference::{StubEngine, TextEngine};
+use crate::memory::Memory;
 use crate::runtime::Cortex;
 use crate::state::RuntimeState;
-use crate::{Message, Result};
+use crate::{CortexError, Message, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// File holding a session's metadata, next to `session.state`
+const META_FILE: &str = "session.meta.json";
+
+/// File locked while a `Session` has the directory open
+const LOCK_FILE: &str = "session.lock";
+
+/// Longest allowed session ID, in bytes
+const MAX_SESSION_ID_LEN: usize = 128;
+
+/// Human-facing session details for pickers and listings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionMeta {
+    /// Session ID (directory name)
+    pub id: String,
+    /// Display title, defaults to the ID
+    pub title: String,
+    /// Free-form tags
+    pub tags: Vec<String>,
+    /// Creation time (unix epoch seconds)
+    pub created_at: u64,
+    /// Time of the last save (unix epoch seconds)
+    pub updated_at: u64,
+    /// Messages in the conversation at the last save
+    pub message_count: usize,
+}
 
-use std::path::PathBuf;
+impl SessionMeta {
+    fn new(id: &str) -> Self {
+        let now = now_unix_secs();
+        Self {
+            id: id.to_string(),
+            title: id.to_string(),
+            tags: Vec::new(),
+            created_at: now,
+            updated_at: now,
+            message_count: 0,
+        }
+    }
+
+    /// Read the metadata of the session in `session_dir`
+    ///
+    /// Sessions created before metadata existed get defaults derived from
+    /// the directory name.
+    fn load(session_dir: &Path, id: &str) -> Self {
+        std::fs::read(session_dir.join(META_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_else(|| Self {
+                created_at: 0,
+                updated_at: 0,
+                ..Self::new(id)
+            })
+    }
+
+    /// Update the ID, carrying over a title that defaulted to the old ID
+    fn rename(&mut self, old_id: &str, new_id: &str) {
+        self.id = new_id.to_string();
+        if self.title == old_id {
+            self.title = new_id.to_string();
+        }
+    }
+
+    fn save(&self, session_dir: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| CortexError::Serialization(e.to_string()))?;
+        std::fs::write(session_dir.join(META_FILE), data)?;
+        Ok(())
+    }
+}
 
 /// A persistent session with automatic state management
 pub struct Session {
@@ -39,6 +113,12 @@ pub struct Session {
 
     /// Auto-save on every message
     auto_save: bool,
+
+    /// Title, tags and timestamps
+    meta: SessionMeta,
+
+    /// Holds the session's lock until the session is dropped
+    _lock: std::fs::File,
 }
 
 impl Session {
@@ -51,12 +131,23 @@ impl Session {
     pub fn with_engine<E: TextEngine + 'static>(
         session_id: impl Into<String>,
         engine: E,
+    ) -> Result<Self> {
+        Self::with_engine_in(default_sessions_dir(), session_id, engine)
+    }
+
+    /// Create or resume a session stored under `sessions_dir`
+    pub fn with_engine_in<E: TextEngine + 'static>(
+        sessions_dir: impl AsRef<Path>,
+        session_id: impl Into<String>,
+        engine: E,
     ) -> Result<Self> {
         let session_id = session_id.into();
-        let session_dir = default_session_dir(&session_id);
+        let session_dir = session_path(sessions_dir.as_ref(), &session_id)?;
+        let is_new = !session_dir.join(META_FILE).exists();
 
         // Create session directory
         std::fs::create_dir_all(&session_dir)?;
+        let lock = lock_session(&session_dir, &session_id)?;
 
         // Create runtime with engine
         let mut runtime = Cortex::with_engine(engine);
@@ -65,16 +156,33 @@ impl Session {
         let state_path = session_dir.join("session.state");
         if state_path.exists() {
             if let Ok(state) = RuntimeState::load(&state_path) {
+                // A different engine starts cold rather than failing the resume
+                if let Err(e) = runtime.set_engine_state(&state.engine_state) {
+                    tracing::warn!("Not restoring engine context: {}", e);
+                }
                 runtime.memory.set_state(state.memory);
+                if let Some(prompt) = state.system_prompt {
+                    runtime.set_system_prompt(prompt);
+                }
                 // Note: Can't restore messages directly, but memory is restored
             }
         }
 
+        let meta = if is_new {
+            let meta = SessionMeta::new(&session_id);
+            meta.save(&session_dir)?;
+            meta
+        } else {
+            SessionMeta::load(&session_dir, &session_id)
+        };
+
         Ok(Self {
             runtime,
             session_id,
             session_dir,
             auto_save: true,
+            meta,
+            _lock: lock,
         })
     }
 
@@ -89,6 +197,46 @@ impl Session {
         &self.session_id
     }
 
+    /// Rename this session, moving its directory
+    ///
+    /// Fails with `CortexError::State` if a session named `new_id` exists.
+    pub fn rename(&mut self, new_id: impl Into<String>) -> Result<()> {
+        let new_id = new_id.into();
+        let sessions_dir = self
+            .session_dir
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        // This session already holds the lock
+        move_session(&sessions_dir, &self.session_id, &new_id)?;
+
+        self.session_dir = session_path(&sessions_dir, &new_id)?;
+        self.meta.rename(&self.session_id, &new_id);
+        self.session_id = new_id;
+        self.meta.save(&self.session_dir)
+    }
+
+    /// Get session metadata
+    pub fn meta(&self) -> &SessionMeta {
+        &self.meta
+    }
+
+    /// Set the display title and persist it
+    pub fn set_title(&mut self, title: impl Into<String>) -> Result<()> {
+        self.meta.title = title.into();
+        self.meta.save(&self.session_dir)
+    }
+
+    /// Add a tag (ignoring duplicates) and persist it
+    pub fn add_tag(&mut self, tag: impl Into<String>) -> Result<()> {
+        let tag = tag.into();
+        if !self.meta.tags.contains(&tag) {
+            self.meta.tags.push(tag);
+        }
+        self.meta.save(&self.session_dir)
+    }
+
     /// Chat with the session
     pub fn chat(&mut self, message: impl Into<String>) -> Result<String> {
         let response = self.runtime.chat(&[Message::user(message)])?;
@@ -135,10 +283,9 @@ impl Session {
         Ok(response)
     }
 
-    /// Add a system message
+    /// Set the system prompt, keeping the conversation so far
     pub fn set_system(&mut self, message: impl Into<String>) {
-        self.runtime.clear_messages();
-        let _ = self.runtime.chat(&[Message::system(message)]);
+        self.runtime.set_system_prompt(message);
     }
 
     /// Remember something
@@ -150,18 +297,44 @@ impl Session {
         Ok(())
     }
 
+    /// Forget a memory by key, returning whether it existed
+    pub fn forget(&mut self, key: &str) -> Result<bool> {
+        let removed = self.runtime.forget(key);
+        if removed && self.auto_save {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Forget every memory
+    pub fn forget_all(&mut self) -> Result<()> {
+        self.runtime.forget_all();
+        if self.auto_save {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Forget the memories whose metadata matches `filter`
+    pub fn forget_matching<F>(&mut self, filter: F) -> Result<usize>
+    where
+        F: Fn(&HashMap<String, String>) -> bool,
+    {
+        let removed = self.runtime.forget_matching(filter)?;
+        if removed > 0 && self.auto_save {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
     /// Recall from memory
     pub fn recall(&self, query: &str, k: usize) -> Result<Vec<String>> {
         self.runtime.recall(query, k)
     }
 
     /// Save session state
-    pub fn save(&self) -> Result<()> {
-        let state = RuntimeState::new(
-            self.runtime.messages().to_vec(),
-            self.runtime.memory.get_state(),
-            EngineState::default(),
-        );
+    pub fn save(&mut self) -> Result<()> {
+        let state = self.runtime.runtime_state()?;
 
         let state_path = self.session_dir.join("session.state");
         state.save(&state_path)?;
@@ -170,6 +343,10 @@ impl Session {
         let memory_path = self.session_dir.join("memory.bin");
         self.runtime.memory.persist(&memory_path)?;
 
+        self.meta.updated_at = now_unix_secs();
+        self.meta.message_count = self.runtime.messages().len();
+        self.meta.save(&self.session_dir)?;
+
         Ok(())
     }
 
@@ -204,23 +381,48 @@ impl Session {
     }
 }
 
-/// Get default session directory
-fn default_session_dir(session_id: &str) -> PathBuf {
-    let base = dirs::data_local_dir()
+/// Get the default directory holding all sessions
+fn default_sessions_dir() -> PathBuf {
+    dirs::data_local_dir()
         .unwrap_or_else(|| PathBuf::from("."))
         .join("cortex")
-        .join("sessions");
+        .join("sessions")
+}
 
-    base.join(session_id)
+/// Directory of the session `session_id` under `sessions_dir`
+///
+/// IDs become directory names, so they may only contain ASCII letters,
+/// digits, `_` and `-`, and are at most 128 bytes. Anything else, such as
+/// `../etc`, fails with `CortexError::Config`.
+fn session_path(sessions_dir: &Path, session_id: &str) -> Result<PathBuf> {
+    if !is_valid_session_id(session_id) {
+        return Err(CortexError::Config(format!(
+            "Invalid session ID '{}': use 1-{} letters, digits, '_' or '-'",
+            session_id.escape_debug(),
+            MAX_SESSION_ID_LEN
+        )));
+    }
+    Ok(sessions_dir.join(session_id))
+}
+
+fn is_valid_session_id(session_id: &str) -> bool {
+    !session_id.is_empty()
+        && session_id.len() <= MAX_SESSION_ID_LEN
+        && session_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
 }
 
 /// List all sessions in the default directory
 pub fn list_sessions() -> Result<Vec<String>> {
-    let base = dirs::data_local_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("cortex")
-        .join("sessions");
+    list_sessions_in(default_sessions_dir())
+}
 
+/// List all sessions under `sessions_dir`
+///
+/// Directories whose names aren't valid session IDs are skipped.
+pub fn list_sessions_in(sessions_dir: impl AsRef<Path>) -> Result<Vec<String>> {
+    let base = sessions_dir.as_ref();
     if !base.exists() {
         return Ok(vec![]);
     }
@@ -229,8 +431,9 @@ pub fn list_sessions() -> Result<Vec<String>> {
     for entry in std::fs::read_dir(base)? {
         let entry = entry?;
         if entry.file_type()?.is_dir() {
-            if let Some(name) = entry.file_name().to_str() {
-                sessions.push(name.to_string());
+            match entry.file_name().to_str() {
+                Some(name) if is_valid_session_id(name) => sessions.push(name.to_string()),
+                _ => {}
             }
         }
     }
@@ -238,11 +441,290 @@ pub fn list_sessions() -> Result<Vec<String>> {
     Ok(sessions)
 }
 
-/// Delete a session
+/// Rename a session in the default directory
+pub fn rename_session(old_id: &str, new_id: &str) -> Result<()> {
+    rename_session_in(default_sessions_dir(), old_id, new_id)
+}
+
+/// Rename a session under `sessions_dir`
+///
+/// Moves the session directory and updates the ID stored in its metadata.
+/// Fails with `CortexError::State` if `old_id` doesn't exist, `new_id`
+/// already does, or the session is open elsewhere.
+pub fn rename_session_in(
+    sessions_dir: impl AsRef<Path>,
+    old_id: &str,
+    new_id: &str,
+) -> Result<()> {
+    let base = sessions_dir.as_ref();
+    let old_dir = session_path(base, old_id)?;
+    if !old_dir.is_dir() {
+        return Err(CortexError::State(format!("Session '{}' does not exist", old_id)));
+    }
+
+    let _lock = lock_session(&old_dir, old_id)?;
+    move_session(base, old_id, new_id)
+}
+
+/// Move a session directory; the caller must hold its lock
+fn move_session(base: &Path, old_id: &str, new_id: &str) -> Result<()> {
+    let old_dir = session_path(base, old_id)?;
+    let new_dir = session_path(base, new_id)?;
+
+    if !old_dir.is_dir() {
+        return Err(CortexError::State(format!("Session '{}' does not exist", old_id)));
+    }
+    if new_dir.exists() {
+        return Err(CortexError::State(format!("Session '{}' already exists", new_id)));
+    }
+
+    std::fs::rename(&old_dir, &new_dir)?;
+
+    let mut meta = SessionMeta::load(&new_dir, old_id);
+    meta.rename(old_id, new_id);
+    meta.save(&new_dir)
+}
+
+/// List metadata for all sessions, most recently updated first
+pub fn list_sessions_meta() -> Result<Vec<SessionMeta>> {
+    list_sessions_meta_in(default_sessions_dir())
+}
+
+/// List metadata for all sessions under `sessions_dir`
+pub fn list_sessions_meta_in(sessions_dir: impl AsRef<Path>) -> Result<Vec<SessionMeta>> {
+    let base = sessions_dir.as_ref();
+    let mut metas: Vec<SessionMeta> = list_sessions_in(base)?
+        .into_iter()
+        .map(|id| SessionMeta::load(&base.join(&id), &id))
+        .collect();
+    metas.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.id.cmp(&b.id)));
+    Ok(metas)
+}
+
+/// Take the OS-level lock on a session directory
+///
+/// The OS releases the lock when the holder exits, so a crashed process
+/// never leaves the session stuck.
+fn lock_session(session_dir: &Path, session_id: &str) -> Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(session_dir.join(LOCK_FILE))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(CortexError::State(format!(
+            "session in use: '{}' is open elsewhere",
+            session_id
+        ))),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Load a stored session's memory without starting a runtime
+pub fn load_session_memory(session_id: &str) -> Result<Memory> {
+    load_session_memory_in(default_sessions_dir(), session_id)
+}
+
+/// Load the memory of a session stored under `sessions_dir`
+///
+/// Fails with `CortexError::State` if the session has never saved memory.
+pub fn load_session_memory_in(sessions_dir: impl AsRef<Path>, session_id: &str) -> Result<Memory> {
+    let path = session_path(sessions_dir.as_ref(), session_id)?.join("memory.bin");
+    if !path.exists() {
+        return Err(CortexError::State(format!(
+            "Session '{}' has no stored memory",
+            session_id
+        )));
+    }
+    Memory::load(path)
+}
+
+/// Delete a session in the default directory
 pub fn delete_session(session_id: &str) -> Result<()> {
-    let session_dir = default_session_dir(session_id);
-    if session_dir.exists() {
+    delete_session_in(default_sessions_dir(), session_id)
+}
+
+/// Delete a session under `sessions_dir`, if it exists
+///
+/// Fails with `CortexError::State` if the session is open elsewhere.
+pub fn delete_session_in(sessions_dir: impl AsRef<Path>, session_id: &str) -> Result<()> {
+    let session_dir = session_path(sessions_dir.as_ref(), session_id)?;
+    if session_dir.is_dir() {
+        let _lock = lock_session(&session_dir, session_id)?;
         std::fs::remove_dir_all(session_dir)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::EngineState;
+
+    #[test]
+    fn test_meta_survives_reload() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut session = Session::with_engine_in(dir.path(), "chat", StubEngine::new()).unwrap();
+        session.set_title("Trip planning").unwrap();
+        session.add_tag("travel").unwrap();
+        session.add_tag("travel").unwrap();
+        let created_at = session.meta().created_at;
+        drop(session);
+
+        let session = Session::with_engine_in(dir.path(), "chat", StubEngine::new()).unwrap();
+        assert_eq!(session.meta().title, "Trip planning");
+        assert_eq!(session.meta().tags, vec!["travel"]);
+        assert_eq!(session.meta().created_at, created_at);
+
+        let metas = list_sessions_meta_in(dir.path()).unwrap();
+        assert_eq!(metas, vec![session.meta().clone()]);
+    }
+
+    #[test]
+    fn test_engine_state_survives_reload() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut session = Session::with_engine_in(dir.path(), "warm", StubEngine::new()).unwrap();
+        session.set_system("Be brief.");
+        session.chat("Hello there").unwrap();
+        let used = session.runtime().context_used();
+        assert!(used > 0);
+        session.save().unwrap();
+        drop(session);
+
+        let mut session = Session::with_engine_in(dir.path(), "warm", StubEngine::new()).unwrap();
+        assert_eq!(session.runtime().context_used(), used);
+        assert_eq!(session.runtime().system_prompt(), Some("Be brief."));
+
+        // State from another kind of engine is refused
+        let foreign = EngineState {
+            engine_id: "candle".to_string(),
+            ..Default::default()
+        };
+        assert!(session.runtime_mut().set_engine_state(&foreign).is_err());
+        assert_eq!(session.runtime().context_used(), used);
+    }
+
+    #[test]
+    fn test_session_lock() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let session = Session::with_engine_in(dir.path(), "busy", StubEngine::new()).unwrap();
+        let err = Session::with_engine_in(dir.path(), "busy", StubEngine::new()).err();
+        assert!(matches!(&err, Some(CortexError::State(msg)) if msg.contains("session in use")));
+
+        // An open session can't be renamed or deleted from outside
+        let in_use = |result: Result<()>| {
+            matches!(result, Err(CortexError::State(msg)) if msg.contains("session in use"))
+        };
+        assert!(in_use(rename_session_in(dir.path(), "busy", "moved")));
+        assert!(in_use(delete_session_in(dir.path(), "busy")));
+
+        // Other sessions are unaffected, and dropping releases the lock
+        assert!(Session::with_engine_in(dir.path(), "other", StubEngine::new()).is_ok());
+        drop(session);
+        assert!(Session::with_engine_in(dir.path(), "busy", StubEngine::new()).is_ok());
+        rename_session_in(dir.path(), "busy", "moved").unwrap();
+        delete_session_in(dir.path(), "moved").unwrap();
+        assert!(!dir.path().join("moved").exists());
+    }
+
+    #[test]
+    fn test_session_ids_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = dir.path().join("sessions");
+
+        for id in ["../escape", "a/b", "", ".", &"x".repeat(MAX_SESSION_ID_LEN + 1)] {
+            let err = Session::with_engine_in(&sessions, id, StubEngine::new()).err();
+            assert!(matches!(err, Some(CortexError::Config(_))), "{:?}", id);
+            assert!(matches!(
+                delete_session_in(&sessions, id),
+                Err(CortexError::Config(_))
+            ));
+        }
+        assert!(!dir.path().join("escape").exists());
+
+        // Stray directories that couldn't be session IDs aren't listed
+        std::fs::create_dir_all(sessions.join("not a session")).unwrap();
+        let session =
+            Session::with_engine_in(&sessions, "user_123-a", StubEngine::new()).unwrap();
+        assert_eq!(list_sessions_in(&sessions).unwrap(), vec!["user_123-a"]);
+        drop(session);
+
+        delete_session_in(&sessions, "user_123-a").unwrap();
+        assert!(list_sessions_in(&sessions).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::with_engine_in(dir.path(), "old", StubEngine::new()).unwrap();
+        session.remember("fact", "The sky is blue").unwrap();
+
+        session.rename("new").unwrap();
+        assert_eq!(session.id(), "new");
+        assert_eq!(session.meta().id, "new");
+        assert_eq!(session.meta().title, "new");
+        assert_eq!(list_sessions_in(dir.path()).unwrap(), vec!["new"]);
+        drop(session);
+
+        // State followed the directory
+        let session = Session::with_engine_in(dir.path(), "new", StubEngine::new()).unwrap();
+        assert_eq!(session.runtime().memory.len(), 1);
+        assert_eq!(session.meta().id, "new");
+    }
+
+    #[test]
+    fn test_rename_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        Session::with_engine_in(dir.path(), "a", StubEngine::new()).unwrap();
+        Session::with_engine_in(dir.path(), "b", StubEngine::new()).unwrap();
+
+        let err = rename_session_in(dir.path(), "a", "b").unwrap_err();
+        assert!(matches!(err, CortexError::State(_)));
+        assert!(rename_session_in(dir.path(), "missing", "c").is_err());
+
+        let mut ids = list_sessions_in(dir.path()).unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_search_session_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::with_engine_in(dir.path(), "notes", StubEngine::new()).unwrap();
+        session.remember("sky", "The sky is blue").unwrap();
+        session.remember("grass", "Grass is green").unwrap();
+        session.remember("sea", "The sea is blue too").unwrap();
+        drop(session);
+
+        let memory = load_session_memory_in(dir.path(), "notes").unwrap();
+        assert_eq!(memory.len(), 3);
+
+        let query = StubEngine::new().embed("The sky is blue").unwrap();
+        let results = memory.search_with_threshold(&query, 3, f32::MIN);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].entry.key, "sky");
+        assert!(results.windows(2).all(|w| w[0].score >= w[1].score));
+
+        assert!(load_session_memory_in(dir.path(), "missing").is_err());
+    }
+
+    #[test]
+    fn test_updated_at_advances() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = Session::with_engine_in(dir.path(), "chat", StubEngine::new()).unwrap();
+        let before = session.meta().updated_at;
+
+        crate::clock::advance(5);
+        session.chat("Hello").unwrap();
+
+        assert!(session.meta().updated_at > before);
+        assert_eq!(session.meta().message_count, 2);
+        let stored = SessionMeta::load(&dir.path().join("chat"), "chat");
+        assert_eq!(&stored, session.meta());
+    }
+}
@@ -1,6 +1,13 @@
 //! Checkpoint and branching primitives
 
 use super::RuntimeState;
+use crate::memory::MemoryState;
+use crate::{CortexError, Message, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Current version of the portable checkpoint format
+const PORTABLE_FORMAT_VERSION: u32 = 1;
 
 /// A checkpoint handle
 ///
@@ -26,6 +33,67 @@ impl Checkpoint {
     }
 }
 
+/// A self-describing checkpoint bundle that can be moved between machines
+///
+/// Messages and memory are stored as plain JSON. The engine state is
+/// tagged with the identity of the model that produced it, so importing
+/// against a different model fails instead of restoring garbage context.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortableCheckpoint {
+    /// Format version of the bundle
+    pub format_version: u32,
+    /// Identity of the model the engine state belongs to
+    pub model_id: String,
+    /// The checkpointed runtime state
+    pub state: RuntimeState,
+}
+
+impl PortableCheckpoint {
+    /// Bundle a runtime state for the given model
+    pub fn new(state: RuntimeState, model_id: impl Into<String>) -> Self {
+        Self {
+            format_version: PORTABLE_FORMAT_VERSION,
+            model_id: model_id.into(),
+            state,
+        }
+    }
+
+    /// Write the bundle to a JSON file
+    pub fn export_portable(&self, path: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| CortexError::Serialization(e.to_string()))?;
+        std::fs::write(path.as_ref(), data)?;
+        Ok(())
+    }
+
+    /// Read a bundle from a JSON file
+    pub fn import_portable(path: impl AsRef<Path>) -> Result<Self> {
+        let data = std::fs::read(path.as_ref())?;
+        let bundle: Self = serde_json::from_slice(&data)
+            .map_err(|e| CortexError::Serialization(e.to_string()))?;
+
+        if bundle.format_version > PORTABLE_FORMAT_VERSION {
+            return Err(CortexError::InvalidCheckpoint(format!(
+                "Unsupported portable checkpoint version {} (max supported: {})",
+                bundle.format_version, PORTABLE_FORMAT_VERSION
+            )));
+        }
+
+        Ok(bundle)
+    }
+
+    /// Check that the bundle was produced by the given model
+    pub fn check_model(&self, model_id: &str) -> Result<()> {
+        if self.model_id != model_id {
+            return Err(CortexError::InvalidCheckpoint(format!(
+                "Checkpoint was exported from model '{}' but the current model is '{}'",
+                self.model_id, model_id
+            )));
+        }
+        Ok(())
+    }
+}
+
 /// A branch of execution
 ///
 /// Branches are independent copies of the runtime state
@@ -66,6 +134,34 @@ impl Branch {
     }
 }
 
+/// How to combine a branch with a base state
+///
+/// Memories are always merged by (namespace, key); when both sides hold
+/// the same key, the newer entry wins, with ties going to the branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Union of memories; messages and engine state come from the base
+    Union,
+    /// Union of memories, with the branch's new messages appended to the
+    /// base's; engine state comes from the base
+    Concatenate,
+    /// Like `Concatenate`, but takes the branch's engine state so
+    /// generation continues from the branch's context
+    ThreeWay,
+}
+
+/// Order for `CheckpointManager::list_sorted`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckpointSort {
+    /// Newest first
+    #[default]
+    CreatedAtDesc,
+    /// Oldest first
+    CreatedAtAsc,
+    /// By name, unnamed checkpoints last
+    NameAsc,
+}
+
 /// Manages checkpoints for a runtime
 pub struct CheckpointManager {
     /// All checkpoints
@@ -108,25 +204,127 @@ impl CheckpointManager {
         &self.checkpoints
     }
 
+    /// A page of checkpoints in the given order
+    ///
+    /// Skips `offset` checkpoints and returns up to `limit` of the rest;
+    /// an offset past the end gives an empty page. Checkpoints created in
+    /// the same second keep the order they were recorded in.
+    pub fn list_sorted(&self, by: CheckpointSort, offset: usize, limit: usize) -> Vec<&Checkpoint> {
+        let mut sorted: Vec<&Checkpoint> = self.checkpoints.iter().collect();
+        match by {
+            CheckpointSort::CreatedAtAsc => sorted.sort_by_key(|c| c.created_at),
+            CheckpointSort::CreatedAtDesc => {
+                sorted.reverse();
+                sorted.sort_by_key(|c| std::cmp::Reverse(c.created_at));
+            }
+            CheckpointSort::NameAsc => {
+                sorted.sort_by(|a, b| match (&a.name, &b.name) {
+                    (Some(a), Some(b)) => a.cmp(b),
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => std::cmp::Ordering::Equal,
+                })
+            }
+        }
+        sorted.into_iter().skip(offset).take(limit).collect()
+    }
+
     /// Clear all checkpoints
     pub fn clear(&mut self) {
         self.checkpoints.clear();
     }
+
+    /// Merge a branch back into a base state
+    ///
+    /// Returns the merged state as a new checkpoint state and records its
+    /// handle. The caller is responsible for storing the state.
+    pub fn merge(
+        &mut self,
+        base: &RuntimeState,
+        branch: Branch,
+        strategy: MergeStrategy,
+    ) -> RuntimeState {
+        let branch_id = branch.id.clone();
+        let branch = branch.into_state();
+
+        let memory = merge_memory(&base.memory, branch.memory);
+
+        let messages = match strategy {
+            MergeStrategy::Union => base.messages.clone(),
+            MergeStrategy::Concatenate | MergeStrategy::ThreeWay => {
+                let shared = shared_prefix_len(&base.messages, &branch.messages);
+                let mut messages = base.messages.clone();
+                messages.extend(branch.messages.into_iter().skip(shared));
+                messages
+            }
+        };
+
+        // The system prompt goes with the engine context it was encoded in
+        let (engine_state, system_prompt) = match strategy {
+            MergeStrategy::ThreeWay => (branch.engine_state, branch.system_prompt),
+            MergeStrategy::Union | MergeStrategy::Concatenate => {
+                (base.engine_state.clone(), base.system_prompt.clone())
+            }
+        };
+
+        let mut merged = RuntimeState::new(messages, memory, engine_state);
+        merged.name = base.name.clone();
+        merged.system_prompt = system_prompt;
+        merged.metadata.insert("merge_base".to_string(), base.id.clone());
+        merged.metadata.insert("merge_branch".to_string(), branch_id);
+
+        self.record(Checkpoint::from_state(&merged));
+        merged
+    }
+}
+
+/// Union two memory states by (namespace, key), newest entry winning
+fn merge_memory(base: &MemoryState, branch: MemoryState) -> MemoryState {
+    let mut entries = base.entries.clone();
+
+    for entry in branch.entries {
+        let existing = entries
+            .iter_mut()
+            .find(|e| e.namespace == entry.namespace && e.key == entry.key);
+        match existing {
+            Some(existing) if existing.created_at > entry.created_at => {}
+            Some(existing) => *existing = entry,
+            None => entries.push(entry),
+        }
+    }
+
+    MemoryState {
+        version: base.version,
+        embedding_dim: base.embedding_dim,
+        max_entries: base.max_entries,
+        entries,
+        storage: base.storage,
+    }
+}
+
+/// Number of leading messages two histories have in common
+fn shared_prefix_len(a: &[Message], b: &[Message]) -> usize {
+    a.iter()
+        .zip(b)
+        .take_while(|(x, y)| x.role == y.role && x.content == y.content && x.name == y.name)
+        .count()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::inference::EngineState;
-    use crate::memory::MemoryState;
+    use crate::memory::{MemoryEntry, MEMORY_FORMAT_VERSION};
 
     fn make_state() -> RuntimeState {
         RuntimeState::new(
             vec![],
             MemoryState {
+                version: MEMORY_FORMAT_VERSION,
                 embedding_dim: 64,
                 max_entries: 100,
                 entries: vec![],
+                storage: Default::default(),
             },
             EngineState::default(),
         )
@@ -146,6 +344,52 @@ mod tests {
         assert_eq!(manager.list().len(), 3);
     }
 
+    fn handle(id: &str, name: Option<&str>, created_at: u64) -> Checkpoint {
+        Checkpoint {
+            id: id.to_string(),
+            name: name.map(str::to_string),
+            created_at,
+        }
+    }
+
+    #[test]
+    fn test_list_sorted_by_name() {
+        let mut manager = CheckpointManager::new(10);
+        manager.record(handle("1", Some("beta"), 1));
+        manager.record(handle("2", None, 2));
+        manager.record(handle("3", Some("alpha"), 3));
+
+        let ids = |sort| -> Vec<String> {
+            manager.list_sorted(sort, 0, 10).iter().map(|c| c.id.clone()).collect()
+        };
+        assert_eq!(ids(CheckpointSort::NameAsc), vec!["3", "1", "2"]);
+        assert_eq!(ids(CheckpointSort::CreatedAtAsc), vec!["1", "2", "3"]);
+        assert_eq!(ids(CheckpointSort::CreatedAtDesc), vec!["3", "2", "1"]);
+    }
+
+    #[test]
+    fn test_list_sorted_pages() {
+        let mut manager = CheckpointManager::new(10);
+        for i in 0..5 {
+            // Two checkpoints per second: ties keep recording order
+            manager.record(handle(&i.to_string(), None, i / 2));
+        }
+
+        let page = |offset, limit| -> Vec<String> {
+            manager
+                .list_sorted(CheckpointSort::CreatedAtDesc, offset, limit)
+                .iter()
+                .map(|c| c.id.clone())
+                .collect()
+        };
+        assert_eq!(page(0, 2), vec!["4", "3"]);
+        assert_eq!(page(2, 2), vec!["2", "1"]);
+        assert_eq!(page(4, 2), vec!["0"]);
+        assert!(page(5, 2).is_empty());
+        assert!(page(usize::MAX, usize::MAX).is_empty());
+        assert!(page(0, 0).is_empty());
+    }
+
     #[test]
     fn test_branch() {
         let state = make_state();
@@ -154,4 +398,62 @@ mod tests {
 
         assert_eq!(branch.parent_id, checkpoint.id);
     }
+
+    fn entry(key: &str, content: &str, created_at: u64) -> MemoryEntry {
+        MemoryEntry {
+            key: key.to_string(),
+            content: content.to_string(),
+            embedding: vec![0.0; 64],
+            metadata: Default::default(),
+            created_at,
+            namespace: "default".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_union_merge() {
+        let mut base = make_state();
+        base.memory.entries.push(entry("base_only", "from base", 1));
+        let mut branch = Branch::new(base.id.clone(), base.clone());
+        branch.state_mut().memory.entries.push(entry("branch_only", "from branch", 2));
+        base.memory.entries.push(entry("base_later", "added after forking", 3));
+
+        let mut manager = CheckpointManager::new(10);
+        let merged = manager.merge(&base, branch, MergeStrategy::Union);
+
+        let mut keys: Vec<&str> = merged.memory.entries.iter().map(|e| e.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["base_later", "base_only", "branch_only"]);
+        assert_eq!(manager.latest().unwrap().id, merged.id);
+        assert_eq!(merged.metadata["merge_base"], base.id);
+    }
+
+    #[test]
+    fn test_merge_newest_wins() {
+        let mut base = make_state();
+        base.messages.push(Message::user("Hello"));
+        base.memory.entries.push(entry("color", "blue", 10));
+        base.memory.entries.push(entry("city", "Paris", 30));
+
+        let mut branch = Branch::new(base.id.clone(), base.clone());
+        let state = branch.state_mut();
+        state.messages.push(Message::assistant("Hi!"));
+        state.memory.entries[0] = entry("color", "green", 20); // newer than base
+        state.memory.entries[1] = entry("city", "Rome", 5); // older than base
+        state.engine_state.n_tokens = 42;
+
+        let mut manager = CheckpointManager::new(10);
+        let merged = manager.merge(&base, branch, MergeStrategy::ThreeWay);
+
+        let content = |key: &str| {
+            merged.memory.entries.iter().find(|e| e.key == key).unwrap().content.clone()
+        };
+        assert_eq!(content("color"), "green");
+        assert_eq!(content("city"), "Paris");
+        assert_eq!(merged.memory.entries.len(), 2);
+
+        // Shared history isn't duplicated, and the branch's context is kept
+        assert_eq!(merged.messages.len(), 2);
+        assert_eq!(merged.engine_state.n_tokens, 42);
+    }
 }
@@ -7,13 +7,41 @@
 
 mod checkpoint;
 
-pub use checkpoint::{Branch, Checkpoint, CheckpointManager};
+pub use checkpoint::{
+    Branch, Checkpoint, CheckpointManager, CheckpointSort, MergeStrategy, PortableCheckpoint,
+};
 
+use crate::clock::{now_unix_secs, unix_secs};
+use crate::config::StateFormat;
 use crate::inference::EngineState;
-use crate::memory::MemoryState;
+use crate::memory::{MemoryEntry, MemoryState, DEFAULT_NAMESPACE};
 use crate::{CortexError, Message, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// First bytes of every gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// First bytes of a checkpoint file that opens with a `CheckpointHeader`
+const HEADER_MAGIC: &[u8; 8] = b"CXCKPT01";
+
+/// Largest header `read_header` accepts
+const MAX_HEADER_LEN: usize = 64 * 1024;
+
+/// File in the persist directory mapping checkpoint IDs to file names
+const INDEX_FILE: &str = "index.json";
+
+/// Length of the ID suffix on named checkpoint files
+const SHORT_ID_LEN: usize = 8;
+
+/// Longest name prefix kept in a checkpoint file name
+const MAX_NAME_LEN: usize = 48;
 
 /// Complete runtime state that can be checkpointed
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +66,10 @@ pub struct RuntimeState {
 
     /// Custom metadata
     pub metadata: std::collections::HashMap<String, String>,
+
+    /// System prompt sent ahead of the messages
+    #[serde(default)]
+    pub system_prompt: Option<String>,
 }
 
 impl RuntimeState {
@@ -53,11 +85,9 @@ impl RuntimeState {
             messages,
             memory,
             engine_state,
-            created_at: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            created_at: now_unix_secs(),
             metadata: Default::default(),
+            system_prompt: None,
         }
     }
 
@@ -67,23 +97,219 @@ impl RuntimeState {
         self
     }
 
+    /// Compare this state with a later one
+    ///
+    /// Memory entries are matched by (namespace, key); an entry counts as
+    /// changed when its content, metadata or embedding differs. Appended
+    /// messages are those in `other` after the history both share.
+    pub fn diff(&self, other: &RuntimeState) -> StateDiff {
+        let label = |e: &MemoryEntry| {
+            if e.namespace == DEFAULT_NAMESPACE {
+                e.key.clone()
+            } else {
+                format!("{}/{}", e.namespace, e.key)
+            }
+        };
+        let find = |entries: &[MemoryEntry], e: &MemoryEntry| {
+            entries
+                .iter()
+                .find(|o| o.namespace == e.namespace && o.key == e.key)
+                .cloned()
+        };
+
+        let mut diff = StateDiff::default();
+        for entry in &self.memory.entries {
+            match find(&other.memory.entries, entry) {
+                None => diff.removed_keys.push(label(entry)),
+                Some(new) => {
+                    if new.content != entry.content
+                        || new.metadata != entry.metadata
+                        || new.embedding != entry.embedding
+                    {
+                        diff.changed_keys.push(label(entry));
+                    }
+                }
+            }
+        }
+        for entry in &other.memory.entries {
+            if find(&self.memory.entries, entry).is_none() {
+                diff.added_keys.push(label(entry));
+            }
+        }
+
+        let shared = self
+            .messages
+            .iter()
+            .zip(&other.messages)
+            .take_while(|(a, b)| a.role == b.role && a.content == b.content && a.name == b.name)
+            .count();
+        diff.message_delta = other.messages.len() as i64 - self.messages.len() as i64;
+        diff.appended_messages = other.messages[shared..].to_vec();
+        diff.engine_tokens_changed = self.engine_state.n_tokens != other.engine_state.n_tokens;
+
+        diff
+    }
+
     /// Save to file
     pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
-        let data =
-            bincode::serialize(self).map_err(|e| CortexError::Serialization(e.to_string()))?;
-        std::fs::write(path.as_ref(), data)?;
+        self.save_with(path, StateFormat::Bincode, false)
+    }
+
+    /// Save to file in the given format, optionally gzip-compressed
+    pub fn save_with(
+        &self,
+        path: impl AsRef<Path>,
+        format: StateFormat,
+        compress: bool,
+    ) -> Result<()> {
+        std::fs::write(path.as_ref(), self.encode(format, compress)?)?;
         Ok(())
     }
 
+    /// Serialize in the given format, optionally gzip-compressed
+    fn encode(&self, format: StateFormat, compress: bool) -> Result<Vec<u8>> {
+        let data = match format {
+            StateFormat::Bincode => bincode::serialize(self)
+                .map_err(|e| CortexError::Serialization(e.to_string()))?,
+            StateFormat::Json => serde_json::to_vec_pretty(self)
+                .map_err(|e| CortexError::Serialization(e.to_string()))?,
+        };
+
+        if !compress {
+            return Ok(data);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data)?;
+        Ok(encoder.finish()?)
+    }
+
     /// Load from file
+    ///
+    /// Gzip-compressed files are detected by their magic bytes, and JSON
+    /// files by a leading `{`; anything else is read as bincode. A header
+    /// written by `StateStore` is skipped.
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
-        let data = std::fs::read(path.as_ref())?;
-        let state: Self =
-            bincode::deserialize(&data).map_err(|e| CortexError::Serialization(e.to_string()))?;
+        let mut data = std::fs::read(path.as_ref())?;
+        if let Some(len) = header_len(&data).filter(|&len| len <= data.len()) {
+            data.drain(..len);
+        }
+
+        if data.starts_with(&GZIP_MAGIC) {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+            data = decompressed;
+        }
+
+        let state: Self = if data.first() == Some(&b'{') {
+            serde_json::from_slice(&data).map_err(|e| CortexError::Serialization(e.to_string()))?
+        } else {
+            bincode::deserialize(&data).map_err(|e| CortexError::Serialization(e.to_string()))?
+        };
         Ok(state)
     }
 }
 
+/// Differences between two runtime states, from `RuntimeState::diff`
+///
+/// Memory keys outside the default namespace are shown as `namespace/key`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StateDiff {
+    /// Memory keys only in the newer state
+    pub added_keys: Vec<String>,
+    /// Memory keys only in the older state
+    pub removed_keys: Vec<String>,
+    /// Memory keys in both states with different entries
+    pub changed_keys: Vec<String>,
+    /// Change in message count (negative if history shrank)
+    pub message_delta: i64,
+    /// Messages in the newer state after the shared history
+    pub appended_messages: Vec<Message>,
+    /// Whether the engine's context token count changed
+    pub engine_tokens_changed: bool,
+}
+
+impl StateDiff {
+    /// Whether the two states are equivalent
+    pub fn is_empty(&self) -> bool {
+        self.added_keys.is_empty()
+            && self.removed_keys.is_empty()
+            && self.changed_keys.is_empty()
+            && self.appended_messages.is_empty()
+            && self.message_delta == 0
+            && !self.engine_tokens_changed
+    }
+}
+
+/// Metadata written ahead of a checkpoint body in a persist directory
+///
+/// Stored as `HEADER_MAGIC`, a little-endian `u32` length and that many
+/// bytes of JSON, so a store can list a file without reading its body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CheckpointHeader {
+    id: String,
+    #[serde(default)]
+    name: Option<String>,
+    created_at: u64,
+}
+
+impl CheckpointHeader {
+    fn of(state: &RuntimeState) -> Self {
+        Self {
+            id: state.id.clone(),
+            name: state.name.clone(),
+            created_at: state.created_at,
+        }
+    }
+
+    /// Header bytes followed by `body`
+    fn prepend(&self, body: Vec<u8>) -> Result<Vec<u8>> {
+        let json =
+            serde_json::to_vec(self).map_err(|e| CortexError::Serialization(e.to_string()))?;
+        let mut data = Vec::with_capacity(HEADER_MAGIC.len() + 4 + json.len() + body.len());
+        data.extend_from_slice(HEADER_MAGIC);
+        data.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        data.extend_from_slice(&json);
+        data.extend_from_slice(&body);
+        Ok(data)
+    }
+}
+
+/// Total length of the header at the start of `data`, if it has one
+fn header_len(data: &[u8]) -> Option<usize> {
+    let rest = data.strip_prefix(HEADER_MAGIC)?;
+    let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+    Some(HEADER_MAGIC.len() + 4 + len)
+}
+
+/// Read just the header of a checkpoint file
+///
+/// Files written by `RuntimeState::save` have none.
+fn read_header(path: &Path) -> Option<CheckpointHeader> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut prefix = [0u8; HEADER_MAGIC.len() + 4];
+    file.read_exact(&mut prefix).ok()?;
+    let len = header_len(&prefix)? - prefix.len();
+    if len > MAX_HEADER_LEN {
+        return None;
+    }
+    let mut json = vec![0u8; len];
+    file.read_exact(&mut json).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// What the index records about a checkpoint file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    /// File name within the persist directory
+    file: String,
+    /// Checkpoint name, if any
+    #[serde(default)]
+    name: Option<String>,
+    /// Creation timestamp, for ordering without reading the file
+    #[serde(default)]
+    created_at: u64,
+}
+
 /// State store for managing checkpoints
 pub struct StateStore {
     /// In-memory checkpoints
@@ -95,65 +321,130 @@ pub struct StateStore {
     /// Maximum checkpoints to keep
     max_checkpoints: usize,
 
-    /// Checkpoint IDs in order (for LRU eviction)
+    /// Checkpoint IDs oldest first, including ones only on disk
     checkpoint_order: Vec<String>,
+
+    /// Logical clock for access tracking
+    clock: Cell<u64>,
+
+    /// Last save or load of each checkpoint, for LRU eviction
+    last_access: RefCell<HashMap<String, u64>>,
+
+    /// Gzip checkpoint files on disk
+    compress: bool,
+
+    /// Encoding for checkpoint files on disk
+    format: StateFormat,
+
+    /// Checkpoint files by ID, mirrored in `index.json`
+    files: BTreeMap<String, IndexEntry>,
 }
 
 impl StateStore {
     /// Create new state store
+    ///
+    /// Checkpoints already in `persist_dir` are listed oldest first, using
+    /// `index.json` for their names and timestamps. For `.ckpt` files
+    /// missing from the index, only the header is read. No checkpoint
+    /// bodies are read until loaded.
     pub fn new(persist_dir: Option<std::path::PathBuf>, max_checkpoints: usize) -> Self {
+        let files = persist_dir.as_deref().map(scan_dir).unwrap_or_default();
+        let mut order: Vec<(&String, &IndexEntry)> = files.iter().collect();
+        order.sort_by_key(|(id, entry)| (entry.created_at, *id));
+        let checkpoint_order = order.into_iter().map(|(id, _)| id.clone()).collect();
+
         Self {
             checkpoints: std::collections::HashMap::new(),
             persist_dir,
             max_checkpoints,
-            checkpoint_order: Vec::new(),
+            checkpoint_order,
+            clock: Cell::new(0),
+            last_access: RefCell::new(HashMap::new()),
+            compress: false,
+            format: StateFormat::Bincode,
+            files,
         }
     }
 
+    /// Gzip checkpoint files written to disk
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Encoding for checkpoint files written to disk
+    pub fn with_format(mut self, format: StateFormat) -> Self {
+        self.format = format;
+        self
+    }
+
     /// Save a checkpoint
+    ///
+    /// On disk, unnamed checkpoints are written to `<id>.ckpt` and named
+    /// ones to `<name>-<shortid>.ckpt`, with the name sanitized and the ID
+    /// suffix lengthened until the file name is unique. Each file opens
+    /// with a small header holding the ID, name and timestamp.
+    ///
+    /// Over capacity, the least recently saved or loaded checkpoint is
+    /// evicted from memory and disk. Checkpoints found on disk at startup
+    /// and not used since go first, oldest first.
     pub fn save(&mut self, state: RuntimeState) -> Result<String> {
         let id = state.id.clone();
 
         // Persist if enabled
-        if let Some(dir) = &self.persist_dir {
-            std::fs::create_dir_all(dir)?;
-            let path = dir.join(format!("{}.ckpt", &id));
-            state.save(&path)?;
+        if let Some(dir) = self.persist_dir.clone() {
+            std::fs::create_dir_all(&dir)?;
+            let file = match self.files.get(&id) {
+                Some(entry) => entry.file.clone(),
+                None => self.file_name(&dir, &id, state.name.as_deref()),
+            };
+            let body = state.encode(self.format, self.compress)?;
+            std::fs::write(dir.join(&file), CheckpointHeader::of(&state).prepend(body)?)?;
+            let entry = IndexEntry {
+                file,
+                name: state.name.clone(),
+                created_at: state.created_at,
+            };
+            self.files.insert(id.clone(), entry);
+            self.write_index()?;
         }
 
         // Store in memory
         self.checkpoints.insert(id.clone(), state);
+        self.checkpoint_order.retain(|i| *i != id);
         self.checkpoint_order.push(id.clone());
-
-        // Evict oldest if over limit
-        while self.checkpoints.len() > self.max_checkpoints {
-            if let Some(oldest_id) = self.checkpoint_order.first().cloned() {
-                self.checkpoints.remove(&oldest_id);
-                self.checkpoint_order.remove(0);
-
-                // Remove from disk too
-                if let Some(dir) = &self.persist_dir {
-                    let path = dir.join(format!("{}.ckpt", &oldest_id));
-                    let _ = std::fs::remove_file(path);
-                }
-            }
+        self.touch(&id);
+
+        // Evict least recently used if over limit
+        while self.checkpoint_order.len() > self.max_checkpoints {
+            let Some(victim) = self.eviction_candidate() else {
+                break;
+            };
+            self.checkpoint_order.retain(|i| *i != victim);
+            self.checkpoints.remove(&victim);
+            self.last_access.borrow_mut().remove(&victim);
+
+            // Remove from disk too
+            self.remove_file(&victim)?;
         }
 
         Ok(id)
     }
 
-    /// Load a checkpoint
+    /// Load a checkpoint, marking it recently used
     pub fn load(&self, id: &str) -> Result<RuntimeState> {
         // Try memory first
         if let Some(state) = self.checkpoints.get(id) {
+            self.touch(id);
             return Ok(state.clone());
         }
 
         // Try disk
-        if let Some(dir) = &self.persist_dir {
-            let path = dir.join(format!("{}.ckpt", id));
+        if let Some(path) = self.path_for(id) {
             if path.exists() {
-                return RuntimeState::load(&path);
+                let state = RuntimeState::load(&path)?;
+                self.touch(id);
+                return Ok(state);
             }
         }
 
@@ -163,31 +454,426 @@ impl StateStore {
         )))
     }
 
+    /// Find the most recent checkpoint with the given name, marking it
+    /// recently used
+    ///
+    /// Names of checkpoints only on disk come from the index, so their
+    /// bodies are read only for the match.
+    pub fn find_by_name(&self, name: &str) -> Result<Option<RuntimeState>> {
+        let id = self
+            .checkpoint_order
+            .iter()
+            .rev()
+            .find(|id| self.name_of(id) == Some(name));
+        id.map(|id| self.load(id)).transpose()
+    }
+
+    /// Name of a checkpoint, from memory or the index
+    fn name_of(&self, id: &str) -> Option<&str> {
+        match self.checkpoints.get(id) {
+            Some(state) => state.name.as_deref(),
+            None => self.files.get(id).and_then(|e| e.name.as_deref()),
+        }
+    }
+
+    /// Record a use of a checkpoint for LRU eviction
+    fn touch(&self, id: &str) {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        self.last_access.borrow_mut().insert(id.to_string(), now);
+    }
+
+    /// Least recently used checkpoint, ties going to the oldest
+    fn eviction_candidate(&self) -> Option<String> {
+        let last_access = self.last_access.borrow();
+        self.checkpoint_order
+            .iter()
+            .min_by_key(|id| last_access.get(*id).copied().unwrap_or(0))
+            .cloned()
+    }
+
+    /// ID of the most recent checkpoint, in memory or on disk
+    pub fn latest(&self) -> Option<&str> {
+        self.checkpoint_order.last().map(String::as_str)
+    }
+
     /// Delete a checkpoint
     pub fn delete(&mut self, id: &str) -> bool {
-        let removed = self.checkpoints.remove(id).is_some();
+        let known = self.checkpoint_order.iter().any(|i| i == id);
+        let removed = self.checkpoints.remove(id).is_some() || known;
         self.checkpoint_order.retain(|i| i != id);
+        self.last_access.borrow_mut().remove(id);
 
-        if let Some(dir) = &self.persist_dir {
-            let path = dir.join(format!("{}.ckpt", id));
-            let _ = std::fs::remove_file(path);
-        }
+        let _ = self.remove_file(id);
 
         removed
     }
 
-    /// List all checkpoint IDs
-    pub fn list(&self) -> Vec<&str> {
-        self.checkpoint_order.iter().map(|s| s.as_str()).collect()
+    /// List all checkpoints as `(id, name)`, oldest first
+    pub fn list(&self) -> Vec<(&str, Option<&str>)> {
+        self.checkpoint_order
+            .iter()
+            .map(|id| (id.as_str(), self.name_of(id)))
+            .collect()
     }
 
     /// Get checkpoint count
     pub fn len(&self) -> usize {
-        self.checkpoints.len()
+        self.checkpoint_order.len()
     }
 
     /// Check if empty
     pub fn is_empty(&self) -> bool {
-        self.checkpoints.is_empty()
+        self.checkpoint_order.is_empty()
+    }
+
+    /// Path of a checkpoint's file, falling back to `<id>.ckpt` for IDs
+    /// missing from the index
+    fn path_for(&self, id: &str) -> Option<PathBuf> {
+        let dir = self.persist_dir.as_ref()?;
+        Some(match self.files.get(id) {
+            Some(entry) => dir.join(&entry.file),
+            None => dir.join(format!("{}.ckpt", id)),
+        })
+    }
+
+    /// Pick an unused file name for a new checkpoint
+    fn file_name(&self, dir: &Path, id: &str, name: Option<&str>) -> String {
+        let stem = name.map(sanitize_name).unwrap_or_default();
+        if stem.is_empty() {
+            return format!("{}.ckpt", id);
+        }
+
+        let taken = |file: &str| {
+            self.files.values().any(|e| e.file == file) || dir.join(file).exists()
+        };
+        let short: Vec<char> = id.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        for len in SHORT_ID_LEN..=short.len() {
+            let suffix: String = short[..len].iter().collect();
+            let file = format!("{}-{}.ckpt", stem, suffix);
+            if !taken(&file) {
+                return file;
+            }
+        }
+        format!("{}-{}.ckpt", stem, id)
+    }
+
+    /// Delete a checkpoint's file and drop it from the index
+    fn remove_file(&mut self, id: &str) -> Result<()> {
+        let Some(path) = self.path_for(id) else {
+            return Ok(());
+        };
+        let _ = std::fs::remove_file(path);
+        if self.files.remove(id).is_some() {
+            self.write_index()?;
+        }
+        Ok(())
+    }
+
+    fn write_index(&self) -> Result<()> {
+        let Some(dir) = &self.persist_dir else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(&self.files)
+            .map_err(|e| CortexError::Serialization(e.to_string()))?;
+        std::fs::write(dir.join(INDEX_FILE), data)?;
+        Ok(())
+    }
+}
+
+/// Find the checkpoints in a persist directory
+///
+/// Index entries whose file is gone are dropped. `.ckpt` files the index
+/// doesn't mention are identified by their header. Headerless files are
+/// taken to be `<id>.ckpt`, dated by modification time.
+fn scan_dir(dir: &Path) -> BTreeMap<String, IndexEntry> {
+    let mut files = read_index(dir);
+    files.retain(|_, entry| dir.join(&entry.file).is_file());
+
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return files;
+    };
+    for dir_entry in read_dir.flatten() {
+        let path = dir_entry.path();
+        let (Some(file), Some(id)) = (
+            path.file_name().and_then(|f| f.to_str()),
+            path.file_stem().and_then(|s| s.to_str()),
+        ) else {
+            continue;
+        };
+        if path.extension().is_none_or(|ext| ext != "ckpt")
+            || files.values().any(|e| e.file == file)
+        {
+            continue;
+        }
+
+        let (id, name, created_at) = match read_header(&path) {
+            Some(header) => (header.id, header.name, header.created_at),
+            None => {
+                let modified = dir_entry.metadata().and_then(|m| m.modified());
+                (id.to_string(), None, modified.map_or(0, unix_secs))
+            }
+        };
+        files.insert(
+            id,
+            IndexEntry {
+                file: file.to_string(),
+                name,
+                created_at,
+            },
+        );
+    }
+    files
+}
+
+/// Read the ID-to-file index from a persist directory
+///
+/// A missing or unreadable index is treated as empty; checkpoints then
+/// load from `<id>.ckpt`.
+fn read_index(dir: &Path) -> BTreeMap<String, IndexEntry> {
+    let Ok(data) = std::fs::read(dir.join(INDEX_FILE)) else {
+        return BTreeMap::new();
+    };
+    serde_json::from_slice(&data).unwrap_or_else(|e| {
+        tracing::warn!("Ignoring malformed checkpoint index: {}", e);
+        BTreeMap::new()
+    })
+}
+
+/// Make a checkpoint name safe to use in a file name
+fn sanitize_name(name: &str) -> String {
+    let mapped: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    mapped
+        .trim_matches('-')
+        .chars()
+        .take(MAX_NAME_LEN)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{MemoryEntry, MEMORY_FORMAT_VERSION};
+
+    /// A checkpoint with no messages or memories
+    fn empty_state(name: Option<&str>, created_at: u64) -> RuntimeState {
+        let memory = MemoryState {
+            version: MEMORY_FORMAT_VERSION,
+            embedding_dim: 3,
+            max_entries: 10,
+            entries: vec![],
+            storage: Default::default(),
+        };
+        let mut state = RuntimeState::new(vec![], memory, EngineState::default());
+        state.name = name.map(str::to_string);
+        state.created_at = created_at;
+        state
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let entries = (0..200)
+            .map(|i| MemoryEntry {
+                key: format!("key_{}", i),
+                content: "The same sentence, over and over again.".to_string(),
+                embedding: vec![0.25; 64],
+                metadata: Default::default(),
+                created_at: 0,
+                namespace: "default".to_string(),
+            })
+            .collect();
+        let memory = MemoryState {
+            version: MEMORY_FORMAT_VERSION,
+            embedding_dim: 64,
+            max_entries: 1000,
+            entries,
+            storage: Default::default(),
+        };
+        let state = RuntimeState::new(vec![Message::user("Hello")], memory, EngineState::default());
+
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.ckpt");
+        let compressed = dir.path().join("compressed.ckpt");
+        state.save(&plain).unwrap();
+        state.save_with(&compressed, StateFormat::Bincode, true).unwrap();
+
+        let plain_len = std::fs::metadata(&plain).unwrap().len();
+        let compressed_len = std::fs::metadata(&compressed).unwrap().len();
+        assert!(compressed_len < plain_len / 4, "{} vs {}", compressed_len, plain_len);
+
+        // Both formats load, and identically
+        let from_plain = RuntimeState::load(&plain).unwrap();
+        let from_compressed = RuntimeState::load(&compressed).unwrap();
+        assert_eq!(
+            bincode::serialize(&from_plain).unwrap(),
+            bincode::serialize(&from_compressed).unwrap()
+        );
+        assert_eq!(from_compressed.id, state.id);
+        assert_eq!(from_compressed.memory.entries.len(), 200);
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let memory = MemoryState {
+            version: MEMORY_FORMAT_VERSION,
+            embedding_dim: 3,
+            max_entries: 10,
+            entries: vec![MemoryEntry {
+                key: "fact".to_string(),
+                content: "The sky is blue".to_string(),
+                embedding: vec![0.1, -0.5, 0.75],
+                metadata: Default::default(),
+                created_at: 42,
+                namespace: "default".to_string(),
+            }],
+            storage: Default::default(),
+        };
+        let engine_state = EngineState {
+            data: vec![0, 159, 255, 7],
+            n_tokens: 17,
+            engine_id: "stub".to_string(),
+            ..Default::default()
+        };
+        let messages = vec![Message::user("Hi"), Message::assistant("Hello!")];
+        let state = RuntimeState::new(messages, memory, engine_state);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        state.save_with(&path, StateFormat::Json, false).unwrap();
+
+        // Engine bytes are base64 in the JSON form
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with('{'));
+        assert!(text.contains("\"AJ//Bw==\""));
+
+        let loaded = RuntimeState::load(&path).unwrap();
+        assert_eq!(loaded.id, state.id);
+        assert_eq!(loaded.messages.len(), 2);
+        assert_eq!(loaded.messages[1].content, "Hello!");
+        assert_eq!(loaded.memory.entries[0].content, "The sky is blue");
+        assert_eq!(loaded.memory.entries[0].embedding, vec![0.1, -0.5, 0.75]);
+        assert_eq!(loaded.engine_state.n_tokens, 17);
+        assert_eq!(loaded.engine_state.data, vec![0, 159, 255, 7]);
+    }
+
+    #[test]
+    fn test_named_checkpoint_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = StateStore::new(Some(dir.path().to_path_buf()), 10);
+        let first = store.save(empty_state(Some("draft"), 1)).unwrap();
+        let second = store.save(empty_state(Some("draft"), 2)).unwrap();
+        assert_eq!(
+            store.list(),
+            vec![(first.as_str(), Some("draft")), (second.as_str(), Some("draft"))]
+        );
+
+        let mut files: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|f| f.ends_with(".ckpt"))
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        assert_ne!(files[0], files[1]);
+        assert!(files.iter().all(|f| f.starts_with("draft-")));
+
+        // A fresh store finds both through the index
+        let reopened = StateStore::new(Some(dir.path().to_path_buf()), 10);
+        assert_eq!(reopened.load(&first).unwrap().id, first);
+        assert_eq!(reopened.load(&second).unwrap().id, second);
+        let found = reopened.find_by_name("draft").unwrap().unwrap();
+        assert_eq!(found.id, second);
+        assert!(reopened.find_by_name("missing").unwrap().is_none());
+        assert_eq!(sanitize_name("my plan/v2!"), "my-plan-v2");
+    }
+
+    #[test]
+    fn test_loaded_checkpoint_survives_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = StateStore::new(Some(dir.path().to_path_buf()), 2);
+        let oldest = store.save(empty_state(None, 1)).unwrap();
+        let middle = store.save(empty_state(None, 2)).unwrap();
+        store.load(&oldest).unwrap();
+
+        let newest = store.save(empty_state(None, 3)).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.load(&middle).is_err());
+        assert!(store.load(&oldest).is_ok());
+
+        // The evicted checkpoint's file went with it
+        let reopened = StateStore::new(Some(dir.path().to_path_buf()), 2);
+        let mut ids: Vec<&str> = reopened.list().into_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        let mut expected = vec![oldest.as_str(), newest.as_str()];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn test_lists_existing_checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Saved out of timestamp order
+        let mut store = StateStore::new(Some(dir.path().to_path_buf()), 10);
+        let late = store.save(empty_state(Some("late"), 300)).unwrap();
+        let early = store.save(empty_state(Some("early"), 100)).unwrap();
+        let middle = store.save(empty_state(Some("middle"), 200)).unwrap();
+        drop(store);
+
+        let mut reopened = StateStore::new(Some(dir.path().to_path_buf()), 3);
+        assert_eq!(
+            reopened.list(),
+            vec![
+                (early.as_str(), Some("early")),
+                (middle.as_str(), Some("middle")),
+                (late.as_str(), Some("late")),
+            ]
+        );
+        assert_eq!(reopened.latest(), Some(late.as_str()));
+        assert_eq!(reopened.load(&middle).unwrap().name.as_deref(), Some("middle"));
+
+        // On-disk checkpoints count toward eviction
+        reopened.save(empty_state(Some("newest"), 400)).unwrap();
+        assert_eq!(reopened.len(), 3);
+        assert!(reopened.load(&early).is_err());
+    }
+
+    #[test]
+    fn test_lists_unindexed_checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Written newest first, so modification times run backwards
+        let mut store = StateStore::new(Some(dir.path().to_path_buf()), 10)
+            .with_compression(true);
+        let late = store.save(empty_state(Some("late"), 300)).unwrap();
+        let early = store.save(empty_state(Some("early"), 100)).unwrap();
+        drop(store);
+        let plain = empty_state(None, 200);
+        plain.save(dir.path().join(format!("{}.ckpt", plain.id))).unwrap();
+        std::fs::remove_file(dir.path().join(INDEX_FILE)).unwrap();
+
+        // Headers give the ID, name and timestamp; headerless files fall
+        // back to their file name and modification time
+        let reopened = StateStore::new(Some(dir.path().to_path_buf()), 10);
+        assert_eq!(
+            reopened.list(),
+            vec![
+                (early.as_str(), Some("early")),
+                (late.as_str(), Some("late")),
+                (plain.id.as_str(), None),
+            ]
+        );
+        assert_eq!(reopened.load(&early).unwrap().created_at, 100);
+        assert_eq!(reopened.load(&plain.id).unwrap().created_at, 200);
+        assert_eq!(reopened.find_by_name("late").unwrap().unwrap().id, late);
     }
 }
@@ -0,0 +1,178 @@
+//! Tool calling
+//!
+//! Tools are named functions the model can ask the runtime to run. The
+//! model requests one by emitting a JSON object in `<tool_call>` tags:
+//!
+//! ```text
+//! <tool_call>{"name": "add", "arguments": {"a": 2, "b": 3}}</tool_call>
+//! ```
+//!
+//! `Cortex::run_agent` drives the loop of generating, running the
+//! requested tool and feeding its result back.
+
+use crate::{CortexError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const CALL_OPEN: &str = "<tool_call>";
+const CALL_CLOSE: &str = "</tool_call>";
+
+/// A function the model can call with JSON arguments
+pub type ToolFn = Box<dyn Fn(serde_json::Value) -> Result<String> + Send>;
+
+struct Tool {
+    description: String,
+    function: ToolFn,
+}
+
+/// Tools available to an agent, by name
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Tool>,
+}
+
+impl ToolRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a tool, replacing any with the same name
+    ///
+    /// The description tells the model what the tool does and which
+    /// arguments it takes.
+    pub fn with_tool<F>(
+        mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        function: F,
+    ) -> Self
+    where
+        F: Fn(serde_json::Value) -> Result<String> + Send + 'static,
+    {
+        self.register(name, description, function);
+        self
+    }
+
+    /// Add a tool, replacing any with the same name
+    pub fn register<F>(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        function: F,
+    ) where
+        F: Fn(serde_json::Value) -> Result<String> + Send + 'static,
+    {
+        self.tools.insert(
+            name.into(),
+            Tool {
+                description: description.into(),
+                function: Box::new(function),
+            },
+        );
+    }
+
+    /// Names of the registered tools, sorted
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tools.keys().map(String::as_str)
+    }
+
+    /// Check if no tools are registered
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Run the tool a call names
+    pub fn call(&self, call: &ToolCall) -> Result<String> {
+        let tool = self
+            .tools
+            .get(&call.name)
+            .ok_or_else(|| CortexError::Tool(format!("Unknown tool '{}'", call.name)))?;
+        (tool.function)(call.arguments.clone())
+    }
+
+    /// System prompt describing the tools and how to call them
+    pub fn system_prompt(&self) -> String {
+        let mut prompt = String::from("You can call these tools:\n");
+        for (name, tool) in &self.tools {
+            prompt.push_str(&format!("- {}: {}\n", name, tool.description));
+        }
+        prompt.push_str(&format!(
+            "\nTo call a tool, reply with only {}{{\"name\": \"<tool>\", \"arguments\": {{...}}}}{}. \
+             The result comes back in a tool message. Reply normally once you have the answer.",
+            CALL_OPEN, CALL_CLOSE
+        ));
+        prompt
+    }
+}
+
+/// A request from the model to run a tool
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// Find a tool call in model output
+    ///
+    /// Returns `None` if the output contains no `<tool_call>` tag, and a
+    /// `Tool` error if the tagged JSON is malformed. A missing closing tag
+    /// is tolerated, since generation may stop right before it.
+    pub fn parse(output: &str) -> Result<Option<ToolCall>> {
+        let Some(start) = output.find(CALL_OPEN) else {
+            return Ok(None);
+        };
+        let body = &output[start + CALL_OPEN.len()..];
+        let body = body.find(CALL_CLOSE).map_or(body, |end| &body[..end]);
+
+        serde_json::from_str(body.trim())
+            .map(Some)
+            .map_err(|e| CortexError::Tool(format!("Malformed tool call: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_tool_call() {
+        let call = ToolCall::parse(
+            "Let me check.\n<tool_call>{\"name\": \"add\", \"arguments\": {\"a\": 1}}</tool_call>",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(call.name, "add");
+        assert_eq!(call.arguments, json!({ "a": 1 }));
+
+        assert_eq!(ToolCall::parse("The answer is 3.").unwrap(), None);
+        assert!(ToolCall::parse("<tool_call>{\"name\": \"x\"}")
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            ToolCall::parse("<tool_call>{name: add}</tool_call>"),
+            Err(CortexError::Tool(_))
+        ));
+    }
+
+    #[test]
+    fn test_registry_call() {
+        let registry =
+            ToolRegistry::new().with_tool("echo", "Echo the input", |args| Ok(args.to_string()));
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["echo"]);
+        assert!(registry.system_prompt().contains("- echo: Echo the input"));
+
+        let call = |name: &str| ToolCall {
+            name: name.to_string(),
+            arguments: json!([1, 2]),
+        };
+        assert_eq!(registry.call(&call("echo")).unwrap(), "[1,2]");
+        assert!(matches!(
+            registry.call(&call("missing")),
+            Err(CortexError::Tool(_))
+        ));
+    }
+}